@@ -0,0 +1,10 @@
+-- Create Email Change Requests Table
+CREATE TABLE email_change_requests(
+    email_change_token TEXT NOT NULL,
+    PRIMARY KEY (email_change_token),
+    -- 変更対象の購読者
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    -- 確認が完了したら切り替える新しいメールアドレス
+    new_email TEXT NOT NULL,
+    requested_at timestamptz NOT NULL
+);
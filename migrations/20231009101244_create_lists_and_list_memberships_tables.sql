@@ -0,0 +1,36 @@
+-- Create Lists Table
+CREATE TABLE lists(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    -- フォームやAPIでリストを指定するための識別子 (例: engineering-blog)
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
+
+-- Create List Memberships Table
+CREATE TABLE list_memberships(
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    list_id uuid NOT NULL REFERENCES lists (id),
+    PRIMARY KEY (subscriber_id, list_id),
+    -- 確認はリストごとに行う (pending_confirmation または confirmed)
+    status TEXT NOT NULL,
+    joined_at timestamptz NOT NULL
+);
+
+-- Create Subscription Tokens Table
+CREATE TABLE subscription_tokens(
+    subscription_token TEXT NOT NULL,
+    PRIMARY KEY (subscription_token),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    list_id uuid NOT NULL REFERENCES lists (id)
+);
+
+-- listパラメータを省略したときに使う既定のリスト
+INSERT INTO lists (id, slug, name, created_at)
+VALUES ('5f4d9c2e-7a43-4c1b-9a1e-1c3f2b8d6e01', 'newsletter', 'Newsletter', now());
+
+-- 既存の購読者は確認済みとして既定のリストに所属させる
+INSERT INTO list_memberships (subscriber_id, list_id, status, joined_at)
+SELECT id, '5f4d9c2e-7a43-4c1b-9a1e-1c3f2b8d6e01', 'confirmed', subscribed_at
+FROM subscriptions;
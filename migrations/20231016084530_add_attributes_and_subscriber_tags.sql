@@ -0,0 +1,13 @@
+-- Add attributes column to subscriptions
+-- 設定ファイルで定義した任意の属性 (company, plan など) を保存する
+ALTER TABLE subscriptions ADD COLUMN attributes JSONB NOT NULL DEFAULT '{}'::jsonb;
+
+-- Create Subscriber Tags Table
+CREATE TABLE subscriber_tags(
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    tag TEXT NOT NULL,
+    PRIMARY KEY (subscriber_id, tag),
+    tagged_at timestamptz NOT NULL
+);
+-- タグから購読者を探すためのインデックス
+CREATE INDEX subscriber_tags_tag_idx ON subscriber_tags (tag);
@@ -0,0 +1,36 @@
+-- Create Newsletter Issues Table
+CREATE TABLE newsletter_issues(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL,
+    -- 配信対象 (リストのスラッグとセグメント式)
+    lists TEXT[] NOT NULL,
+    segment TEXT NULL,
+    -- scheduled / cancelled / published / failed
+    status TEXT NOT NULL,
+    -- 予約配信の日時。即時配信の場合はNULL
+    scheduled_for timestamptz NULL,
+    created_at timestamptz NOT NULL,
+    -- 配信キューに登録した日時
+    published_at timestamptz NULL
+);
+-- スケジューラが期限の来た予約を探すためのインデックス
+CREATE INDEX newsletter_issues_scheduled_idx ON newsletter_issues (scheduled_for)
+    WHERE status = 'scheduled';
+
+-- Create Issue Deliveries Table
+-- 配信キュー兼配信結果の記録
+CREATE TABLE issue_deliveries(
+    newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+    subscriber_email TEXT NOT NULL,
+    PRIMARY KEY (newsletter_issue_id, subscriber_email),
+    -- queued / sent / failed
+    status TEXT NOT NULL,
+    enqueued_at timestamptz NOT NULL,
+    sent_at timestamptz NULL,
+    failure_reason TEXT NULL
+);
+CREATE INDEX issue_deliveries_queued_idx ON issue_deliveries (enqueued_at)
+    WHERE status = 'queued';
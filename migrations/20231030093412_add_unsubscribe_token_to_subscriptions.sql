@@ -0,0 +1,6 @@
+-- Add unsubscribe token to subscriptions
+-- メールのフッターに入れる配信停止リンク用のトークン。既存の購読者にはランダムな値を割り当てる
+ALTER TABLE subscriptions ADD COLUMN unsubscribe_token TEXT NULL;
+UPDATE subscriptions SET unsubscribe_token = md5(random()::text || id::text);
+ALTER TABLE subscriptions ALTER COLUMN unsubscribe_token SET NOT NULL;
+CREATE UNIQUE INDEX subscriptions_unsubscribe_token_idx ON subscriptions (unsubscribe_token);
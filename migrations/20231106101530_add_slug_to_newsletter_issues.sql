@@ -0,0 +1,9 @@
+-- Add slug to newsletter issues
+-- 公開アーカイブのURL (/archive/{slug}) に使う。既存の号はIDをスラッグにする
+ALTER TABLE newsletter_issues ADD COLUMN slug TEXT NULL;
+UPDATE newsletter_issues SET slug = id::text;
+ALTER TABLE newsletter_issues ALTER COLUMN slug SET NOT NULL;
+CREATE UNIQUE INDEX newsletter_issues_slug_idx ON newsletter_issues (slug);
+-- アーカイブの一覧で配信済みの号を新しい順に並べるためのインデックス
+CREATE INDEX newsletter_issues_published_idx ON newsletter_issues (published_at)
+    WHERE status = 'published';
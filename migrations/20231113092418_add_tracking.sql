@@ -0,0 +1,19 @@
+-- Add open and click tracking
+-- 号ごとに開封とクリックを計測するかどうか
+ALTER TABLE newsletter_issues ADD COLUMN track_opens BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE newsletter_issues ADD COLUMN track_clicks BOOLEAN NOT NULL DEFAULT false;
+
+-- Create Tracking Events Table
+-- 計測用のURLへのアクセスを購読者と号ごとに記録する
+CREATE TABLE tracking_events(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    -- open / click
+    kind TEXT NOT NULL,
+    -- クリックされたリンクの元のURL。開封の場合はNULL
+    url TEXT NULL,
+    occurred_at timestamptz NOT NULL
+);
+CREATE INDEX tracking_events_issue_idx ON tracking_events (newsletter_issue_id, kind);
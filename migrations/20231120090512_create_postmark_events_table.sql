@@ -0,0 +1,19 @@
+-- Add Postmark message ids to issue deliveries
+-- バウンスや配信完了の通知を配信タスクと突き合わせるために、送信時のMessageIDを保存する
+ALTER TABLE issue_deliveries ADD COLUMN message_id TEXT NULL;
+-- bounced (status) になった日時と、受信側のサーバに届いた日時
+ALTER TABLE issue_deliveries ADD COLUMN bounced_at timestamptz NULL;
+ALTER TABLE issue_deliveries ADD COLUMN delivered_at timestamptz NULL;
+CREATE INDEX issue_deliveries_message_id_idx ON issue_deliveries (message_id);
+
+-- Create Postmark Events Table
+-- 処理済みのWebhookのイベント。同じイベントが再送されても一度だけ処理する
+CREATE TABLE postmark_events(
+    -- "<RecordType>:<イベントのID>"
+    event_id TEXT NOT NULL,
+    PRIMARY KEY (event_id),
+    record_type TEXT NOT NULL,
+    email TEXT NOT NULL,
+    payload jsonb NOT NULL,
+    received_at timestamptz NOT NULL
+);
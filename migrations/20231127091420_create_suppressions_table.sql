@@ -0,0 +1,12 @@
+-- Create Suppressions Table
+-- 送信してはいけないアドレス。配信キューへの登録時と送信の直前に確認する
+CREATE TABLE suppressions(
+    -- 大文字小文字の違いで抜けないように小文字で保存する
+    email TEXT NOT NULL,
+    PRIMARY KEY (email),
+    -- hard_bounce / spam_complaint / manual
+    reason TEXT NOT NULL,
+    -- postmark_webhook / admin
+    source TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
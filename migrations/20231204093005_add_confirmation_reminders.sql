@@ -0,0 +1,8 @@
+-- Add creation time to subscription tokens
+-- 確認リンクの有効期限を判定するために発行日時を記録する (既存のトークンはマイグレーション時点で発行したものとする)
+ALTER TABLE subscription_tokens ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
+ALTER TABLE subscription_tokens ALTER COLUMN created_at DROP DEFAULT;
+
+-- Add reminder timestamp to list memberships
+-- 確認を促すリマインダーを送った日時。リマインダーは1回だけ送る
+ALTER TABLE list_memberships ADD COLUMN reminder_sent_at timestamptz NULL;
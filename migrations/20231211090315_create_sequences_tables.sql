@@ -0,0 +1,39 @@
+-- Create Sequences Table
+-- リストの購読を確認した購読者に、決まった間隔で順番に送るメールのシリーズ
+CREATE TABLE sequences(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    list_id uuid NOT NULL REFERENCES lists (id),
+    name TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
+
+-- Create Sequence Steps Table
+CREATE TABLE sequence_steps(
+    sequence_id uuid NOT NULL REFERENCES sequences (id),
+    -- 0から始まる送信順
+    position INT NOT NULL,
+    PRIMARY KEY (sequence_id, position),
+    -- 購読を確認してから送るまでの日数
+    delay_days INT NOT NULL,
+    title TEXT NOT NULL,
+    text_content TEXT NOT NULL,
+    html_content TEXT NOT NULL
+);
+
+-- Create Sequence Enrollments Table
+-- 購読者ごとのシリーズの進み具合
+CREATE TABLE sequence_enrollments(
+    sequence_id uuid NOT NULL REFERENCES sequences (id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    PRIMARY KEY (sequence_id, subscriber_id),
+    -- active / completed / exited (配信停止などで途中で抜けた)
+    status TEXT NOT NULL,
+    enrolled_at timestamptz NOT NULL,
+    -- 次に送るステップと送る日時 (activeのときだけ)
+    next_position INT NOT NULL,
+    next_due_at timestamptz NULL,
+    last_sent_at timestamptz NULL
+);
+CREATE INDEX sequence_enrollments_due_idx ON sequence_enrollments (next_due_at)
+    WHERE status = 'active';
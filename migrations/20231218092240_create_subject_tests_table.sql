@@ -0,0 +1,22 @@
+-- Create Subject Tests Table
+-- 号の件名のA/Bテスト。抽出した購読者に2つの件名を送り、開封率の高い件名を残りの購読者に送る
+CREATE TABLE subject_tests(
+    newsletter_issue_id uuid NOT NULL REFERENCES newsletter_issues (id),
+    PRIMARY KEY (newsletter_issue_id),
+    -- 件名A は号のタイトル
+    subject_b TEXT NOT NULL,
+    -- 配信対象のうちテストに使う割合 (%)
+    sample_percent INT NOT NULL,
+    -- 開封を計測する時間
+    window_hours INT NOT NULL,
+    -- 判定する日時 (テスト用の配信をキューに登録したときに決まる)
+    decide_at timestamptz NULL,
+    -- a / b (判定前はNULL)
+    winner TEXT NULL,
+    decided_at timestamptz NULL
+);
+CREATE INDEX subject_tests_decide_at_idx ON subject_tests (decide_at)
+    WHERE winner IS NULL;
+
+-- 配信タスクごとに送った件名 (a / b)。テストのない号はNULL
+ALTER TABLE issue_deliveries ADD COLUMN variant TEXT NULL;
@@ -0,0 +1,9 @@
+-- 送信サービスごとの1日の送信数
+-- 再起動しても1日の上限を超えないようにデータベースで数える
+CREATE TABLE email_send_quotas(
+    provider TEXT NOT NULL,
+    -- UTCの日付
+    day DATE NOT NULL,
+    sent_count INTEGER NOT NULL,
+    PRIMARY KEY (provider, day)
+);
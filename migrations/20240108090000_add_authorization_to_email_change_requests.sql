@@ -0,0 +1,5 @@
+-- Add Authorization To Email Change Requests
+-- 変更は現在のアドレスに送ったリンクで承認されてから、新しいアドレスの確認に進む
+ALTER TABLE email_change_requests ADD COLUMN authorization_token TEXT NULL UNIQUE;
+-- 承認前はNULL。承認されていない申請では切り替えない
+ALTER TABLE email_change_requests ADD COLUMN authorized_at timestamptz NULL;
@@ -0,0 +1,3 @@
+-- Add Attributes To Subscription Tokens
+-- 既存の購読者が送った追加属性は、確認リンクで持ち主が確認するまで購読者に反映しない
+ALTER TABLE subscription_tokens ADD COLUMN attributes jsonb NULL;
@@ -0,0 +1,13 @@
+-- 1日の送信数の上限に達したために後で送るメール
+-- 購読の確認などHTTPのリクエストの中で送るメールは、上限に達していてもリクエストを失敗させずにここに入れる
+CREATE TABLE deferred_emails(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    -- transactional / broadcast
+    kind TEXT NOT NULL,
+    deferred_at timestamptz NOT NULL
+);
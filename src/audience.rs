@@ -0,0 +1,149 @@
+use crate::domain::ListSlug;
+use crate::segment::Segment;
+use sqlx::{PgPool, Postgres, QueryBuilder, Row};
+
+// 配信対象。指定したリストのいずれか (省略時は任意のリスト) で確認済みの購読者のうち、
+// セグメント式に一致する購読者を表す
+#[derive(Debug)]
+pub struct Audience {
+    lists: Vec<ListSlug>,
+    segment: Option<Segment>,
+    // パース前のセグメント式 (予約配信のために保存する)
+    segment_source: Option<String>,
+}
+
+// プレビュー用の購読者の情報
+#[derive(serde::Serialize)]
+pub struct AudienceMember {
+    pub email: String,
+    pub name: String,
+}
+
+impl Audience {
+    /// リストのスラッグとセグメント式から配信対象を組み立てる
+    /// 未確認の購読者に送らないよう、リストかセグメントのどちらかは必須とする
+    pub fn parse(lists: Vec<String>, segment: Option<String>) -> Result<Audience, String> {
+        // 同じリストが重複して指定されていても1つとして扱う
+        let mut lists = lists;
+        lists.sort();
+        lists.dedup();
+        let lists = lists
+            .into_iter()
+            .map(ListSlug::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        let segment_source = segment;
+        let segment = segment_source.as_deref().map(Segment::parse).transpose()?;
+        if lists.is_empty() && segment.is_none() {
+            return Err("Either lists or a segment must be provided.".into());
+        }
+        Ok(Self {
+            lists,
+            segment,
+            segment_source,
+        })
+    }
+
+    /// 指定されたリストがすべて存在するかを返す
+    #[tracing::instrument(name = "Check that the audience lists exist", skip(self, pool))]
+    pub async fn lists_exist(&self, pool: &PgPool) -> Result<bool, sqlx::Error> {
+        let slugs = self.list_slugs();
+        let result = sqlx::query!(
+            r#"SELECT COUNT(*) AS "count!" FROM lists WHERE slug = ANY($1)"#,
+            &slugs
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        Ok(result.count == slugs.len() as i64)
+    }
+
+    /// 配信対象の人数を返す
+    #[tracing::instrument(name = "Count the audience", skip(self, pool))]
+    pub async fn count(&self, pool: &PgPool) -> Result<i64, sqlx::Error> {
+        let mut builder = QueryBuilder::new("SELECT COUNT(*) FROM subscriptions s");
+        self.push_where(&mut builder);
+        let row = builder.build().fetch_one(pool).await.map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        row.try_get(0)
+    }
+
+    /// 配信対象から登録の古い順に最大limit人を返す
+    #[tracing::instrument(name = "Sample the audience", skip(self, pool))]
+    pub async fn sample(
+        &self,
+        pool: &PgPool,
+        limit: i64,
+    ) -> Result<Vec<AudienceMember>, sqlx::Error> {
+        let mut builder = QueryBuilder::new("SELECT s.email, s.name FROM subscriptions s");
+        self.push_where(&mut builder);
+        builder.push(" ORDER BY s.subscribed_at LIMIT ");
+        builder.push_bind(limit);
+        let rows = builder.build().fetch_all(pool).await.map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+        rows.into_iter()
+            .map(|r| {
+                Ok(AudienceMember {
+                    email: r.try_get("email")?,
+                    name: r.try_get("name")?,
+                })
+            })
+            .collect()
+    }
+
+    /// 保存用にリストのスラッグを返す
+    pub fn list_slugs(&self) -> Vec<String> {
+        self.lists.iter().map(|l| l.as_ref().to_string()).collect()
+    }
+
+    /// 保存用にセグメント式を返す
+    pub fn segment(&self) -> Option<&str> {
+        self.segment_source.as_deref()
+    }
+
+    /// 確認済みの所属があること、抑止リストに載っていないこと、およびセグメント式の条件をWHERE句に追加する
+    /// 呼び出し側でsubscriptionsをsとして参照していること
+    pub fn push_where(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        builder.push(
+            " WHERE EXISTS (SELECT 1 FROM list_memberships m JOIN lists l ON l.id = m.list_id \
+            WHERE m.subscriber_id = s.id AND m.status = 'confirmed'",
+        );
+        if !self.lists.is_empty() {
+            builder.push(" AND l.slug = ANY(");
+            builder.push_bind(self.list_slugs());
+            builder.push(")");
+        }
+        builder.push(")");
+        builder
+            .push(" AND NOT EXISTS (SELECT 1 FROM suppressions x WHERE x.email = lower(s.email))");
+        if let Some(segment) = &self.segment {
+            builder.push(" AND ");
+            segment.push_condition(builder);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audience::Audience;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn either_lists_or_a_segment_is_required() {
+        assert_err!(Audience::parse(vec![], None));
+        assert_ok!(Audience::parse(vec!["newsletter".into()], None));
+        assert_ok!(Audience::parse(vec![], Some("tag:beta".into())));
+    }
+
+    #[test]
+    fn invalid_lists_and_segments_are_rejected() {
+        assert_err!(Audience::parse(vec!["Not A Slug".into()], None));
+        assert_err!(Audience::parse(vec![], Some("tag:".into())));
+    }
+}
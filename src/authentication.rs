@@ -0,0 +1,135 @@
+use crate::configuration::AdminSettings;
+use actix_web::http::header::HeaderMap;
+use base64::Engine;
+use secrecy::{ExposeSecret, Secret};
+use subtle::ConstantTimeEq;
+
+// Basic認証で送られてきた認証情報 (パスワードはSecretなのでDebug出力ではマスクされる)
+#[derive(Debug)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+/// AuthorizationヘッダからBasic認証の認証情報を取り出す
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, String> {
+    // ヘッダの値は "Basic <base64でエンコードしたusername:password>" の形式
+    let header_value = headers
+        .get("Authorization")
+        .ok_or("The 'Authorization' header was missing")?
+        .to_str()
+        .map_err(|_| "The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .ok_or("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encoded_segment)
+        .map_err(|_| "Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .map_err(|_| "The decoded credential string is not valid UTF8.")?;
+
+    // 最初の':'でユーザー名とパスワードに分割する
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .ok_or("A password must be provided in 'Basic' auth.")?;
+    Ok(Credentials {
+        username: username.to_string(),
+        password: Secret::new(password.to_string()),
+    })
+}
+
+/// 認証情報が設定ファイルの管理者と一致するかを返す
+/// 応答時間からパスワードを推測されないように、定数時間で比較する
+pub fn validate_credentials(credentials: &Credentials, admin: &AdminSettings) -> bool {
+    let username = credentials
+        .username
+        .as_bytes()
+        .ct_eq(admin.username.as_bytes());
+    let password = credentials
+        .password
+        .expose_secret()
+        .as_bytes()
+        .ct_eq(admin.password.expose_secret().as_bytes());
+    (username & password).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{basic_authentication, validate_credentials, Credentials};
+    use crate::configuration::AdminSettings;
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use base64::Engine;
+    use claim::{assert_err, assert_ok};
+    use secrecy::{ExposeSecret, Secret};
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("authorization"),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn valid_basic_credentials_are_parsed() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode("admin:pa:ss");
+        let credentials = assert_ok!(basic_authentication(&headers(&format!(
+            "Basic {}",
+            encoded
+        ))));
+        assert_eq!(credentials.username, "admin");
+        // パスワードに':'が含まれていてもよい
+        assert_eq!(credentials.password.expose_secret(), "pa:ss");
+    }
+
+    #[test]
+    fn a_missing_header_is_rejected() {
+        assert_err!(basic_authentication(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn a_non_basic_scheme_is_rejected() {
+        assert_err!(basic_authentication(&headers("Bearer some-token")));
+    }
+
+    #[test]
+    fn credentials_without_a_password_are_rejected() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode("admin");
+        assert_err!(basic_authentication(&headers(&format!(
+            "Basic {}",
+            encoded
+        ))));
+    }
+
+    fn credentials(username: &str, password: &str) -> Credentials {
+        Credentials {
+            username: username.into(),
+            password: Secret::new(password.into()),
+        }
+    }
+
+    #[test]
+    fn only_the_configured_admin_is_valid() {
+        let admin = AdminSettings {
+            username: "admin".into(),
+            password: Secret::new("correct horse".into()),
+        };
+        assert!(validate_credentials(
+            &credentials("admin", "correct horse"),
+            &admin
+        ));
+        assert!(!validate_credentials(
+            &credentials("admin", "correct horsf"),
+            &admin
+        ));
+        assert!(!validate_credentials(
+            &credentials("admin", "correct"),
+            &admin
+        ));
+        assert!(!validate_credentials(
+            &credentials("editor", "correct horse"),
+            &admin
+        ));
+    }
+}
@@ -0,0 +1,252 @@
+// 送信サービスのサーキットブレーカー
+// 送信サービスの障害中に、すべての送信がタイムアウトまで待たされないようにする
+// 連続して失敗すると回路を開いて送信をすぐに失敗させ、一定時間後に1件だけ試しに送って復旧を確認する
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CircuitState {
+    // 通常どおり送信する
+    Closed,
+    // 送信せずにすぐ失敗させる
+    Open,
+    // 復旧を確認するために1件だけ送信している
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+
+    // メトリクスのゲージとして出力する値
+    pub fn as_gauge(&self) -> u8 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        }
+    }
+}
+
+// 起動してからの回数 (メトリクスのカウンタとして出力する)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CircuitCounters {
+    // 回路を開いた回数
+    pub opened: u64,
+    // 回路が開いていたために送信しなかった回数
+    pub rejected: u64,
+}
+
+/// cloneしたものは状態を共有するので、同じ送信サービスへの送信はまとめて判定される
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    // 回路を開くまでの連続した失敗の回数
+    failure_threshold: u32,
+    // 回路を開いてから試しに送るまでの時間
+    open_duration: Duration,
+    state: CircuitState,
+    consecutive_failures: u32,
+    // Openでは試しに送れるようになる日時、HalfOpenでは試しに送り始めた日時
+    changed_at: Instant,
+    counters: CircuitCounters,
+}
+
+impl Inner {
+    fn try_acquire(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if now >= self.changed_at => {
+                self.transition(CircuitState::HalfOpen, now);
+                true
+            }
+            // 試しに送ったリクエストが結果を記録せずに中断された場合に備え、
+            // open_durationが過ぎたら次のリクエストで試し直す
+            CircuitState::HalfOpen if now >= self.changed_at + self.open_duration => {
+                self.changed_at = now;
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => {
+                self.counters.rejected += 1;
+                false
+            }
+        }
+    }
+
+    fn record_success(&mut self, now: Instant) {
+        self.consecutive_failures = 0;
+        if self.state != CircuitState::Closed {
+            self.transition(CircuitState::Closed, now);
+        }
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        let should_open = match self.state {
+            CircuitState::Closed => self.consecutive_failures >= self.failure_threshold,
+            // 試しに送って失敗したら、また一定時間開く
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if should_open {
+            self.transition(CircuitState::Open, now + self.open_duration);
+            self.counters.opened += 1;
+        }
+    }
+
+    fn transition(&mut self, state: CircuitState, changed_at: Instant) {
+        // 状態の変化は構造化ログとして出力し、ログ基盤でも監視できるようにする
+        match state {
+            CircuitState::Open => tracing::warn!(
+                circuit_state = state.as_str(),
+                consecutive_failures = self.consecutive_failures,
+                "Opened the email provider circuit"
+            ),
+            _ => tracing::info!(
+                circuit_state = state.as_str(),
+                "Email provider circuit changed state"
+            ),
+        }
+        self.state = state;
+        self.changed_at = changed_at;
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                failure_threshold: failure_threshold.max(1),
+                open_duration,
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                changed_at: Instant::now(),
+                counters: CircuitCounters::default(),
+            })),
+        }
+    }
+
+    /// 送信してよければtrueを返す。回路が開いていて、まだ試しに送る時間でなければfalseを返す
+    pub fn try_acquire(&self) -> bool {
+        self.inner.lock().unwrap().try_acquire(Instant::now())
+    }
+
+    pub fn record_success(&self) {
+        self.inner.lock().unwrap().record_success(Instant::now());
+    }
+
+    pub fn record_failure(&self) {
+        self.inner.lock().unwrap().record_failure(Instant::now());
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// 今送信しようとしても失敗させられる状態かどうか
+    /// 試しに送れる時間が来ていればfalseを返す (状態は変えない)
+    pub fn is_rejecting(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.state {
+            CircuitState::Closed => false,
+            CircuitState::Open => now < inner.changed_at,
+            CircuitState::HalfOpen => now < inner.changed_at + inner.open_duration,
+        }
+    }
+
+    pub fn counters(&self) -> CircuitCounters {
+        self.inner.lock().unwrap().counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    const OPEN_DURATION: Duration = Duration::from_secs(30);
+
+    fn open_breaker(now: Instant) -> CircuitBreaker {
+        let breaker = CircuitBreaker::new(3, OPEN_DURATION);
+        {
+            let mut inner = breaker.inner.lock().unwrap();
+            for _ in 0..3 {
+                assert!(inner.try_acquire(now));
+                inner.record_failure(now);
+            }
+        }
+        breaker
+    }
+
+    #[test]
+    fn the_circuit_opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let breaker = open_breaker(now);
+        let mut inner = breaker.inner.lock().unwrap();
+        assert_eq!(inner.state, CircuitState::Open);
+        assert!(!inner.try_acquire(now + Duration::from_secs(1)));
+        assert_eq!(inner.counters.opened, 1);
+        assert_eq!(inner.counters.rejected, 1);
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let breaker = CircuitBreaker::new(3, OPEN_DURATION);
+        let now = Instant::now();
+        let mut inner = breaker.inner.lock().unwrap();
+        inner.record_failure(now);
+        inner.record_failure(now);
+        inner.record_success(now);
+        inner.record_failure(now);
+        inner.record_failure(now);
+        assert_eq!(inner.state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn only_one_probe_is_sent_while_half_open() {
+        let now = Instant::now();
+        let breaker = open_breaker(now);
+        let mut inner = breaker.inner.lock().unwrap();
+        let later = now + OPEN_DURATION;
+        assert!(inner.try_acquire(later));
+        assert_eq!(inner.state, CircuitState::HalfOpen);
+        assert!(!inner.try_acquire(later));
+    }
+
+    #[test]
+    fn a_successful_probe_closes_the_circuit() {
+        let now = Instant::now();
+        let breaker = open_breaker(now);
+        let mut inner = breaker.inner.lock().unwrap();
+        let later = now + OPEN_DURATION;
+        inner.try_acquire(later);
+        inner.record_success(later);
+        assert_eq!(inner.state, CircuitState::Closed);
+        assert!(inner.try_acquire(later));
+    }
+
+    #[test]
+    fn a_failed_probe_opens_the_circuit_again() {
+        let now = Instant::now();
+        let breaker = open_breaker(now);
+        let mut inner = breaker.inner.lock().unwrap();
+        let later = now + OPEN_DURATION;
+        inner.try_acquire(later);
+        inner.record_failure(later);
+        assert_eq!(inner.state, CircuitState::Open);
+        assert!(!inner.try_acquire(later + Duration::from_secs(1)));
+        assert!(inner.try_acquire(later + OPEN_DURATION));
+        assert_eq!(inner.counters.opened, 2);
+    }
+}
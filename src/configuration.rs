@@ -1,4 +1,8 @@
+use crate::dkim::{DkimAlgorithm, DkimSigner};
 use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
@@ -14,6 +18,63 @@ pub struct Settings {
     pub application: ApplicationSettings,
     // Email送信用の設定
     pub email_client: EmailClientSettings,
+    // 管理者用APIの設定
+    pub admin: AdminSettings,
+    // 配信キューや予約配信などのバックグラウンド処理の設定
+    pub background_jobs: BackgroundJobSettings,
+    // 確認されない購読や長く反応のない購読者を整理する設定
+    pub retention: RetentionSettings,
+    // 購読の確認リンクの有効期限とリマインダーの設定
+    pub confirmation: ConfirmationSettings,
+    // メール本文のテンプレートの設定
+    pub templates: TemplateSettings,
+    // 購読時に受け付ける追加属性の定義 (定義されていない項目は受け付けない)
+    #[serde(default)]
+    pub subscriber_attributes: Vec<SubscriberAttributeSettings>,
+    // 配信前のテスト送信を受け取る社内の宛先
+    #[serde(default)]
+    pub test_recipients: Vec<TestRecipientSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TestRecipientSettings {
+    pub email: String,
+    // テンプレートの{{ subscriber.name }}に入る名前
+    pub name: String,
+}
+
+impl TestRecipientSettings {
+    pub fn parse(&self) -> Result<SubscriberEmail, String> {
+        SubscriberEmail::parse(self.email.clone())
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SubscriberAttributeSettings {
+    // フォームのフィールド名兼attributesのキー
+    pub name: String,
+    // 値の型
+    pub kind: SubscriberAttributeKind,
+    // 必須項目かどうか
+    #[serde(default)]
+    pub required: bool,
+}
+
+// 属性値の型。フォームの文字列をこの型のJSON値に変換して保存する
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubscriberAttributeKind {
+    String,
+    Integer,
+    Boolean,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct AdminSettings {
+    // 管理者用APIのBasic認証のユーザー名
+    pub username: String,
+    // 管理者用APIのBasic認証のパスワード
+    pub password: Secret<String>,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -26,9 +87,209 @@ pub struct EmailClientSettings {
     pub authorization_token: Secret<String>,
     // リクエストのタイムアウト時間
     pub timeout_milliseconds: u64,
+    // バウンスなどを通知するPostmarkのWebhookの認証情報
+    pub webhook: PostmarkWebhookSettings,
+    // 送信サービスの送信数の制限。省略すると制限しない
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    // 送信サービスの障害中に送信をすぐ失敗させるサーキットブレーカーの設定
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerSettings,
+    // メールの種類ごとに使うPostmarkのメッセージストリーム
+    #[serde(default)]
+    pub message_streams: MessageStreamSettings,
+    // Postmarkが5xxを返すかタイムアウトしたときに、順番に使う送信サービス (PostmarkのAPIで送信できること)
+    #[serde(default)]
+    pub fallback_providers: Vec<EmailProviderSettings>,
+    // Postmarkと予備の送信サービスがすべて使えないときに使うSMTPリレー。省略すると使わない
+    #[serde(default)]
+    pub smtp_relay: Option<SmtpRelaySettings>,
+    // SMTPリレーに送るMIME形式のメッセージに付けるDKIM署名。省略すると署名しない
+    #[serde(default)]
+    pub dkim: Option<DkimSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpRelaySettings {
+    // ログやメトリクス、1日の送信数に使う名前
+    pub name: String,
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    // 省略すると認証せずに送る
+    #[serde(default)]
+    pub credentials: Option<SmtpCredentials>,
+    // trueにするとSTARTTLSを使わずに接続する (同じホストで動かすリレー向け)
+    #[serde(default)]
+    pub plaintext: bool,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpCredentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+impl SmtpRelaySettings {
+    pub fn transport(
+        &self,
+        timeout: std::time::Duration,
+    ) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+        let builder = if self.plaintext {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.host)
+                .map_err(|e| e.to_string())?
+        };
+        let builder = builder.port(self.port).timeout(Some(timeout));
+        let builder = match &self.credentials {
+            Some(credentials) => builder.credentials(Credentials::new(
+                credentials.username.clone(),
+                credentials.password.expose_secret().clone(),
+            )),
+            None => builder,
+        };
+        Ok(builder.build())
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DkimSettings {
+    // 署名するドメイン (d=)
+    pub domain: String,
+    // 公開鍵を置いたDNSレコードのセレクタ (s=)
+    pub selector: String,
+    // 秘密鍵の種類 (rsa か ed25519)
+    pub algorithm: DkimAlgorithm,
+    // PEM形式の秘密鍵
+    pub private_key: Secret<String>,
+}
+
+impl DkimSettings {
+    pub fn signer(&self) -> Result<DkimSigner, String> {
+        DkimSigner::from_pem(
+            self.domain.clone(),
+            self.selector.clone(),
+            self.algorithm,
+            self.private_key.expose_secret(),
+        )
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct MessageStreamSettings {
+    // 購読の確認やアドレス変更の確認などに使うストリーム
+    pub transactional: String,
+    // ニュースレターやシーケンスに使うストリーム
+    pub broadcast: String,
+}
+
+// Postmarkがサーバを作ると用意されるストリーム
+impl Default for MessageStreamSettings {
+    fn default() -> Self {
+        Self {
+            transactional: "outbound".into(),
+            broadcast: "broadcast".into(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailProviderSettings {
+    // ログやメトリクスに出力する名前
+    pub name: String,
+    pub base_url: String,
+    pub authorization_token: Secret<String>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct CircuitBreakerSettings {
+    // この回数続けて失敗したら回路を開く
+    pub failure_threshold: u32,
+    // 回路を開いてから、試しに1件送るまでの秒数
+    pub open_seconds: u64,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_seconds: 30,
+        }
+    }
+}
+
+impl CircuitBreakerSettings {
+    pub fn open_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.open_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct RateLimitSettings {
+    // 1秒あたりの送信数の上限。超える分は送信を待たせる
+    pub per_second: Option<u32>,
+    // 続けて送れる数の上限。省略するとper_secondと同じ
+    pub burst: Option<u32>,
+    // 1日 (UTC) の送信数の上限。配信キューなどのバックグラウンド処理は上限に達すると翌日まで送信を止める
+    pub daily_quota: Option<u32>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PostmarkWebhookSettings {
+    // WebhookのURLに設定するBasic認証のユーザー名
+    pub username: String,
+    // Basic認証のパスワード。X-Postmark-Webhook-Secretヘッダで送ることもできる
+    pub secret: Secret<String>,
 }
 
 impl EmailClientSettings {
+    /// 設定からEmailClientを作成する
+    pub fn client(self) -> EmailClient {
+        let sender_email = self.sender().expect("Invalid sender email address.");
+        let timeout = self.timeout();
+        let email_client = EmailClient::new(
+            self.base_url,
+            sender_email,
+            self.authorization_token,
+            timeout,
+        )
+        .with_rate_limit(&self.rate_limit)
+        .with_circuit_breaker(&self.circuit_breaker)
+        .with_message_streams(self.message_streams);
+        let email_client = match &self.dkim {
+            Some(dkim) => {
+                email_client.with_dkim_signer(dkim.signer().expect("Invalid DKIM settings."))
+            }
+            None => email_client,
+        };
+        let email_client =
+            self.fallback_providers
+                .into_iter()
+                .fold(email_client, |email_client, provider| {
+                    email_client.with_fallback_provider(
+                        provider.name,
+                        provider.base_url,
+                        provider.authorization_token,
+                        &provider.rate_limit,
+                    )
+                });
+        match &self.smtp_relay {
+            Some(smtp_relay) => email_client.with_smtp_relay(
+                smtp_relay.name.clone(),
+                smtp_relay
+                    .transport(timeout)
+                    .expect("Invalid SMTP relay settings."),
+                &smtp_relay.rate_limit,
+            ),
+            None => email_client,
+        }
+    }
+
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
@@ -38,6 +299,61 @@ impl EmailClientSettings {
     }
 }
 
+#[derive(serde::Deserialize, Clone)]
+pub struct TemplateSettings {
+    // テンプレートを置くディレクトリ (layouts, partials, emailsを含む)
+    pub directory: String,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct BackgroundJobSettings {
+    // falseにするとアプリケーション起動時にバックグラウンド処理を起動しない (テスト用)
+    pub enabled: bool,
+    // 処理待ちがないときに次に確認するまでの間隔
+    pub poll_interval_milliseconds: u64,
+}
+
+impl BackgroundJobSettings {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.poll_interval_milliseconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RetentionSettings {
+    // 確認されないまま、この日数を過ぎた購読を削除する
+    pub unconfirmed_days: u32,
+    // この月数のあいだ開封もクリックもない購読者にinactiveタグを付ける。省略すると付けない
+    #[serde(default)]
+    pub inactive_months: Option<u32>,
+    // 整理を実行する間隔
+    pub run_interval_minutes: u64,
+}
+
+impl RetentionSettings {
+    pub fn run_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.run_interval_minutes * 60)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ConfirmationSettings {
+    // 申し込みからこの時間が過ぎても確認されていなければリマインダーを送る
+    pub reminder_after_hours: u32,
+    // 確認リンクの有効期限 (リマインダーには新しいリンクを入れる)
+    pub token_lifetime_hours: u32,
+}
+
+impl ConfirmationSettings {
+    pub fn reminder_after(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.reminder_after_hours.into())
+    }
+
+    pub fn token_lifetime(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.token_lifetime_hours.into())
+    }
+}
+
 #[derive(serde::Deserialize, Clone)]
 pub struct ApplicationSettings {
     // アプリケーションのポート番号 serdeのdeserialize_with属性を使って文字列から数値に変換する
@@ -45,6 +361,10 @@ pub struct ApplicationSettings {
     pub port: u16,
     // アプリケーションのホスト名
     pub host: String,
+    // メール内のリンクなどに使うアプリケーションのベースURL
+    pub base_url: String,
+    // 開封・クリックの計測用URLの署名に使う鍵
+    pub hmac_secret: Secret<String>,
 }
 
 #[derive(serde::Deserialize, Clone)]
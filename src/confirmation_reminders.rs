@@ -0,0 +1,179 @@
+// 確認を促すリマインダー
+// 申し込みから一定時間が過ぎても確認されていないリストへの所属ごとに、一度だけ新しい確認リンクを送る
+use crate::configuration::ConfirmationSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_message::EmailMessage;
+use crate::email_templates::{ConfirmationReminderEmail, EmailTemplates, Recipient};
+use crate::issue_delivery_worker::{run_tasks_until_stopped, DeliveryError, ExecutionOutcome};
+use crate::routes::{confirmation_link, generate_token};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+// リマインダーを送る所属
+struct PendingMembership {
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    email: String,
+    name: String,
+    list_name: String,
+}
+
+/// リマインダーを送るべき所属を1件取り出して送信する
+#[tracing::instrument(skip_all, fields(subscriber_email = tracing::field::Empty), err)]
+pub async fn try_send_confirmation_reminder(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+    settings: &ConfirmationSettings,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    // 送信サービスの障害中は、送れなかったリマインダーを送信済みにしないように取り出さない
+    if email_client.is_unavailable() {
+        return Ok(ExecutionOutcome::ProviderUnavailable);
+    }
+    let mut transaction = pool.begin().await?;
+    let membership = match dequeue_pending_membership(&mut transaction, settings).await? {
+        Some(membership) => membership,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current().record("subscriber_email", display(&membership.email));
+
+    // 以前のリンクは期限切れの可能性があるので、新しいトークンを発行する
+    // 使えるリンクが複数残らないように以前のトークンは削除し、確認待ちの属性は最新のものを引き継ぐ
+    let subscription_token = generate_token();
+    sqlx::query!(
+        r#"
+    WITH previous AS (
+        DELETE FROM subscription_tokens WHERE subscriber_id = $1 AND list_id = $2
+        RETURNING attributes, created_at
+    )
+    INSERT INTO subscription_tokens (subscription_token, subscriber_id, list_id, created_at, attributes)
+    VALUES ($3, $1, $2, $4, (SELECT attributes FROM previous ORDER BY created_at DESC LIMIT 1))
+            "#,
+        membership.subscriber_id,
+        membership.list_id,
+        subscription_token,
+        Utc::now()
+    )
+    .execute(&mut *transaction)
+    .await?;
+    match send_reminder(
+        email_client,
+        templates,
+        base_url,
+        &membership,
+        &subscription_token,
+    )
+    .await
+    {
+        Ok(()) => {}
+        // 1日の上限に達している場合はロールバックして、次の確認のときに送る
+        Err(DeliveryError::QuotaExhausted) => return Ok(ExecutionOutcome::QuotaExhausted),
+        Err(DeliveryError::Database(e)) => return Err(e),
+        // 送信に失敗しても再送はしない (何度も送ることになるよりはよい)
+        Err(e) => {
+            tracing::error!(
+                error.message = %e,
+                "Failed to send a confirmation reminder. Skipping.",
+            );
+        }
+    }
+    sqlx::query!(
+        r#"
+    UPDATE list_memberships SET reminder_sent_at = $1
+    WHERE subscriber_id = $2 AND list_id = $3
+            "#,
+        Utc::now(),
+        membership.subscriber_id,
+        membership.list_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+// 他のワーカーが処理中の所属を飛ばして、リマインダーを送っていない未確認の所属を1件ロックして取り出す
+// 抑止リストに載っているアドレスには送らない
+async fn dequeue_pending_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    settings: &ConfirmationSettings,
+) -> Result<Option<PendingMembership>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+    SELECT m.subscriber_id, m.list_id, s.email, s.name, l.name AS list_name
+    FROM list_memberships m
+    JOIN subscriptions s ON s.id = m.subscriber_id
+    JOIN lists l ON l.id = m.list_id
+    WHERE m.status = 'pending_confirmation'
+        AND m.reminder_sent_at IS NULL
+        AND m.joined_at <= $1
+        AND NOT EXISTS (SELECT 1 FROM suppressions x WHERE x.email = lower(s.email))
+    ORDER BY m.joined_at
+    FOR UPDATE OF m
+    SKIP LOCKED
+    LIMIT 1
+            "#,
+        Utc::now() - settings.reminder_after()
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(r.map(|r| PendingMembership {
+        subscriber_id: r.subscriber_id,
+        list_id: r.list_id,
+        email: r.email,
+        name: r.name,
+        list_name: r.list_name,
+    }))
+}
+
+async fn send_reminder(
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+    membership: &PendingMembership,
+    subscription_token: &str,
+) -> Result<(), DeliveryError> {
+    let recipient = SubscriberEmail::parse(membership.email.clone())?;
+    let confirmation_link = confirmation_link(base_url, subscription_token);
+    let email = templates
+        .render(&ConfirmationReminderEmail {
+            subscriber: Recipient {
+                name: &membership.name,
+                email: recipient.as_ref(),
+            },
+            list_name: &membership.list_name,
+            confirmation_link: &confirmation_link,
+        })
+        .map_err(|e| e.to_string())?;
+    email_client
+        .send_email(EmailMessage::new(
+            recipient,
+            "Please confirm your subscription",
+            &email.html,
+            &email.text,
+        ))
+        .await?;
+    Ok(())
+}
+
+/// リマインダーを送り続ける。送るべき所属がない間はpoll_intervalごとに確認する
+pub async fn run_confirmation_reminders_until_stopped(
+    pool: PgPool,
+    email_client: EmailClient,
+    templates: Arc<EmailTemplates>,
+    base_url: String,
+    settings: ConfirmationSettings,
+    poll_interval: Duration,
+) -> Result<(), std::io::Error> {
+    run_tasks_until_stopped(
+        || try_send_confirmation_reminder(&pool, &email_client, &templates, &base_url, &settings),
+        poll_interval,
+    )
+    .await
+}
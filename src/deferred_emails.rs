@@ -0,0 +1,136 @@
+// 1日の送信数の上限に達したために後で送るメール
+// 購読の確認などHTTPのリクエストの中で送るメールは、上限に達してもリクエストを失敗させずにここに保存し、
+// 上限が戻ってからワーカーが古い順に送る
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_message::{EmailMessage, MessageKind};
+use crate::issue_delivery_worker::{run_tasks_until_stopped, DeliveryError, ExecutionOutcome};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+// 取り出した後で送るメール
+struct DeferredEmail {
+    id: Uuid,
+    recipient: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    kind: String,
+}
+
+/// 後で送るメールとして保存する
+#[tracing::instrument(name = "Defer an email", skip_all, fields(recipient = %message.to.as_ref()))]
+pub async fn defer_email(pool: &PgPool, message: &EmailMessage<'_>) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO deferred_emails (id, recipient, subject, html_body, text_body, kind, deferred_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7)
+            "#,
+        Uuid::new_v4(),
+        message.to.as_ref(),
+        message.subject,
+        message.html_body,
+        message.text_body,
+        message.kind.as_str(),
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// 後で送るメールを1件取り出して送信する
+#[tracing::instrument(skip_all, fields(recipient = tracing::field::Empty), err)]
+pub async fn try_send_deferred_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    // 送信サービスの障害中は、送れなかったメールを削除しないように取り出さない
+    if email_client.is_unavailable() {
+        return Ok(ExecutionOutcome::ProviderUnavailable);
+    }
+    let mut transaction = pool.begin().await?;
+    let email = match dequeue_deferred_email(&mut transaction).await? {
+        Some(email) => email,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current().record("recipient", display(&email.recipient));
+
+    match send_deferred_email(email_client, &email).await {
+        Ok(()) => {}
+        // まだ1日の上限に達している場合はロールバックして、次の確認のときに送る
+        Err(DeliveryError::QuotaExhausted) => return Ok(ExecutionOutcome::QuotaExhausted),
+        Err(DeliveryError::Database(e)) => return Err(e),
+        // 保存した後に抑止リストに載ったアドレスには送らない
+        Err(DeliveryError::Suppressed) => {}
+        // 送信に失敗しても再送はしない
+        Err(e) => {
+            tracing::error!(
+                error.message = %e,
+                "Failed to send a deferred email. Skipping.",
+            );
+        }
+    }
+    sqlx::query!("DELETE FROM deferred_emails WHERE id = $1", email.id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+// 他のワーカーが処理中のメールを飛ばして、最も古いメールを1件ロックして取り出す
+async fn dequeue_deferred_email(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<DeferredEmail>, sqlx::Error> {
+    sqlx::query_as!(
+        DeferredEmail,
+        r#"
+    SELECT id, recipient, subject, html_body, text_body, kind
+    FROM deferred_emails
+    ORDER BY deferred_at
+    FOR UPDATE
+    SKIP LOCKED
+    LIMIT 1
+            "#,
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
+
+async fn send_deferred_email(
+    email_client: &EmailClient,
+    email: &DeferredEmail,
+) -> Result<(), DeliveryError> {
+    let recipient = SubscriberEmail::parse(email.recipient.clone())?;
+    let mut message = EmailMessage::new(
+        recipient,
+        &email.subject,
+        &email.html_body,
+        &email.text_body,
+    );
+    if email.kind == MessageKind::Broadcast.as_str() {
+        message = message.broadcast();
+    }
+    email_client.send_email(message).await?;
+    Ok(())
+}
+
+/// 後で送るメールを送り続ける。送るメールがないか上限に達している間はpoll_intervalごとに確認する
+pub async fn run_deferred_emails_until_stopped(
+    pool: PgPool,
+    email_client: EmailClient,
+    poll_interval: Duration,
+) -> Result<(), std::io::Error> {
+    run_tasks_until_stopped(
+        || try_send_deferred_email(&pool, &email_client),
+        poll_interval,
+    )
+    .await
+}
@@ -0,0 +1,348 @@
+// MIME形式のメッセージにDKIM署名 (RFC 6376) を付ける
+// ヘッダと本文はどちらもrelaxedで正規化し、鍵はRSA (rsa-sha256) かEd25519 (ed25519-sha256, RFC 8463) を使う
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use sha2::{Digest, Sha256};
+
+// 署名するヘッダ (メッセージにあるものだけを署名する)
+const SIGNED_HEADERS: [&str; 9] = [
+    "from",
+    "to",
+    "cc",
+    "reply-to",
+    "subject",
+    "date",
+    "message-id",
+    "mime-version",
+    "content-type",
+];
+
+/// 署名に使う鍵の種類
+#[derive(serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DkimAlgorithm {
+    Rsa,
+    Ed25519,
+}
+
+enum DkimKey {
+    Rsa(rsa::pkcs1v15::SigningKey<Sha256>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+/// 送信ドメインの秘密鍵でメッセージに署名する
+/// 受信側はs=のセレクタとd=のドメインから、DNSのTXTレコード (<selector>._domainkey.<domain>) の公開鍵で検証する
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    key: DkimKey,
+}
+
+impl DkimSigner {
+    /// PEM形式の秘密鍵 (RSAはPKCS#8かPKCS#1、Ed25519はPKCS#8) から署名者を作る
+    pub fn from_pem(
+        domain: String,
+        selector: String,
+        algorithm: DkimAlgorithm,
+        private_key_pem: &str,
+    ) -> Result<Self, String> {
+        let key = match algorithm {
+            DkimAlgorithm::Rsa => rsa::RsaPrivateKey::from_pkcs8_pem(private_key_pem)
+                .or_else(|_| rsa::RsaPrivateKey::from_pkcs1_pem(private_key_pem))
+                .map(|key| DkimKey::Rsa(rsa::pkcs1v15::SigningKey::new(key)))
+                .map_err(|e| format!("Invalid RSA private key for DKIM: {}", e))?,
+            DkimAlgorithm::Ed25519 => ed25519_dalek::SigningKey::from_pkcs8_pem(private_key_pem)
+                .map(DkimKey::Ed25519)
+                .map_err(|e| format!("Invalid Ed25519 private key for DKIM: {}", e))?,
+        };
+        Ok(Self {
+            domain,
+            selector,
+            key,
+        })
+    }
+
+    /// メッセージの先頭にDKIM-Signatureヘッダを付けて返す
+    /// 署名した後にヘッダや本文を書き換えると受信側の検証に失敗する
+    pub fn sign(&self, message: &str, timestamp: DateTime<Utc>) -> String {
+        let (header_block, body) = split_message(message);
+        let headers = parse_headers(header_block);
+        let signed_headers = SIGNED_HEADERS
+            .iter()
+            .filter(|name| find_header(&headers, name).is_some())
+            .copied()
+            .collect::<Vec<_>>();
+        let body_hash =
+            base64::engine::general_purpose::STANDARD.encode(Sha256::digest(relaxed_body(body)));
+        let algorithm = match self.key {
+            DkimKey::Rsa(_) => "rsa-sha256",
+            DkimKey::Ed25519(_) => "ed25519-sha256",
+        };
+        // b=は最後に置き、署名する間は空にしておく
+        let unsigned = format!(
+            "DKIM-Signature: v=1; a={}; c=relaxed/relaxed; d={}; s={};\r\n\tt={}; h={};\r\n\tbh={};\r\n\tb=",
+            algorithm,
+            self.domain,
+            self.selector,
+            timestamp.timestamp(),
+            signed_headers.join(":"),
+            body_hash
+        );
+        let mut signing_input = signed_headers
+            .iter()
+            .filter_map(|name| find_header(&headers, name))
+            .map(relaxed_header)
+            .collect::<String>();
+        // 署名ヘッダ自身は末尾の改行を付けずに署名する
+        signing_input.push_str(relaxed_header(&unsigned).trim_end_matches("\r\n"));
+        let signature = match &self.key {
+            DkimKey::Rsa(key) => key.sign(signing_input.as_bytes()).to_vec(),
+            DkimKey::Ed25519(key) => key.sign(&Sha256::digest(signing_input.as_bytes())).to_vec(),
+        };
+        format!(
+            "{}{}\r\n{}",
+            unsigned,
+            base64::engine::general_purpose::STANDARD.encode(signature),
+            message
+        )
+    }
+}
+
+// ヘッダ部分と本文に分ける (空行のないメッセージは本文が空)
+pub(crate) fn split_message(message: &str) -> (&str, &str) {
+    match message.find("\r\n\r\n") {
+        Some(index) => (&message[..index + 2], &message[index + 4..]),
+        None => (message, ""),
+    }
+}
+
+// ヘッダ部分を、折り返しを含めたヘッダごとに分ける
+pub(crate) fn parse_headers(header_block: &str) -> Vec<&str> {
+    let mut headers = Vec::new();
+    let mut start = 0;
+    for (index, _) in header_block.match_indices("\r\n") {
+        let next = index + 2;
+        // 空白で始まる行は前のヘッダの続き
+        if !header_block[next..].starts_with([' ', '\t']) {
+            headers.push(&header_block[start..next]);
+            start = next;
+        }
+    }
+    headers
+}
+
+// 名前が一致するヘッダのうち、最後のものを返す (RFC 6376 5.4.2)
+pub(crate) fn find_header<'a>(headers: &[&'a str], name: &str) -> Option<&'a str> {
+    headers.iter().rev().copied().find(|header| {
+        header
+            .split_once(':')
+            .is_some_and(|(header_name, _)| header_name.trim().eq_ignore_ascii_case(name))
+    })
+}
+
+// ヘッダのrelaxed正規化 (RFC 6376 3.4.2)
+// 名前を小文字にし、折り返しを戻して連続する空白を1つにまとめ、値の前後の空白を取り除く
+pub(crate) fn relaxed_header(header: &str) -> String {
+    let (name, value) = header.split_once(':').unwrap_or((header, ""));
+    let value = value.replace("\r\n", "");
+    format!(
+        "{}:{}\r\n",
+        name.trim().to_ascii_lowercase(),
+        collapse_whitespace(&value).trim()
+    )
+}
+
+// 本文のrelaxed正規化 (RFC 6376 3.4.4)
+// 行ごとに連続する空白を1つにまとめて行末の空白を取り除き、末尾の空行を取り除く
+pub(crate) fn relaxed_body(body: &str) -> String {
+    let mut lines = body
+        .split("\r\n")
+        .map(|line| collapse_whitespace(line).trim_end().to_owned())
+        .collect::<Vec<_>>();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    lines.into_iter().map(|line| line + "\r\n").collect()
+}
+
+fn collapse_whitespace(value: &str) -> String {
+    let mut collapsed = String::with_capacity(value.len());
+    let mut in_whitespace = false;
+    for c in value.chars() {
+        if c == ' ' || c == '\t' {
+            if !in_whitespace {
+                collapsed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            collapsed.push(c);
+            in_whitespace = false;
+        }
+    }
+    collapsed
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::dkim::{
+        find_header, parse_headers, relaxed_body, relaxed_header, split_message, DkimAlgorithm,
+        DkimSigner,
+    };
+    use crate::domain::SubscriberEmail;
+    use crate::email_message::EmailMessage;
+    use crate::mime::build_mime_message;
+    use base64::Engine;
+    use chrono::Utc;
+    use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+    use rsa::signature::Verifier;
+    use sha2::{Digest, Sha256};
+
+    // 検証に使う公開鍵
+    pub(crate) enum PublicKey {
+        Rsa(rsa::pkcs1v15::VerifyingKey<Sha256>),
+        Ed25519(ed25519_dalek::VerifyingKey),
+    }
+
+    fn rsa_key_pair() -> (String, PublicKey) {
+        let private_key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let pem = private_key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        let public_key = rsa::pkcs1v15::VerifyingKey::new(private_key.to_public_key());
+        (pem.to_string(), PublicKey::Rsa(public_key))
+    }
+
+    pub(crate) fn ed25519_key_pair() -> (String, PublicKey) {
+        let private_key = ed25519_dalek::SigningKey::from_bytes(&rand::random());
+        let pem = private_key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        let public_key = private_key.verifying_key();
+        (pem.to_string(), PublicKey::Ed25519(public_key))
+    }
+
+    fn signed_message(algorithm: DkimAlgorithm, private_key_pem: &str) -> String {
+        let signer = DkimSigner::from_pem(
+            "example.com".into(),
+            "newsletter".into(),
+            algorithm,
+            private_key_pem,
+        )
+        .unwrap();
+        let message = EmailMessage::new(
+            SubscriberEmail::parse("ursula@example.com".into()).unwrap(),
+            "今週のニュースレター",
+            "<p>Hi</p>",
+            "Hi",
+        );
+        let sender = SubscriberEmail::parse("news@example.com".into()).unwrap();
+        let mime = build_mime_message(&message, &sender, Utc::now());
+        signer.sign(&mime, Utc::now())
+    }
+
+    // DKIM-Signatureのタグを取り出す
+    fn tag<'a>(signature: &'a str, name: &str) -> &'a str {
+        signature
+            .split(';')
+            .filter_map(|tag| tag.split_once('='))
+            .find(|(tag_name, _)| tag_name.trim() == name)
+            .map(|(_, value)| value.trim())
+            .unwrap()
+    }
+
+    // 受信側と同じ手順で、署名を公開鍵で検証する
+    pub(crate) fn verify(message: &str, public_key: &PublicKey) -> bool {
+        let (header_block, body) = split_message(message);
+        let headers = parse_headers(header_block);
+        let signature_header = find_header(&headers, "dkim-signature").unwrap();
+        let canonical_signature = relaxed_header(signature_header);
+        let tags = canonical_signature.trim_end_matches("\r\n");
+
+        let body_hash =
+            base64::engine::general_purpose::STANDARD.encode(Sha256::digest(relaxed_body(body)));
+        if tag(tags, "bh") != body_hash {
+            return false;
+        }
+        let mut signing_input = tag(tags, "h")
+            .split(':')
+            .filter_map(|name| find_header(&headers, name))
+            .map(relaxed_header)
+            .collect::<String>();
+        // b=の値を空にした署名ヘッダを末尾の改行なしで加える
+        let b_index = tags.find("; b=").unwrap() + "; b=".len();
+        signing_input.push_str(&tags[..b_index]);
+        let signature = base64::engine::general_purpose::STANDARD
+            .decode(&tags[b_index..])
+            .unwrap();
+        match public_key {
+            PublicKey::Rsa(key) => {
+                let signature = rsa::pkcs1v15::Signature::try_from(signature.as_slice()).unwrap();
+                key.verify(signing_input.as_bytes(), &signature).is_ok()
+            }
+            PublicKey::Ed25519(key) => {
+                let signature = ed25519_dalek::Signature::from_slice(&signature).unwrap();
+                key.verify(&Sha256::digest(signing_input.as_bytes()), &signature)
+                    .is_ok()
+            }
+        }
+    }
+
+    #[test]
+    fn rsa_signatures_are_verified_with_the_public_key() {
+        let (private_key_pem, public_key) = rsa_key_pair();
+
+        let message = signed_message(DkimAlgorithm::Rsa, &private_key_pem);
+
+        assert!(message.starts_with(
+            "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com; s=newsletter;"
+        ));
+        assert!(verify(&message, &public_key));
+    }
+
+    #[test]
+    fn ed25519_signatures_are_verified_with_the_public_key() {
+        let (private_key_pem, public_key) = ed25519_key_pair();
+
+        let message = signed_message(DkimAlgorithm::Ed25519, &private_key_pem);
+
+        assert!(message.starts_with("DKIM-Signature: v=1; a=ed25519-sha256;"));
+        assert!(verify(&message, &public_key));
+    }
+
+    #[test]
+    fn signatures_of_modified_messages_are_rejected() {
+        let (private_key_pem, public_key) = ed25519_key_pair();
+        let message = signed_message(DkimAlgorithm::Ed25519, &private_key_pem);
+
+        // 宛先を書き換える
+        let modified_header = message.replace("To: ursula@example.com", "To: eve@example.com");
+        // 本文を書き換える (Base64でエンコードしたHiをHoにする)
+        let modified_body = message.replace("\r\nSGk=\r\n", "\r\nSG8=\r\n");
+
+        assert!(!verify(&modified_header, &public_key));
+        assert!(!verify(&modified_body, &public_key));
+    }
+
+    #[test]
+    fn an_invalid_private_key_is_rejected() {
+        let result = DkimSigner::from_pem(
+            "example.com".into(),
+            "newsletter".into(),
+            DkimAlgorithm::Rsa,
+            "not a key",
+        );
+
+        assert!(result.is_err());
+    }
+
+    // RFC 6376 3.4.5の例
+    #[test]
+    fn relaxed_canonicalization_matches_the_rfc_example() {
+        let headers = parse_headers("A: X\r\nB : Y\t\r\n\tZ  \r\n");
+
+        let canonical_headers = headers.into_iter().map(relaxed_header).collect::<String>();
+        let canonical_body = relaxed_body(" C \r\nD \t E\r\n\r\n\r\n");
+
+        assert_eq!(canonical_headers, "a:X\r\nb:Y Z\r\n");
+        assert_eq!(canonical_body, " C\r\nD E\r\n");
+    }
+}
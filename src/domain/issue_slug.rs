@@ -0,0 +1,81 @@
+// 公開アーカイブで号を識別するスラッグ (例: weekly-update-42)
+#[derive(Debug, Clone)]
+pub struct IssueSlug(String);
+
+// タイトルから作るスラッグの最大長 (重複時の連番を付ける余地を残す)
+const MAX_LENGTH: usize = 60;
+
+impl IssueSlug {
+    /// タイトルの英数字を小文字にしてハイフンでつなぐ
+    /// 英数字を含まないタイトル (日本語のみなど) は"issue"にする
+    pub fn from_title(title: &str) -> IssueSlug {
+        let mut slug = String::new();
+        for word in title
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|w| !w.is_empty())
+        {
+            if slug.len() + word.len() + 1 > MAX_LENGTH {
+                // 最初の単語だけで長すぎる場合は切り詰める
+                if slug.is_empty() {
+                    slug.push_str(&word[..MAX_LENGTH].to_ascii_lowercase());
+                }
+                break;
+            }
+            if !slug.is_empty() {
+                slug.push('-');
+            }
+            slug.push_str(&word.to_ascii_lowercase());
+        }
+        if slug.is_empty() {
+            slug.push_str("issue");
+        }
+        Self(slug)
+    }
+
+    /// 同じスラッグの号が既にある場合に使う、連番を付けたスラッグ (2から始める)
+    pub fn with_suffix(&self, n: u32) -> IssueSlug {
+        Self(format!("{}-{}", self.0, n))
+    }
+}
+
+impl AsRef<str> for IssueSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::IssueSlug;
+
+    #[test]
+    fn titles_are_lowercased_and_joined_with_hyphens() {
+        let slug = IssueSlug::from_title("Weekly Update #42: What's new?");
+        assert_eq!(slug.as_ref(), "weekly-update-42-what-s-new");
+    }
+
+    #[test]
+    fn titles_without_ascii_words_fall_back_to_issue() {
+        assert_eq!(IssueSlug::from_title("今週のお知らせ").as_ref(), "issue");
+        assert_eq!(IssueSlug::from_title("").as_ref(), "issue");
+    }
+
+    #[test]
+    fn long_titles_are_truncated_at_a_word_boundary() {
+        let slug = IssueSlug::from_title(&"word ".repeat(30));
+        assert!(slug.as_ref().len() <= 60);
+        assert!(slug.as_ref().ends_with("word"));
+    }
+
+    #[test]
+    fn a_single_long_word_is_truncated() {
+        let slug = IssueSlug::from_title(&"a".repeat(100));
+        assert_eq!(slug.as_ref(), "a".repeat(60));
+    }
+
+    #[test]
+    fn suffixes_are_appended() {
+        let slug = IssueSlug::from_title("Weekly");
+        assert_eq!(slug.with_suffix(2).as_ref(), "weekly-2");
+    }
+}
@@ -0,0 +1,62 @@
+// メーリングリストを識別するスラッグ (例: engineering-blog)
+#[derive(Debug)]
+pub struct ListSlug(String);
+
+impl ListSlug {
+    /// 英小文字・数字・ハイフンからなる64文字以内の文字列ならばListSlugを返す
+    pub fn parse(s: String) -> Result<ListSlug, String> {
+        let is_empty = s.is_empty();
+        let is_too_long = s.len() > 64;
+        let has_invalid_characters = !s
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        // 先頭と末尾のハイフンは見た目が紛らわしいので禁止する
+        let has_edge_hyphen = s.starts_with('-') || s.ends_with('-');
+
+        if is_empty || is_too_long || has_invalid_characters || has_edge_hyphen {
+            Err(format!("{} is not a valid list slug.", s))
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+
+impl AsRef<str> for ListSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::ListSlug;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn a_valid_slug_is_parsed_successfully() {
+        assert_ok!(ListSlug::parse("engineering-blog".to_string()));
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(ListSlug::parse("".to_string()));
+    }
+
+    #[test]
+    fn a_slug_longer_than_64_characters_is_rejected() {
+        assert_err!(ListSlug::parse("a".repeat(65)));
+    }
+
+    #[test]
+    fn slugs_containing_an_invalid_character_are_rejected() {
+        for slug in &["Events", "product updates", "events/2023", "イベント"] {
+            assert_err!(ListSlug::parse(slug.to_string()));
+        }
+    }
+
+    #[test]
+    fn slugs_starting_or_ending_with_a_hyphen_are_rejected() {
+        assert_err!(ListSlug::parse("-events".to_string()));
+        assert_err!(ListSlug::parse("events-".to_string()));
+    }
+}
@@ -1,7 +1,15 @@
+mod issue_slug;
+mod list_slug;
 mod new_subscriber;
+mod subscriber_attributes;
 mod subscriber_email;
 mod subscriber_name;
+mod subscriber_tag;
 
+pub use issue_slug::IssueSlug;
+pub use list_slug::ListSlug;
 pub use new_subscriber::NewSubscriber;
+pub use subscriber_attributes::SubscriberAttributes;
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
+pub use subscriber_tag::SubscriberTag;
@@ -0,0 +1,148 @@
+use crate::configuration::{SubscriberAttributeKind, SubscriberAttributeSettings};
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+// 購読者の追加属性。attributesカラムにJSONオブジェクトとして保存する
+#[derive(Debug)]
+pub struct SubscriberAttributes(serde_json::Map<String, serde_json::Value>);
+
+impl SubscriberAttributes {
+    /// フォームの追加フィールドを設定された定義に従ってパースする
+    /// 定義にないフィールドや型の合わない値、必須項目の欠落はエラーにする
+    pub fn parse(
+        fields: HashMap<String, String>,
+        schema: &[SubscriberAttributeSettings],
+    ) -> Result<SubscriberAttributes, String> {
+        if let Some(unknown) = fields
+            .keys()
+            .find(|name| !schema.iter().any(|attribute| &attribute.name == *name))
+        {
+            return Err(format!("{} is not a known subscriber attribute.", unknown));
+        }
+
+        let mut attributes = serde_json::Map::new();
+        for attribute in schema {
+            // 空文字は未入力として扱う
+            let raw_value = match fields.get(&attribute.name).map(|v| v.trim()) {
+                Some(raw_value) if !raw_value.is_empty() => raw_value,
+                _ if attribute.required => {
+                    return Err(format!("{} is a required attribute.", attribute.name))
+                }
+                _ => continue,
+            };
+            let value = parse_value(raw_value, attribute.kind)
+                .ok_or_else(|| format!("{} is not a valid {}.", raw_value, attribute.name))?;
+            attributes.insert(attribute.name.clone(), value);
+        }
+        Ok(Self(attributes))
+    }
+
+    pub fn as_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(self.0.clone())
+    }
+}
+
+// 文字列を属性の型に合わせたJSON値に変換する
+fn parse_value(raw_value: &str, kind: SubscriberAttributeKind) -> Option<serde_json::Value> {
+    match kind {
+        SubscriberAttributeKind::String if raw_value.graphemes(true).count() <= 256 => {
+            Some(serde_json::Value::String(raw_value.to_string()))
+        }
+        SubscriberAttributeKind::String => None,
+        SubscriberAttributeKind::Integer => raw_value.parse::<i64>().ok().map(Into::into),
+        SubscriberAttributeKind::Boolean => raw_value.parse::<bool>().ok().map(Into::into),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::{SubscriberAttributeKind, SubscriberAttributeSettings};
+    use crate::domain::SubscriberAttributes;
+    use claim::{assert_err, assert_ok};
+    use std::collections::HashMap;
+
+    fn schema() -> Vec<SubscriberAttributeSettings> {
+        vec![
+            SubscriberAttributeSettings {
+                name: "company".into(),
+                kind: SubscriberAttributeKind::String,
+                required: true,
+            },
+            SubscriberAttributeSettings {
+                name: "seats".into(),
+                kind: SubscriberAttributeKind::Integer,
+                required: false,
+            },
+            SubscriberAttributeSettings {
+                name: "beta".into(),
+                kind: SubscriberAttributeKind::Boolean,
+                required: false,
+            },
+        ]
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn values_are_converted_to_the_configured_kind() {
+        let attributes = assert_ok!(SubscriberAttributes::parse(
+            fields(&[("company", "Acme"), ("seats", "12"), ("beta", "true")]),
+            &schema()
+        ));
+        assert_eq!(
+            attributes.as_json(),
+            serde_json::json!({"company": "Acme", "seats": 12, "beta": true})
+        );
+    }
+
+    #[test]
+    fn empty_optional_values_are_omitted() {
+        let attributes = assert_ok!(SubscriberAttributes::parse(
+            fields(&[("company", "Acme"), ("seats", "")]),
+            &schema()
+        ));
+        assert_eq!(attributes.as_json(), serde_json::json!({"company": "Acme"}));
+    }
+
+    #[test]
+    fn unknown_attributes_are_rejected() {
+        assert_err!(SubscriberAttributes::parse(
+            fields(&[("company", "Acme"), ("favourite_colour", "blue")]),
+            &schema()
+        ));
+    }
+
+    #[test]
+    fn missing_required_attributes_are_rejected() {
+        assert_err!(SubscriberAttributes::parse(
+            fields(&[("seats", "12")]),
+            &schema()
+        ));
+    }
+
+    #[test]
+    fn values_of_the_wrong_kind_are_rejected() {
+        assert_err!(SubscriberAttributes::parse(
+            fields(&[("company", "Acme"), ("seats", "a dozen")]),
+            &schema()
+        ));
+        assert_err!(SubscriberAttributes::parse(
+            fields(&[("company", "Acme"), ("beta", "yes")]),
+            &schema()
+        ));
+    }
+
+    #[test]
+    fn a_string_longer_than_256_graphemes_is_rejected() {
+        let company = "a".repeat(257);
+        assert_err!(SubscriberAttributes::parse(
+            fields(&[("company", &company)]),
+            &schema()
+        ));
+    }
+}
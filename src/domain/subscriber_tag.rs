@@ -0,0 +1,56 @@
+// 購読者を分類するためのタグ (例: beta)
+#[derive(Debug)]
+pub struct SubscriberTag(String);
+
+impl SubscriberTag {
+    /// 英小文字・数字・ハイフン・アンダースコアからなる64文字以内の文字列ならばSubscriberTagを返す
+    pub fn parse(s: String) -> Result<SubscriberTag, String> {
+        let is_empty = s.is_empty();
+        let is_too_long = s.len() > 64;
+        let has_invalid_characters = !s
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+
+        if is_empty || is_too_long || has_invalid_characters {
+            Err(format!("{} is not a valid subscriber tag.", s))
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+
+impl AsRef<str> for SubscriberTag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberTag;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn valid_tags_are_parsed_successfully() {
+        for tag in &["beta", "early_adopter", "2023-q4"] {
+            assert_ok!(SubscriberTag::parse(tag.to_string()));
+        }
+    }
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(SubscriberTag::parse("".to_string()));
+    }
+
+    #[test]
+    fn a_tag_longer_than_64_characters_is_rejected() {
+        assert_err!(SubscriberTag::parse("a".repeat(65)));
+    }
+
+    #[test]
+    fn tags_containing_an_invalid_character_are_rejected() {
+        for tag in &["Beta", "beta tester", "beta:1", "ベータ"] {
+            assert_err!(SubscriberTag::parse(tag.to_string()));
+        }
+    }
+}
@@ -1,16 +1,196 @@
+use crate::circuit_breaker::CircuitBreaker;
+use crate::configuration::{CircuitBreakerSettings, MessageStreamSettings, RateLimitSettings};
+use crate::deferred_emails::defer_email;
+use crate::dkim::DkimSigner;
 use crate::domain::SubscriberEmail;
+use crate::email_message::{EmailMessage, MessageKind, MAX_MESSAGE_SIZE};
+use crate::mime::build_mime_message;
+use crate::rate_limit::{release_daily_send, reserve_daily_send, TokenBucket};
+use crate::suppressions::is_suppressed;
+use chrono::{NaiveDate, Utc};
+use lettre::address::Envelope;
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
 use reqwest::Client;
 use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 
+// 主の送信サービスの名前
+const PRIMARY_PROVIDER: &str = "postmark";
+
+// reqwest::Clientは内部でArcを使っているのでcloneしても接続プールは共有される
+#[derive(Clone)]
 pub struct EmailClient {
     // Clientのインスタンスを保持する
     http_client: Client,
-    // リクエストを行うAPIのURL
-    base_url: String,
     // メールの送信者として設定するアドレス
     sender: SubscriberEmail,
-    // Postmarkの認証トークン
-    authorization_token: Secret<String>,
+    // 送信に使う順に並べた送信サービス (先頭が主の送信サービス)
+    providers: Vec<EmailProvider>,
+    // 送信サービスごとのサーキットブレーカーの設定
+    circuit_breaker_settings: CircuitBreakerSettings,
+    // メールの種類ごとのメッセージストリーム
+    message_streams: MessageStreamSettings,
+    // 送信前の抑止リストの確認と、1日の送信数の記録に使うデータベース
+    // (設定されていなければどちらも行わない)
+    db_pool: Option<PgPool>,
+    // MIME形式のメッセージに付けるDKIM署名 (設定されていなければ署名しない)
+    dkim_signer: Option<Arc<DkimSigner>>,
+}
+
+/// PostmarkのAPIかSMTPリレーで送信する送信サービス
+#[derive(Clone)]
+pub struct EmailProvider {
+    // ログやメトリクス、1日の送信数に使う名前
+    name: String,
+    // 送信サービスへの送り方
+    transport: ProviderTransport,
+    // 1秒あたりの送信数の制限 (cloneしたクライアントと共有する)
+    rate_limiter: Option<TokenBucket>,
+    // 1日の送信数の上限
+    daily_quota: Option<u32>,
+    // 障害中の送信をすぐに失敗させるサーキットブレーカー (cloneしたクライアントと共有する)
+    circuit_breaker: CircuitBreaker,
+}
+
+// 送信サービスへの送り方
+#[derive(Clone)]
+enum ProviderTransport {
+    // PostmarkのAPIにJSONでリクエストする
+    Postmark {
+        // リクエストを行うAPIのURL
+        base_url: String,
+        // APIの認証トークン
+        authorization_token: Secret<String>,
+    },
+    // SMTPリレーにMIME形式のメッセージを送る (DKIM署名が設定されていれば署名する)
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+}
+
+impl EmailProvider {
+    fn new(
+        name: String,
+        transport: ProviderTransport,
+        circuit_breaker_settings: &CircuitBreakerSettings,
+    ) -> Self {
+        Self {
+            name,
+            transport,
+            rate_limiter: None,
+            daily_quota: None,
+            circuit_breaker: CircuitBreaker::new(
+                circuit_breaker_settings.failure_threshold,
+                circuit_breaker_settings.open_duration(),
+            ),
+        }
+    }
+
+    fn set_rate_limit(&mut self, settings: &RateLimitSettings) {
+        self.rate_limiter = settings
+            .per_second
+            .map(|per_second| TokenBucket::new(per_second, settings.burst));
+        self.daily_quota = settings.daily_quota;
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn daily_quota(&self) -> Option<u32> {
+        self.daily_quota
+    }
+
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+}
+
+/// メール送信のエラー
+#[derive(Debug)]
+pub enum SendEmailError {
+    // すべての送信サービスのサーキットブレーカーの回路が開いているため送信しなかった
+    CircuitOpen,
+    // 本文と添付ファイルが送信サービスの上限より大きいため送信しなかった
+    MessageTooLarge(usize),
+    // 上限のあるすべての送信サービスが1日の上限に達しているため送信しなかった
+    QuotaExhausted,
+    // 宛先が抑止リストに載っているため送信しなかった
+    Suppressed,
+    // 抑止リストの確認か送信数の記録に失敗したため送信しなかった
+    Database(sqlx::Error),
+    // 送信サービスへのリクエストが失敗した
+    Request(reqwest::Error),
+    // SMTPリレーへの送信が失敗した
+    Smtp(lettre::transport::smtp::Error),
+    // SMTPのエンベロープに使えないアドレスのため、SMTPリレーでは送信しなかった
+    InvalidAddress(String),
+}
+
+impl std::fmt::Display for SendEmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendEmailError::CircuitOpen => {
+                write!(
+                    f,
+                    "The email provider circuit is open. The email was not sent."
+                )
+            }
+            SendEmailError::MessageTooLarge(size) => write!(
+                f,
+                "The email is {} bytes, which exceeds the limit of {} bytes.",
+                size, MAX_MESSAGE_SIZE
+            ),
+            SendEmailError::QuotaExhausted => write!(
+                f,
+                "The daily send quota has been reached. The email was not sent."
+            ),
+            SendEmailError::Suppressed => write!(
+                f,
+                "The recipient is on the suppression list. The email was not sent."
+            ),
+            SendEmailError::Database(e) => write!(f, "{}", e),
+            SendEmailError::Request(e) => write!(f, "{}", e),
+            SendEmailError::Smtp(e) => write!(f, "{}", e),
+            SendEmailError::InvalidAddress(address) => {
+                write!(f, "{} cannot be used as an SMTP address.", address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendEmailError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SendEmailError::CircuitOpen
+            | SendEmailError::MessageTooLarge(_)
+            | SendEmailError::QuotaExhausted
+            | SendEmailError::Suppressed
+            | SendEmailError::InvalidAddress(_) => None,
+            SendEmailError::Database(e) => Some(e),
+            SendEmailError::Request(e) => Some(e),
+            SendEmailError::Smtp(e) => Some(e),
+        }
+    }
+}
+
+/// send_email_or_deferで送ったメールがどうなったか
+#[derive(Debug, PartialEq)]
+pub enum Delivery {
+    Sent,
+    // 1日の上限に達していたため、後で送るように保存した
+    Deferred,
+}
+
+// 5xxとタイムアウトや接続のエラーだけを送信サービスの障害として数える
+// 4xxは宛先などリクエストの問題なので、他の送信サービスでも失敗する
+// SMTPでは逆に5xxが恒久的な失敗 (宛先の問題) で、4xxは一時的な失敗になる
+fn is_provider_failure(e: &SendEmailError) -> bool {
+    match e {
+        SendEmailError::Request(e) => e.status().is_none_or(|s| s.is_server_error()),
+        SendEmailError::Smtp(e) => !e.is_permanent(),
+        _ => false,
+    }
 }
 
 impl EmailClient {
@@ -21,48 +201,346 @@ impl EmailClient {
         timeout: std::time::Duration,
     ) -> Self {
         let http_client = Client::builder().timeout(timeout).build().unwrap();
+        let circuit_breaker_settings = CircuitBreakerSettings::default();
+        let primary = EmailProvider::new(
+            PRIMARY_PROVIDER.into(),
+            ProviderTransport::Postmark {
+                base_url,
+                authorization_token,
+            },
+            &circuit_breaker_settings,
+        );
         Self {
             http_client,
-            base_url,
             sender,
-            authorization_token,
+            providers: vec![primary],
+            circuit_breaker_settings,
+            message_streams: MessageStreamSettings::default(),
+            db_pool: None,
+            dkim_signer: None,
         }
     }
 
-    // メールを送信する
+    /// 主の送信サービスが使えないときに使う送信サービスを追加する。追加した順に使う
+    pub fn with_fallback_provider(
+        mut self,
+        name: String,
+        base_url: String,
+        authorization_token: Secret<String>,
+        rate_limit: &RateLimitSettings,
+    ) -> Self {
+        let mut provider = EmailProvider::new(
+            name,
+            ProviderTransport::Postmark {
+                base_url,
+                authorization_token,
+            },
+            &self.circuit_breaker_settings,
+        );
+        provider.set_rate_limit(rate_limit);
+        self.providers.push(provider);
+        self
+    }
+
+    /// SMTPリレーを送信サービスとして追加する。追加した順に使う
+    /// SMTPリレーにはMIME形式のメッセージを送り、DKIM署名が設定されていれば署名する
+    pub fn with_smtp_relay(
+        mut self,
+        name: String,
+        transport: AsyncSmtpTransport<Tokio1Executor>,
+        rate_limit: &RateLimitSettings,
+    ) -> Self {
+        let mut provider = EmailProvider::new(
+            name,
+            ProviderTransport::Smtp(transport),
+            &self.circuit_breaker_settings,
+        );
+        provider.set_rate_limit(rate_limit);
+        self.providers.push(provider);
+        self
+    }
+
+    /// すべての送信サービスのサーキットブレーカーを設定する
+    pub fn with_circuit_breaker(mut self, settings: &CircuitBreakerSettings) -> Self {
+        for provider in &mut self.providers {
+            provider.circuit_breaker =
+                CircuitBreaker::new(settings.failure_threshold, settings.open_duration());
+        }
+        self.circuit_breaker_settings = settings.clone();
+        self
+    }
+
+    /// メールの種類ごとのメッセージストリームを設定する
+    pub fn with_message_streams(mut self, message_streams: MessageStreamSettings) -> Self {
+        self.message_streams = message_streams;
+        self
+    }
+
+    /// 送信のたびに抑止リストを確認し、1日の送信数を数えるデータベースを設定する
+    pub fn with_db_pool(mut self, db_pool: PgPool) -> Self {
+        self.db_pool = Some(db_pool);
+        self
+    }
+
+    /// SMTPリレーに送るMIME形式のメッセージに付けるDKIM署名を設定する
+    pub fn with_dkim_signer(mut self, dkim_signer: DkimSigner) -> Self {
+        self.dkim_signer = Some(Arc::new(dkim_signer));
+        self
+    }
+
+    /// 主の送信サービスの送信数の制限を設定する
+    pub fn with_rate_limit(mut self, settings: &RateLimitSettings) -> Self {
+        self.providers[0].set_rate_limit(settings);
+        self
+    }
+
+    pub fn providers(&self) -> &[EmailProvider] {
+        &self.providers
+    }
+
+    // SMTPで送るMIME形式のメッセージを組み立てる。DKIM署名が設定されていれば署名する
+    fn mime_message(&self, message: &EmailMessage<'_>) -> String {
+        let now = Utc::now();
+        let mime = build_mime_message(message, &self.sender, now);
+        match &self.dkim_signer {
+            Some(dkim_signer) => dkim_signer.sign(&mime, now),
+            None => mime,
+        }
+    }
+
+    /// すべての送信サービスの回路が開いていて、今は送信できないかどうか
+    pub fn is_unavailable(&self) -> bool {
+        self.providers
+            .iter()
+            .all(|p| p.circuit_breaker.is_rejecting())
+    }
+
+    // メールを送信し、バウンスなどのWebhookと突き合わせるためのPostmarkのMessageIDを返す
+    // MessageIDが含まれないレスポンスの場合と、SMTPリレーで送った場合はNoneを返す
+    // 送信サービスが5xxを返すかタイムアウトした場合は、次の送信サービスで送り直す
+    // 1日の上限に達した送信サービスも飛ばし、すべて上限に達していればQuotaExhaustedを返す
+    // (タイムアウトした送信サービスが実際には送っていた場合は、同じメールが2通届く)
     pub async fn send_email(
         &self,
-        recipient: SubscriberEmail,
-        subject: &str,
-        html_content: &str,
-        text_content: &str,
-    ) -> Result<(), reqwest::Error> {
+        mut message: EmailMessage<'_>,
+    ) -> Result<Option<String>, SendEmailError> {
+        // 送信サービスに拒否される大きさのメールは送る前に失敗させる
+        let size = message.size();
+        if size > MAX_MESSAGE_SIZE {
+            return Err(SendEmailError::MessageTooLarge(size));
+        }
+        // 抑止リストに載っている宛先には、どの経路から送ろうとしても送らない
+        if let Some(db_pool) = &self.db_pool {
+            self.remove_suppressed_recipients(db_pool, &mut message)
+                .await?;
+        }
+        // メールの種類に応じたストリームで送る
+        let message_stream = message.message_stream.unwrap_or(match message.kind {
+            MessageKind::Transactional => &self.message_streams.transactional,
+            MessageKind::Broadcast => &self.message_streams.broadcast,
+        });
+        let request_body = SendEmailRequest::new(self.sender.as_ref(), &message, message_stream);
+        // 1日の送信数はUTCの日付ごとに数える
+        let today = Utc::now().date_naive();
+        let mut last_error = SendEmailError::CircuitOpen;
+        for provider in &self.providers {
+            // 1日の上限に達している送信サービスは飛ばして、次の送信サービスで送る
+            // 回路が試しに送る番を使ってしまわないように、サーキットブレーカーより先に確認する
+            if !self.reserve_daily_send(provider, today).await? {
+                last_error = SendEmailError::QuotaExhausted;
+                continue;
+            }
+            // 回路が開いている送信サービスはタイムアウトまで待たずに飛ばす
+            if !provider.circuit_breaker.try_acquire() {
+                self.release_daily_send(provider, today).await;
+                continue;
+            }
+            // 1秒あたりの上限に達している場合は送れるようになるまで待つ
+            if let Some(rate_limiter) = &provider.rate_limiter {
+                rate_limiter.acquire().await;
+            }
+            let result = match &provider.transport {
+                ProviderTransport::Postmark {
+                    base_url,
+                    authorization_token,
+                } => self
+                    .post_email(base_url, authorization_token, &request_body)
+                    .await
+                    .map_err(SendEmailError::Request),
+                ProviderTransport::Smtp(transport) => self.send_smtp(transport, &message).await,
+            };
+            match result {
+                Ok(message_id) => {
+                    provider.circuit_breaker.record_success();
+                    tracing::info!(
+                        provider = provider.name(),
+                        message_id = message_id.as_deref(),
+                        "Sent an email"
+                    );
+                    return Ok(message_id);
+                }
+                // 送れなかったメールは1日の送信数に数えない
+                Err(e) if is_provider_failure(&e) => {
+                    provider.circuit_breaker.record_failure();
+                    self.release_daily_send(provider, today).await;
+                    tracing::warn!(
+                        provider = provider.name(),
+                        error.message = %e,
+                        "The email provider failed to send an email"
+                    );
+                    last_error = e;
+                }
+                Err(e) => {
+                    provider.circuit_breaker.record_success();
+                    self.release_daily_send(provider, today).await;
+                    return Err(e);
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// HTTPのリクエストの中で送るメールを送信する
+    /// 1日の上限に達していればエラーにせず、上限が戻ってからワーカーが送るように保存する
+    /// (保存できるのは宛先と件名、本文だけのメールで、それ以外はQuotaExhaustedを返す)
+    pub async fn send_email_or_defer(
+        &self,
+        message: EmailMessage<'_>,
+    ) -> Result<Delivery, SendEmailError> {
+        let plain_copy = message.plain_copy();
+        match self.send_email(message).await {
+            Ok(_) => Ok(Delivery::Sent),
+            Err(SendEmailError::QuotaExhausted) => match (&self.db_pool, plain_copy) {
+                (Some(db_pool), Some(message)) => {
+                    defer_email(db_pool, &message)
+                        .await
+                        .map_err(SendEmailError::Database)?;
+                    Ok(Delivery::Deferred)
+                }
+                _ => Err(SendEmailError::QuotaExhausted),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    // 1日の上限がある送信サービスでは1通分を確保する。上限に達していればfalseを返す
+    // データベースが設定されていないか、上限がない送信サービスでは数えずにtrueを返す
+    async fn reserve_daily_send(
+        &self,
+        provider: &EmailProvider,
+        day: NaiveDate,
+    ) -> Result<bool, SendEmailError> {
+        match (&self.db_pool, provider.daily_quota) {
+            (Some(db_pool), Some(daily_quota)) => {
+                reserve_daily_send(db_pool, provider.name(), daily_quota, day)
+                    .await
+                    .map_err(SendEmailError::Database)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    // 確保した1通分を戻す。戻せなくても送信の結果は変えない (エラーはログに出力済み)
+    async fn release_daily_send(&self, provider: &EmailProvider, day: NaiveDate) {
+        if let (Some(db_pool), Some(_)) = (&self.db_pool, provider.daily_quota) {
+            let _ = release_daily_send(db_pool, provider.name(), day).await;
+        }
+    }
+
+    // 宛先が抑止リストに載っていればエラーにし、CCとBCCは載っているアドレスだけ外す
+    async fn remove_suppressed_recipients(
+        &self,
+        db_pool: &PgPool,
+        message: &mut EmailMessage<'_>,
+    ) -> Result<(), SendEmailError> {
+        if is_suppressed(db_pool, message.to.as_ref())
+            .await
+            .map_err(SendEmailError::Database)?
+        {
+            tracing::info!("Skipping an email to a suppressed address.");
+            return Err(SendEmailError::Suppressed);
+        }
+        for recipients in [&mut message.cc, &mut message.bcc] {
+            let mut deliverable = Vec::with_capacity(recipients.len());
+            for recipient in recipients.drain(..) {
+                if !is_suppressed(db_pool, recipient.as_ref())
+                    .await
+                    .map_err(SendEmailError::Database)?
+                {
+                    deliverable.push(recipient);
+                }
+            }
+            *recipients = deliverable;
+        }
+        Ok(())
+    }
+
+    async fn post_email(
+        &self,
+        base_url: &str,
+        authorization_token: &Secret<String>,
+        request_body: &SendEmailRequest<'_>,
+    ) -> Result<Option<String>, reqwest::Error> {
         // リクエストURL
-        let url = format!("{}/email", self.base_url);
-        // リクエストボディ
-        let request_body = SendEmailRequest {
-            from: self.sender.as_ref(),
-            to: recipient.as_ref(),
-            subject,
-            html_body: html_content,
-            text_body: text_content,
-        };
+        let url = format!("{}/email", base_url);
         // リクエスト送信
-        self.http_client
+        let response = self
+            .http_client
             .post(&url)
             // Postmarkの認証トークンをヘッダに設定
             .header(
                 "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret(),
+                authorization_token.expose_secret(),
             )
-            .json(&request_body)
+            .json(request_body)
             .send()
             .await?
             .error_for_status()?;
-        Ok(())
+        let message_id = response
+            .json::<SendEmailResponse>()
+            .await
+            .ok()
+            .map(|r| r.message_id);
+        Ok(message_id)
+    }
+
+    // MIME形式のメッセージをSMTPリレーに送る
+    // タグやメタデータなどPostmarkだけの項目は使わず、Webhookと突き合わせるMessageIDもないのでNoneを返す
+    async fn send_smtp(
+        &self,
+        transport: &AsyncSmtpTransport<Tokio1Executor>,
+        message: &EmailMessage<'_>,
+    ) -> Result<Option<String>, SendEmailError> {
+        let envelope = smtp_envelope(&self.sender, message)?;
+        let mime = self.mime_message(message);
+        transport
+            .send_raw(&envelope, mime.as_bytes())
+            .await
+            .map_err(SendEmailError::Smtp)?;
+        Ok(None)
     }
 }
 
+// SMTPのエンベロープ。BCCの宛先はヘッダに書かないので、ここにだけ指定する
+fn smtp_envelope(
+    sender: &SubscriberEmail,
+    message: &EmailMessage<'_>,
+) -> Result<Envelope, SendEmailError> {
+    let address = |email: &SubscriberEmail| {
+        email
+            .as_ref()
+            .parse::<Address>()
+            .map_err(|_| SendEmailError::InvalidAddress(email.as_ref().to_owned()))
+    };
+    let recipients = std::iter::once(&message.to)
+        .chain(&message.cc)
+        .chain(&message.bcc)
+        .map(address)
+        .collect::<Result<Vec<_>, _>>()?;
+    Envelope::new(Some(address(sender)?), recipients)
+        .map_err(|_| SendEmailError::InvalidAddress(message.to.as_ref().to_owned()))
+}
+
 // リクエストボディの構造体
 // jsonシリアライズ可能にするためにSerializeをつける
 // PascalCaseはfromをFromに、toをToなどに変換する
@@ -75,16 +553,112 @@ struct SendEmailRequest<'a> {
     subject: &'a str,
     html_body: &'a str,
     text_body: &'a str,
+    // 以降の項目は、設定されていない場合は項目ごと省略する
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<AttachmentRequest<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    // 複数の宛先はカンマ区切りで送る
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bcc: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<&'a str>,
+    message_stream: &'a str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: &'a BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<HeaderRequest<'a>>,
+}
+
+// 宛先をカンマ区切りにする。宛先がなければNoneを返す
+fn join_addresses(addresses: &[SubscriberEmail]) -> Option<String> {
+    (!addresses.is_empty()).then(|| {
+        addresses
+            .iter()
+            .map(|address| address.as_ref())
+            .collect::<Vec<_>>()
+            .join(",")
+    })
+}
+
+impl<'a> SendEmailRequest<'a> {
+    fn new(from: &'a str, message: &'a EmailMessage<'a>, message_stream: &'a str) -> Self {
+        Self {
+            from,
+            to: message.to.as_ref(),
+            subject: message.subject,
+            html_body: message.html_body,
+            text_body: message.text_body,
+            attachments: message
+                .attachments
+                .iter()
+                .map(|attachment| AttachmentRequest {
+                    name: &attachment.name,
+                    content: attachment.encoded_content(),
+                    content_type: &attachment.content_type,
+                    content_id: attachment
+                        .content_id
+                        .as_ref()
+                        .map(|content_id| format!("cid:{}", content_id)),
+                })
+                .collect(),
+            reply_to: message.reply_to.as_ref().map(|reply_to| reply_to.as_ref()),
+            cc: join_addresses(&message.cc),
+            bcc: join_addresses(&message.bcc),
+            tag: message.tag,
+            message_stream,
+            metadata: &message.metadata,
+            headers: message
+                .headers
+                .iter()
+                .map(|(name, value)| HeaderRequest { name, value })
+                .collect(),
+        }
+    }
+}
+
+// {"Name": "xxx", "Value": "xxx"}
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HeaderRequest<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+// {"Name": "xxx", "Content": "<Base64>", "ContentType": "xxx", "ContentID": "cid:xxx"}
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AttachmentRequest<'a> {
+    name: &'a str,
+    content: String,
+    content_type: &'a str,
+    #[serde(rename = "ContentID", skip_serializing_if = "Option::is_none")]
+    content_id: Option<String>,
+}
+
+// レスポンスボディのうち使う項目
+#[derive(serde::Deserialize)]
+struct SendEmailResponse {
+    #[serde(rename = "MessageID")]
+    message_id: String,
 }
 
 // 単体テスト
 #[cfg(test)]
 mod tests {
+    use crate::circuit_breaker::CircuitState;
+    use crate::configuration::{CircuitBreakerSettings, MessageStreamSettings, RateLimitSettings};
+    use crate::dkim::tests::{ed25519_key_pair, verify};
+    use crate::dkim::{DkimAlgorithm, DkimSigner};
     use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
+    use crate::email_client::{EmailClient, SendEmailError};
+    use crate::email_message::{Attachment, EmailMessage, MAX_MESSAGE_SIZE};
     use claim::assert_err;
     use claim::assert_ok;
     use fake::faker::internet::en::SafeEmail;
+    use lettre::{AsyncSmtpTransport, Tokio1Executor};
     use secrecy::Secret;
     use wiremock::matchers::any;
     // 英語のランダムな文章を生成する
@@ -172,7 +746,12 @@ mod tests {
         // [Act]
         // メールを送信する
         let _ = email_client
-            .send_email(email(), &subject(), &content(), &content())
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
             .await;
 
         // [Assert]
@@ -194,7 +773,12 @@ mod tests {
 
         // [Act]
         let outcome = email_client
-            .send_email(email(), &subject(), &content(), &content())
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
             .await;
 
         // [Assert]
@@ -218,7 +802,12 @@ mod tests {
 
         // [Act]
         let outcome = email_client
-            .send_email(email(), &subject(), &content(), &content())
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
             .await;
 
         // [Assert]
@@ -243,11 +832,670 @@ mod tests {
 
         // [Act]
         let outcome = email_client
-            .send_email(email(), &subject(), &content(), &content())
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
             .await;
 
         // [Assert]
         // send_emailメソッドがErrを返せばテスト成功
         assert_err!(outcome);
     }
+
+    /// 2回続けて失敗すると回路を開くEmailClientを取得
+    fn email_client_with_circuit_breaker(base_url: String) -> EmailClient {
+        email_client(base_url).with_circuit_breaker(&CircuitBreakerSettings {
+            failure_threshold: 2,
+            open_seconds: 60,
+        })
+    }
+
+    // 続けて失敗すると、回路が開いている間はサーバにリクエストせずにすぐ失敗する
+    #[tokio::test]
+    async fn send_email_short_circuits_after_consecutive_server_errors() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri());
+
+        // 回路が開いた後のリクエストはサーバに届かない
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        for _ in 0..2 {
+            let _ = email_client
+                .send_email(EmailMessage::new(
+                    email(),
+                    &subject(),
+                    &content(),
+                    &content(),
+                ))
+                .await;
+        }
+        let outcome = email_client
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
+            .await;
+
+        // [Assert]
+        assert!(matches!(outcome, Err(SendEmailError::CircuitOpen)));
+        assert_eq!(
+            email_client.providers()[0].circuit_breaker().state(),
+            CircuitState::Open
+        );
+    }
+
+    // 4xxは送信サービスの障害ではないので回路を開かない
+    #[tokio::test]
+    async fn client_errors_do_not_open_the_circuit() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        for _ in 0..3 {
+            let outcome = email_client
+                .send_email(EmailMessage::new(
+                    email(),
+                    &subject(),
+                    &content(),
+                    &content(),
+                ))
+                .await;
+
+            // [Assert]
+            assert!(matches!(outcome, Err(SendEmailError::Request(_))));
+        }
+        assert_eq!(
+            email_client.providers()[0].circuit_breaker().state(),
+            CircuitState::Closed
+        );
+    }
+
+    // タイムアウトは送信サービスの障害として数える
+    #[tokio::test]
+    async fn timeouts_open_the_circuit() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_circuit_breaker(mock_server.uri());
+
+        let response = ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(180));
+        Mock::given(any())
+            .respond_with(response)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        for _ in 0..2 {
+            let _ = email_client
+                .send_email(EmailMessage::new(
+                    email(),
+                    &subject(),
+                    &content(),
+                    &content(),
+                ))
+                .await;
+        }
+
+        // [Assert]
+        assert_eq!(
+            email_client.providers()[0].circuit_breaker().state(),
+            CircuitState::Open
+        );
+    }
+
+    /// 主の送信サービスが2回続けて失敗すると、予備の送信サービスだけを使うEmailClientを取得
+    fn email_client_with_fallback(primary_url: String, fallback_url: String) -> EmailClient {
+        email_client_with_circuit_breaker(primary_url).with_fallback_provider(
+            "fallback".into(),
+            fallback_url,
+            Secret::new(Faker.fake()),
+            &RateLimitSettings::default(),
+        )
+    }
+
+    // 主の送信サービスが500を返したら、予備の送信サービスで送り直す
+    #[tokio::test]
+    async fn send_email_fails_over_to_the_next_provider_on_server_errors() {
+        // [Arrange]
+        let primary = MockServer::start().await;
+        let fallback = MockServer::start().await;
+        let email_client = email_client_with_fallback(primary.uri(), fallback.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&fallback)
+            .await;
+
+        // [Act]
+        let outcome = email_client
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
+            .await;
+
+        // [Assert]
+        assert_ok!(outcome);
+    }
+
+    // 4xxはどの送信サービスでも失敗するので、送り直さない
+    #[tokio::test]
+    async fn send_email_does_not_fail_over_on_client_errors() {
+        // [Arrange]
+        let primary = MockServer::start().await;
+        let fallback = MockServer::start().await;
+        let email_client = email_client_with_fallback(primary.uri(), fallback.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&fallback)
+            .await;
+
+        // [Act]
+        let outcome = email_client
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
+            .await;
+
+        // [Assert]
+        assert_err!(outcome);
+    }
+
+    // 主の送信サービスの回路が開いている間は、予備の送信サービスに直接送る
+    #[tokio::test]
+    async fn send_email_skips_providers_with_an_open_circuit() {
+        // [Arrange]
+        let primary = MockServer::start().await;
+        let fallback = MockServer::start().await;
+        let email_client = email_client_with_fallback(primary.uri(), fallback.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(2)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(4)
+            .mount(&fallback)
+            .await;
+
+        // [Act]
+        for _ in 0..4 {
+            let outcome = email_client
+                .send_email(EmailMessage::new(
+                    email(),
+                    &subject(),
+                    &content(),
+                    &content(),
+                ))
+                .await;
+
+            // [Assert]
+            assert_ok!(outcome);
+        }
+        // 主の送信サービスの回路が開き、以降は予備の送信サービスだけで送る
+        assert!(email_client.providers()[0].circuit_breaker().is_rejecting());
+    }
+
+    // すべての送信サービスが失敗したら、最後のエラーを返す
+    #[tokio::test]
+    async fn send_email_fails_if_every_provider_fails() {
+        // [Arrange]
+        let primary = MockServer::start().await;
+        let fallback = MockServer::start().await;
+        let email_client = email_client_with_fallback(primary.uri(), fallback.uri());
+
+        for server in [&primary, &fallback] {
+            Mock::given(any())
+                .respond_with(ResponseTemplate::new(503))
+                .expect(1)
+                .mount(server)
+                .await;
+        }
+
+        // [Act]
+        let outcome = email_client
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
+            .await;
+
+        // [Assert]
+        assert!(matches!(outcome, Err(SendEmailError::Request(_))));
+    }
+
+    // 添付ファイルはBase64でエンコードしてAttachmentsに入れる
+    #[tokio::test]
+    async fn send_email_encodes_attachments_and_inline_images() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        let message = EmailMessage::new(email(), "Event", "<img src=\"cid:logo\">", "Event")
+            .attachment(Attachment::new(
+                "event.ics",
+                "text/calendar",
+                b"BEGIN:VCALENDAR".to_vec(),
+            ))
+            .attachment(Attachment::new("logo.png", "image/png", vec![1, 2, 3]).inline("logo"));
+        let outcome = email_client.send_email(message).await;
+
+        // [Assert]
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["Attachments"],
+            serde_json::json!([
+                {
+                    "Name": "event.ics",
+                    "Content": "QkVHSU46VkNBTEVOREFS",
+                    "ContentType": "text/calendar"
+                },
+                {
+                    "Name": "logo.png",
+                    "Content": "AQID",
+                    "ContentType": "image/png",
+                    "ContentID": "cid:logo"
+                }
+            ])
+        );
+    }
+
+    /// メールを送信し、モックサーバが受け取ったリクエストボディを返す
+    async fn sent_body(message: EmailMessage<'_>) -> serde_json::Value {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        assert_ok!(email_client.send_email(message).await);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        serde_json::from_slice(&request.body).unwrap()
+    }
+
+    fn address(address: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(address.into()).unwrap()
+    }
+
+    // 設定していない項目は送らない
+    #[tokio::test]
+    async fn send_email_omits_optional_fields_that_are_not_set() {
+        // [Act]
+        let body = sent_body(EmailMessage::new(
+            email(),
+            &subject(),
+            &content(),
+            &content(),
+        ))
+        .await;
+
+        // [Assert]
+        for field in [
+            "Attachments",
+            "ReplyTo",
+            "Cc",
+            "Bcc",
+            "Tag",
+            "Metadata",
+            "Headers",
+        ] {
+            assert!(body.get(field).is_none(), "{} was sent.", field);
+        }
+    }
+
+    // 返信先はReplyToに入れる
+    #[tokio::test]
+    async fn send_email_sets_the_reply_to_address() {
+        // [Act]
+        let body = sent_body(
+            EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi")
+                .reply_to(address("editors@example.com")),
+        )
+        .await;
+
+        // [Assert]
+        assert_eq!(body["ReplyTo"], "editors@example.com");
+    }
+
+    // CCとBCCの宛先はカンマ区切りで入れる
+    #[tokio::test]
+    async fn send_email_joins_cc_and_bcc_addresses() {
+        // [Act]
+        let body = sent_body(
+            EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi")
+                .cc(address("first@example.com"))
+                .cc(address("second@example.com"))
+                .bcc(address("archive@example.com")),
+        )
+        .await;
+
+        // [Assert]
+        assert_eq!(body["Cc"], "first@example.com,second@example.com");
+        assert_eq!(body["Bcc"], "archive@example.com");
+    }
+
+    // 購読者の操作に応じて送るメールは既定でtransactionalのストリームで送る
+    #[tokio::test]
+    async fn transactional_messages_use_the_transactional_stream() {
+        // [Act]
+        let body = sent_body(EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi")).await;
+
+        // [Assert]
+        assert_eq!(body["MessageStream"], "outbound");
+    }
+
+    // まとめて送るメールはbroadcastのストリームで送る
+    #[tokio::test]
+    async fn broadcast_messages_use_the_broadcast_stream() {
+        // [Act]
+        let body =
+            sent_body(EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi").broadcast()).await;
+
+        // [Assert]
+        assert_eq!(body["MessageStream"], "broadcast");
+    }
+
+    // 設定したストリーム名を使う
+    #[tokio::test]
+    async fn message_streams_are_read_from_the_settings() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let email_client =
+            email_client(mock_server.uri()).with_message_streams(MessageStreamSettings {
+                transactional: "account-emails".into(),
+                broadcast: "weekly-newsletter".into(),
+            });
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        let message = || EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi");
+        assert_ok!(email_client.send_email(message()).await);
+        assert_ok!(email_client.send_email(message().broadcast()).await);
+
+        // [Assert]
+        let streams: Vec<_> = mock_server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|r| {
+                let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+                body["MessageStream"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(streams, vec!["account-emails", "weekly-newsletter"]);
+    }
+
+    // タグとメッセージストリームを指定するとそのまま入れる
+    #[tokio::test]
+    async fn send_email_sets_the_tag_and_message_stream() {
+        // [Act]
+        let body = sent_body(
+            EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi")
+                .tag("confirmation")
+                .broadcast()
+                .message_stream("announcements"),
+        )
+        .await;
+
+        // [Assert]
+        assert_eq!(body["Tag"], "confirmation");
+        assert_eq!(body["MessageStream"], "announcements");
+    }
+
+    // メタデータはオブジェクトとして入れ、同じキーは後の値で上書きする
+    #[tokio::test]
+    async fn send_email_sets_metadata() {
+        // [Act]
+        let body = sent_body(
+            EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi")
+                .metadata("newsletter_issue_id", "first")
+                .metadata("newsletter_issue_id", "second")
+                .metadata("list", "newsletter"),
+        )
+        .await;
+
+        // [Assert]
+        assert_eq!(
+            body["Metadata"],
+            serde_json::json!({ "newsletter_issue_id": "second", "list": "newsletter" })
+        );
+    }
+
+    // ヘッダは追加した順にName/Valueの組で入れる
+    #[tokio::test]
+    async fn send_email_sets_custom_headers() {
+        // [Act]
+        let body = sent_body(
+            EmailMessage::new(email(), "Hello", "<p>Hi</p>", "Hi")
+                .header("List-Unsubscribe", "<https://example.com/unsubscribe>")
+                .header("X-Campaign", "weekly"),
+        )
+        .await;
+
+        // [Assert]
+        assert_eq!(
+            body["Headers"],
+            serde_json::json!([
+                { "Name": "List-Unsubscribe", "Value": "<https://example.com/unsubscribe>" },
+                { "Name": "X-Campaign", "Value": "weekly" }
+            ])
+        );
+    }
+
+    // 上限を超える大きさのメールはリクエストせずに失敗する
+    #[tokio::test]
+    async fn send_email_rejects_messages_over_the_size_limit() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        // Base64でエンコードすると4/3倍になるので、上限の3/4を超える添付ファイルは送れない
+        let message = EmailMessage::new(email(), "Event", "", "").attachment(Attachment::new(
+            "large.bin",
+            "application/octet-stream",
+            vec![0; MAX_MESSAGE_SIZE / 4 * 3 + 3],
+        ));
+        let outcome = email_client.send_email(message).await;
+
+        // [Assert]
+        assert!(matches!(outcome, Err(SendEmailError::MessageTooLarge(_))));
+    }
+
+    // SMTPリレーの代わりにやりとりを記録するSMTPサーバ
+    // 1つの接続を受け付け、RCPT TOで受け取った宛先とDATAで受け取ったメッセージを返す
+    async fn fake_smtp_relay(
+        rcpt_reply: &'static str,
+    ) -> (u16, tokio::task::JoinHandle<(Vec<String>, String)>) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut recipients = Vec::new();
+            let mut data = String::new();
+            writer
+                .write_all(b"220 relay.example.com\r\n")
+                .await
+                .unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply = if let Some(recipient) = line.strip_prefix("RCPT TO:") {
+                    recipients.push(recipient.trim_matches(['<', '>']).to_owned());
+                    rcpt_reply
+                } else if line == "DATA" {
+                    writer.write_all(b"354 Go ahead\r\n").await.unwrap();
+                    while let Some(line) = lines.next_line().await.unwrap() {
+                        if line == "." {
+                            break;
+                        }
+                        // 行頭のピリオドは送信側で二重にしてある
+                        data.push_str(line.strip_prefix('.').unwrap_or(&line));
+                        data.push_str("\r\n");
+                    }
+                    "250 Queued"
+                } else if line == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").await.unwrap();
+                    break;
+                } else {
+                    "250 OK"
+                };
+                writer
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .await
+                    .unwrap();
+            }
+            (recipients, data)
+        });
+        (port, relay)
+    }
+
+    // Postmarkが使えないときはSMTPリレーで送るクライアント (1回失敗すると回路を開く)
+    fn email_client_with_smtp_relay(base_url: String, relay_port: u16) -> EmailClient {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+            .port(relay_port)
+            .build();
+        let circuit_breaker = CircuitBreakerSettings {
+            failure_threshold: 1,
+            open_seconds: 60,
+        };
+        email_client(base_url)
+            .with_circuit_breaker(&circuit_breaker)
+            .with_smtp_relay("relay".into(), transport, &RateLimitSettings::default())
+    }
+
+    // SMTPリレーにはDKIM署名を付けたMIME形式のメッセージを送り、BCCの宛先はエンベロープにだけ指定する
+    #[tokio::test]
+    async fn smtp_relay_messages_are_signed_with_dkim() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let (relay_port, relay) = fake_smtp_relay("250 OK").await;
+        let (private_key_pem, public_key) = ed25519_key_pair();
+        let signer = DkimSigner::from_pem(
+            "example.com".into(),
+            "newsletter".into(),
+            DkimAlgorithm::Ed25519,
+            &private_key_pem,
+        )
+        .unwrap();
+        let email_client =
+            email_client_with_smtp_relay(mock_server.uri(), relay_port).with_dkim_signer(signer);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        let (subject, content) = (subject(), content());
+        let message =
+            EmailMessage::new(address("ursula@example.com"), &subject, &content, &content)
+                .bcc(address("bcc@example.com"));
+        let outcome = email_client.send_email(message).await;
+
+        // [Assert]
+        assert_eq!(outcome.unwrap(), None);
+        let (recipients, data) = relay.await.unwrap();
+        assert_eq!(recipients, vec!["ursula@example.com", "bcc@example.com"]);
+        assert!(data.starts_with("DKIM-Signature: v=1; a=ed25519-sha256;"));
+        assert!(verify(&data, &public_key));
+        assert!(!data.contains("bcc@example.com"));
+    }
+
+    // SMTPリレーが宛先を恒久的に拒否した場合は、送信サービスの障害として数えない
+    #[tokio::test]
+    async fn permanent_smtp_errors_do_not_open_the_circuit() {
+        // [Arrange]
+        let mock_server = MockServer::start().await;
+        let (relay_port, _relay) = fake_smtp_relay("550 No such user").await;
+        let email_client = email_client_with_smtp_relay(mock_server.uri(), relay_port);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // [Act]
+        let outcome = email_client
+            .send_email(EmailMessage::new(
+                email(),
+                &subject(),
+                &content(),
+                &content(),
+            ))
+            .await;
+
+        // [Assert]
+        assert!(matches!(outcome, Err(SendEmailError::Smtp(_))));
+        let relay = &email_client.providers()[1];
+        assert_eq!(relay.name(), "relay");
+        assert_eq!(relay.circuit_breaker().state(), CircuitState::Closed);
+    }
 }
@@ -0,0 +1,223 @@
+// 送信するメール
+// EmailClient::send_emailに渡す。宛先と件名、本文のほかは必要なものだけ設定する
+use crate::domain::SubscriberEmail;
+use base64::Engine;
+use std::collections::BTreeMap;
+
+// Postmarkが受け付ける1通の大きさの上限 (本文と、Base64でエンコードした添付ファイルの合計)
+pub const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// メールの種類。Postmarkでは種類ごとに別のメッセージストリームで送る必要がある
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageKind {
+    // 購読の確認など、購読者の操作に応じて1通ずつ送るメール
+    Transactional,
+    // ニュースレターなど、購読者にまとめて送るメール
+    Broadcast,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Transactional => "transactional",
+            MessageKind::Broadcast => "broadcast",
+        }
+    }
+}
+
+pub struct EmailMessage<'a> {
+    pub(crate) to: SubscriberEmail,
+    pub(crate) subject: &'a str,
+    pub(crate) html_body: &'a str,
+    pub(crate) text_body: &'a str,
+    pub(crate) attachments: Vec<Attachment>,
+    pub(crate) kind: MessageKind,
+    pub(crate) reply_to: Option<SubscriberEmail>,
+    pub(crate) cc: Vec<SubscriberEmail>,
+    pub(crate) bcc: Vec<SubscriberEmail>,
+    // Postmarkの統計を分類するタグ
+    pub(crate) tag: Option<&'a str>,
+    // Postmarkのメッセージストリーム。省略すると種類に応じて設定のストリームで送る
+    pub(crate) message_stream: Option<&'a str>,
+    // バウンスなどのWebhookにそのまま返ってくる値
+    pub(crate) metadata: BTreeMap<String, String>,
+    pub(crate) headers: Vec<(String, String)>,
+}
+
+impl<'a> EmailMessage<'a> {
+    pub fn new(
+        to: SubscriberEmail,
+        subject: &'a str,
+        html_body: &'a str,
+        text_body: &'a str,
+    ) -> Self {
+        Self {
+            to,
+            subject,
+            html_body,
+            text_body,
+            attachments: Vec::new(),
+            kind: MessageKind::Transactional,
+            reply_to: None,
+            cc: Vec::new(),
+            bcc: Vec::new(),
+            tag: None,
+            message_stream: None,
+            metadata: BTreeMap::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    /// 購読者にまとめて送るメールにする (既定は購読者の操作に応じて送るメール)
+    pub fn broadcast(mut self) -> Self {
+        self.kind = MessageKind::Broadcast;
+        self
+    }
+
+    /// 返信先を送信者以外のアドレスにする
+    pub fn reply_to(mut self, reply_to: SubscriberEmail) -> Self {
+        self.reply_to = Some(reply_to);
+        self
+    }
+
+    /// CCの宛先を追加する
+    pub fn cc(mut self, cc: SubscriberEmail) -> Self {
+        self.cc.push(cc);
+        self
+    }
+
+    /// BCCの宛先を追加する
+    pub fn bcc(mut self, bcc: SubscriberEmail) -> Self {
+        self.bcc.push(bcc);
+        self
+    }
+
+    pub fn tag(mut self, tag: &'a str) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// 種類に関係なく、指定したメッセージストリームで送る
+    pub fn message_stream(mut self, message_stream: &'a str) -> Self {
+        self.message_stream = Some(message_stream);
+        self
+    }
+
+    /// メタデータを追加する。同じキーは後から追加した値で上書きする
+    pub fn metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// 任意のヘッダを追加する
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// 添付ファイルを追加する
+    pub fn attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    /// 送信するときの大きさ (バイト)
+    pub fn size(&self) -> usize {
+        self.html_body.len()
+            + self.text_body.len()
+            + self
+                .attachments
+                .iter()
+                .map(Attachment::encoded_len)
+                .sum::<usize>()
+    }
+
+    // 宛先と件名、本文、種類だけのメールならその複製を返す
+    // 後で送るためにデータベースに保存できるのはこの形のメールだけ
+    pub(crate) fn plain_copy(&self) -> Option<Self> {
+        let is_plain = self.attachments.is_empty()
+            && self.reply_to.is_none()
+            && self.cc.is_empty()
+            && self.bcc.is_empty()
+            && self.tag.is_none()
+            && self.message_stream.is_none()
+            && self.metadata.is_empty()
+            && self.headers.is_empty();
+        is_plain.then(|| Self {
+            kind: self.kind,
+            ..Self::new(
+                self.to.clone(),
+                self.subject,
+                self.html_body,
+                self.text_body,
+            )
+        })
+    }
+}
+
+/// 添付ファイル。content_idを付けると、HTML本文から cid:<content_id> で参照するインライン画像になる
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub(crate) name: String,
+    pub(crate) content_type: String,
+    content: Vec<u8>,
+    pub(crate) content_id: Option<String>,
+}
+
+impl Attachment {
+    pub fn new(name: impl Into<String>, content_type: impl Into<String>, content: Vec<u8>) -> Self {
+        Self {
+            name: name.into(),
+            content_type: content_type.into(),
+            content,
+            content_id: None,
+        }
+    }
+
+    /// 本文に埋め込むインライン画像にする
+    pub fn inline(mut self, content_id: impl Into<String>) -> Self {
+        self.content_id = Some(content_id.into());
+        self
+    }
+
+    /// エンコードする前の内容
+    pub(crate) fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// Base64でエンコードした内容
+    pub(crate) fn encoded_content(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(&self.content)
+    }
+
+    // Base64でエンコードした後の長さ (3バイトごとに4文字、端数はパディングする)
+    fn encoded_len(&self) -> usize {
+        self.content.len().div_ceil(3) * 4
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_message::{Attachment, EmailMessage};
+
+    fn recipient() -> SubscriberEmail {
+        SubscriberEmail::parse("ursula@example.com".into()).unwrap()
+    }
+
+    #[test]
+    fn the_size_counts_bodies_and_encoded_attachments() {
+        let message = EmailMessage::new(recipient(), "Event", "<p>Hi</p>", "Hi")
+            .attachment(Attachment::new("event.ics", "text/calendar", vec![0; 4]));
+        // 4バイトはBase64で8文字になる
+        assert_eq!(message.size(), 9 + 2 + 8);
+    }
+
+    #[test]
+    fn the_encoded_length_matches_the_encoded_content() {
+        for len in 0..10 {
+            let attachment = Attachment::new("logo.png", "image/png", vec![7; len]);
+            assert_eq!(attachment.encoded_len(), attachment.encoded_content().len());
+        }
+    }
+}
@@ -0,0 +1,480 @@
+// メール本文と公開アーカイブのページ、フィードのテンプレート
+// templatesディレクトリのテンプレートを起動時に読み込み、サンプルの値で一度描画して検証する
+// 変数名の誤りなどは配信中ではなく起動時のエラーになる
+use crate::configuration::TemplateSettings;
+use crate::issue_stats::{
+    DeliveryCounts, EngagementStats, IssueStats, IssueSummary, LinkStats, TimelineBucket,
+};
+use crate::newsletter_issues::IssueContent;
+use tera::{Context, Tera};
+
+#[derive(Debug)]
+pub struct EmailTemplates {
+    tera: Tera,
+}
+
+// 描画したメール本文
+pub struct RenderedEmail {
+    pub html: String,
+    pub text: String,
+}
+
+/// テンプレートに渡す値。emails/{NAME}.htmlとemails/{NAME}.txtを描画する
+pub trait EmailTemplate: serde::Serialize {
+    const NAME: &'static str;
+
+    /// 起動時の検証に使うサンプルの値
+    fn sample() -> Self;
+}
+
+/// ページのテンプレートに渡す値。pages/{NAME}.htmlを描画する
+pub trait PageTemplate: serde::Serialize {
+    const NAME: &'static str;
+
+    /// 起動時の検証に使うサンプルの値
+    fn sample() -> Self;
+}
+
+/// フィードのテンプレートに渡す値。feeds/{NAME}.xmlを描画する
+pub trait FeedTemplate: serde::Serialize {
+    const NAME: &'static str;
+
+    /// 起動時の検証に使うサンプルの値
+    fn sample() -> Self;
+}
+
+// 宛先の購読者 ({{ subscriber.name }}などで参照する)
+#[derive(serde::Serialize)]
+pub struct Recipient<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+}
+
+const SAMPLE_RECIPIENT: Recipient<'static> = Recipient {
+    name: "Ursula Le Guin",
+    email: "ursula@example.com",
+};
+
+// 購読の確認メール
+#[derive(serde::Serialize)]
+pub struct ConfirmationEmail<'a> {
+    pub subscriber: Recipient<'a>,
+    pub list_name: &'a str,
+    pub confirmation_link: &'a str,
+}
+
+impl EmailTemplate for ConfirmationEmail<'_> {
+    const NAME: &'static str = "confirmation";
+
+    fn sample() -> Self {
+        Self {
+            subscriber: SAMPLE_RECIPIENT,
+            list_name: "Newsletter",
+            confirmation_link: "https://example.com/subscriptions/confirm",
+        }
+    }
+}
+
+// 確認されていない購読者に一度だけ送るリマインダー
+#[derive(serde::Serialize)]
+pub struct ConfirmationReminderEmail<'a> {
+    pub subscriber: Recipient<'a>,
+    pub list_name: &'a str,
+    pub confirmation_link: &'a str,
+}
+
+impl EmailTemplate for ConfirmationReminderEmail<'_> {
+    const NAME: &'static str = "confirmation_reminder";
+
+    fn sample() -> Self {
+        Self {
+            subscriber: SAMPLE_RECIPIENT,
+            list_name: "Newsletter",
+            confirmation_link: "https://example.com/subscriptions/confirm",
+        }
+    }
+}
+
+// 新しいメールアドレスに送る確認メール
+#[derive(serde::Serialize)]
+pub struct EmailChangeVerificationEmail<'a> {
+    pub subscriber: Recipient<'a>,
+    pub confirmation_link: &'a str,
+}
+
+impl EmailTemplate for EmailChangeVerificationEmail<'_> {
+    const NAME: &'static str = "email_change_verification";
+
+    fn sample() -> Self {
+        Self {
+            subscriber: SAMPLE_RECIPIENT,
+            confirmation_link: "https://example.com/subscriptions/email_change/confirm",
+        }
+    }
+}
+
+// 現在のメールアドレスに送る変更申請の承認依頼
+#[derive(serde::Serialize)]
+pub struct EmailChangeAuthorizationEmail<'a> {
+    pub subscriber: Recipient<'a>,
+    pub new_email: &'a str,
+    pub authorization_link: &'a str,
+}
+
+impl EmailTemplate for EmailChangeAuthorizationEmail<'_> {
+    const NAME: &'static str = "email_change_authorization";
+
+    fn sample() -> Self {
+        Self {
+            subscriber: SAMPLE_RECIPIENT,
+            new_email: "ursula@example.com",
+            authorization_link: "https://example.com/subscriptions/email_change/authorize",
+        }
+    }
+}
+
+// ニュースレターの号
+#[derive(serde::Serialize)]
+pub struct NewsletterIssueEmail<'a> {
+    pub subscriber: Recipient<'a>,
+    pub issue: IssueContent<'a>,
+    // 公開アーカイブでこの号を表示するURL
+    pub view_in_browser_url: &'a str,
+    pub unsubscribe_url: &'a str,
+    // 開封を計測する画像のURL (計測しない号はNone)
+    pub open_tracking_url: Option<&'a str>,
+}
+
+impl EmailTemplate for NewsletterIssueEmail<'_> {
+    const NAME: &'static str = "newsletter_issue";
+
+    fn sample() -> Self {
+        Self {
+            subscriber: SAMPLE_RECIPIENT,
+            issue: IssueContent {
+                title: "Newsletter title",
+                text_content: "Newsletter body as plain text",
+                html_content: "<p>Newsletter body as HTML</p>",
+            },
+            view_in_browser_url: "https://example.com/archive/newsletter-title",
+            unsubscribe_url: "https://example.com/subscriptions/unsubscribe",
+            open_tracking_url: Some("https://example.com/t/o/token"),
+        }
+    }
+}
+
+// ウェルカムシリーズなどのシーケンスの1通
+#[derive(serde::Serialize)]
+pub struct SequenceStepEmail<'a> {
+    pub subscriber: Recipient<'a>,
+    pub step: IssueContent<'a>,
+    pub unsubscribe_url: &'a str,
+}
+
+impl EmailTemplate for SequenceStepEmail<'_> {
+    const NAME: &'static str = "sequence_step";
+
+    fn sample() -> Self {
+        Self {
+            subscriber: SAMPLE_RECIPIENT,
+            step: IssueContent {
+                title: "Welcome aboard",
+                text_content: "Here is what to expect.",
+                html_content: "<p>Here is what to expect.</p>",
+            },
+            unsubscribe_url: "https://example.com/subscriptions/unsubscribe",
+        }
+    }
+}
+
+// アーカイブの一覧に並べる号
+#[derive(serde::Serialize)]
+pub struct ArchivedIssueSummary {
+    pub title: String,
+    pub slug: String,
+    pub published_on: String,
+}
+
+// アーカイブの一覧ページ
+#[derive(serde::Serialize)]
+pub struct ArchiveIndexPage {
+    pub issues: Vec<ArchivedIssueSummary>,
+    // 新しい号のページと古い号のページの番号 (なければNone)
+    pub previous_page: Option<u32>,
+    pub next_page: Option<u32>,
+}
+
+impl PageTemplate for ArchiveIndexPage {
+    const NAME: &'static str = "archive_index";
+
+    fn sample() -> Self {
+        Self {
+            issues: vec![ArchivedIssueSummary {
+                title: "Newsletter title".into(),
+                slug: "newsletter-title".into(),
+                published_on: "2023-11-06".into(),
+            }],
+            previous_page: Some(1),
+            next_page: Some(3),
+        }
+    }
+}
+
+// アーカイブの号のページ
+#[derive(serde::Serialize)]
+pub struct ArchiveIssuePage<'a> {
+    pub title: &'a str,
+    pub published_on: &'a str,
+    pub html_content: &'a str,
+}
+
+impl PageTemplate for ArchiveIssuePage<'_> {
+    const NAME: &'static str = "archive_issue";
+
+    fn sample() -> Self {
+        Self {
+            title: "Newsletter title",
+            published_on: "2023-11-06",
+            html_content: "<p>Newsletter body as HTML</p>",
+        }
+    }
+}
+
+// 管理者向けの号の集計ページ
+impl PageTemplate for IssueStats {
+    const NAME: &'static str = "newsletter_stats";
+
+    fn sample() -> Self {
+        let published_at = chrono::DateTime::UNIX_EPOCH;
+        let engagement = || EngagementStats {
+            unique: 1,
+            total: 2,
+            timeline: vec![TimelineBucket {
+                hour: published_at,
+                unique: 1,
+            }],
+        };
+        Self {
+            issue: IssueSummary {
+                id: uuid::Uuid::nil(),
+                title: "Newsletter title".into(),
+                status: "published".into(),
+                published_at: Some(published_at),
+            },
+            deliveries: DeliveryCounts {
+                queued: 1,
+                sent: 2,
+                failed: 3,
+                bounced: 4,
+                suppressed: 5,
+            },
+            opens: engagement(),
+            clicks: engagement(),
+            top_links: vec![LinkStats {
+                url: "https://example.com".into(),
+                unique: 1,
+                total: 2,
+            }],
+        }
+    }
+}
+
+// フィードに載せる号。日付はフィードの形式に合わせて整形する
+#[derive(serde::Serialize)]
+pub struct FeedEntry {
+    pub title: String,
+    // アーカイブの号のページの絶対URL
+    pub url: String,
+    pub html_content: String,
+    pub published: String,
+}
+
+// RSS 2.0のフィード (日付はRFC 2822)
+#[derive(serde::Serialize)]
+pub struct RssFeed {
+    pub archive_url: String,
+    pub feed_url: String,
+    pub updated: String,
+    pub entries: Vec<FeedEntry>,
+}
+
+impl FeedTemplate for RssFeed {
+    const NAME: &'static str = "rss";
+
+    fn sample() -> Self {
+        Self {
+            archive_url: "https://example.com/archive".into(),
+            feed_url: "https://example.com/feed.rss".into(),
+            updated: "Mon, 6 Nov 2023 10:15:30 +0000".into(),
+            entries: vec![FeedEntry {
+                title: "Newsletter title".into(),
+                url: "https://example.com/archive/newsletter-title".into(),
+                html_content: "<p>Newsletter body as HTML</p>".into(),
+                published: "Mon, 6 Nov 2023 10:15:30 +0000".into(),
+            }],
+        }
+    }
+}
+
+// Atom 1.0のフィード (日付はRFC 3339)
+#[derive(serde::Serialize)]
+pub struct AtomFeed {
+    pub archive_url: String,
+    pub feed_url: String,
+    pub updated: String,
+    pub entries: Vec<FeedEntry>,
+}
+
+impl FeedTemplate for AtomFeed {
+    const NAME: &'static str = "atom";
+
+    fn sample() -> Self {
+        Self {
+            archive_url: "https://example.com/archive".into(),
+            feed_url: "https://example.com/feed.atom".into(),
+            updated: "2023-11-06T10:15:30Z".into(),
+            entries: vec![FeedEntry {
+                title: "Newsletter title".into(),
+                url: "https://example.com/archive/newsletter-title".into(),
+                html_content: "<p>Newsletter body as HTML</p>".into(),
+                published: "2023-11-06T10:15:30Z".into(),
+            }],
+        }
+    }
+}
+
+impl EmailTemplates {
+    /// テンプレートを読み込み、すべてのメールをサンプルの値で描画できることを確認する
+    pub fn load(settings: &TemplateSettings) -> Result<Self, tera::Error> {
+        let glob = format!("{}/**/*", settings.directory.trim_end_matches('/'));
+        let mut tera = Tera::new(&glob)?;
+        // 既定のエスケープはURLの/まで置き換えてしまうので、HTMLとして必要な文字だけを置き換える
+        tera.set_escape_fn(escape_html);
+        let templates = Self { tera };
+        templates.validate::<ConfirmationEmail>()?;
+        templates.validate::<ConfirmationReminderEmail>()?;
+        templates.validate::<EmailChangeVerificationEmail>()?;
+        templates.validate::<EmailChangeAuthorizationEmail>()?;
+        templates.validate::<NewsletterIssueEmail>()?;
+        templates.validate::<SequenceStepEmail>()?;
+        templates.validate_page::<ArchiveIndexPage>()?;
+        templates.validate_page::<ArchiveIssuePage>()?;
+        templates.validate_page::<IssueStats>()?;
+        templates.validate_feed::<RssFeed>()?;
+        templates.validate_feed::<AtomFeed>()?;
+        Ok(templates)
+    }
+
+    fn validate<T: EmailTemplate>(&self) -> Result<(), tera::Error> {
+        self.render(&T::sample()).map(|_| ())
+    }
+
+    fn validate_page<T: PageTemplate>(&self) -> Result<(), tera::Error> {
+        self.render_page(&T::sample()).map(|_| ())
+    }
+
+    fn validate_feed<T: FeedTemplate>(&self) -> Result<(), tera::Error> {
+        self.render_feed(&T::sample()).map(|_| ())
+    }
+
+    /// フィードを描画する。値はXMLとしてエスケープされる
+    pub fn render_feed<T: FeedTemplate>(&self, feed: &T) -> Result<String, tera::Error> {
+        let context = Context::from_serialize(feed)?;
+        self.tera
+            .render(&format!("feeds/{}.xml", T::NAME), &context)
+    }
+
+    /// ページを描画する
+    pub fn render_page<T: PageTemplate>(&self, page: &T) -> Result<String, tera::Error> {
+        let context = Context::from_serialize(page)?;
+        self.tera
+            .render(&format!("pages/{}.html", T::NAME), &context)
+    }
+
+    /// HTML版とテキスト版を描画する。HTML版では値はエスケープされる
+    pub fn render<T: EmailTemplate>(&self, email: &T) -> Result<RenderedEmail, tera::Error> {
+        let context = Context::from_serialize(email)?;
+        Ok(RenderedEmail {
+            html: self
+                .tera
+                .render(&format!("emails/{}.html", T::NAME), &context)?,
+            text: self
+                .tera
+                .render(&format!("emails/{}.txt", T::NAME), &context)?,
+        })
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configuration::TemplateSettings;
+    use crate::email_templates::{ConfirmationEmail, EmailTemplates, Recipient};
+    use claim::{assert_err, assert_ok};
+    use std::path::PathBuf;
+
+    fn settings(directory: &str) -> TemplateSettings {
+        TemplateSettings {
+            directory: directory.into(),
+        }
+    }
+
+    // 本番のテンプレートをコピーし、1ファイルだけ書き換えたディレクトリを作る
+    fn templates_with(name: &str, content: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        for sub_directory in ["layouts", "partials", "emails", "pages", "feeds"] {
+            let source = PathBuf::from("templates").join(sub_directory);
+            std::fs::create_dir_all(directory.join(sub_directory)).unwrap();
+            for entry in std::fs::read_dir(source).unwrap() {
+                let entry = entry.unwrap();
+                std::fs::copy(
+                    entry.path(),
+                    directory.join(sub_directory).join(entry.file_name()),
+                )
+                .unwrap();
+            }
+        }
+        std::fs::write(directory.join(name), content).unwrap();
+        directory
+    }
+
+    #[test]
+    fn the_bundled_templates_are_valid() {
+        assert_ok!(EmailTemplates::load(&settings("templates")));
+    }
+
+    #[test]
+    fn a_missing_directory_is_rejected() {
+        assert_err!(EmailTemplates::load(&settings("no-such-directory")));
+    }
+
+    #[test]
+    fn an_unknown_merge_field_is_rejected_at_load_time() {
+        let directory = templates_with("partials/greeting.txt", "Hi {{ subscriber.nmae }},");
+        assert_err!(EmailTemplates::load(&settings(directory.to_str().unwrap())));
+    }
+
+    #[test]
+    fn values_are_escaped_in_html_but_not_in_text() {
+        let templates = EmailTemplates::load(&settings("templates")).unwrap();
+        let rendered = templates
+            .render(&ConfirmationEmail {
+                subscriber: Recipient {
+                    name: "<b>Ursula</b>",
+                    email: "ursula@example.com",
+                },
+                list_name: "Newsletter",
+                confirmation_link: "https://example.com/confirm",
+            })
+            .unwrap();
+        assert!(rendered.html.contains("Hi &lt;b&gt;Ursula&lt;/b&gt;,"));
+        assert!(rendered.text.contains("Hi <b>Ursula</b>,"));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, SendEmailError};
+use crate::email_message::EmailMessage;
+use crate::email_templates::{EmailTemplates, NewsletterIssueEmail, Recipient};
+use crate::newsletter_issues::{get_issue, IssueContent, NewsletterIssue};
+use crate::routes::{archive_url, unsubscribe_url};
+use crate::tracking::{rewrite_links, TrackingLinks};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+// 配信タスクを1件処理した結果
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+    // 送信サービスの1日の上限に達したため、取り出したタスクをキューに戻した
+    QuotaExhausted,
+    // 送信サービスの回路が開いているため、タスクを取り出さなかった
+    ProviderUnavailable,
+}
+
+/// 配信キューからタスクを1件取り出してメールを送信する
+#[tracing::instrument(
+    skip_all,
+    fields(
+        newsletter_issue_id = tracing::field::Empty,
+        subscriber_email = tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+    tracking_links: &TrackingLinks,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    // 送信サービスの障害中はタスクを取り出さず、失敗として記録しない
+    if email_client.is_unavailable() {
+        return Ok(ExecutionOutcome::ProviderUnavailable);
+    }
+    let task = dequeue_task(pool).await?;
+    let (mut transaction, task) = match task {
+        Some(task) => task,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("newsletter_issue_id", display(task.issue_id))
+        .record("subscriber_email", display(&task.email));
+
+    let issue = get_issue(pool, task.issue_id).await?;
+    let result = match deliver_issue(
+        email_client,
+        templates,
+        base_url,
+        tracking_links,
+        &issue,
+        &task,
+    )
+    .await
+    {
+        // 1日の上限に達している場合や、抑止リストを確認できなかった場合は
+        // ロールバックしてタスクをキューに残す
+        Err(DeliveryError::QuotaExhausted) => return Ok(ExecutionOutcome::QuotaExhausted),
+        Err(DeliveryError::Database(e)) => return Err(e),
+        result => result,
+    };
+    // 送信に失敗したタスクは再送せず、理由とともに記録する
+    if let Err(e @ DeliveryError::Failed(_)) = &result {
+        tracing::error!(
+            error.message = %e,
+            "Failed to deliver issue to a confirmed subscriber. Skipping.",
+        );
+    }
+    complete_task(&mut transaction, task.issue_id, &task.email, result).await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+// 取り出した配信タスク
+struct Task {
+    issue_id: Uuid,
+    email: String,
+    // A/Bテストで割り当てた件名 (a / b)
+    variant: Option<String>,
+    // 配信停止などで購読者が見つからない場合はNone
+    subscriber: Option<Subscriber>,
+}
+
+// 配信先の購読者
+struct Subscriber {
+    id: Uuid,
+    name: String,
+    unsubscribe_token: String,
+}
+
+/// 1通のメールを送れなかった理由。シーケンスやリマインダーのワーカーでも使う
+pub enum DeliveryError {
+    // 送信サービスの1日の上限に達しているため送信しなかった
+    QuotaExhausted,
+    // 抑止リストに載っていたため送信しなかった
+    Suppressed,
+    // 抑止リストの確認か送信数の記録に失敗した
+    Database(sqlx::Error),
+    // 送信に失敗した
+    Failed(String),
+}
+
+impl std::fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::QuotaExhausted => write!(f, "The daily send quota has been reached."),
+            DeliveryError::Suppressed => write!(f, "The recipient is on the suppression list."),
+            DeliveryError::Database(e) => write!(f, "{}", e),
+            DeliveryError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for DeliveryError {
+    fn from(e: String) -> Self {
+        DeliveryError::Failed(e)
+    }
+}
+
+impl From<SendEmailError> for DeliveryError {
+    fn from(e: SendEmailError) -> Self {
+        match e {
+            SendEmailError::QuotaExhausted => DeliveryError::QuotaExhausted,
+            SendEmailError::Suppressed => DeliveryError::Suppressed,
+            SendEmailError::Database(e) => DeliveryError::Database(e),
+            e => DeliveryError::Failed(e.to_string()),
+        }
+    }
+}
+
+// 購読者ごとにテンプレートを描画して1通送信し、PostmarkのMessageIDを返す
+async fn deliver_issue(
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+    tracking_links: &TrackingLinks,
+    issue: &NewsletterIssue,
+    task: &Task,
+) -> Result<Option<String>, DeliveryError> {
+    let subscriber = task
+        .subscriber
+        .as_ref()
+        .ok_or_else(|| "The subscriber no longer exists.".to_string())?;
+    let recipient = SubscriberEmail::parse(task.email.clone())?;
+    let view_in_browser_url = archive_url(base_url, &issue.slug);
+    let unsubscribe_url = unsubscribe_url(base_url, &subscriber.unsubscribe_token);
+    // 計測する号では、本文のリンクを購読者ごとの計測用URLに置き換える
+    // テキスト版はURLがそのまま読まれるので置き換えない
+    let html_content = if issue.track_clicks {
+        rewrite_links(&issue.html_content, |url| {
+            tracking_links.click_url(task.issue_id, subscriber.id, url)
+        })
+    } else {
+        issue.html_content.clone()
+    };
+    let open_tracking_url = issue
+        .track_opens
+        .then(|| tracking_links.open_url(task.issue_id, subscriber.id));
+    let email = templates
+        .render(&NewsletterIssueEmail {
+            subscriber: Recipient {
+                name: &subscriber.name,
+                email: recipient.as_ref(),
+            },
+            issue: IssueContent {
+                title: &issue.title,
+                text_content: &issue.text_content,
+                html_content: &html_content,
+            },
+            view_in_browser_url: &view_in_browser_url,
+            unsubscribe_url: &unsubscribe_url,
+            open_tracking_url: open_tracking_url.as_deref(),
+        })
+        .map_err(|e| e.to_string())?;
+    // 件名Bを割り当てた購読者には件名Bで送る
+    let subject = match (task.variant.as_deref(), &issue.subject_b) {
+        (Some("b"), Some(subject_b)) => subject_b,
+        _ => &issue.title,
+    };
+    // ニュースレターは確認メールなどとは別のストリームで送る
+    email_client
+        .send_email(EmailMessage::new(recipient, subject, &email.html, &email.text).broadcast())
+        .await
+        .map_err(DeliveryError::from)
+}
+
+// 他のワーカーが処理中のタスクを飛ばして、未処理のタスクを1件ロックして取り出す
+#[tracing::instrument(skip_all)]
+async fn dequeue_task(pool: &PgPool) -> Result<Option<(PgTransaction, Task)>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let r = sqlx::query!(
+        r#"
+    SELECT d.newsletter_issue_id, d.subscriber_email, d.variant,
+        s.id AS "subscriber_id?", s.name AS "name?",
+        s.unsubscribe_token AS "unsubscribe_token?"
+    FROM issue_deliveries d
+    LEFT JOIN subscriptions s ON s.email = d.subscriber_email
+    WHERE d.status = 'queued'
+    ORDER BY d.enqueued_at
+    FOR UPDATE OF d
+    SKIP LOCKED
+    LIMIT 1
+            "#,
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    Ok(r.map(|r| {
+        let task = Task {
+            issue_id: r.newsletter_issue_id,
+            email: r.subscriber_email,
+            variant: r.variant,
+            subscriber: match (r.subscriber_id, r.name, r.unsubscribe_token) {
+                (Some(id), Some(name), Some(unsubscribe_token)) => Some(Subscriber {
+                    id,
+                    name,
+                    unsubscribe_token,
+                }),
+                _ => None,
+            },
+        };
+        (transaction, task)
+    }))
+}
+
+// 送信結果を記録する。MessageIDはバウンスの通知と突き合わせるために保存する
+#[tracing::instrument(skip_all)]
+async fn complete_task(
+    transaction: &mut PgTransaction,
+    issue_id: Uuid,
+    email: &str,
+    result: Result<Option<String>, DeliveryError>,
+) -> Result<(), sqlx::Error> {
+    let (status, sent_at, message_id, failure_reason) = match result {
+        Ok(message_id) => ("sent", Some(Utc::now()), message_id, None),
+        // キューに登録した後で抑止リストに追加されたアドレスには送らない
+        Err(DeliveryError::Suppressed) => ("suppressed", None, None, None),
+        Err(e) => ("failed", None, None, Some(e.to_string())),
+    };
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries
+    SET status = $1, sent_at = $2, message_id = $3, failure_reason = $4
+    WHERE newsletter_issue_id = $5 AND subscriber_email = $6
+            "#,
+        status,
+        sent_at,
+        message_id,
+        failure_reason,
+        issue_id,
+        email
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// 配信キューを処理し続ける。キューが空の間はpoll_intervalごとに確認する
+pub async fn run_worker_until_stopped(
+    pool: PgPool,
+    email_client: EmailClient,
+    templates: Arc<EmailTemplates>,
+    base_url: String,
+    tracking_links: TrackingLinks,
+    poll_interval: Duration,
+) -> Result<(), std::io::Error> {
+    run_tasks_until_stopped(
+        || try_execute_task(&pool, &email_client, &templates, &base_url, &tracking_links),
+        poll_interval,
+    )
+    .await
+}
+
+/// 1件ずつ送る処理をexecute_taskで繰り返す。配信キュー、シーケンス、リマインダーのワーカーで共通
+/// 送れるものがない間と、1日の上限に達している間や送信サービスの障害中はidle_delayだけ待つ
+pub async fn run_tasks_until_stopped<F, Fut>(
+    mut execute_task: F,
+    idle_delay: Duration,
+) -> Result<(), std::io::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<ExecutionOutcome, sqlx::Error>>,
+{
+    loop {
+        match execute_task().await {
+            Ok(
+                ExecutionOutcome::EmptyQueue
+                | ExecutionOutcome::QuotaExhausted
+                | ExecutionOutcome::ProviderUnavailable,
+            ) => {
+                tokio::time::sleep(idle_delay).await;
+            }
+            // データベースのエラーなどは少し待ってから再試行する
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+}
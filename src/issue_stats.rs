@@ -0,0 +1,197 @@
+// 号ごとの配信結果と開封・クリックの集計
+// 配信キュー (issue_deliveries) と計測の記録 (tracking_events) から集計する
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// 上位のリンクとして表示する数
+const TOP_LINKS: i64 = 10;
+
+#[derive(serde::Serialize)]
+pub struct IssueStats {
+    pub issue: IssueSummary,
+    pub deliveries: DeliveryCounts,
+    pub opens: EngagementStats,
+    pub clicks: EngagementStats,
+    pub top_links: Vec<LinkStats>,
+}
+
+#[derive(serde::Serialize)]
+pub struct IssueSummary {
+    pub id: Uuid,
+    pub title: String,
+    pub status: String,
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+// 配信タスクの状態ごとの件数
+#[derive(serde::Serialize, Default)]
+pub struct DeliveryCounts {
+    pub queued: i64,
+    pub sent: i64,
+    pub failed: i64,
+    pub bounced: i64,
+    // 抑止リストに載っていたため送信しなかった件数
+    pub suppressed: i64,
+}
+
+#[derive(serde::Serialize)]
+pub struct EngagementStats {
+    // 開封 (クリック) した購読者の数
+    pub unique: i64,
+    // 計測用URLへのアクセスの延べ数
+    pub total: i64,
+    // 1時間ごとの、初めて開封 (クリック) した購読者の数
+    pub timeline: Vec<TimelineBucket>,
+}
+
+#[derive(serde::Serialize)]
+pub struct TimelineBucket {
+    pub hour: DateTime<Utc>,
+    pub unique: i64,
+}
+
+#[derive(serde::Serialize)]
+pub struct LinkStats {
+    pub url: String,
+    pub unique: i64,
+    pub total: i64,
+}
+
+/// 号の集計を返す。号が存在しなければNoneを返す
+#[tracing::instrument(name = "Get newsletter issue stats", skip(pool))]
+pub async fn get_issue_stats(
+    pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<Option<IssueStats>, sqlx::Error> {
+    let issue = match get_issue_summary(pool, issue_id).await? {
+        Some(issue) => issue,
+        None => return Ok(None),
+    };
+    Ok(Some(IssueStats {
+        issue,
+        deliveries: get_delivery_counts(pool, issue_id).await?,
+        opens: get_engagement_stats(pool, issue_id, "open").await?,
+        clicks: get_engagement_stats(pool, issue_id, "click").await?,
+        top_links: get_top_links(pool, issue_id).await?,
+    }))
+}
+
+async fn get_issue_summary(
+    pool: &PgPool,
+    issue_id: Uuid,
+) -> Result<Option<IssueSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        IssueSummary,
+        r#"SELECT id, title, status, published_at FROM newsletter_issues WHERE id = $1"#,
+        issue_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
+
+async fn get_delivery_counts(pool: &PgPool, issue_id: Uuid) -> Result<DeliveryCounts, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+    SELECT status, COUNT(*) AS "count!"
+    FROM issue_deliveries
+    WHERE newsletter_issue_id = $1
+    GROUP BY status
+            "#,
+        issue_id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let mut counts = DeliveryCounts::default();
+    for row in rows {
+        match row.status.as_str() {
+            "queued" => counts.queued = row.count,
+            "sent" => counts.sent = row.count,
+            "failed" => counts.failed = row.count,
+            "bounced" => counts.bounced = row.count,
+            "suppressed" => counts.suppressed = row.count,
+            _ => {}
+        }
+    }
+    Ok(counts)
+}
+
+async fn get_engagement_stats(
+    pool: &PgPool,
+    issue_id: Uuid,
+    kind: &str,
+) -> Result<EngagementStats, sqlx::Error> {
+    let totals = sqlx::query!(
+        r#"
+    SELECT COUNT(DISTINCT subscriber_id) AS "unique!", COUNT(*) AS "total!"
+    FROM tracking_events
+    WHERE newsletter_issue_id = $1 AND kind = $2
+            "#,
+        issue_id,
+        kind
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // 購読者ごとに最初のアクセスだけを数えると、合計がユニーク数に一致する
+    let timeline = sqlx::query_as!(
+        TimelineBucket,
+        r#"
+    SELECT date_trunc('hour', first_occurred_at) AS "hour!", COUNT(*) AS "unique!"
+    FROM (
+        SELECT subscriber_id, MIN(occurred_at) AS first_occurred_at
+        FROM tracking_events
+        WHERE newsletter_issue_id = $1 AND kind = $2
+        GROUP BY subscriber_id
+    ) AS first_events
+    GROUP BY 1
+    ORDER BY 1
+            "#,
+        issue_id,
+        kind
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(EngagementStats {
+        unique: totals.unique,
+        total: totals.total,
+        timeline,
+    })
+}
+
+async fn get_top_links(pool: &PgPool, issue_id: Uuid) -> Result<Vec<LinkStats>, sqlx::Error> {
+    sqlx::query_as!(
+        LinkStats,
+        r#"
+    SELECT url AS "url!", COUNT(DISTINCT subscriber_id) AS "unique!", COUNT(*) AS "total!"
+    FROM tracking_events
+    WHERE newsletter_issue_id = $1 AND kind = 'click'
+    GROUP BY url
+    ORDER BY 2 DESC, 3 DESC, url
+    LIMIT $2
+            "#,
+        issue_id,
+        TOP_LINKS
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
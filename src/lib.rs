@@ -1,7 +1,28 @@
 // モジュールを公開して他のコードからも利用できるようにする
+pub mod audience;
+pub mod authentication;
+pub mod circuit_breaker;
 pub mod configuration;
+pub mod confirmation_reminders;
+pub mod deferred_emails;
+pub mod dkim;
 pub mod domain;
 pub mod email_client;
+pub mod email_message;
+pub mod email_templates;
+pub mod issue_delivery_worker;
+pub mod issue_stats;
+pub mod markdown;
+pub mod mime;
+pub mod newsletter_issues;
+pub mod rate_limit;
+pub mod retention;
 pub mod routes;
+pub mod scheduler;
+pub mod segment;
+pub mod sequences;
 pub mod startup;
+pub mod subject_tests;
+pub mod suppressions;
 pub mod telemetry;
+pub mod tracking;
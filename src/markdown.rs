@@ -0,0 +1,240 @@
+// Markdownで書かれたニュースレターの本文からHTML版とテキスト版を生成する
+use pulldown_cmark::{html, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+
+// 生成したメール本文
+pub struct RenderedContent {
+    pub html: String,
+    pub text: String,
+}
+
+/// 本文をHTML版とテキスト版に変換する。レイアウトはテンプレートで付ける
+pub fn render_markdown(markdown: &str) -> RenderedContent {
+    RenderedContent {
+        html: render_html(markdown),
+        text: render_text(markdown),
+    }
+}
+
+fn options() -> Options {
+    Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH
+}
+
+/// MarkdownをHTMLに変換する
+/// 本文に書かれた生のHTMLやjavascript:のリンクが届かないよう、変換後にサニタイズする
+pub fn render_html(markdown: &str) -> String {
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(markdown, options()));
+    sanitize_html(&unsafe_html)
+}
+
+/// スクリプトやイベントハンドラ、javascript:のリンクなどを取り除いたHTMLを返す
+/// 公開アーカイブやフィードにそのまま載せるので、管理者が書いたHTMLも必ず通す
+pub fn sanitize_html(html: &str) -> String {
+    ammonia::clean(html)
+}
+
+/// Markdownを読みやすいプレーンテキストに変換する
+/// リンクは本文中に[1]のような番号を付け、URLは末尾に脚注としてまとめる
+pub fn render_text(markdown: &str) -> String {
+    let mut writer = TextWriter::default();
+    for event in Parser::new_ext(markdown, options()) {
+        writer.handle(event);
+    }
+    writer.finish()
+}
+
+#[derive(Default)]
+struct TextWriter {
+    output: String,
+    // 脚注にするリンク先
+    links: Vec<String>,
+    // 処理中のリンクの開始位置とリンク先
+    open_links: Vec<(usize, String)>,
+    // 処理中の見出しの開始位置
+    heading_start: usize,
+    // 入れ子になったリストごとの次の番号 (箇条書きはNone)
+    lists: Vec<Option<u64>>,
+    in_code_block: bool,
+}
+
+impl TextWriter {
+    fn handle(&mut self, event: Event<'_>) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) if self.in_code_block => {
+                for line in text.lines() {
+                    self.output.push_str("    ");
+                    self.output.push_str(line);
+                    self.output.push('\n');
+                }
+            }
+            Event::Text(text) | Event::Code(text) => self.output.push_str(&text),
+            Event::SoftBreak | Event::HardBreak => self.output.push('\n'),
+            Event::Rule => {
+                self.start_block();
+                self.output.push_str("----------");
+            }
+            Event::TaskListMarker(checked) => {
+                self.output.push_str(if checked { "[x] " } else { "[ ] " })
+            }
+            // 生のHTMLはテキスト版には含めない
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, tag: Tag<'_>) {
+        match tag {
+            // リスト項目内の段落は項目の行に続けて書く
+            Tag::Paragraph | Tag::BlockQuote(_) | Tag::Table(_) if self.lists.is_empty() => {
+                self.start_block();
+            }
+            Tag::Heading { .. } => {
+                self.start_block();
+                self.heading_start = self.output.len();
+            }
+            Tag::CodeBlock(_) => {
+                self.start_block();
+                self.in_code_block = true;
+            }
+            Tag::List(first_number) => {
+                if self.lists.is_empty() {
+                    self.start_block();
+                }
+                self.lists.push(first_number);
+            }
+            Tag::Item => {
+                self.start_line();
+                let depth = self.lists.len().saturating_sub(1);
+                self.output.push_str(&"  ".repeat(depth));
+                match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        self.output.push_str(&format!("{}. ", number));
+                        *number += 1;
+                    }
+                    _ => self.output.push_str("- "),
+                }
+            }
+            Tag::TableRow | Tag::TableHead => self.start_line(),
+            Tag::TableCell if !self.output.ends_with('\n') => self.output.push_str(" | "),
+            Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                self.open_links
+                    .push((self.output.len(), dest_url.into_string()));
+            }
+            _ => {}
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Heading(level) => {
+                // 大きな見出しには下線を引く
+                let underline = match level {
+                    HeadingLevel::H1 => '=',
+                    HeadingLevel::H2 => '-',
+                    _ => return,
+                };
+                let length = self.output[self.heading_start..].chars().count();
+                self.output.push('\n');
+                self.output.push_str(&underline.to_string().repeat(length));
+            }
+            TagEnd::CodeBlock => self.in_code_block = false,
+            TagEnd::List(_) => {
+                self.lists.pop();
+            }
+            TagEnd::Link | TagEnd::Image => {
+                if let Some((start, url)) = self.open_links.pop() {
+                    // URLがそのまま書かれたリンクには番号を付けない
+                    if self.output[start..] != url {
+                        self.links.push(url);
+                        self.output.push_str(&format!(" [{}]", self.links.len()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // 前のブロックとの間に空行を入れる
+    fn start_block(&mut self) {
+        if self.output.is_empty() {
+            return;
+        }
+        while !self.output.ends_with("\n\n") {
+            self.output.push('\n');
+        }
+    }
+
+    // 行の途中であれば改行する
+    fn start_line(&mut self) {
+        if !self.output.is_empty() && !self.output.ends_with('\n') {
+            self.output.push('\n');
+        }
+    }
+
+    fn finish(mut self) -> String {
+        let mut text = self.output.trim_end().to_string();
+        if !self.links.is_empty() {
+            text.push_str("\n\n");
+            for (i, url) in self.links.drain(..).enumerate() {
+                text.push_str(&format!("[{}] {}\n", i + 1, url));
+            }
+        }
+        text.trim_end().to_string() + "\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::{render_html, render_text};
+
+    #[test]
+    fn markdown_is_rendered_to_html() {
+        let html = render_html("# Hello\n\nSome *emphasis* and a [link](https://example.com).");
+        assert!(html.contains("<h1>Hello</h1>"));
+        assert!(html.contains("<em>emphasis</em>"));
+        assert!(html.contains(r#"href="https://example.com""#));
+    }
+
+    #[test]
+    fn dangerous_html_is_removed() {
+        let html = render_html(
+            "<script>alert(1)</script>\n\n<img src=x onerror=alert(1)>\n\n[click](javascript:alert(1))",
+        );
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("onerror"));
+        assert!(!html.contains("javascript:"));
+    }
+
+    #[test]
+    fn links_become_footnotes_in_plain_text() {
+        let text = render_text(
+            "Read [the docs](https://example.com/docs) and [the blog](https://example.com/blog).\n\n\
+            Or visit <https://example.com>.",
+        );
+        assert_eq!(
+            text,
+            "Read the docs [1] and the blog [2].\n\n\
+            Or visit https://example.com.\n\n\
+            [1] https://example.com/docs\n\
+            [2] https://example.com/blog\n"
+        );
+    }
+
+    #[test]
+    fn blocks_are_laid_out_in_plain_text() {
+        let text = render_text(
+            "# Title\n\nIntro\n\n## Section\n\n- one\n- two\n  1. nested\n\n```\nlet x = 1;\n```\n",
+        );
+        assert_eq!(
+            text,
+            "Title\n=====\n\nIntro\n\nSection\n-------\n\n\
+            - one\n- two\n  1. nested\n\n    let x = 1;\n"
+        );
+    }
+
+    #[test]
+    fn raw_html_is_left_out_of_plain_text() {
+        assert_eq!(render_text("Hello <b>world</b>"), "Hello world\n");
+    }
+}
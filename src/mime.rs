@@ -0,0 +1,306 @@
+// EmailMessageからSMTPで送るMIME形式のメッセージを組み立てる
+// 本文はmultipart/alternative (テキストとHTML) にし、インライン画像があればmultipart/related、
+// 通常の添付ファイルがあればmultipart/mixedで包む。行末はすべてCRLFにする
+use crate::domain::SubscriberEmail;
+use crate::email_message::{Attachment, EmailMessage};
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+// Base64でエンコードした本文の1行の長さ (RFC 2045の上限)
+const BASE64_LINE_LENGTH: usize = 76;
+// RFC 2047でエンコードするときに1つのencoded-wordに入れるバイト数の目安
+const ENCODED_WORD_BYTES: usize = 45;
+
+/// 送信者と送信日時を付けてMIME形式のメッセージを組み立てる
+/// BCCの宛先はヘッダに書かない (SMTPのエンベロープにだけ指定する)
+pub fn build_mime_message(
+    message: &EmailMessage<'_>,
+    sender: &SubscriberEmail,
+    date: DateTime<Utc>,
+) -> String {
+    let mut headers = vec![
+        ("From", sender.as_ref().to_owned()),
+        ("To", message.to.as_ref().to_owned()),
+    ];
+    if !message.cc.is_empty() {
+        let cc = message
+            .cc
+            .iter()
+            .map(|cc| cc.as_ref())
+            .collect::<Vec<_>>()
+            .join(", ");
+        headers.push(("Cc", cc));
+    }
+    if let Some(reply_to) = &message.reply_to {
+        headers.push(("Reply-To", reply_to.as_ref().to_owned()));
+    }
+    headers.push(("Subject", encode_header_value(message.subject)));
+    headers.push(("Date", date.to_rfc2822()));
+    headers.push(("Message-ID", message_id(sender)));
+    headers.push(("MIME-Version", "1.0".into()));
+
+    let mut mime = String::new();
+    for (name, value) in headers {
+        push_header(&mut mime, name, &value);
+    }
+    for (name, value) in &message.headers {
+        push_header(&mut mime, name, &encode_header_value(value));
+    }
+    mime.push_str(&body_part(message));
+    mime
+}
+
+// 本文と添付ファイルを、必要な分だけmultipartで包んだパートにする
+fn body_part(message: &EmailMessage<'_>) -> String {
+    let mut part = multipart(
+        "alternative",
+        vec![
+            leaf_part(
+                "text/plain; charset=utf-8",
+                &[],
+                message.text_body.as_bytes(),
+            ),
+            leaf_part(
+                "text/html; charset=utf-8",
+                &[],
+                message.html_body.as_bytes(),
+            ),
+        ],
+    );
+    let (inline, attached): (Vec<&Attachment>, Vec<&Attachment>) = message
+        .attachments
+        .iter()
+        .partition(|attachment| attachment.content_id.is_some());
+    if !inline.is_empty() {
+        let mut parts = vec![part];
+        parts.extend(inline.into_iter().map(attachment_part));
+        part = multipart("related", parts);
+    }
+    if !attached.is_empty() {
+        let mut parts = vec![part];
+        parts.extend(attached.into_iter().map(attachment_part));
+        part = multipart("mixed", parts);
+    }
+    part
+}
+
+// 子パートを区切り文字で並べたmultipartのパート
+fn multipart(subtype: &str, parts: Vec<String>) -> String {
+    // "=_" はBase64の本文には現れないので、区切り文字が本文と衝突しない
+    let boundary = format!("=_{}", Uuid::new_v4().simple());
+    let mut part = String::new();
+    push_header(
+        &mut part,
+        "Content-Type",
+        &format!("multipart/{}; boundary=\"{}\"", subtype, boundary),
+    );
+    part.push_str("\r\n");
+    for child in parts {
+        part.push_str(&format!("--{}\r\n", boundary));
+        part.push_str(&child);
+        part.push_str("\r\n");
+    }
+    part.push_str(&format!("--{}--\r\n", boundary));
+    part
+}
+
+// 添付ファイルのパート。content_idがあればインライン画像にする
+fn attachment_part(attachment: &Attachment) -> String {
+    let name = quoted_parameter(&attachment.name);
+    let content_type = format!(
+        "{}; name={}",
+        encode_header_value(&attachment.content_type),
+        name
+    );
+    let headers = match &attachment.content_id {
+        Some(content_id) => vec![
+            (
+                "Content-ID",
+                format!("<{}>", encode_header_value(content_id)),
+            ),
+            ("Content-Disposition", format!("inline; filename={}", name)),
+        ],
+        None => vec![(
+            "Content-Disposition",
+            format!("attachment; filename={}", name),
+        )],
+    };
+    leaf_part(&content_type, &headers, attachment.content())
+}
+
+// Base64でエンコードした内容を持つパート
+fn leaf_part(content_type: &str, headers: &[(&str, String)], content: &[u8]) -> String {
+    let mut part = String::new();
+    push_header(&mut part, "Content-Type", content_type);
+    push_header(&mut part, "Content-Transfer-Encoding", "base64");
+    for (name, value) in headers {
+        push_header(&mut part, name, value);
+    }
+    part.push_str("\r\n");
+    let encoded = base64::engine::general_purpose::STANDARD.encode(content);
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LENGTH) {
+        // Base64の出力はASCIIなので、どこで区切っても文字列として正しい
+        part.push_str(std::str::from_utf8(line).unwrap());
+        part.push_str("\r\n");
+    }
+    part
+}
+
+// ヘッダを1行追加する
+fn push_header(mime: &mut String, name: &str, value: &str) {
+    mime.push_str(&format!("{}: {}\r\n", name, value));
+}
+
+// 送信者のドメインを使って、メッセージごとに一意なMessage-IDを作る
+fn message_id(sender: &SubscriberEmail) -> String {
+    let domain = sender
+        .as_ref()
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain);
+    format!("<{}@{}>", Uuid::new_v4(), domain)
+}
+
+// ASCII以外を含む値はRFC 2047のencoded-wordにする
+// 長い値は途中で折り返せるよう、文字の境界で複数のencoded-wordに分ける
+// 改行を含む値でヘッダを追加されないよう、ASCIIの値の改行は空白にする
+fn encode_header_value(value: &str) -> String {
+    if value.is_ascii() {
+        return value.replace(['\r', '\n'], " ");
+    }
+    let mut words = Vec::new();
+    let mut start = 0;
+    for (index, c) in value.char_indices() {
+        if index + c.len_utf8() - start > ENCODED_WORD_BYTES {
+            words.push(&value[start..index]);
+            start = index;
+        }
+    }
+    words.push(&value[start..]);
+    words
+        .into_iter()
+        .map(|word| {
+            format!(
+                "=?UTF-8?B?{}?=",
+                base64::engine::general_purpose::STANDARD.encode(word)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n ")
+}
+
+// ファイル名などのパラメータを引用符で囲む
+fn quoted_parameter(value: &str) -> String {
+    let value = encode_header_value(value).replace("\r\n ", "");
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_message::{Attachment, EmailMessage};
+    use crate::mime::{build_mime_message, encode_header_value};
+    use base64::Engine;
+    use chrono::{TimeZone, Utc};
+
+    fn email(address: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(address.into()).unwrap()
+    }
+
+    fn build(message: &EmailMessage<'_>) -> String {
+        let date = Utc.with_ymd_and_hms(2024, 1, 8, 9, 0, 0).unwrap();
+        build_mime_message(message, &email("news@example.com"), date)
+    }
+
+    #[test]
+    fn a_message_without_attachments_is_multipart_alternative() {
+        let message = EmailMessage::new(email("ursula@example.com"), "Hello", "<p>Hi</p>", "Hi")
+            .cc(email("cc@example.com"))
+            .bcc(email("bcc@example.com"));
+
+        let mime = build(&message);
+
+        let (headers, body) = mime.split_once("\r\n\r\n").unwrap();
+        assert!(headers.starts_with("From: news@example.com\r\nTo: ursula@example.com\r\n"));
+        assert!(headers.contains("\r\nCc: cc@example.com\r\n"));
+        assert!(headers.contains("\r\nDate: Mon, 8 Jan 2024 09:00:00 +0000\r\n"));
+        assert!(headers.contains("@example.com>\r\nMIME-Version: 1.0\r\n"));
+        assert!(headers.contains("Content-Type: multipart/alternative; boundary="));
+        // BCCの宛先はヘッダに出さない
+        assert!(!mime.contains("bcc@example.com"));
+        // 本文はBase64でエンコードする
+        assert!(body.contains("Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\nSGk=\r\n"));
+        assert!(!mime.replace("\r\n", "").contains('\n'));
+    }
+
+    #[test]
+    fn attachments_and_inline_images_are_nested_in_mixed_and_related_parts() {
+        let message = EmailMessage::new(email("ursula@example.com"), "Event", "<p>Hi</p>", "Hi")
+            .attachment(Attachment::new(
+                "event.ics",
+                "text/calendar",
+                b"BEGIN".to_vec(),
+            ))
+            .attachment(Attachment::new("logo.png", "image/png", vec![1, 2, 3]).inline("logo"));
+
+        let mime = build(&message);
+
+        let mixed = mime.find("multipart/mixed").unwrap();
+        let related = mime.find("multipart/related").unwrap();
+        let alternative = mime.find("multipart/alternative").unwrap();
+        assert!(mixed < related && related < alternative);
+        assert!(mime.contains(
+            "Content-Type: image/png; name=\"logo.png\"\r\nContent-Transfer-Encoding: base64\r\n\
+            Content-ID: <logo>\r\nContent-Disposition: inline; filename=\"logo.png\"\r\n\r\nAQID\r\n"
+        ));
+        assert!(mime.contains(
+            "Content-Disposition: attachment; filename=\"event.ics\"\r\n\r\nQkVHSU4=\r\n"
+        ));
+    }
+
+    #[test]
+    fn long_base64_bodies_are_wrapped_at_76_characters() {
+        let text = "a".repeat(200);
+        let message = EmailMessage::new(email("ursula@example.com"), "Hello", "<p>Hi</p>", &text);
+
+        let mime = build(&message);
+
+        assert!(mime.split("\r\n").all(|line| line.len() <= 998));
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&text);
+        assert!(mime.contains(&format!("\r\n{}\r\n", &encoded[..76])));
+    }
+
+    #[test]
+    fn non_ascii_subjects_are_encoded_as_encoded_words() {
+        let subject = "今週のニュースレター: 新しい機能のお知らせと、来月のイベントのご案内";
+
+        let encoded = encode_header_value(subject);
+
+        let decoded = encoded
+            .split("\r\n ")
+            .map(|word| {
+                let word = word
+                    .strip_prefix("=?UTF-8?B?")
+                    .and_then(|word| word.strip_suffix("?="))
+                    .unwrap();
+                base64::engine::general_purpose::STANDARD
+                    .decode(word)
+                    .unwrap()
+            })
+            .map(|bytes| String::from_utf8(bytes).unwrap())
+            .collect::<String>();
+        assert_eq!(decoded, subject);
+        assert!(encoded.split("\r\n ").count() > 1);
+    }
+
+    #[test]
+    fn line_breaks_in_custom_headers_do_not_inject_headers() {
+        let message = EmailMessage::new(email("ursula@example.com"), "Hello", "<p>Hi</p>", "Hi")
+            .header("X-Campaign", "spring\r\nBcc: attacker@example.com");
+
+        let mime = build(&message);
+
+        assert!(mime.contains("\r\nX-Campaign: spring  Bcc: attacker@example.com\r\n"));
+        assert!(!mime.contains("\r\nBcc:"));
+    }
+}
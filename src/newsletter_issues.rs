@@ -0,0 +1,176 @@
+use crate::audience::Audience;
+use crate::domain::IssueSlug;
+use crate::subject_tests::{enqueue_subject_test_sample, Variant};
+use crate::tracking::TrackingOptions;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
+use uuid::Uuid;
+
+// 保存されたニュースレターの号
+pub struct NewsletterIssue {
+    pub title: String,
+    pub slug: String,
+    pub text_content: String,
+    pub html_content: String,
+    pub track_opens: bool,
+    pub track_clicks: bool,
+    // A/Bテストの件名B (テストのない号はNone)
+    pub subject_b: Option<String>,
+}
+
+// 号の本文
+#[derive(serde::Serialize)]
+pub struct IssueContent<'a> {
+    pub title: &'a str,
+    pub text_content: &'a str,
+    pub html_content: &'a str,
+}
+
+/// ニュースレターの号を保存し、IDとアーカイブ用のスラッグを返す
+/// scheduled_forがNoneなら即時配信として配信キューに登録済みの状態で保存する
+#[tracing::instrument(name = "Save newsletter issue", skip_all)]
+pub async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    content: IssueContent<'_>,
+    audience: &Audience,
+    scheduled_for: Option<DateTime<Utc>>,
+    tracking: TrackingOptions,
+) -> Result<(Uuid, IssueSlug), sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    let (status, published_at) = match scheduled_for {
+        Some(_) => ("scheduled", None),
+        None => ("published", Some(Utc::now())),
+    };
+    // 同じタイトルの号が既にあれば連番を付ける
+    // 同時に保存された号とスラッグが衝突しても、INSERT自体で検出して次の連番を試す
+    let base_slug = IssueSlug::from_title(content.title);
+    let mut slug = base_slug.clone();
+    let mut n = 1;
+    loop {
+        let inserted = sqlx::query!(
+            r#"
+        INSERT INTO newsletter_issues (
+            id, title, slug, text_content, html_content, lists, segment,
+            status, scheduled_for, created_at, published_at, track_opens, track_clicks
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        ON CONFLICT (slug) DO NOTHING
+                "#,
+            newsletter_issue_id,
+            content.title,
+            slug.as_ref(),
+            content.text_content,
+            content.html_content,
+            &audience.list_slugs(),
+            audience.segment(),
+            status,
+            scheduled_for,
+            Utc::now(),
+            published_at,
+            tracking.opens,
+            tracking.clicks
+        )
+        .execute(&mut **transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?
+        .rows_affected();
+        if inserted > 0 {
+            return Ok((newsletter_issue_id, slug));
+        }
+        n += 1;
+        slug = base_slug.with_suffix(n);
+    }
+}
+
+/// 配信対象の購読者ごとに配信タスクをキューに登録し、登録した件数を返す
+/// 件名のA/Bテストがある号は、テスト用に抽出した購読者だけを登録する
+#[tracing::instrument(name = "Enqueue delivery tasks", skip(transaction, audience))]
+pub async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    audience: &Audience,
+) -> Result<u64, sqlx::Error> {
+    if let Some(tasks) =
+        enqueue_subject_test_sample(transaction, newsletter_issue_id, audience).await?
+    {
+        return Ok(tasks);
+    }
+    let mut builder = QueryBuilder::new(
+        "INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_email, status, enqueued_at) \
+        SELECT ",
+    );
+    builder.push_bind(newsletter_issue_id);
+    builder.push(", s.email, 'queued', ");
+    builder.push_bind(Utc::now());
+    builder.push(" FROM subscriptions s");
+    audience.push_where(&mut builder);
+    let result = builder
+        .build()
+        .execute(&mut **transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(result.rows_affected())
+}
+
+/// A/Bテストの判定後に、テストに使わなかった購読者の配信タスクを勝った件名で登録する
+#[tracing::instrument(name = "Enqueue remaining delivery tasks", skip(transaction, audience))]
+pub async fn enqueue_remaining_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    audience: &Audience,
+    variant: Variant,
+) -> Result<u64, sqlx::Error> {
+    let mut builder = QueryBuilder::new(
+        "INSERT INTO issue_deliveries \
+        (newsletter_issue_id, subscriber_email, status, enqueued_at, variant) SELECT ",
+    );
+    builder.push_bind(newsletter_issue_id);
+    builder.push(", s.email, 'queued', ");
+    builder.push_bind(Utc::now());
+    builder.push(", ");
+    builder.push_bind(variant.as_str());
+    builder.push(" FROM subscriptions s");
+    audience.push_where(&mut builder);
+    // テスト用に送った購読者には送らない
+    builder.push(" ON CONFLICT (newsletter_issue_id, subscriber_email) DO NOTHING");
+    let result = builder
+        .build()
+        .execute(&mut **transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(result.rows_affected())
+}
+
+#[tracing::instrument(name = "Get newsletter issue", skip(pool))]
+pub async fn get_issue(
+    pool: &PgPool,
+    newsletter_issue_id: Uuid,
+) -> Result<NewsletterIssue, sqlx::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+    SELECT i.title, i.slug, i.text_content, i.html_content, i.track_opens, i.track_clicks,
+        t.subject_b AS "subject_b?"
+    FROM newsletter_issues i
+    LEFT JOIN subject_tests t ON t.newsletter_issue_id = i.id
+    WHERE i.id = $1
+            "#,
+        newsletter_issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(issue)
+}
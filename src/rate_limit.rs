@@ -0,0 +1,183 @@
+// 送信サービスの送信数の制限
+// 1秒あたりの送信数はトークンバケットで待たせ、1日の送信数はデータベースで数える
+use chrono::NaiveDate;
+use sqlx::PgPool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// 1秒あたりの送信数を制限するトークンバケット
+/// cloneしたものはバケットを共有するので、同じ送信サービスへの送信はまとめて制限される
+#[derive(Clone)]
+pub struct TokenBucket {
+    state: Arc<Mutex<BucketState>>,
+}
+
+struct BucketState {
+    // 1秒あたりに補充するトークンの数
+    per_second: f64,
+    // 貯められるトークンの上限 (連続して送れる数)
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl BucketState {
+    // 経過時間の分だけ補充してからトークンを1つ取る
+    // 足りない場合は、1つ貯まるまでの待ち時間を返す
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.refilled_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+impl TokenBucket {
+    /// burstを省略すると1秒分のトークンまで貯められる
+    pub fn new(per_second: u32, burst: Option<u32>) -> Self {
+        let per_second = f64::from(per_second.max(1));
+        let capacity = burst.map(|b| f64::from(b.max(1))).unwrap_or(per_second);
+        Self {
+            state: Arc::new(Mutex::new(BucketState {
+                per_second,
+                capacity,
+                tokens: capacity,
+                refilled_at: Instant::now(),
+            })),
+        }
+    }
+
+    /// トークンが取れるまで待つ。上限に達してもエラーにはせず、送信を遅らせる
+    pub async fn acquire(&self) {
+        loop {
+            let wait = match self.state.lock().unwrap().take(Instant::now()) {
+                Ok(()) => return,
+                Err(wait) => wait,
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// 送信サービスの1日の送信数を1通分確保する。上限に達していればfalseを返す
+#[tracing::instrument(name = "Reserve daily send quota", skip(pool))]
+pub async fn reserve_daily_send(
+    pool: &PgPool,
+    provider: &str,
+    daily_quota: u32,
+    day: NaiveDate,
+) -> Result<bool, sqlx::Error> {
+    if daily_quota == 0 {
+        return Ok(false);
+    }
+    // 上限に達している場合は更新されず、行が返らない
+    let reserved = sqlx::query!(
+        r#"
+    INSERT INTO email_send_quotas (provider, day, sent_count)
+    VALUES ($1, $2, 1)
+    ON CONFLICT (provider, day) DO UPDATE SET sent_count = email_send_quotas.sent_count + 1
+    WHERE email_send_quotas.sent_count < $3
+    RETURNING sent_count
+            "#,
+        provider,
+        day,
+        i32::try_from(daily_quota).unwrap_or(i32::MAX)
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    if reserved.is_none() {
+        tracing::warn!("The daily send quota has been reached.");
+    }
+    Ok(reserved.is_some())
+}
+
+/// 送信に失敗したときに、確保した1通分を戻す
+#[tracing::instrument(name = "Release daily send quota", skip(pool))]
+pub async fn release_daily_send(
+    pool: &PgPool,
+    provider: &str,
+    day: NaiveDate,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE email_send_quotas SET sent_count = sent_count - 1
+    WHERE provider = $1 AND day = $2 AND sent_count > 0
+            "#,
+        provider,
+        day
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_limit::TokenBucket;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    // 貯まっているトークンを使い切った状態で、次のトークンまでの待ち時間を返す
+    fn wait_after(bucket: &TokenBucket, taken: usize, now: Instant) -> Option<Duration> {
+        let mut state = bucket.state.lock().unwrap();
+        for _ in 0..taken {
+            state.take(now).unwrap();
+        }
+        state.take(now).err()
+    }
+
+    #[test]
+    fn a_full_bucket_allows_a_burst() {
+        let bucket = TokenBucket::new(2, Some(5));
+        let now = Instant::now();
+        let wait = wait_after(&bucket, 5, now).unwrap();
+        assert_eq!(wait, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn the_burst_defaults_to_one_second_of_tokens() {
+        let bucket = TokenBucket::new(10, None);
+        let now = Instant::now();
+        assert!(wait_after(&bucket, 10, now).is_some());
+    }
+
+    #[test]
+    fn tokens_are_refilled_over_time_up_to_the_capacity() {
+        let bucket = TokenBucket::new(4, Some(2));
+        let now = Instant::now();
+        assert!(wait_after(&bucket, 2, now).is_some());
+        // 1秒で4つ補充されるが、貯められるのは2つまで
+        let later = now + Duration::from_secs(1);
+        assert!(wait_after(&bucket, 2, later).is_some());
+        let much_later = later + Duration::from_millis(250);
+        assert!(wait_after(&bucket, 1, much_later).is_some());
+    }
+
+    #[tokio::test]
+    async fn acquire_waits_instead_of_failing() {
+        let bucket = TokenBucket::new(20, Some(1));
+        let started = Instant::now();
+        for _ in 0..3 {
+            bucket.acquire().await;
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,217 @@
+// 購読者の整理
+// 確認されないまま放置された購読を削除し、長く反応のない購読者にタグを付ける
+use crate::configuration::RetentionSettings;
+use chrono::{DateTime, Duration, Months, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+// 反応のない購読者に付けるタグ (セグメント tag:inactive で再エンゲージメントの号を送れる)
+pub const INACTIVE_TAG: &str = "inactive";
+
+// 1回の整理で変更した件数
+#[derive(Debug, Default, PartialEq)]
+pub struct RetentionReport {
+    // 削除した未確認の購読
+    pub deleted_unconfirmed: u64,
+    // 新しくinactiveタグを付けた購読者
+    pub flagged_inactive: u64,
+    // 再び反応があったためinactiveタグを外した購読者
+    pub unflagged_active: u64,
+}
+
+/// 起動してからの整理の件数の合計 (メトリクスのカウンタとして出力する)
+/// cloneしたものは合計を共有するので、バックグラウンドジョブで数えた件数をメトリクスで返せる
+#[derive(Clone, Default)]
+pub struct RetentionCounters {
+    deleted_unconfirmed: Arc<AtomicU64>,
+    flagged_inactive: Arc<AtomicU64>,
+    unflagged_active: Arc<AtomicU64>,
+}
+
+impl RetentionCounters {
+    fn record(&self, report: &RetentionReport) {
+        self.deleted_unconfirmed
+            .fetch_add(report.deleted_unconfirmed, Ordering::Relaxed);
+        self.flagged_inactive
+            .fetch_add(report.flagged_inactive, Ordering::Relaxed);
+        self.unflagged_active
+            .fetch_add(report.unflagged_active, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> RetentionReport {
+        RetentionReport {
+            deleted_unconfirmed: self.deleted_unconfirmed.load(Ordering::Relaxed),
+            flagged_inactive: self.flagged_inactive.load(Ordering::Relaxed),
+            unflagged_active: self.unflagged_active.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 設定に従って購読者を整理し、変更した件数を返す
+#[tracing::instrument(name = "Apply retention policy", skip_all, err)]
+pub async fn try_apply_retention(
+    pool: &PgPool,
+    settings: &RetentionSettings,
+    counters: &RetentionCounters,
+) -> Result<RetentionReport, sqlx::Error> {
+    let now = Utc::now();
+    let mut transaction = pool.begin().await?;
+    let mut report = RetentionReport {
+        deleted_unconfirmed: delete_unconfirmed_subscribers(
+            &mut transaction,
+            now - Duration::days(settings.unconfirmed_days.into()),
+        )
+        .await?,
+        ..Default::default()
+    };
+    if let Some(months) = settings.inactive_months {
+        let cutoff = now - Months::new(months);
+        report.flagged_inactive = flag_inactive_subscribers(&mut transaction, cutoff).await?;
+        report.unflagged_active = unflag_active_subscribers(&mut transaction, cutoff).await?;
+    }
+    transaction.commit().await?;
+    counters.record(&report);
+    // 件数は構造化ログとして出力し、ログ基盤でも集計できるようにする
+    tracing::info!(
+        deleted_unconfirmed = report.deleted_unconfirmed,
+        flagged_inactive = report.flagged_inactive,
+        unflagged_active = report.unflagged_active,
+        "Applied retention policy"
+    );
+    Ok(report)
+}
+
+// cutoffより前に申し込まれ、どのリストでも確認されていない購読者を関連する行とともに削除する
+// 確認済みのリストがある購読者や、最近別のリストに申し込んだ購読者は残す
+async fn delete_unconfirmed_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let subscriber_ids: Vec<Uuid> = sqlx::query!(
+        r#"
+    SELECT s.id
+    FROM subscriptions s
+    WHERE s.subscribed_at < $1
+        AND NOT EXISTS (
+            SELECT 1 FROM list_memberships m
+            WHERE m.subscriber_id = s.id
+                AND (m.status <> 'pending_confirmation' OR m.joined_at >= $1)
+        )
+    FOR UPDATE
+            "#,
+        cutoff
+    )
+    .fetch_all(&mut **transaction)
+    .await?
+    .into_iter()
+    .map(|r| r.id)
+    .collect();
+    if subscriber_ids.is_empty() {
+        return Ok(0);
+    }
+
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)"#,
+        &subscriber_ids
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM email_change_requests WHERE subscriber_id = ANY($1)"#,
+        &subscriber_ids
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM subscriber_tags WHERE subscriber_id = ANY($1)"#,
+        &subscriber_ids
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM list_memberships WHERE subscriber_id = ANY($1)"#,
+        &subscriber_ids
+    )
+    .execute(&mut **transaction)
+    .await?;
+    let result = sqlx::query!(
+        r#"DELETE FROM subscriptions WHERE id = ANY($1)"#,
+        &subscriber_ids
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// cutoff以降に開封を計測する号を受け取ったのに、開封もクリックもしていない購読者にタグを付ける
+// 開封を計測していない号しか受け取っていない購読者は、反応がないとは判断できないので対象にしない
+async fn flag_inactive_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    INSERT INTO subscriber_tags (subscriber_id, tag, tagged_at)
+    SELECT s.id, $1, $2
+    FROM subscriptions s
+    WHERE EXISTS (
+            SELECT 1 FROM list_memberships m
+            WHERE m.subscriber_id = s.id AND m.status = 'confirmed' AND m.joined_at < $3
+        )
+        AND EXISTS (
+            SELECT 1 FROM issue_deliveries d
+            JOIN newsletter_issues i ON i.id = d.newsletter_issue_id
+            WHERE d.subscriber_email = s.email AND d.status = 'sent'
+                AND d.sent_at >= $3 AND i.track_opens
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM tracking_events e
+            WHERE e.subscriber_id = s.id AND e.occurred_at >= $3
+        )
+    ON CONFLICT (subscriber_id, tag) DO NOTHING
+            "#,
+        INACTIVE_TAG,
+        Utc::now(),
+        cutoff
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// cutoff以降に開封かクリックのあった購読者からタグを外す
+async fn unflag_active_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    cutoff: DateTime<Utc>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    DELETE FROM subscriber_tags t
+    WHERE t.tag = $1
+        AND EXISTS (
+            SELECT 1 FROM tracking_events e
+            WHERE e.subscriber_id = t.subscriber_id AND e.occurred_at >= $2
+        )
+            "#,
+        INACTIVE_TAG,
+        cutoff
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// 設定された間隔で購読者の整理を実行し続ける
+pub async fn run_retention_until_stopped(
+    pool: PgPool,
+    settings: RetentionSettings,
+    counters: RetentionCounters,
+) -> Result<(), std::io::Error> {
+    loop {
+        // エラーはログに出力済みなので次の実行まで待つ
+        let _ = try_apply_retention(&pool, &settings, &counters).await;
+        tokio::time::sleep(settings.run_interval()).await;
+    }
+}
@@ -0,0 +1,69 @@
+use crate::configuration::AdminSettings;
+use crate::domain::ListSlug;
+use crate::routes::admin::reject_unauthenticated_admin;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct NewListData {
+    slug: String,
+    name: String,
+}
+
+/// 新しいメーリングリストを作成する
+#[tracing::instrument(
+    name = "Creating a new list",
+    skip(body, pool, admin, request),
+    fields(list_slug = %body.slug)
+)]
+pub async fn create_list(
+    body: web::Json<NewListData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    let body = body.0;
+    let slug = match ListSlug::parse(body.slug) {
+        Ok(slug) => slug,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    if body.name.trim().is_empty() {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    match insert_list(&pool, &slug, &body.name).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        // 同じスラッグのリストが既にある場合は409を返す
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            HttpResponse::Conflict().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Saving new list in the database", skip(pool))]
+pub async fn insert_list(pool: &PgPool, slug: &ListSlug, name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO lists (id, slug, name, created_at)
+    VALUES ($1, $2, $3, $4)
+            "#,
+        Uuid::new_v4(),
+        slug.as_ref(),
+        name,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,47 @@
+// 管理者用APIのサブモジュールを定義
+mod lists;
+mod newsletter_previews;
+mod newsletter_stats;
+mod newsletters;
+mod segments;
+mod sequences;
+mod suppressions;
+mod tags;
+
+pub use lists::*;
+pub use newsletter_previews::*;
+pub use newsletter_stats::*;
+pub use newsletters::*;
+pub use segments::*;
+pub use sequences::*;
+pub use suppressions::*;
+pub use tags::*;
+
+use crate::authentication::{basic_authentication, validate_credentials};
+use crate::configuration::AdminSettings;
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+
+/// 管理者のBasic認証を行う。認証できなければ返すべき401レスポンスを返す
+pub fn reject_unauthenticated_admin(
+    request: &HttpRequest,
+    admin: &AdminSettings,
+) -> Option<HttpResponse> {
+    let authenticated = match basic_authentication(request.headers()) {
+        Ok(credentials) => validate_credentials(&credentials, admin),
+        Err(e) => {
+            tracing::warn!("Failed to parse basic credentials: {}", e);
+            false
+        }
+    };
+    if authenticated {
+        None
+    } else {
+        // ブラウザに認証ダイアログを出させるためにWWW-Authenticateヘッダを付ける
+        Some(
+            HttpResponse::Unauthorized()
+                .insert_header((header::WWW_AUTHENTICATE, r#"Basic realm="admin""#))
+                .finish(),
+        )
+    }
+}
@@ -0,0 +1,214 @@
+use crate::configuration::{AdminSettings, TestRecipientSettings};
+use crate::domain::IssueSlug;
+use crate::email_client::{Delivery, EmailClient, SendEmailError};
+use crate::email_message::EmailMessage;
+use crate::email_templates::{EmailTemplates, NewsletterIssueEmail, Recipient, RenderedEmail};
+use crate::newsletter_issues::IssueContent;
+use crate::routes::admin::{reject_unauthenticated_admin, Content};
+use crate::routes::{archive_url, unsubscribe_url};
+use crate::startup::ApplicationBaseUrl;
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+// テスト送信の件名に付ける接頭辞
+const TEST_SUBJECT_PREFIX: &str = "[TEST] ";
+
+// プレビューとテスト送信の配信停止リンクに入れるトークン (どの購読者にも一致しない)
+const PREVIEW_UNSUBSCRIBE_TOKEN: &str = "preview";
+
+#[derive(serde::Deserialize)]
+pub struct PreviewNewsletterData {
+    title: String,
+    content: Content,
+    // 差し込みに使う購読者のメールアドレス (省略時はサンプルの購読者)
+    subscriber_email: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PreviewNewsletterResponse {
+    subject: String,
+    html: String,
+    text: String,
+}
+
+/// 指定した購読者に届くメールを描画して返す
+#[tracing::instrument(
+    name = "Preview a newsletter issue",
+    skip(body, pool, templates, base_url, admin, request),
+    fields(subscriber_email = ?body.subscriber_email)
+)]
+pub async fn preview_newsletter(
+    body: web::Json<PreviewNewsletterData>,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    let body = body.0;
+    // 購読者が見つからなければ404を返す
+    let (name, email, unsubscribe_token) = match body.subscriber_email {
+        Some(email) => match get_preview_subscriber(&pool, &email).await {
+            Ok(Some((name, unsubscribe_token))) => (name, email, unsubscribe_token),
+            Ok(None) => return HttpResponse::NotFound().finish(),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        },
+        None => (
+            "Ursula Le Guin".to_string(),
+            "ursula@example.com".to_string(),
+            PREVIEW_UNSUBSCRIBE_TOKEN.to_string(),
+        ),
+    };
+
+    let content = body.content.into_rendered();
+    let issue = IssueContent {
+        title: &body.title,
+        text_content: &content.text,
+        html_content: &content.html,
+    };
+    let recipient = Recipient {
+        name: &name,
+        email: &email,
+    };
+    match render_issue(
+        &templates,
+        &base_url.0,
+        issue,
+        recipient,
+        &unsubscribe_token,
+    ) {
+        Ok(email) => HttpResponse::Ok().json(PreviewNewsletterResponse {
+            subject: body.title,
+            html: email.html,
+            text: email.text,
+        }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct TestSendNewsletterData {
+    title: String,
+    content: Content,
+}
+
+/// 設定された社内の宛先にだけ、件名に[TEST]を付けて送信する
+#[tracing::instrument(name = "Test-send a newsletter issue", skip_all)]
+pub async fn test_send_newsletter(
+    body: web::Json<TestSendNewsletterData>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    test_recipients: web::Data<Vec<TestRecipientSettings>>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+    if test_recipients.is_empty() {
+        return HttpResponse::Conflict().body("No test recipients are configured.");
+    }
+
+    let body = body.0;
+    let content = body.content.into_rendered();
+    let subject = format!("{}{}", TEST_SUBJECT_PREFIX, body.title);
+    let mut sent = 0;
+    let mut deferred = 0;
+    let mut suppressed = 0;
+    for test_recipient in test_recipients.iter() {
+        // 宛先は起動時に検証済み
+        let email = match test_recipient.parse() {
+            Ok(email) => email,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+        let issue = IssueContent {
+            title: &body.title,
+            text_content: &content.text,
+            html_content: &content.html,
+        };
+        let recipient = Recipient {
+            name: &test_recipient.name,
+            email: email.as_ref(),
+        };
+        let rendered = match render_issue(
+            &templates,
+            &base_url.0,
+            issue,
+            recipient,
+            PREVIEW_UNSUBSCRIBE_TOKEN,
+        ) {
+            Ok(rendered) => rendered,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+        match email_client
+            .send_email_or_defer(
+                EmailMessage::new(email, &subject, &rendered.html, &rendered.text).broadcast(),
+            )
+            .await
+        {
+            Ok(Delivery::Sent) => sent += 1,
+            // 1日の上限に達している場合は後で送り、後で送る数を返す
+            Ok(Delivery::Deferred) => deferred += 1,
+            // 抑止リストに載っている宛先には送らず、送らなかった数を返す
+            Err(SendEmailError::Suppressed) => suppressed += 1,
+            Err(e) => {
+                tracing::error!("Failed to send a test email: {:?}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "sent": sent,
+        "deferred": deferred,
+        "suppressed": suppressed
+    }))
+}
+
+fn render_issue(
+    templates: &EmailTemplates,
+    base_url: &str,
+    issue: IssueContent<'_>,
+    subscriber: Recipient<'_>,
+    unsubscribe_token: &str,
+) -> Result<RenderedEmail, tera::Error> {
+    templates
+        .render(&NewsletterIssueEmail {
+            subscriber,
+            // 配信前なのでタイトルから作ったスラッグで仮のURLにする
+            view_in_browser_url: &archive_url(
+                base_url,
+                IssueSlug::from_title(issue.title).as_ref(),
+            ),
+            issue,
+            unsubscribe_url: &unsubscribe_url(base_url, unsubscribe_token),
+            // プレビューとテスト送信は計測しない
+            open_tracking_url: None,
+        })
+        .map_err(|e| {
+            tracing::error!("Failed to render email: {:?}", e);
+            e
+        })
+}
+
+#[tracing::instrument(name = "Get preview subscriber", skip(pool))]
+async fn get_preview_subscriber(
+    pool: &PgPool,
+    email: &str,
+) -> Result<Option<(String, String)>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT name, unsubscribe_token FROM subscriptions WHERE email = $1"#,
+        email
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.map(|r| (r.name, r.unsubscribe_token)))
+}
@@ -0,0 +1,53 @@
+use crate::configuration::AdminSettings;
+use crate::email_templates::EmailTemplates;
+use crate::issue_stats::get_issue_stats;
+use crate::routes::admin::reject_unauthenticated_admin;
+use actix_web::http::header::{self, ContentType};
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// 号の配信結果と開封・クリックの集計を返す
+/// ブラウザ (Acceptにtext/htmlを含む) にはHTMLを、それ以外にはJSONを返す
+#[tracing::instrument(
+    name = "Show newsletter issue stats",
+    skip(pool, templates, admin, request)
+)]
+pub async fn newsletter_stats(
+    path: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    let stats = match get_issue_stats(&pool, path.into_inner()).await {
+        Ok(Some(stats)) => stats,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if !accepts_html(&request) {
+        return HttpResponse::Ok().json(stats);
+    }
+    match templates.render_page(&stats) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(body),
+        Err(e) => {
+            tracing::error!("Failed to render page: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn accepts_html(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains("text/html"))
+        .unwrap_or(false)
+}
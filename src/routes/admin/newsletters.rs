@@ -0,0 +1,246 @@
+use crate::audience::Audience;
+use crate::configuration::AdminSettings;
+use crate::markdown::{render_markdown, sanitize_html, RenderedContent};
+use crate::newsletter_issues::{enqueue_delivery_tasks, insert_newsletter_issue, IssueContent};
+use crate::routes::admin::reject_unauthenticated_admin;
+use crate::subject_tests::{insert_subject_test, SubjectTestOptions};
+use crate::tracking::TrackingOptions;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    // 配信先リストのスラッグ
+    #[serde(default)]
+    lists: Vec<String>,
+    // 配信先を絞り込むセグメント式 (例: tag:beta AND attributes.country = 'JP')
+    segment: Option<String>,
+    // 配信予定日時 (RFC 3339)。省略時は即時配信する
+    scheduled_for: Option<DateTime<Utc>>,
+    // 開封とクリックを計測するかどうか (省略時は計測しない)
+    #[serde(default)]
+    tracking: TrackingOptions,
+    // 件名のA/Bテスト (省略時はテストしない)。開封率で判定するので開封は必ず計測する
+    subject_test: Option<SubjectTestOptions>,
+}
+
+// 本文はMarkdownで書くか、HTML版とテキスト版の両方を指定する
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Markdown { markdown: String },
+    Rendered { html: String, text: String },
+}
+
+impl Content {
+    /// HTML版とテキスト版の本文を返す。HTML版はどちらの書き方でもサニタイズする
+    pub fn into_rendered(self) -> RenderedContent {
+        match self {
+            Content::Markdown { markdown } => render_markdown(&markdown),
+            Content::Rendered { html, text } => RenderedContent {
+                html: sanitize_html(&html),
+                text,
+            },
+        }
+    }
+}
+
+/// 指定したリストの確認済み購読者のうち、セグメント式に一致する購読者にニュースレターを配信する
+/// 号を保存して配信キューに登録するだけで、実際の送信はバックグラウンドのワーカーが行う
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(body, pool, admin, request),
+    fields(lists = ?body.lists, segment = ?body.segment, scheduled_for = ?body.scheduled_for)
+)]
+pub async fn publish_newsletter(
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    let mut body = body.0;
+    // 過去の日時には予約できない
+    if matches!(body.scheduled_for, Some(scheduled_for) if scheduled_for <= Utc::now()) {
+        return HttpResponse::BadRequest().finish();
+    }
+    if let Some(subject_test) = &body.subject_test {
+        if let Err(e) = subject_test.validate() {
+            return HttpResponse::BadRequest().body(e);
+        }
+        body.tracking.opens = true;
+    }
+    // 配信対象が指定されていない、式が不正、または存在しないリストを含む場合は400を返す
+    let audience = match Audience::parse(body.lists, body.segment) {
+        Ok(audience) => audience,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    match audience.lists_exist(&pool).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let rendered = body.content.into_rendered();
+    let content = IssueContent {
+        title: &body.title,
+        text_content: &rendered.text,
+        html_content: &rendered.html,
+    };
+    let (issue_id, slug) = match insert_newsletter_issue(
+        &mut transaction,
+        content,
+        &audience,
+        body.scheduled_for,
+        body.tracking,
+    )
+    .await
+    {
+        Ok(issue) => issue,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    if let Some(subject_test) = &body.subject_test {
+        if insert_subject_test(&mut transaction, issue_id, subject_test)
+            .await
+            .is_err()
+        {
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    // 予約配信の場合は予約日時にスケジューラがキューに登録する
+    let status = match body.scheduled_for {
+        Some(_) => "scheduled",
+        None => {
+            if enqueue_delivery_tasks(&mut transaction, issue_id, &audience)
+                .await
+                .is_err()
+            {
+                return HttpResponse::InternalServerError().finish();
+            }
+            "published"
+        }
+    };
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Accepted().json(serde_json::json!({
+        "id": issue_id,
+        "slug": slug.as_ref(),
+        "status": status
+    }))
+}
+
+/// 配信前の予約を取り消す
+#[tracing::instrument(
+    name = "Cancel a scheduled newsletter issue",
+    skip(pool, admin, request)
+)]
+pub async fn cancel_newsletter(
+    path: web::Path<Uuid>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    let issue_id = path.into_inner();
+    let result = sqlx::query!(
+        r#"
+    UPDATE newsletter_issues SET status = 'cancelled'
+    WHERE id = $1 AND status = 'scheduled'
+            "#,
+        issue_id
+    )
+    .execute(pool.get_ref())
+    .await;
+    update_scheduled_issue_response(&pool, issue_id, result).await
+}
+
+#[derive(serde::Deserialize)]
+pub struct RescheduleData {
+    scheduled_for: DateTime<Utc>,
+}
+
+/// 配信前の号の配信予定日時を変更する
+#[tracing::instrument(
+    name = "Reschedule a newsletter issue",
+    skip(body, pool, admin, request),
+    fields(scheduled_for = %body.scheduled_for)
+)]
+pub async fn reschedule_newsletter(
+    path: web::Path<Uuid>,
+    body: web::Json<RescheduleData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+    if body.scheduled_for <= Utc::now() {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let issue_id = path.into_inner();
+    let result = sqlx::query!(
+        r#"
+    UPDATE newsletter_issues SET scheduled_for = $1
+    WHERE id = $2 AND status = 'scheduled'
+            "#,
+        body.scheduled_for,
+        issue_id
+    )
+    .execute(pool.get_ref())
+    .await;
+    update_scheduled_issue_response(&pool, issue_id, result).await
+}
+
+// 予約中の号の更新結果をレスポンスに変換する
+// 更新できなかった場合、号が存在しなければ404、既に配信済みか取消済みなら409を返す
+async fn update_scheduled_issue_response(
+    pool: &PgPool,
+    issue_id: Uuid,
+    result: Result<sqlx::postgres::PgQueryResult, sqlx::Error>,
+) -> HttpResponse {
+    match result {
+        Ok(result) if result.rows_affected() > 0 => return HttpResponse::Ok().finish(),
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!("Failed to execute query: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    match issue_exists(pool, issue_id).await {
+        Ok(true) => HttpResponse::Conflict().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Check that a newsletter issue exists", skip(pool))]
+async fn issue_exists(pool: &PgPool, issue_id: Uuid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT EXISTS (SELECT 1 FROM newsletter_issues WHERE id = $1) AS "exists!""#,
+        issue_id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.exists)
+}
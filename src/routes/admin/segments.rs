@@ -0,0 +1,62 @@
+use crate::audience::{Audience, AudienceMember};
+use crate::configuration::AdminSettings;
+use crate::routes::admin::reject_unauthenticated_admin;
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+// プレビューで返すサンプルの最大人数
+const SAMPLE_SIZE: i64 = 10;
+
+#[derive(serde::Deserialize)]
+pub struct PreviewData {
+    #[serde(default)]
+    lists: Vec<String>,
+    segment: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PreviewResponse {
+    // 配信対象の人数
+    count: i64,
+    // 配信対象の一部
+    sample: Vec<AudienceMember>,
+}
+
+/// 配信前に配信対象の人数とサンプルを確認する
+#[tracing::instrument(
+    name = "Preview a newsletter audience",
+    skip(body, pool, admin, request),
+    fields(lists = ?body.lists, segment = ?body.segment)
+)]
+pub async fn preview_segment(
+    body: web::Json<PreviewData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    let body = body.0;
+    let audience = match Audience::parse(body.lists, body.segment) {
+        Ok(audience) => audience,
+        // 式の誤りを編集者が直せるようにエラー内容を返す
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    match audience.lists_exist(&pool).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest().body("Unknown list."),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let count = match audience.count(&pool).await {
+        Ok(count) => count,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let sample = match audience.sample(&pool, SAMPLE_SIZE).await {
+        Ok(sample) => sample,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    HttpResponse::Ok().json(PreviewResponse { count, sample })
+}
@@ -0,0 +1,182 @@
+use crate::configuration::AdminSettings;
+use crate::domain::ListSlug;
+use crate::markdown::RenderedContent;
+use crate::routes::admin::{reject_unauthenticated_admin, Content};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct NewSequenceData {
+    // 購読を確認するとシーケンスに登録されるリストのスラッグ
+    list: String,
+    name: String,
+    steps: Vec<StepData>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct StepData {
+    // 購読を確認してから送るまでの日数 (0なら確認の直後)
+    delay_days: i32,
+    title: String,
+    content: Content,
+}
+
+/// リストの購読を確認した購読者に順番に送るシーケンスを作成する
+/// 作成後に購読を確認した購読者から登録される
+#[tracing::instrument(
+    name = "Creating a new sequence",
+    skip(body, pool, admin, request),
+    fields(list_slug = %body.list, steps = body.steps.len())
+)]
+pub async fn create_sequence(
+    body: web::Json<NewSequenceData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    let body = body.0;
+    let slug = match ListSlug::parse(body.list) {
+        Ok(slug) => slug,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    if let Err(e) = validate_steps(&body.name, &body.steps) {
+        return HttpResponse::BadRequest().body(e);
+    }
+
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    // 存在しないリストが指定された場合は400を返す
+    let list_id = match get_list_id(&mut transaction, &slug).await {
+        Ok(Some(list_id)) => list_id,
+        Ok(None) => return HttpResponse::BadRequest().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let sequence_id = match insert_sequence(&mut transaction, list_id, &body.name).await {
+        Ok(sequence_id) => sequence_id,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    for (position, step) in body.steps.into_iter().enumerate() {
+        let content = step.content.into_rendered();
+        if insert_sequence_step(
+            &mut transaction,
+            sequence_id,
+            position as i32,
+            step.delay_days,
+            &step.title,
+            &content,
+        )
+        .await
+        .is_err()
+        {
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Created().json(serde_json::json!({ "id": sequence_id }))
+}
+
+// ステップは1つ以上で、送る日数は0以上かつ前のステップ以降であること
+fn validate_steps(name: &str, steps: &[StepData]) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("The sequence name is empty.".into());
+    }
+    if steps.is_empty() {
+        return Err("A sequence needs at least one step.".into());
+    }
+    let mut previous_delay = 0;
+    for step in steps {
+        if step.title.trim().is_empty() {
+            return Err("A step title is empty.".into());
+        }
+        if step.delay_days < previous_delay {
+            return Err("Steps must be ordered by delay_days, starting at 0 or later.".into());
+        }
+        previous_delay = step.delay_days;
+    }
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get list id from slug", skip(transaction))]
+async fn get_list_id(
+    transaction: &mut Transaction<'_, Postgres>,
+    slug: &ListSlug,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(r#"SELECT id FROM lists WHERE slug = $1"#, slug.as_ref())
+        .fetch_optional(&mut **transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(result.map(|r| r.id))
+}
+
+#[tracing::instrument(name = "Saving new sequence in the database", skip(transaction))]
+async fn insert_sequence(
+    transaction: &mut Transaction<'_, Postgres>,
+    list_id: Uuid,
+    name: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let sequence_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+    INSERT INTO sequences (id, list_id, name, created_at)
+    VALUES ($1, $2, $3, $4)
+            "#,
+        sequence_id,
+        list_id,
+        name,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(sequence_id)
+}
+
+#[tracing::instrument(
+    name = "Saving sequence step in the database",
+    skip(transaction, title, content)
+)]
+async fn insert_sequence_step(
+    transaction: &mut Transaction<'_, Postgres>,
+    sequence_id: Uuid,
+    position: i32,
+    delay_days: i32,
+    title: &str,
+    content: &RenderedContent,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO sequence_steps
+        (sequence_id, position, delay_days, title, text_content, html_content)
+    VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        sequence_id,
+        position,
+        delay_days,
+        title,
+        content.text,
+        content.html
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use crate::configuration::AdminSettings;
+use crate::domain::SubscriberEmail;
+use crate::routes::admin::reject_unauthenticated_admin;
+use crate::suppressions::{suppress, unsuppress, SuppressionReason, SuppressionSource};
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct SuppressionData {
+    email: String,
+}
+
+/// アドレスを抑止リストに追加する。新しく追加したら201、登録済みなら200を返す
+#[tracing::instrument(
+    name = "Adding an address to the suppression list",
+    skip(body, pool, admin, request),
+    fields(email = %body.email)
+)]
+pub async fn add_suppression(
+    body: web::Json<SuppressionData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+    let email = match SubscriberEmail::parse(body.0.email) {
+        Ok(email) => email,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    match suppress(
+        &**pool,
+        email.as_ref(),
+        SuppressionReason::Manual,
+        SuppressionSource::Admin,
+    )
+    .await
+    {
+        Ok(true) => HttpResponse::Created().finish(),
+        Ok(false) => HttpResponse::Ok().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// アドレスを抑止リストから外す。載っていなければ404を返す
+/// 購読の状態は戻さないので、再び受け取るには購読し直す必要がある
+#[tracing::instrument(
+    name = "Removing an address from the suppression list",
+    skip(path, pool, admin, request),
+    fields(email = %path)
+)]
+pub async fn remove_suppression(
+    path: web::Path<String>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+
+    match unsuppress(&**pool, &path).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,126 @@
+use crate::configuration::AdminSettings;
+use crate::domain::SubscriberTag;
+use crate::routes::admin::reject_unauthenticated_admin;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+
+#[derive(serde::Deserialize)]
+pub struct BulkTagData {
+    tag: String,
+    // 対象の購読者のメールアドレス (登録されていないアドレスは無視する)
+    emails: Vec<String>,
+}
+
+// 一括操作で実際に変更された購読者の数
+#[derive(serde::Serialize)]
+pub struct BulkTagResponse {
+    affected: u64,
+}
+
+/// 指定したメールアドレスの購読者にまとめてタグを付ける
+#[tracing::instrument(
+    name = "Tagging subscribers in bulk",
+    skip(body, pool, admin, request),
+    fields(tag = %body.tag, emails = body.emails.len())
+)]
+pub async fn tag_subscribers(
+    body: web::Json<BulkTagData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+    let body = body.0;
+    let tag = match SubscriberTag::parse(body.tag) {
+        Ok(tag) => tag,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    match insert_subscriber_tags(&pool, &tag, &body.emails).await {
+        Ok(affected) => HttpResponse::Ok().json(BulkTagResponse { affected }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// 指定したメールアドレスの購読者からまとめてタグを外す
+#[tracing::instrument(
+    name = "Untagging subscribers in bulk",
+    skip(body, pool, admin, request),
+    fields(tag = %body.tag, emails = body.emails.len())
+)]
+pub async fn untag_subscribers(
+    body: web::Json<BulkTagData>,
+    pool: web::Data<PgPool>,
+    admin: web::Data<AdminSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if let Some(response) = reject_unauthenticated_admin(&request, &admin) {
+        return response;
+    }
+    let body = body.0;
+    let tag = match SubscriberTag::parse(body.tag) {
+        Ok(tag) => tag,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    match delete_subscriber_tags(&pool, &tag, &body.emails).await {
+        Ok(affected) => HttpResponse::Ok().json(BulkTagResponse { affected }),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[tracing::instrument(name = "Saving subscriber tags in the database", skip(pool, emails))]
+pub async fn insert_subscriber_tags(
+    pool: &PgPool,
+    tag: &SubscriberTag,
+    emails: &[String],
+) -> Result<u64, sqlx::Error> {
+    // 既にタグが付いている購読者は数えない
+    let result = sqlx::query!(
+        r#"
+    INSERT INTO subscriber_tags (subscriber_id, tag, tagged_at)
+    SELECT id, $1, $2 FROM subscriptions WHERE email = ANY($3)
+    ON CONFLICT (subscriber_id, tag) DO NOTHING
+            "#,
+        tag.as_ref(),
+        Utc::now(),
+        emails
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected())
+}
+
+#[tracing::instrument(
+    name = "Deleting subscriber tags from the database",
+    skip(pool, emails)
+)]
+pub async fn delete_subscriber_tags(
+    pool: &PgPool,
+    tag: &SubscriberTag,
+    emails: &[String],
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    DELETE FROM subscriber_tags t
+    USING subscriptions s
+    WHERE t.subscriber_id = s.id AND t.tag = $1 AND s.email = ANY($2)
+            "#,
+        tag.as_ref(),
+        emails
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,162 @@
+use crate::email_templates::{
+    ArchiveIndexPage, ArchiveIssuePage, ArchivedIssueSummary, EmailTemplates,
+};
+use crate::markdown::sanitize_html;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+// アーカイブの一覧の1ページあたりの号の数
+const PAGE_SIZE: i64 = 20;
+
+#[derive(serde::Deserialize)]
+pub struct ArchiveParameters {
+    // 1から始まるページ番号 (省略時は1)
+    page: Option<u32>,
+}
+
+/// 公開アーカイブで号を表示するURLを組み立てる
+pub fn archive_url(base_url: &str, slug: &str) -> String {
+    format!("{}/archive/{}", base_url, slug)
+}
+
+/// 配信済みの号を新しい順に一覧表示する
+#[tracing::instrument(
+    name = "Show the newsletter archive",
+    skip(parameters, pool, templates)
+)]
+pub async fn archive_index(
+    parameters: web::Query<ArchiveParameters>,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+) -> HttpResponse {
+    let page = parameters.page.unwrap_or(1);
+    if page == 0 {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let mut issues = match get_published_issues(&pool, page).await {
+        Ok(issues) => issues,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    // 2ページ目以降で号がなければ404を返す
+    if page > 1 && issues.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
+    // 1件多く取得して次のページがあるかを判定する
+    let has_next_page = issues.len() as i64 > PAGE_SIZE;
+    issues.truncate(PAGE_SIZE as usize);
+
+    let archive_page = ArchiveIndexPage {
+        issues,
+        previous_page: (page > 1).then(|| page - 1),
+        next_page: has_next_page.then(|| page + 1),
+    };
+    match templates.render_page(&archive_page) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(body),
+        Err(e) => {
+            tracing::error!("Failed to render page: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// 配信済みの号の本文を表示する
+#[tracing::instrument(name = "Show an archived newsletter issue", skip(pool, templates))]
+pub async fn archive_issue(
+    path: web::Path<String>,
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+) -> HttpResponse {
+    // 予約中や取消済みの号は公開しない
+    let issue = match get_published_issue(&pool, &path).await {
+        Ok(Some(issue)) => issue,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let published_on = format_date(issue.published_at);
+    // 保存時にサニタイズする前の号もあるので、公開ページに載せる前にもサニタイズする
+    let html_content = sanitize_html(&issue.html_content);
+    let issue_page = ArchiveIssuePage {
+        title: &issue.title,
+        published_on: &published_on,
+        html_content: &html_content,
+    };
+    match templates.render_page(&issue_page) {
+        Ok(body) => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(body),
+        Err(e) => {
+            tracing::error!("Failed to render page: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+#[tracing::instrument(name = "Get published issues", skip(pool))]
+async fn get_published_issues(
+    pool: &PgPool,
+    page: u32,
+) -> Result<Vec<ArchivedIssueSummary>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+    SELECT title, slug, published_at AS "published_at!"
+    FROM newsletter_issues
+    WHERE status = 'published'
+    ORDER BY published_at DESC, id
+    LIMIT $1 OFFSET $2
+            "#,
+        PAGE_SIZE + 1,
+        (i64::from(page) - 1) * PAGE_SIZE
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(rows
+        .into_iter()
+        .map(|r| ArchivedIssueSummary {
+            title: r.title,
+            slug: r.slug,
+            published_on: format_date(r.published_at),
+        })
+        .collect())
+}
+
+struct PublishedIssue {
+    title: String,
+    html_content: String,
+    published_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get published issue from slug", skip(pool))]
+async fn get_published_issue(
+    pool: &PgPool,
+    slug: &str,
+) -> Result<Option<PublishedIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        PublishedIssue,
+        r#"
+    SELECT title, html_content, published_at AS "published_at!"
+    FROM newsletter_issues
+    WHERE slug = $1 AND status = 'published'
+            "#,
+        slug
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
@@ -0,0 +1,183 @@
+use crate::email_templates::{AtomFeed, EmailTemplates, FeedEntry, FeedTemplate, RssFeed};
+use crate::markdown::sanitize_html;
+use crate::routes::archive_url;
+use crate::startup::ApplicationBaseUrl;
+use actix_web::http::header::{
+    ETag, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, LastModified,
+};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use sqlx::PgPool;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime};
+
+// フィードに載せる号の数
+const FEED_SIZE: i64 = 20;
+
+/// 配信済みの号のRSS 2.0フィード
+#[tracing::instrument(name = "Show the RSS feed", skip_all)]
+pub async fn rss_feed(
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let issues = match get_feed_issues(&pool).await {
+        Ok(issues) => issues,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let last_modified = last_modified(&issues);
+    let feed = RssFeed {
+        archive_url: format!("{}/archive", base_url.0),
+        feed_url: format!("{}/feed.rss", base_url.0),
+        updated: last_modified.to_rfc2822(),
+        entries: feed_entries(&base_url.0, issues, |date| date.to_rfc2822()),
+    };
+    feed_response(
+        &request,
+        &templates,
+        &feed,
+        "application/rss+xml; charset=utf-8",
+        last_modified,
+    )
+}
+
+/// 配信済みの号のAtom 1.0フィード
+#[tracing::instrument(name = "Show the Atom feed", skip_all)]
+pub async fn atom_feed(
+    pool: web::Data<PgPool>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    request: HttpRequest,
+) -> HttpResponse {
+    let issues = match get_feed_issues(&pool).await {
+        Ok(issues) => issues,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let last_modified = last_modified(&issues);
+    let feed = AtomFeed {
+        archive_url: format!("{}/archive", base_url.0),
+        feed_url: format!("{}/feed.atom", base_url.0),
+        updated: rfc3339(last_modified),
+        entries: feed_entries(&base_url.0, issues, rfc3339),
+    };
+    feed_response(
+        &request,
+        &templates,
+        &feed,
+        "application/atom+xml; charset=utf-8",
+        last_modified,
+    )
+}
+
+fn rfc3339(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// 最新の号の配信日時。号がなければUNIXエポックにする
+fn last_modified(issues: &[FeedIssue]) -> DateTime<Utc> {
+    issues
+        .iter()
+        .map(|issue| issue.published_at)
+        .max()
+        .unwrap_or_default()
+}
+
+fn feed_entries(
+    base_url: &str,
+    issues: Vec<FeedIssue>,
+    format_date: impl Fn(DateTime<Utc>) -> String,
+) -> Vec<FeedEntry> {
+    issues
+        .into_iter()
+        .map(|issue| FeedEntry {
+            url: archive_url(base_url, &issue.slug),
+            published: format_date(issue.published_at),
+            title: issue.title,
+            // 公開アーカイブと同じく、載せる前にサニタイズする
+            html_content: sanitize_html(&issue.html_content),
+        })
+        .collect()
+}
+
+// フィードを描画し、条件付きリクエストで変更がなければ304を返す
+fn feed_response<T: FeedTemplate>(
+    request: &HttpRequest,
+    templates: &EmailTemplates,
+    feed: &T,
+    content_type: &'static str,
+    last_modified: DateTime<Utc>,
+) -> HttpResponse {
+    let body = match templates.render_feed(feed) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!("Failed to render feed: {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let etag = EntityTag::new_strong(body_hash(&body));
+    // HTTPの日付は秒単位なので、If-Modified-Sinceと比較できるよう秒未満を切り捨てる
+    let last_modified = HttpDate::from(
+        SystemTime::UNIX_EPOCH + Duration::from_secs(last_modified.timestamp().max(0) as u64),
+    );
+
+    if !is_modified(request, &etag, last_modified) {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(LastModified(last_modified))
+            .finish();
+    }
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ETag(etag))
+        .insert_header(LastModified(last_modified))
+        .body(body)
+}
+
+// If-None-Matchがあればそれだけで判定し、なければIf-Modified-Sinceで判定する
+fn is_modified(request: &HttpRequest, etag: &EntityTag, last_modified: HttpDate) -> bool {
+    match request.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => false,
+        Some(IfNoneMatch::Items(tags)) => !tags.iter().any(|tag| tag.weak_eq(etag)),
+        None => match request.get_header::<IfModifiedSince>() {
+            Some(IfModifiedSince(since)) => last_modified > since,
+            None => true,
+        },
+    }
+}
+
+// 本文が同じなら同じETagになるようにハッシュ値から作る
+// DefaultHasher::newは固定の鍵を使うので、同じビルドの複数のインスタンスで値が一致する
+fn body_hash(body: &str) -> String {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+struct FeedIssue {
+    title: String,
+    slug: String,
+    html_content: String,
+    published_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(name = "Get issues for the feed", skip(pool))]
+async fn get_feed_issues(pool: &PgPool) -> Result<Vec<FeedIssue>, sqlx::Error> {
+    sqlx::query_as!(
+        FeedIssue,
+        r#"
+    SELECT title, slug, html_content, published_at AS "published_at!"
+    FROM newsletter_issues
+    WHERE status = 'published'
+    ORDER BY published_at DESC, id
+    LIMIT $1
+            "#,
+        FEED_SIZE
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
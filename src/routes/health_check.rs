@@ -1,8 +1,21 @@
-use actix_web::HttpResponse;
+use crate::email_client::EmailClient;
+use actix_web::{web, HttpResponse};
 
-pub async fn health_check() -> HttpResponse {
-    // finish()することでHttpResponseBuildeからHttpResponseに変換する
-    // finish()しない場合はimpl Responderを返すことになる
-    // impl Responder for HttpResponseBuilderはfinish()を呼び出しているので結果的に同じこと
-    HttpResponse::Ok().finish()
+/// アプリケーションが動いていれば200を返す
+/// 送信サービスの障害はアプリケーションの障害ではないので、回路が開いていても200を返し状態だけを伝える
+pub async fn health_check(email_client: web::Data<EmailClient>) -> HttpResponse {
+    let providers: Vec<_> = email_client
+        .providers()
+        .iter()
+        .map(|provider| {
+            serde_json::json!({
+                "name": provider.name(),
+                "circuit": provider.circuit_breaker().state().as_str(),
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "email_providers": providers,
+    }))
 }
@@ -0,0 +1,79 @@
+use crate::email_client::EmailClient;
+use crate::retention::RetentionCounters;
+use actix_web::{web, HttpResponse};
+use std::fmt::Write;
+
+/// Prometheusのテキスト形式でメトリクスを返す
+pub async fn metrics(
+    email_client: web::Data<EmailClient>,
+    retention_counters: web::Data<RetentionCounters>,
+) -> HttpResponse {
+    let providers = email_client.providers();
+    let mut body = String::new();
+    // Stringへの書き込みは失敗しない
+    let _ = writeln!(
+        body,
+        "# HELP email_provider_circuit_state Circuit breaker state (0 = closed, 1 = half open, 2 = open).\n\
+        # TYPE email_provider_circuit_state gauge"
+    );
+    for provider in providers {
+        let _ = writeln!(
+            body,
+            "email_provider_circuit_state{{provider=\"{}\"}} {}",
+            provider.name(),
+            provider.circuit_breaker().state().as_gauge()
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP email_provider_circuit_opened_total Number of times the circuit was opened.\n\
+        # TYPE email_provider_circuit_opened_total counter"
+    );
+    for provider in providers {
+        let _ = writeln!(
+            body,
+            "email_provider_circuit_opened_total{{provider=\"{}\"}} {}",
+            provider.name(),
+            provider.circuit_breaker().counters().opened
+        );
+    }
+    let _ = writeln!(
+        body,
+        "# HELP email_provider_circuit_rejected_total Number of emails not sent because the circuit was open.\n\
+        # TYPE email_provider_circuit_rejected_total counter"
+    );
+    for provider in providers {
+        let _ = writeln!(
+            body,
+            "email_provider_circuit_rejected_total{{provider=\"{}\"}} {}",
+            provider.name(),
+            provider.circuit_breaker().counters().rejected
+        );
+    }
+    let retention = retention_counters.totals();
+    for (name, help, value) in [
+        (
+            "retention_deleted_unconfirmed_total",
+            "Number of unconfirmed subscribers deleted by the retention policy.",
+            retention.deleted_unconfirmed,
+        ),
+        (
+            "retention_flagged_inactive_total",
+            "Number of subscribers tagged as inactive by the retention policy.",
+            retention.flagged_inactive,
+        ),
+        (
+            "retention_unflagged_active_total",
+            "Number of subscribers whose inactive tag was removed by the retention policy.",
+            retention.unflagged_active,
+        ),
+    ] {
+        let _ = writeln!(
+            body,
+            "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}"
+        );
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
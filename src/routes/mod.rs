@@ -1,7 +1,25 @@
 // サブモジュールを定義
+mod admin;
+mod archive;
+mod feeds;
 mod health_check;
+mod metrics;
 mod subscriptions;
+mod subscriptions_confirm;
+mod subscriptions_email_change;
+mod subscriptions_unsubscribe;
+mod tracking;
+mod webhooks;
 
 // サブモジュールを公開
+pub use admin::*;
+pub use archive::*;
+pub use feeds::*;
 pub use health_check::*;
+pub use metrics::*;
 pub use subscriptions::*;
+pub use subscriptions_confirm::*;
+pub use subscriptions_email_change::*;
+pub use subscriptions_unsubscribe::*;
+pub use tracking::*;
+pub use webhooks::*;
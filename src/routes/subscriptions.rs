@@ -1,13 +1,33 @@
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::configuration::SubscriberAttributeSettings;
+use crate::domain::{
+    ListSlug, NewSubscriber, SubscriberAttributes, SubscriberEmail, SubscriberName,
+};
+use crate::email_client::EmailClient;
+use crate::email_message::EmailMessage;
+use crate::email_templates::{ConfirmationEmail, EmailTemplates, Recipient};
+use crate::routes::confirmation_link;
+use crate::startup::ApplicationBaseUrl;
+use crate::suppressions::is_suppressed;
 use actix_web::{web, HttpResponse};
 use chrono::Utc;
-use sqlx::PgPool;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
 use uuid::Uuid;
 
+// listパラメータを省略したときに購読するリスト
+pub const DEFAULT_LIST_SLUG: &str = "newsletter";
+
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
     name: String,
+    // 購読するリストのスラッグ (省略時は既定のリスト)
+    list: Option<String>,
+    // 上記以外のフィールドは追加属性として受け取る
+    #[serde(flatten)]
+    attributes: HashMap<String, String>,
 }
 
 // FormDataからNewSubscriberに変換を試みるトレイトを実装
@@ -28,53 +48,313 @@ impl TryFrom<FormData> for NewSubscriber {
     // ログのトレース名
     name = "Adding a new subscriber",
     // ログから除外するフィールド
-    skip(form, pool),
+    skip(form, pool, email_client, templates, base_url, attribute_schema),
     // ログに追加するフィールド
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name
     )
 )]
-pub async fn subscribe(form: web::Form<FormData>, pool: web::Data<PgPool>) -> HttpResponse {
+pub async fn subscribe(
+    mut form: web::Form<FormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    attribute_schema: web::Data<Vec<SubscriberAttributeSettings>>,
+) -> HttpResponse {
+    // 追加属性を設定された定義に従ってパースする。定義に合わなければ400を返す
+    let attributes = std::mem::take(&mut form.0.attributes);
+    let attributes = match SubscriberAttributes::parse(attributes, &attribute_schema) {
+        Ok(attributes) => attributes,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
+    // リストのスラッグをパースする。省略されていれば既定のリストを使う
+    let list_slug = form
+        .0
+        .list
+        .take()
+        .unwrap_or_else(|| DEFAULT_LIST_SLUG.into());
+    let list_slug = match ListSlug::parse(list_slug) {
+        Ok(list_slug) => list_slug,
+        Err(_) => return HttpResponse::BadRequest().finish(),
+    };
     // フォームをパースしてNewSubscriberを取得する。パースに失敗した場合は400を返す
-    let new_subscriber = match form.0.try_into() {
+    let new_subscriber: NewSubscriber = match form.0.try_into() {
         Ok(subscriber) => subscriber,
         Err(_) => return HttpResponse::BadRequest().finish(),
     };
 
-    match insert_subscriber(&pool, &new_subscriber).await {
-        Ok(_) => HttpResponse::Ok().finish(),
-        Err(_) => HttpResponse::InternalServerError().finish(),
+    // 抑止リストに載っているアドレスは、登録済みかどうかを知られないように何もせず200を返す
+    match is_suppressed(&**pool, new_subscriber.email.as_ref()).await {
+        Ok(true) => return HttpResponse::Ok().finish(),
+        Ok(false) => {}
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // 存在しないリストが指定された場合は400を返す
+    let (list_id, list_name) = match get_list_from_slug(&mut transaction, &list_slug).await {
+        Ok(Some(list)) => list,
+        Ok(None) => return HttpResponse::BadRequest().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // 別のリストを購読済みなら既存の購読者に新しいリストを追加する
+    // 誰でも他人のアドレスで申し込めるので、既存の購読者の属性は確認リンクで確認されるまで変えない
+    let (subscriber_id, pending_attributes) = match get_subscriber_id_from_email(
+        &mut *transaction,
+        new_subscriber.email.as_ref(),
+    )
+    .await
+    {
+        Ok(Some(subscriber_id)) => (subscriber_id, Some(&attributes)),
+        Ok(None) => match insert_subscriber(&mut transaction, &new_subscriber, &attributes).await {
+            Ok(subscriber_id) => (subscriber_id, None),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // 確認済みのリストであれば何もしない
+    match insert_list_membership(&mut transaction, subscriber_id, list_id).await {
+        Ok(status) if status == "confirmed" => {
+            return match transaction.commit().await {
+                Ok(_) => HttpResponse::Ok().finish(),
+                Err(_) => HttpResponse::InternalServerError().finish(),
+            };
+        }
+        Ok(_) => {}
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let subscription_token = generate_token();
+    if store_token(
+        &mut transaction,
+        subscriber_id,
+        list_id,
+        &subscription_token,
+        pending_attributes,
+    )
+    .await
+    .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
     }
+
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    if send_confirmation_email(
+        &email_client,
+        &templates,
+        new_subscriber,
+        &list_name,
+        &base_url.0,
+        &subscription_token,
+    )
+    .await
+    .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+/// 確認リンクなどに使うランダムな英数字25文字のトークンを生成する
+pub fn generate_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
+}
+
+#[tracing::instrument(name = "Get list from slug", skip(transaction))]
+pub async fn get_list_from_slug(
+    transaction: &mut Transaction<'_, Postgres>,
+    list_slug: &ListSlug,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id, name FROM lists WHERE slug = $1"#,
+        list_slug.as_ref()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.map(|r| (r.id, r.name)))
+}
+
+#[tracing::instrument(name = "Get subscriber_id from email", skip(executor))]
+pub async fn get_subscriber_id_from_email(
+    executor: impl sqlx::PgExecutor<'_>,
+    email: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(r#"SELECT id FROM subscriptions WHERE email = $1"#, email)
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(result.map(|r| r.id))
 }
 
 #[tracing::instrument(
     // ログのトレース名
     name = "Saving new subscriber details in the database",
     // ログから除外するフィールド
-    skip(new_subscriber, pool)
+    skip(new_subscriber, attributes, transaction)
 )]
 pub async fn insert_subscriber(
-    pool: &PgPool,
+    transaction: &mut Transaction<'_, Postgres>,
     new_subscriber: &NewSubscriber,
-) -> Result<(), sqlx::Error> {
+    attributes: &SubscriberAttributes,
+) -> Result<Uuid, sqlx::Error> {
+    let subscriber_id = Uuid::new_v4();
     // クエリ実行
     sqlx::query!(
         r#"
-    INSERT INTO subscriptions (id, email, name, subscribed_at)
-    VALUES ($1, $2, $3, $4)
+    INSERT INTO subscriptions (id, email, name, subscribed_at, attributes, unsubscribe_token)
+    VALUES ($1, $2, $3, $4, $5, $6)
             "#,
-        Uuid::new_v4(),
+        subscriber_id,
         new_subscriber.email.as_ref(),
         new_subscriber.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        attributes.as_json(),
+        generate_token()
     )
-    .execute(pool)
+    .execute(&mut **transaction)
     .await
     // map_errはErrのときに処理を行う。?をつけてeを返却する
     .map_err(|e| {
         tracing::error!("Failed to execute query: {:?}", e);
         e
     })?;
+    Ok(subscriber_id)
+}
+
+/// リストへの所属を登録し、登録後のステータスを返す (既に所属していればそのステータス)
+/// 購読を解除したリストに登録し直した場合は、確認待ちに戻して改めて確認してもらう
+#[tracing::instrument(name = "Saving list membership in the database", skip(transaction))]
+pub async fn insert_list_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO list_memberships (subscriber_id, list_id, status, joined_at)
+    VALUES ($1, $2, 'pending_confirmation', $3)
+    ON CONFLICT (subscriber_id, list_id) DO UPDATE
+    SET status = 'pending_confirmation', joined_at = EXCLUDED.joined_at, reminder_sent_at = NULL
+    WHERE list_memberships.status = 'unsubscribed'
+            "#,
+        subscriber_id,
+        list_id,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    let membership = sqlx::query!(
+        r#"SELECT status FROM list_memberships WHERE subscriber_id = $1 AND list_id = $2"#,
+        subscriber_id,
+        list_id
+    )
+    .fetch_one(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(membership.status)
+}
+
+/// 確認リンクのトークンを保存する
+/// attributesは確認されたときに既存の購読者の属性にマージする
+#[tracing::instrument(
+    name = "Store subscription token in the database",
+    skip(subscription_token, attributes, transaction)
+)]
+pub async fn store_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    subscription_token: &str,
+    attributes: Option<&SubscriberAttributes>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO subscription_tokens (subscription_token, subscriber_id, list_id, created_at, attributes)
+    VALUES ($1, $2, $3, $4, $5)
+            "#,
+        subscription_token,
+        subscriber_id,
+        list_id,
+        Utc::now(),
+        attributes.map(SubscriberAttributes::as_json)
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Send a confirmation email to a new subscriber",
+    skip(email_client, templates, new_subscriber, base_url, subscription_token)
+)]
+pub async fn send_confirmation_email(
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    new_subscriber: NewSubscriber,
+    list_name: &str,
+    base_url: &str,
+    subscription_token: &str,
+) -> Result<(), String> {
+    let confirmation_link = confirmation_link(base_url, subscription_token);
+    let email = templates
+        .render(&ConfirmationEmail {
+            subscriber: Recipient {
+                name: new_subscriber.name.as_ref(),
+                email: new_subscriber.email.as_ref(),
+            },
+            list_name,
+            confirmation_link: &confirmation_link,
+        })
+        .map_err(|e| {
+            tracing::error!("Failed to render email: {:?}", e);
+            e.to_string()
+        })?;
+    // 1日の上限に達していれば、申し込みは受け付けて確認メールは後で送る
+    email_client
+        .send_email_or_defer(EmailMessage::new(
+            new_subscriber.email,
+            "Welcome!",
+            &email.html,
+            &email.text,
+        ))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to send email: {:?}", e);
+            e.to_string()
+        })?;
     Ok(())
 }
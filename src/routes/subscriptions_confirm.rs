@@ -0,0 +1,157 @@
+use crate::configuration::ConfirmationSettings;
+use crate::sequences::enroll_in_sequences;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct Parameters {
+    subscription_token: String,
+}
+
+/// 確認メールに入れる購読の確認リンクを組み立てる
+pub fn confirmation_link(base_url: &str, subscription_token: &str) -> String {
+    format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        base_url, subscription_token
+    )
+}
+
+/// 確認リンクのトークンに対応するリストへの所属を確認済みにする
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(parameters, pool, settings)
+)]
+pub async fn confirm(
+    parameters: web::Query<Parameters>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConfirmationSettings>,
+) -> HttpResponse {
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    // トークンが見つからないか、有効期限が切れていれば401を返す
+    // 使ったトークンは削除し、同じリンクで確認し直せないようにする
+    let token = match consume_subscription_token(
+        &mut transaction,
+        &parameters.subscription_token,
+        &settings,
+    )
+    .await
+    {
+        Ok(Some(token)) => token,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let confirmed =
+        match confirm_membership(&mut transaction, token.subscriber_id, token.list_id).await {
+            Ok(confirmed) => confirmed,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+    // 既存の購読者が申し込んだときの属性は、持ち主が確認してから反映する
+    if let (true, Some(attributes)) = (confirmed, &token.attributes) {
+        if merge_subscriber_attributes(&mut transaction, token.subscriber_id, attributes)
+            .await
+            .is_err()
+        {
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+    // リストにシーケンスがあれば、確認した時点から順番に送る
+    if confirmed
+        && enroll_in_sequences(&mut transaction, token.subscriber_id, token.list_id)
+            .await
+            .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().finish()
+}
+
+/// 確認待ちの所属だけを確認済みにし、確認済みにしたかどうかを返す
+/// 配信停止した後に古いリンクで購読し直されることはない
+#[tracing::instrument(name = "Mark list membership as confirmed", skip(transaction))]
+pub async fn confirm_membership(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = 'confirmed'
+    WHERE subscriber_id = $1 AND list_id = $2 AND status = 'pending_confirmation'
+            "#,
+        subscriber_id,
+        list_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// 既存の購読者の属性に、確認されたトークンの属性を上書きでマージする
+#[tracing::instrument(name = "Merging subscriber attributes", skip(attributes, transaction))]
+pub async fn merge_subscriber_attributes(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    attributes: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET attributes = attributes || $1 WHERE id = $2"#,
+        attributes,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+// 確認に使ったトークンの内容
+pub struct ConsumedToken {
+    pub subscriber_id: Uuid,
+    pub list_id: Uuid,
+    // 既存の購読者が申し込んだときに送った属性
+    pub attributes: Option<serde_json::Value>,
+}
+
+#[tracing::instrument(
+    name = "Consume subscription token",
+    skip(transaction, subscription_token, settings)
+)]
+pub async fn consume_subscription_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+    settings: &ConfirmationSettings,
+) -> Result<Option<ConsumedToken>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        ConsumedToken,
+        r#"
+    DELETE FROM subscription_tokens
+    WHERE subscription_token = $1 AND created_at > $2
+    RETURNING subscriber_id, list_id, attributes
+            "#,
+        subscription_token,
+        Utc::now() - settings.token_lifetime()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result)
+}
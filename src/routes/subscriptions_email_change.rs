@@ -0,0 +1,451 @@
+use crate::configuration::ConfirmationSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, SendEmailError};
+use crate::email_message::EmailMessage;
+use crate::email_templates::{
+    EmailChangeAuthorizationEmail, EmailChangeVerificationEmail, EmailTemplates, Recipient,
+};
+use crate::routes::{generate_token, get_subscriber_id_from_email};
+use crate::startup::ApplicationBaseUrl;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+#[derive(serde::Deserialize)]
+pub struct EmailChangeFormData {
+    // 現在登録されているメールアドレス
+    email: String,
+    // 変更後のメールアドレス
+    new_email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailChangeAuthorizationParameters {
+    authorization_token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct EmailChangeParameters {
+    email_change_token: String,
+}
+
+/// メールアドレスの変更を受け付け、現在のアドレスへ承認のリンクを送信する
+/// 現在のアドレスの持ち主が承認するまで、新しいアドレスには何も送らない
+#[tracing::instrument(
+    name = "Requesting a subscriber email change",
+    skip(form, pool, email_client, templates, base_url),
+    fields(
+        subscriber_email = %form.email,
+        new_email = %form.new_email
+    )
+)]
+pub async fn request_email_change(
+    form: web::Form<EmailChangeFormData>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> HttpResponse {
+    // いずれかのアドレスの形式が不正なら400を返す
+    let (current_email, new_email) = match (
+        SubscriberEmail::parse(form.0.email),
+        SubscriberEmail::parse(form.0.new_email),
+    ) {
+        (Ok(current_email), Ok(new_email)) => (current_email, new_email),
+        _ => return HttpResponse::BadRequest().finish(),
+    };
+    // 同じアドレスへの変更は受け付けない
+    if current_email.as_ref() == new_email.as_ref() {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    // 購読されているかを調べられないよう、変更できない場合も何も送らずに200を返す
+    // 現在のアドレスで購読者を探す
+    let (subscriber_id, subscriber_name) =
+        match get_subscriber_from_email(&pool, &current_email).await {
+            Ok(Some(subscriber)) => subscriber,
+            Ok(None) => {
+                tracing::info!("No subscriber with the current email address");
+                return HttpResponse::Ok().finish();
+            }
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+    // 新しいアドレスが既に登録されていれば変更しない
+    match get_subscriber_id_from_email(&**pool, new_email.as_ref()).await {
+        Ok(Some(_)) => {
+            tracing::info!("The new email address is already subscribed");
+            return HttpResponse::Ok().finish();
+        }
+        Ok(None) => {}
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    let authorization_token = generate_token();
+    let email_change_token = generate_token();
+    if store_email_change_request(
+        &pool,
+        subscriber_id,
+        &new_email,
+        &authorization_token,
+        &email_change_token,
+    )
+    .await
+    .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    if send_email_change_authorization_email(
+        &email_client,
+        &templates,
+        &subscriber_name,
+        current_email,
+        &new_email,
+        &base_url.0,
+        &authorization_token,
+    )
+    .await
+    .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+/// 現在のアドレスに送ったリンクで変更を承認し、新しいアドレスへ確認メールを送信する
+#[tracing::instrument(
+    name = "Authorize a subscriber email change",
+    skip(parameters, pool, email_client, templates, base_url, settings)
+)]
+pub async fn authorize_email_change(
+    parameters: web::Query<EmailChangeAuthorizationParameters>,
+    pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
+    templates: web::Data<EmailTemplates>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    settings: web::Data<ConfirmationSettings>,
+) -> HttpResponse {
+    // トークンが見つからないか、承認済みか、有効期限が切れていれば401を返す
+    let request =
+        match authorize_email_change_request(&pool, &parameters.authorization_token, &settings)
+            .await
+        {
+            Ok(Some(request)) => request,
+            Ok(None) => return HttpResponse::Unauthorized().finish(),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+    let new_email = match SubscriberEmail::parse(request.new_email) {
+        Ok(new_email) => new_email,
+        Err(e) => {
+            tracing::error!("Stored new email is invalid: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    if send_email_change_verification_email(
+        &email_client,
+        &templates,
+        &request.subscriber_name,
+        new_email,
+        &base_url.0,
+        &request.email_change_token,
+    )
+    .await
+    .is_err()
+    {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+/// 確認リンクのトークンを検証し、購読者のメールアドレスを新しいアドレスに切り替える
+#[tracing::instrument(
+    name = "Confirm a subscriber email change",
+    skip(parameters, pool, settings)
+)]
+pub async fn confirm_email_change(
+    parameters: web::Query<EmailChangeParameters>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<ConfirmationSettings>,
+) -> HttpResponse {
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // トークンが見つからないか、承認されていないか、有効期限が切れていれば401を返す
+    let (subscriber_id, new_email) =
+        match get_email_change_request(&mut transaction, &parameters.email_change_token, &settings)
+            .await
+        {
+            Ok(Some(request)) => request,
+            Ok(None) => return HttpResponse::Unauthorized().finish(),
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+
+    // 申請後に同じアドレスで別の購読が作られていれば切り替えられない
+    match change_subscriber_email(&mut transaction, subscriber_id, &new_email).await {
+        Ok(()) => {}
+        Err(e) if is_unique_violation(&e) => return HttpResponse::Conflict().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().finish()
+}
+
+fn is_unique_violation(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => e.is_unique_violation(),
+        _ => false,
+    }
+}
+
+#[tracing::instrument(
+    name = "Store email change request in the database",
+    skip(pool, new_email, authorization_token, email_change_token)
+)]
+pub async fn store_email_change_request(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+    authorization_token: &str,
+    email_change_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO email_change_requests
+        (email_change_token, authorization_token, subscriber_id, new_email, requested_at)
+    VALUES ($1, $2, $3, $4, $5)
+            "#,
+        email_change_token,
+        authorization_token,
+        subscriber_id,
+        new_email.as_ref(),
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Get subscriber from email", skip(pool, email))]
+pub async fn get_subscriber_from_email(
+    pool: &PgPool,
+    email: &SubscriberEmail,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT id, name FROM subscriptions WHERE email = $1"#,
+        email.as_ref()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.map(|r| (r.id, r.name)))
+}
+
+// 承認された変更申請
+pub struct AuthorizedEmailChange {
+    pub subscriber_name: String,
+    pub new_email: String,
+    pub email_change_token: String,
+}
+
+/// 有効期限内で未承認の申請を承認済みにする。同じリンクで2回承認することはできない
+#[tracing::instrument(
+    name = "Authorize email change request",
+    skip(pool, authorization_token, settings)
+)]
+pub async fn authorize_email_change_request(
+    pool: &PgPool,
+    authorization_token: &str,
+    settings: &ConfirmationSettings,
+) -> Result<Option<AuthorizedEmailChange>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    UPDATE email_change_requests r SET authorized_at = now()
+    FROM subscriptions s
+    WHERE r.authorization_token = $1
+        AND r.authorized_at IS NULL
+        AND r.requested_at > $2
+        AND s.id = r.subscriber_id
+    RETURNING s.name, r.new_email, r.email_change_token
+            "#,
+        authorization_token,
+        Utc::now() - settings.token_lifetime()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.map(|r| AuthorizedEmailChange {
+        subscriber_name: r.name,
+        new_email: r.new_email,
+        email_change_token: r.email_change_token,
+    }))
+}
+
+#[tracing::instrument(name = "Send email change authorization email", skip_all)]
+pub async fn send_email_change_authorization_email(
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    subscriber_name: &str,
+    current_email: SubscriberEmail,
+    new_email: &SubscriberEmail,
+    base_url: &str,
+    authorization_token: &str,
+) -> Result<(), String> {
+    let authorization_link = format!(
+        "{}/subscriptions/email_change/authorize?authorization_token={}",
+        base_url, authorization_token
+    );
+    // 現在のアドレスには、変更先を示して承認のリンクを送る
+    let authorization = templates
+        .render(&EmailChangeAuthorizationEmail {
+            subscriber: Recipient {
+                name: subscriber_name,
+                email: current_email.as_ref(),
+            },
+            new_email: new_email.as_ref(),
+            authorization_link: &authorization_link,
+        })
+        .map_err(|e| {
+            tracing::error!("Failed to render email: {:?}", e);
+            e.to_string()
+        })?;
+
+    match email_client
+        .send_email_or_defer(EmailMessage::new(
+            current_email,
+            "Email address change requested",
+            &authorization.html,
+            &authorization.text,
+        ))
+        .await
+    {
+        // 抑止リストに載っているアドレスには送らず、申請は受け付けたものとして扱う
+        // 1日の上限に達していれば後で送る
+        Ok(_) | Err(SendEmailError::Suppressed) => Ok(()),
+        Err(e) => {
+            tracing::error!("Failed to send email: {:?}", e);
+            Err(e.to_string())
+        }
+    }
+}
+
+#[tracing::instrument(name = "Send email change verification email", skip_all)]
+pub async fn send_email_change_verification_email(
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    subscriber_name: &str,
+    new_email: SubscriberEmail,
+    base_url: &str,
+    email_change_token: &str,
+) -> Result<(), String> {
+    let confirmation_link = format!(
+        "{}/subscriptions/email_change/confirm?email_change_token={}",
+        base_url, email_change_token
+    );
+    // 新しいアドレスには確認リンクを送る
+    let verification = templates
+        .render(&EmailChangeVerificationEmail {
+            subscriber: Recipient {
+                name: subscriber_name,
+                email: new_email.as_ref(),
+            },
+            confirmation_link: &confirmation_link,
+        })
+        .map_err(|e| {
+            tracing::error!("Failed to render email: {:?}", e);
+            e.to_string()
+        })?;
+
+    match email_client
+        .send_email_or_defer(EmailMessage::new(
+            new_email,
+            "Confirm your new email address",
+            &verification.html,
+            &verification.text,
+        ))
+        .await
+    {
+        // 抑止リストに載っているアドレスには送らず、申請は受け付けたものとして扱う
+        // 1日の上限に達していれば後で送る
+        Ok(_) | Err(SendEmailError::Suppressed) => Ok(()),
+        Err(e) => {
+            tracing::error!("Failed to send email: {:?}", e);
+            Err(e.to_string())
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Get email change request from token",
+    skip(transaction, settings)
+)]
+pub async fn get_email_change_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_change_token: &str,
+    settings: &ConfirmationSettings,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    // 現在のアドレスで承認されていない申請は切り替えない
+    let result = sqlx::query!(
+        r#"
+    SELECT subscriber_id, new_email FROM email_change_requests
+    WHERE email_change_token = $1 AND authorized_at IS NOT NULL AND requested_at > $2
+            "#,
+        email_change_token,
+        Utc::now() - settings.token_lifetime()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.map(|r| (r.subscriber_id, r.new_email)))
+}
+
+#[tracing::instrument(name = "Change subscriber email", skip(transaction, new_email))]
+pub async fn change_subscriber_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_email: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET email = $1 WHERE id = $2"#,
+        new_email,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    // 同じ購読者の未使用の申請も含めて破棄する
+    sqlx::query!(
+        r#"DELETE FROM email_change_requests WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use crate::tracking::{TrackedEvent, TrackingLinks};
+use actix_web::http::header::{CacheControl, CacheDirective, LOCATION};
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// 1x1の透明なGIF画像
+const TRACKING_PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// 開封を記録して1x1の画像を返す
+#[tracing::instrument(name = "Track an open", skip_all)]
+pub async fn track_open(
+    path: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracking_links: web::Data<TrackingLinks>,
+) -> HttpResponse {
+    // 署名が正しくない、またはクリック用のトークンであれば400を返す
+    let (issue_id, subscriber_id) = match tracking_links.verify(&path) {
+        Ok(TrackedEvent::Open {
+            issue_id,
+            subscriber_id,
+        }) => (issue_id, subscriber_id),
+        Ok(_) => return HttpResponse::BadRequest().finish(),
+        Err(e) => {
+            tracing::warn!("Rejected a tracking token: {}", e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    // 記録に失敗しても読者には画像を返す
+    let _ = insert_tracking_event(&pool, issue_id, subscriber_id, "open", None).await;
+    HttpResponse::Ok()
+        .content_type("image/gif")
+        // メールクライアントにキャッシュされると2回目以降の開封が記録できない
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .body(TRACKING_PIXEL)
+}
+
+/// クリックを記録して元のURLにリダイレクトする
+#[tracing::instrument(name = "Track a click", skip_all)]
+pub async fn track_click(
+    path: web::Path<String>,
+    pool: web::Data<PgPool>,
+    tracking_links: web::Data<TrackingLinks>,
+) -> HttpResponse {
+    // リダイレクト先は署名済みのURLに限るので、任意のURLへのリダイレクトには使えない
+    let (issue_id, subscriber_id, url) = match tracking_links.verify(&path) {
+        Ok(TrackedEvent::Click {
+            issue_id,
+            subscriber_id,
+            url,
+        }) => (issue_id, subscriber_id, url),
+        Ok(_) => return HttpResponse::BadRequest().finish(),
+        Err(e) => {
+            tracing::warn!("Rejected a tracking token: {}", e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    // ヘッダに入れられる形に正規化し、http(s)以外のリンク先にはリダイレクトしない
+    let url = match reqwest::Url::parse(&url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => {
+            tracing::warn!("Rejected a click tracking target that is not an http(s) URL");
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    // 記録に失敗しても読者はリンク先に送る
+    let _ =
+        insert_tracking_event(&pool, issue_id, subscriber_id, "click", Some(url.as_str())).await;
+    HttpResponse::Found()
+        .insert_header((LOCATION, url.as_str()))
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .finish()
+}
+
+#[tracing::instrument(name = "Save tracking event", skip(pool))]
+async fn insert_tracking_event(
+    pool: &PgPool,
+    issue_id: Uuid,
+    subscriber_id: Uuid,
+    kind: &str,
+    url: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO tracking_events (id, newsletter_issue_id, subscriber_id, kind, url, occurred_at)
+    VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+        Uuid::new_v4(),
+        issue_id,
+        subscriber_id,
+        kind,
+        url,
+        Utc::now()
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
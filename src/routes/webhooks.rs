@@ -0,0 +1,267 @@
+use crate::authentication::basic_authentication;
+use crate::configuration::PostmarkWebhookSettings;
+use crate::suppressions::{suppress, SuppressionReason, SuppressionSource};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use secrecy::ExposeSecret;
+use sqlx::{PgPool, Postgres, Transaction};
+use subtle::ConstantTimeEq;
+
+// 共有の鍵を送るヘッダ (Basic認証の代わりに使える)
+const WEBHOOK_SECRET_HEADER: &str = "X-Postmark-Webhook-Secret";
+
+// PostmarkのWebhookのイベント (RecordTypeで種類を判別する)
+// 扱わない種類 (OpenやClickなど) はOtherとして受け取って無視する
+#[derive(serde::Deserialize)]
+#[serde(tag = "RecordType")]
+enum PostmarkEvent {
+    Bounce(BounceEvent),
+    SpamComplaint(BounceEvent),
+    Delivery(DeliveryEvent),
+    #[serde(other)]
+    Other,
+}
+
+// バウンスと迷惑メール報告は同じ形式で届く
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BounceEvent {
+    #[serde(rename = "ID")]
+    id: i64,
+    // HardBounce、SoftBounce、SpamComplaintなど
+    #[serde(rename = "Type")]
+    kind: String,
+    #[serde(rename = "MessageID")]
+    message_id: String,
+    email: String,
+    // Postmarkがこのアドレスへの送信を停止した場合はtrue
+    #[serde(default)]
+    inactive: bool,
+}
+
+impl BounceEvent {
+    // 再送しても届かないバウンスかどうか
+    fn is_permanent(&self) -> bool {
+        self.kind == "HardBounce" || self.inactive
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeliveryEvent {
+    #[serde(rename = "MessageID")]
+    message_id: String,
+    recipient: String,
+}
+
+/// Postmarkからバウンス、迷惑メール報告、配信完了の通知を受け取り、配信結果と購読者の状態を更新する
+/// Postmarkは200以外を返すと再送するので、同じイベントは一度だけ処理する
+#[tracing::instrument(name = "Receive a Postmark webhook", skip_all)]
+pub async fn postmark_webhook(
+    body: web::Json<serde_json::Value>,
+    pool: web::Data<PgPool>,
+    settings: web::Data<PostmarkWebhookSettings>,
+    request: HttpRequest,
+) -> HttpResponse {
+    if !is_authenticated(&request, &settings) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let payload = body.into_inner();
+    let event = match serde_json::from_value::<PostmarkEvent>(payload.clone()) {
+        Ok(event) => event,
+        Err(e) => {
+            tracing::warn!("Failed to parse a Postmark webhook: {}", e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+    let (record_type, id, email) = match &event {
+        PostmarkEvent::Bounce(e) => ("Bounce", e.id.to_string(), &e.email),
+        PostmarkEvent::SpamComplaint(e) => ("SpamComplaint", e.id.to_string(), &e.email),
+        // 配信完了にはイベントのIDがないので、MessageIDと宛先で識別する
+        PostmarkEvent::Delivery(e) => (
+            "Delivery",
+            format!("{}:{}", e.message_id, e.recipient),
+            &e.recipient,
+        ),
+        PostmarkEvent::Other => return HttpResponse::Ok().finish(),
+    };
+    let event_id = format!("{}:{}", record_type, id);
+
+    let mut transaction = match pool.begin().await {
+        Ok(transaction) => transaction,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    match insert_postmark_event(&mut transaction, &event_id, record_type, email, &payload).await {
+        Ok(true) => {}
+        // 処理済みのイベント
+        Ok(false) => return HttpResponse::Ok().finish(),
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    }
+    if handle_event(&mut transaction, &event).await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    if transaction.commit().await.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    HttpResponse::Ok().finish()
+}
+
+// Basic認証か共有の鍵のヘッダのどちらかで認証する
+// 応答時間から鍵を推測されないように、定数時間で比較する
+fn is_authenticated(request: &HttpRequest, settings: &PostmarkWebhookSettings) -> bool {
+    let secret = settings.secret.expose_secret().as_bytes();
+    if let Some(value) = request.headers().get(WEBHOOK_SECRET_HEADER) {
+        return value.as_bytes().ct_eq(secret).into();
+    }
+    match basic_authentication(request.headers()) {
+        Ok(credentials) => {
+            let username = credentials
+                .username
+                .as_bytes()
+                .ct_eq(settings.username.as_bytes());
+            let password = credentials
+                .password
+                .expose_secret()
+                .as_bytes()
+                .ct_eq(secret);
+            (username & password).into()
+        }
+        Err(e) => {
+            tracing::warn!("Failed to parse basic credentials: {}", e);
+            false
+        }
+    }
+}
+
+async fn handle_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    event: &PostmarkEvent,
+) -> Result<(), sqlx::Error> {
+    match event {
+        PostmarkEvent::Bounce(bounce) => {
+            mark_delivery_bounced(transaction, &bounce.message_id).await?;
+            // 一時的なバウンスでは配信を止めない
+            if bounce.is_permanent() {
+                update_membership_status(transaction, &bounce.email, "bounced").await?;
+                suppress(
+                    &mut **transaction,
+                    &bounce.email,
+                    SuppressionReason::HardBounce,
+                    SuppressionSource::PostmarkWebhook,
+                )
+                .await?;
+            }
+        }
+        PostmarkEvent::SpamComplaint(complaint) => {
+            update_membership_status(transaction, &complaint.email, "complained").await?;
+            suppress(
+                &mut **transaction,
+                &complaint.email,
+                SuppressionReason::SpamComplaint,
+                SuppressionSource::PostmarkWebhook,
+            )
+            .await?;
+        }
+        PostmarkEvent::Delivery(delivery) => {
+            mark_delivery_delivered(transaction, &delivery.message_id).await?;
+        }
+        PostmarkEvent::Other => {}
+    }
+    Ok(())
+}
+
+// イベントを記録する。既に記録済みであればfalseを返す
+#[tracing::instrument(name = "Save Postmark event", skip(transaction, payload))]
+async fn insert_postmark_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    event_id: &str,
+    record_type: &str,
+    email: &str,
+    payload: &serde_json::Value,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    INSERT INTO postmark_events (event_id, record_type, email, payload, received_at)
+    VALUES ($1, $2, $3, $4, $5)
+    ON CONFLICT (event_id) DO NOTHING
+            "#,
+        event_id,
+        record_type,
+        email,
+        payload,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[tracing::instrument(name = "Mark delivery as bounced", skip(transaction))]
+async fn mark_delivery_bounced(
+    transaction: &mut Transaction<'_, Postgres>,
+    message_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE issue_deliveries SET status = 'bounced', bounced_at = $1
+    WHERE message_id = $2
+            "#,
+        Utc::now(),
+        message_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Mark delivery as delivered", skip(transaction))]
+async fn mark_delivery_delivered(
+    transaction: &mut Transaction<'_, Postgres>,
+    message_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE issue_deliveries SET delivered_at = $1 WHERE message_id = $2"#,
+        Utc::now(),
+        message_id
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+// 購読者のすべてのリストへの所属を指定した状態にして、以降の配信対象から外す
+#[tracing::instrument(name = "Update list membership status", skip(transaction))]
+async fn update_membership_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+    status: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE list_memberships SET status = $1
+    WHERE subscriber_id IN (SELECT id FROM subscriptions WHERE lower(email) = lower($2))
+            "#,
+        status,
+        email
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use crate::audience::Audience;
+use crate::newsletter_issues::enqueue_delivery_tasks;
+use crate::subject_tests::try_decide_subject_tests;
+use chrono::Utc;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// 予約日時を過ぎた号の配信タスクをキューに登録し、登録した号の数を返す
+#[tracing::instrument(name = "Enqueue due newsletter issues", skip_all, err)]
+pub async fn try_enqueue_due_issues(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    // 取消や予約変更と競合しないよう、対象の号をロックしてから処理する
+    let due_issues = sqlx::query!(
+        r#"
+    SELECT id, lists, segment
+    FROM newsletter_issues
+    WHERE status = 'scheduled' AND scheduled_for <= $1
+    ORDER BY scheduled_for
+    FOR UPDATE
+    SKIP LOCKED
+            "#,
+        Utc::now()
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let mut enqueued = 0;
+    for issue in due_issues {
+        let (status, published_at) = match Audience::parse(issue.lists, issue.segment) {
+            Ok(audience) => {
+                let tasks = enqueue_delivery_tasks(&mut transaction, issue.id, &audience).await?;
+                tracing::info!(newsletter_issue_id = %issue.id, tasks, "Enqueued a scheduled issue");
+                enqueued += 1;
+                ("published", Some(Utc::now()))
+            }
+            // 保存後に配信対象が解釈できなくなった号は配信しない (公開日時も付けない)
+            Err(e) => {
+                tracing::error!(newsletter_issue_id = %issue.id, error.message = %e, "Failed to parse the audience of a scheduled issue");
+                ("failed", None)
+            }
+        };
+        sqlx::query!(
+            r#"UPDATE newsletter_issues SET status = $1, published_at = $2 WHERE id = $3"#,
+            status,
+            published_at,
+            issue.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(enqueued)
+}
+
+/// poll_intervalごとに予約日時を過ぎた号と、計測時間が過ぎた件名のA/Bテストを確認し続ける
+pub async fn run_scheduler_until_stopped(
+    pool: PgPool,
+    poll_interval: Duration,
+) -> Result<(), std::io::Error> {
+    loop {
+        // エラーはログに出力済みなので次の確認まで待つ
+        let _ = try_enqueue_due_issues(&pool).await;
+        let _ = try_decide_subject_tests(&pool).await;
+        tokio::time::sleep(poll_interval).await;
+    }
+}
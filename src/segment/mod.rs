@@ -0,0 +1,40 @@
+// 配信対象を絞り込むセグメント式
+// 例: confirmed AND tag:beta AND attributes.country = 'JP'
+mod parser;
+mod sql;
+
+use crate::domain::{ListSlug, SubscriberTag};
+
+// セグメント式の構文木
+#[derive(Debug)]
+pub enum Segment {
+    // いずれかのリストで確認済み
+    Confirmed,
+    // タグが付いている (tag:beta)
+    Tag(SubscriberTag),
+    // 指定したリストで確認済み (list:events)
+    List(ListSlug),
+    // 属性の比較 (attributes.country = 'JP')
+    Attribute {
+        key: String,
+        comparison: Comparison,
+        value: String,
+    },
+    Not(Box<Segment>),
+    And(Box<Segment>, Box<Segment>),
+    Or(Box<Segment>, Box<Segment>),
+}
+
+// 属性の比較演算子
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+}
+
+impl Segment {
+    /// セグメント式の文字列をパースする
+    pub fn parse(s: &str) -> Result<Segment, String> {
+        parser::parse(s)
+    }
+}
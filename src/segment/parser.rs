@@ -0,0 +1,329 @@
+// セグメント式の字句解析と構文解析
+//
+// expr     := and_expr ("OR" and_expr)*
+// and_expr := not_expr ("AND" not_expr)*
+// not_expr := "NOT" not_expr | primary
+// primary  := "(" expr ")" | "confirmed" | "tag:" TAG | "list:" SLUG
+//           | "attributes." KEY ("=" | "!=") VALUE
+// VALUE    := 'シングルクォートの文字列' | 数値 | true | false
+use crate::domain::{ListSlug, SubscriberTag};
+use crate::segment::{Comparison, Segment};
+
+// 式の長さとネストの上限 (巨大な式や深い再帰を防ぐ)
+const MAX_EXPRESSION_LENGTH: usize = 1000;
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Confirmed,
+    Tag(String),
+    List(String),
+    Attribute(String),
+    Equal,
+    NotEqual,
+    // 比較する値。数値や真偽値も文字列として比較する
+    Value(String),
+}
+
+pub fn parse(s: &str) -> Result<Segment, String> {
+    if s.len() > MAX_EXPRESSION_LENGTH {
+        return Err(format!(
+            "A segment must be at most {} characters long.",
+            MAX_EXPRESSION_LENGTH
+        ));
+    }
+    let tokens = tokenize(s)?;
+    let mut parser = Parser {
+        tokens: &tokens,
+        position: 0,
+        depth: 0,
+    };
+    let segment = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(segment),
+        Some(token) => Err(format!("Unexpected {:?} in segment.", token)),
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LeftParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RightParen);
+            }
+            '=' => {
+                chars.next();
+                tokens.push(Token::Equal);
+            }
+            '!' => {
+                chars.next();
+                if chars.next() != Some('=') {
+                    return Err("Expected '=' after '!' in segment.".into());
+                }
+                tokens.push(Token::NotEqual);
+            }
+            // 文字列はシングルクォートで囲む。'' でクォート自体を表す
+            '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') if chars.peek() == Some(&'\'') => {
+                            chars.next();
+                            value.push('\'');
+                        }
+                        Some('\'') => break,
+                        Some(c) => value.push(c),
+                        None => return Err("Unterminated string in segment.".into()),
+                    }
+                }
+                tokens.push(Token::Value(value));
+            }
+            c if is_word_character(c) => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !is_word_character(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(classify_word(word)?);
+            }
+            other => return Err(format!("Unexpected character '{}' in segment.", other)),
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_word_character(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':')
+}
+
+// 単語をキーワード・条件・値に分類する
+fn classify_word(word: String) -> Result<Token, String> {
+    if word.eq_ignore_ascii_case("and") {
+        return Ok(Token::And);
+    }
+    if word.eq_ignore_ascii_case("or") {
+        return Ok(Token::Or);
+    }
+    if word.eq_ignore_ascii_case("not") {
+        return Ok(Token::Not);
+    }
+    if word == "confirmed" {
+        return Ok(Token::Confirmed);
+    }
+    if word == "true" || word == "false" || word.parse::<f64>().is_ok() {
+        return Ok(Token::Value(word));
+    }
+    if let Some(tag) = word.strip_prefix("tag:") {
+        return Ok(Token::Tag(tag.into()));
+    }
+    if let Some(slug) = word.strip_prefix("list:") {
+        return Ok(Token::List(slug.into()));
+    }
+    if let Some(key) = word.strip_prefix("attributes.") {
+        // 属性名はSQLに埋め込まないが、設定の属性名と同じ形式に限定する
+        let is_valid_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !is_valid_key {
+            return Err(format!("{} is not a valid attribute name.", key));
+        }
+        return Ok(Token::Attribute(key.into()));
+    }
+    Err(format!("Unknown condition '{}' in segment.", word))
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Segment, String> {
+        let mut segment = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            segment = Segment::Or(Box::new(segment), Box::new(self.parse_and()?));
+        }
+        Ok(segment)
+    }
+
+    fn parse_and(&mut self) -> Result<Segment, String> {
+        let mut segment = self.parse_not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            segment = Segment::And(Box::new(segment), Box::new(self.parse_not()?));
+        }
+        Ok(segment)
+    }
+
+    fn parse_not(&mut self) -> Result<Segment, String> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            self.enter()?;
+            let segment = Segment::Not(Box::new(self.parse_not()?));
+            self.depth -= 1;
+            return Ok(segment);
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Segment, String> {
+        match self.next() {
+            Some(Token::LeftParen) => {
+                self.enter()?;
+                let segment = self.parse_or()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token::RightParen) => Ok(segment),
+                    _ => Err("Expected ')' in segment.".into()),
+                }
+            }
+            Some(Token::Confirmed) => Ok(Segment::Confirmed),
+            Some(Token::Tag(tag)) => Ok(Segment::Tag(SubscriberTag::parse(tag.clone())?)),
+            Some(Token::List(slug)) => Ok(Segment::List(ListSlug::parse(slug.clone())?)),
+            Some(Token::Attribute(key)) => {
+                let comparison = match self.next() {
+                    Some(Token::Equal) => Comparison::Equal,
+                    Some(Token::NotEqual) => Comparison::NotEqual,
+                    _ => return Err(format!("Expected '=' or '!=' after attributes.{}.", key)),
+                };
+                match self.next() {
+                    Some(Token::Value(value)) => Ok(Segment::Attribute {
+                        key: key.clone(),
+                        comparison,
+                        value: value.clone(),
+                    }),
+                    _ => Err(format!("Expected a value after attributes.{}.", key)),
+                }
+            }
+            Some(token) => Err(format!("Unexpected {:?} in segment.", token)),
+            None => Err("Unexpected end of segment.".into()),
+        }
+    }
+
+    // ネストを1段深くする。上限を超えたらエラー
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            Err(format!(
+                "A segment can be nested at most {} levels deep.",
+                MAX_DEPTH
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::segment::{Comparison, Segment};
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let segment = assert_ok!(Segment::parse("tag:a OR tag:b AND confirmed"));
+        match segment {
+            Segment::Or(left, right) => {
+                assert!(matches!(*left, Segment::Tag(ref t) if t.as_ref() == "a"));
+                assert!(matches!(*right, Segment::And(..)));
+            }
+            other => panic!("Unexpected segment {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        let segment = assert_ok!(Segment::parse("(tag:a OR tag:b) AND confirmed"));
+        assert!(matches!(segment, Segment::And(ref left, _) if matches!(**left, Segment::Or(..))));
+    }
+
+    #[test]
+    fn attribute_comparisons_are_parsed() {
+        let segment = assert_ok!(Segment::parse("attributes.country != 'J''P'"));
+        match segment {
+            Segment::Attribute {
+                key,
+                comparison,
+                value,
+            } => {
+                assert_eq!(key, "country");
+                assert_eq!(comparison, Comparison::NotEqual);
+                // '' はクォート1つになる
+                assert_eq!(value, "J'P");
+            }
+            other => panic!("Unexpected segment {:?}", other),
+        }
+    }
+
+    #[test]
+    fn numbers_and_booleans_are_accepted_as_values() {
+        assert_ok!(Segment::parse(
+            "attributes.seats = 12 and attributes.beta = true"
+        ));
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive() {
+        assert_ok!(Segment::parse("not tag:beta And confirmed oR list:events"));
+    }
+
+    #[test]
+    fn invalid_segments_are_rejected() {
+        for segment in &[
+            "",
+            "confirmed AND",
+            "(confirmed",
+            "confirmed)",
+            "tag:Beta",
+            "list:Not_A_Slug",
+            "attributes.country 'JP'",
+            "attributes.country = 'JP",
+            "attributes.Country = 'JP'",
+            "attributes. = 'JP'",
+            "unknown",
+            "confirmed; DROP TABLE subscriptions",
+        ] {
+            assert_err!(Segment::parse(segment), "{} was accepted", segment);
+        }
+    }
+
+    #[test]
+    fn deeply_nested_segments_are_rejected() {
+        let segment = format!("{}confirmed{}", "(".repeat(33), ")".repeat(33));
+        assert_err!(Segment::parse(&segment));
+        let segment = format!("{}confirmed", "NOT ".repeat(33));
+        assert_err!(Segment::parse(&segment));
+    }
+}
@@ -0,0 +1,98 @@
+// セグメント式をsubscriptions(別名s)に対するWHERE句の条件に変換する
+// 値はすべてバインドパラメータとして渡し、SQLには埋め込まない
+use crate::segment::{Comparison, Segment};
+use sqlx::{Postgres, QueryBuilder};
+
+impl Segment {
+    /// 条件をクエリビルダーに追加する。呼び出し側でsubscriptionsをsとして参照していること
+    pub fn push_condition(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            Segment::Confirmed => {
+                builder.push(
+                    "EXISTS (SELECT 1 FROM list_memberships m \
+                    WHERE m.subscriber_id = s.id AND m.status = 'confirmed')",
+                );
+            }
+            Segment::Tag(tag) => {
+                builder.push(
+                    "EXISTS (SELECT 1 FROM subscriber_tags t \
+                    WHERE t.subscriber_id = s.id AND t.tag = ",
+                );
+                builder.push_bind(tag.as_ref().to_string());
+                builder.push(")");
+            }
+            Segment::List(slug) => {
+                builder.push(
+                    "EXISTS (SELECT 1 FROM list_memberships m JOIN lists l ON l.id = m.list_id \
+                    WHERE m.subscriber_id = s.id AND m.status = 'confirmed' AND l.slug = ",
+                );
+                builder.push_bind(slug.as_ref().to_string());
+                builder.push(")");
+            }
+            Segment::Attribute {
+                key,
+                comparison,
+                value,
+            } => {
+                // 属性がない購読者は「等しくない」に含める
+                builder.push("(s.attributes ->> ");
+                builder.push_bind(key.clone());
+                builder.push(match comparison {
+                    Comparison::Equal => ") = ",
+                    Comparison::NotEqual => ") IS DISTINCT FROM ",
+                });
+                builder.push_bind(value.clone());
+            }
+            Segment::Not(segment) => {
+                builder.push("NOT (");
+                segment.push_condition(builder);
+                builder.push(")");
+            }
+            Segment::And(left, right) => {
+                builder.push("(");
+                left.push_condition(builder);
+                builder.push(" AND ");
+                right.push_condition(builder);
+                builder.push(")");
+            }
+            Segment::Or(left, right) => {
+                builder.push("(");
+                left.push_condition(builder);
+                builder.push(" OR ");
+                right.push_condition(builder);
+                builder.push(")");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::segment::Segment;
+    use sqlx::{Postgres, QueryBuilder};
+
+    fn to_sql(segment: &str) -> String {
+        let mut builder = QueryBuilder::<Postgres>::new("");
+        Segment::parse(segment)
+            .unwrap()
+            .push_condition(&mut builder);
+        builder.sql().to_string()
+    }
+
+    #[test]
+    fn values_are_bound_as_parameters() {
+        let sql = to_sql("attributes.country = 'JP'' OR 1=1 --'");
+        assert_eq!(sql, "(s.attributes ->> $1) = $2");
+    }
+
+    #[test]
+    fn boolean_operators_are_parenthesized() {
+        let sql = to_sql("tag:a OR NOT tag:b AND attributes.plan != 'free'");
+        assert_eq!(
+            sql,
+            "(EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = $1) \
+            OR (NOT (EXISTS (SELECT 1 FROM subscriber_tags t WHERE t.subscriber_id = s.id AND t.tag = $2)) \
+            AND (s.attributes ->> $3) IS DISTINCT FROM $4))"
+        );
+    }
+}
@@ -0,0 +1,276 @@
+// ウェルカムシリーズなどのシーケンス
+// リストの購読を確認した購読者を登録し、確認からの日数が来たステップを順番に送る
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailClient;
+use crate::email_message::EmailMessage;
+use crate::email_templates::{EmailTemplates, Recipient, SequenceStepEmail};
+use crate::issue_delivery_worker::{run_tasks_until_stopped, DeliveryError, ExecutionOutcome};
+use crate::newsletter_issues::IssueContent;
+use crate::routes::unsubscribe_url;
+use chrono::{Duration, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use tracing::{field::display, Span};
+use uuid::Uuid;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+/// リストの購読を確認した購読者を、そのリストのシーケンスに登録する
+/// 一度登録されたシーケンスには、確認し直しても登録し直さない
+#[tracing::instrument(name = "Enroll subscriber in sequences", skip(transaction))]
+pub async fn enroll_in_sequences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO sequence_enrollments
+        (sequence_id, subscriber_id, status, enrolled_at, next_position, next_due_at)
+    SELECT q.id, $1, 'active', $3::timestamptz, 0, $3::timestamptz + make_interval(days => st.delay_days)
+    FROM sequences q
+    JOIN sequence_steps st ON st.sequence_id = q.id AND st.position = 0
+    WHERE q.list_id = $2
+    ON CONFLICT (sequence_id, subscriber_id) DO NOTHING
+            "#,
+        subscriber_id,
+        list_id,
+        Utc::now()
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// 購読者が登録されているシーケンスをすべて途中で終了する
+#[tracing::instrument(name = "Exit subscriber from sequences", skip(executor))]
+pub async fn exit_sequences(
+    executor: impl sqlx::PgExecutor<'_>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE sequence_enrollments SET status = 'exited', next_due_at = NULL
+    WHERE subscriber_id = $1 AND status = 'active'
+            "#,
+        subscriber_id
+    )
+    .execute(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+// 送る日時が来たステップ
+struct DueStep {
+    sequence_id: Uuid,
+    subscriber_id: Uuid,
+    position: i32,
+    enrolled_at: chrono::DateTime<Utc>,
+    email: String,
+    name: String,
+    unsubscribe_token: String,
+    title: String,
+    text_content: String,
+    html_content: String,
+    // リストの購読が続いていて、抑止リストにも載っていない
+    deliverable: bool,
+}
+
+/// 送る日時が来たステップを1件取り出して送信し、購読者の進み具合を次のステップに進める
+#[tracing::instrument(
+    skip_all,
+    fields(sequence_id = tracing::field::Empty, subscriber_email = tracing::field::Empty),
+    err
+)]
+pub async fn try_send_sequence_step(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    // 送信サービスの障害中は、送れなかったステップを飛ばさないように取り出さない
+    if email_client.is_unavailable() {
+        return Ok(ExecutionOutcome::ProviderUnavailable);
+    }
+    let mut transaction = pool.begin().await?;
+    let step = match dequeue_due_step(&mut transaction).await? {
+        Some(step) => step,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current()
+        .record("sequence_id", display(step.sequence_id))
+        .record("subscriber_email", display(&step.email));
+
+    // 配信停止やバウンスでリストから外れた購読者はシーケンスを抜ける
+    if !step.deliverable {
+        exit_sequence(&mut transaction, step.sequence_id, step.subscriber_id).await?;
+        transaction.commit().await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+
+    match send_step(email_client, templates, base_url, &step).await {
+        Ok(()) => {}
+        // 1日の上限に達している場合はロールバックして、次の確認のときに送る
+        Err(DeliveryError::QuotaExhausted) => return Ok(ExecutionOutcome::QuotaExhausted),
+        Err(DeliveryError::Database(e)) => return Err(e),
+        // 送信に失敗したステップは再送せず、次のステップに進める
+        Err(e) => {
+            tracing::error!(
+                error.message = %e,
+                "Failed to send a sequence step. Skipping.",
+            );
+        }
+    }
+    advance_enrollment(&mut transaction, &step).await?;
+    transaction.commit().await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+// 他のワーカーが処理中の登録を飛ばして、送る日時が来た登録を1件ロックして取り出す
+async fn dequeue_due_step(transaction: &mut PgTransaction) -> Result<Option<DueStep>, sqlx::Error> {
+    let r = sqlx::query!(
+        r#"
+    SELECT e.sequence_id, e.subscriber_id, e.next_position, e.enrolled_at,
+        s.email, s.name, s.unsubscribe_token,
+        st.title, st.text_content, st.html_content,
+        (
+            EXISTS (
+                SELECT 1 FROM list_memberships m JOIN sequences q ON q.list_id = m.list_id
+                WHERE q.id = e.sequence_id AND m.subscriber_id = e.subscriber_id
+                    AND m.status = 'confirmed'
+            )
+            AND NOT EXISTS (SELECT 1 FROM suppressions x WHERE x.email = lower(s.email))
+        ) AS "deliverable!"
+    FROM sequence_enrollments e
+    JOIN subscriptions s ON s.id = e.subscriber_id
+    JOIN sequence_steps st ON st.sequence_id = e.sequence_id AND st.position = e.next_position
+    WHERE e.status = 'active' AND e.next_due_at <= $1
+    ORDER BY e.next_due_at
+    FOR UPDATE OF e
+    SKIP LOCKED
+    LIMIT 1
+            "#,
+        Utc::now()
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    Ok(r.map(|r| DueStep {
+        sequence_id: r.sequence_id,
+        subscriber_id: r.subscriber_id,
+        position: r.next_position,
+        enrolled_at: r.enrolled_at,
+        email: r.email,
+        name: r.name,
+        unsubscribe_token: r.unsubscribe_token,
+        title: r.title,
+        text_content: r.text_content,
+        html_content: r.html_content,
+        deliverable: r.deliverable,
+    }))
+}
+
+async fn send_step(
+    email_client: &EmailClient,
+    templates: &EmailTemplates,
+    base_url: &str,
+    step: &DueStep,
+) -> Result<(), DeliveryError> {
+    let recipient = SubscriberEmail::parse(step.email.clone())?;
+    let unsubscribe_url = unsubscribe_url(base_url, &step.unsubscribe_token);
+    let email = templates
+        .render(&SequenceStepEmail {
+            subscriber: Recipient {
+                name: &step.name,
+                email: recipient.as_ref(),
+            },
+            step: IssueContent {
+                title: &step.title,
+                text_content: &step.text_content,
+                html_content: &step.html_content,
+            },
+            unsubscribe_url: &unsubscribe_url,
+        })
+        .map_err(|e| e.to_string())?;
+    email_client
+        .send_email(EmailMessage::new(recipient, &step.title, &email.html, &email.text).broadcast())
+        .await?;
+    Ok(())
+}
+
+// 次のステップがあれば送る日時を設定し、なければシーケンスを完了にする
+async fn advance_enrollment(
+    transaction: &mut PgTransaction,
+    step: &DueStep,
+) -> Result<(), sqlx::Error> {
+    let next = sqlx::query!(
+        r#"SELECT delay_days FROM sequence_steps WHERE sequence_id = $1 AND position = $2"#,
+        step.sequence_id,
+        step.position + 1
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    let (status, next_due_at) = match next {
+        Some(next) => (
+            "active",
+            Some(step.enrolled_at + Duration::days(next.delay_days.into())),
+        ),
+        None => ("completed", None),
+    };
+    sqlx::query!(
+        r#"
+    UPDATE sequence_enrollments
+    SET status = $1, next_position = $2, next_due_at = $3, last_sent_at = $4
+    WHERE sequence_id = $5 AND subscriber_id = $6
+            "#,
+        status,
+        step.position + 1,
+        next_due_at,
+        Utc::now(),
+        step.sequence_id,
+        step.subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+async fn exit_sequence(
+    transaction: &mut PgTransaction,
+    sequence_id: Uuid,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    UPDATE sequence_enrollments SET status = 'exited', next_due_at = NULL
+    WHERE sequence_id = $1 AND subscriber_id = $2
+            "#,
+        sequence_id,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// シーケンスのステップを送り続ける。送るステップがない間はpoll_intervalごとに確認する
+pub async fn run_sequences_until_stopped(
+    pool: PgPool,
+    email_client: EmailClient,
+    templates: Arc<EmailTemplates>,
+    base_url: String,
+    poll_interval: std::time::Duration,
+) -> Result<(), std::io::Error> {
+    run_tasks_until_stopped(
+        || try_send_sequence_step(&pool, &email_client, &templates, &base_url),
+        poll_interval,
+    )
+    .await
+}
@@ -1,20 +1,40 @@
 // crateはプロジェクトのルートを指すキーワード
 use crate::configuration::DatabaseSettings;
 use crate::configuration::Settings;
+use crate::confirmation_reminders::run_confirmation_reminders_until_stopped;
+use crate::deferred_emails::run_deferred_emails_until_stopped;
 use crate::email_client::EmailClient;
-use crate::routes::{health_check, subscribe};
+use crate::email_templates::EmailTemplates;
+use crate::issue_delivery_worker::run_worker_until_stopped;
+use crate::retention::{run_retention_until_stopped, RetentionCounters};
+use crate::routes::{
+    add_suppression, archive_index, archive_issue, atom_feed, authorize_email_change,
+    cancel_newsletter, confirm, confirm_email_change, create_list, create_sequence, health_check,
+    metrics, newsletter_stats, postmark_webhook, preview_newsletter, preview_segment,
+    publish_newsletter, remove_suppression, request_email_change, reschedule_newsletter, rss_feed,
+    subscribe, tag_subscribers, test_send_newsletter, track_click, track_open, unsubscribe,
+    untag_subscribers,
+};
+use crate::scheduler::run_scheduler_until_stopped;
+use crate::sequences::run_sequences_until_stopped;
+use crate::tracking::TrackingLinks;
 use actix_web::dev::Server;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
 use tracing_actix_web::TracingLogger;
 
-// サーバとポート番号を保持する構造体
+// サーバとポート番号、バックグラウンドジョブを保持する構造体
 pub struct Application {
     port: u16,
     server: Server,
+    // 購読者の整理の件数 (メトリクスで返す)
+    retention_counters: RetentionCounters,
+    background_jobs: Vec<JoinHandle<Result<(), std::io::Error>>>,
 }
 
 impl Application {
@@ -22,17 +42,71 @@ impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
 
-        let sender_email = configuration
+        // 抑止リストに載っているアドレスには、どの送信経路からも送らない
+        let email_client = configuration
             .email_client
-            .sender()
-            .expect("Invalid sender email address.");
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token,
-            timeout,
+            .clone()
+            .client()
+            .with_db_pool(connection_pool.clone());
+        let tracking_links = TrackingLinks::new(
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret.clone(),
         );
+        // テンプレートの誤りは起動時にエラーにする
+        let templates = EmailTemplates::load(&configuration.templates)
+            .map(Arc::new)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        // テスト送信の宛先も起動時に検証する
+        for recipient in &configuration.test_recipients {
+            recipient
+                .parse()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        }
+
+        // 予約配信のスケジューラと配信ワーカー、シーケンス、確認のリマインダー、後で送るメール、購読者の整理を
+        // サーバと同じランタイムで動かす
+        let retention_counters = RetentionCounters::default();
+        let mut background_jobs = Vec::new();
+        if configuration.background_jobs.enabled {
+            let poll_interval = configuration.background_jobs.poll_interval();
+            background_jobs.push(tokio::spawn(run_scheduler_until_stopped(
+                connection_pool.clone(),
+                poll_interval,
+            )));
+            background_jobs.push(tokio::spawn(run_worker_until_stopped(
+                connection_pool.clone(),
+                email_client.clone(),
+                templates.clone(),
+                configuration.application.base_url.clone(),
+                tracking_links.clone(),
+                poll_interval,
+            )));
+            background_jobs.push(tokio::spawn(run_sequences_until_stopped(
+                connection_pool.clone(),
+                email_client.clone(),
+                templates.clone(),
+                configuration.application.base_url.clone(),
+                poll_interval,
+            )));
+            background_jobs.push(tokio::spawn(run_confirmation_reminders_until_stopped(
+                connection_pool.clone(),
+                email_client.clone(),
+                templates.clone(),
+                configuration.application.base_url.clone(),
+                configuration.confirmation.clone(),
+                poll_interval,
+            )));
+            background_jobs.push(tokio::spawn(run_deferred_emails_until_stopped(
+                connection_pool.clone(),
+                email_client.clone(),
+                poll_interval,
+            )));
+            background_jobs.push(tokio::spawn(run_retention_until_stopped(
+                connection_pool.clone(),
+                configuration.retention.clone(),
+                retention_counters.clone(),
+            )));
+        }
 
         let address = format!(
             "{}:{}",
@@ -40,10 +114,23 @@ impl Application {
         );
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(listener, connection_pool, email_client)?;
+        let server = run(
+            listener,
+            connection_pool,
+            email_client,
+            templates,
+            tracking_links,
+            retention_counters.clone(),
+            configuration,
+        )?;
 
         // Self { port, server} で新しいインスタンスが作成され、Okバリアントでラップされて返される
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            retention_counters,
+            background_jobs,
+        })
     }
 
     /// ポート番号を返す
@@ -51,9 +138,19 @@ impl Application {
         self.port
     }
 
+    /// 購読者の整理の件数を返す (テストから整理を実行してもメトリクスに反映される)
+    pub fn retention_counters(&self) -> RetentionCounters {
+        self.retention_counters.clone()
+    }
+
     /// サーバ実行
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let result = self.server.await;
+        // サーバが止まったらバックグラウンドジョブも止める
+        for job in self.background_jobs {
+            job.abort();
+        }
+        result
     }
 }
 
@@ -63,15 +160,39 @@ pub fn get_connection_pool(configuration: &DatabaseSettings) -> PgPool {
         .connect_lazy_with(configuration.with_db())
 }
 
+// メール内のリンク組立に使うベースURL。Stringのままだと他のStringと区別できないのでラップする
+pub struct ApplicationBaseUrl(pub String);
+
 pub fn run(
     listener: TcpListener,
     db_pool: PgPool,
     email_client: EmailClient,
+    templates: Arc<EmailTemplates>,
+    tracking_links: TrackingLinks,
+    retention_counters: RetentionCounters,
+    configuration: Settings,
 ) -> Result<Server, std::io::Error> {
     // connectionをActixWebアプリ全体で共有するために、web::Data::newとapp_dataを使う
     let db_pool = Data::new(db_pool);
     // EmailClientも同様に共有する
     let email_client = Data::new(email_client);
+    // メールのテンプレートはバックグラウンドの配信ワーカーとも共有する
+    let templates = Data::from(templates);
+    let base_url = Data::new(ApplicationBaseUrl(configuration.application.base_url));
+    // PostmarkのWebhookの認証情報
+    let postmark_webhook_settings = Data::new(configuration.email_client.webhook);
+    // 計測用URLの署名の検証に使う
+    let tracking_links = Data::new(tracking_links);
+    // 管理者用APIの認証情報
+    let admin = Data::new(configuration.admin);
+    // 購読時に受け付ける追加属性の定義
+    let subscriber_attributes = Data::new(configuration.subscriber_attributes);
+    // テスト送信の宛先
+    let test_recipients = Data::new(configuration.test_recipients);
+    // 確認リンクの有効期限
+    let confirmation_settings = Data::new(configuration.confirmation);
+    // 購読者の整理の件数
+    let retention_counters = Data::new(retention_counters);
 
     // 新しいHttpServerオブジェクトを作成する
     let server = HttpServer::new(move || {
@@ -79,9 +200,74 @@ pub fn run(
         App::new()
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(health_check))
+            .route("/metrics", web::get().to(metrics))
+            .route("/archive", web::get().to(archive_index))
+            .route("/archive/{slug}", web::get().to(archive_issue))
+            .route("/feed.rss", web::get().to(rss_feed))
+            .route("/feed.atom", web::get().to(atom_feed))
+            .route("/t/o/{token}", web::get().to(track_open))
+            .route("/t/c/{token}", web::get().to(track_click))
             .route("/subscriptions", web::post().to(subscribe))
+            .route("/subscriptions/confirm", web::get().to(confirm))
+            .route("/subscriptions/unsubscribe", web::get().to(unsubscribe))
+            .route(
+                "/subscriptions/email_change",
+                web::post().to(request_email_change),
+            )
+            .route(
+                "/subscriptions/email_change/authorize",
+                web::get().to(authorize_email_change),
+            )
+            .route(
+                "/subscriptions/email_change/confirm",
+                web::get().to(confirm_email_change),
+            )
+            .route("/webhooks/postmark", web::post().to(postmark_webhook))
+            .route("/admin/lists", web::post().to(create_list))
+            .route("/admin/newsletters", web::post().to(publish_newsletter))
+            .route(
+                "/admin/newsletters/preview",
+                web::post().to(preview_newsletter),
+            )
+            .route(
+                "/admin/newsletters/test-send",
+                web::post().to(test_send_newsletter),
+            )
+            .route(
+                "/admin/newsletters/{id}/cancel",
+                web::post().to(cancel_newsletter),
+            )
+            .route(
+                "/admin/newsletters/{id}/reschedule",
+                web::post().to(reschedule_newsletter),
+            )
+            .route(
+                "/admin/newsletters/{id}/stats",
+                web::get().to(newsletter_stats),
+            )
+            .route("/admin/sequences", web::post().to(create_sequence))
+            .route("/admin/segments/preview", web::post().to(preview_segment))
+            .route("/admin/suppressions", web::post().to(add_suppression))
+            .route(
+                "/admin/suppressions/{email}",
+                web::delete().to(remove_suppression),
+            )
+            .route("/admin/subscribers/tag", web::post().to(tag_subscribers))
+            .route(
+                "/admin/subscribers/untag",
+                web::post().to(untag_subscribers),
+            )
             .app_data(db_pool.clone()) // cloneは新しい参照を作成しているだけで実体を複製しているわけではない
             .app_data(email_client.clone())
+            .app_data(templates.clone())
+            .app_data(base_url.clone())
+            .app_data(admin.clone())
+            .app_data(subscriber_attributes.clone())
+            .app_data(test_recipients.clone())
+            .app_data(tracking_links.clone())
+            .app_data(postmark_webhook_settings.clone())
+            .app_data(confirmation_settings.clone())
+            .app_data(retention_counters.clone())
     })
     .listen(listener)?
     .run();
@@ -0,0 +1,299 @@
+// 件名のA/Bテスト
+// 配信対象から決まった割合の購読者を抽出して2つの件名を半分ずつ送り、
+// 計測時間が過ぎたら開封率の高い件名を残りの購読者に送る
+use crate::audience::Audience;
+use crate::newsletter_issues::enqueue_remaining_delivery_tasks;
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, QueryBuilder, Transaction};
+use uuid::Uuid;
+
+const DEFAULT_SAMPLE_PERCENT: u8 = 20;
+const DEFAULT_WINDOW_HOURS: u32 = 4;
+
+// 号ごとのA/Bテストの設定 (件名Aは号のタイトル)
+#[derive(serde::Deserialize, Debug)]
+pub struct SubjectTestOptions {
+    pub subject_b: String,
+    // 配信対象のうちテストに使う割合 (%)
+    #[serde(default = "default_sample_percent")]
+    pub sample_percent: u8,
+    // 開封を計測する時間
+    #[serde(default = "default_window_hours")]
+    pub window_hours: u32,
+}
+
+fn default_sample_percent() -> u8 {
+    DEFAULT_SAMPLE_PERCENT
+}
+
+fn default_window_hours() -> u32 {
+    DEFAULT_WINDOW_HOURS
+}
+
+impl SubjectTestOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.subject_b.trim().is_empty() {
+            return Err("subject_b is empty.".into());
+        }
+        if !(1..=100).contains(&self.sample_percent) {
+            return Err("sample_percent must be between 1 and 100.".into());
+        }
+        if self.window_hours == 0 {
+            return Err("window_hours must be at least 1.".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Variant {
+    A,
+    B,
+}
+
+impl Variant {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Variant::A => "a",
+            Variant::B => "b",
+        }
+    }
+}
+
+/// 配信対象からテストに使う購読者を抽出し、件名を交互に割り当てる
+/// 号とアドレスのハッシュ順に並べるので、同じ号では何度実行しても同じ結果になる
+pub fn split_sample(
+    issue_id: Uuid,
+    emails: Vec<String>,
+    sample_percent: u8,
+) -> Vec<(String, Variant)> {
+    let sample_size = (emails.len() * usize::from(sample_percent)).div_ceil(100);
+    let mut hashed: Vec<_> = emails
+        .into_iter()
+        .map(|email| {
+            let hash = Sha256::new()
+                .chain_update(issue_id.as_bytes())
+                .chain_update(email.to_lowercase().as_bytes())
+                .finalize();
+            (hash, email)
+        })
+        .collect();
+    hashed.sort();
+    hashed
+        .into_iter()
+        .take(sample_size)
+        .enumerate()
+        .map(|(i, (_, email))| {
+            let variant = if i % 2 == 0 { Variant::A } else { Variant::B };
+            (email, variant)
+        })
+        .collect()
+}
+
+#[tracing::instrument(name = "Save subject test", skip(transaction))]
+pub async fn insert_subject_test(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    options: &SubjectTestOptions,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+    INSERT INTO subject_tests (newsletter_issue_id, subject_b, sample_percent, window_hours)
+    VALUES ($1, $2, $3, $4)
+            "#,
+        newsletter_issue_id,
+        options.subject_b,
+        i32::from(options.sample_percent),
+        options.window_hours as i32
+    )
+    .execute(&mut **transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(())
+}
+
+/// 号にA/Bテストがあれば、テスト用の購読者だけを配信キューに登録して件数を返す
+/// テストのない号はNoneを返す
+#[tracing::instrument(name = "Enqueue subject test sample", skip(transaction, audience))]
+pub async fn enqueue_subject_test_sample(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    audience: &Audience,
+) -> Result<Option<u64>, sqlx::Error> {
+    let test = sqlx::query!(
+        r#"SELECT sample_percent, window_hours FROM subject_tests WHERE newsletter_issue_id = $1"#,
+        newsletter_issue_id
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    let test = match test {
+        Some(test) => test,
+        None => return Ok(None),
+    };
+
+    let mut builder = QueryBuilder::new("SELECT s.email FROM subscriptions s");
+    audience.push_where(&mut builder);
+    let emails: Vec<String> = builder
+        .build_query_scalar()
+        .fetch_all(&mut **transaction)
+        .await?;
+    let (emails, variants): (Vec<String>, Vec<&str>) = split_sample(
+        newsletter_issue_id,
+        emails,
+        test.sample_percent.clamp(1, 100) as u8,
+    )
+    .into_iter()
+    .map(|(email, variant)| (email, variant.as_str()))
+    .unzip();
+    let result = sqlx::query!(
+        r#"
+    INSERT INTO issue_deliveries
+        (newsletter_issue_id, subscriber_email, status, enqueued_at, variant)
+    SELECT $1, email, 'queued', $2, variant
+    FROM UNNEST($3::text[], $4::text[]) AS sample(email, variant)
+            "#,
+        newsletter_issue_id,
+        Utc::now(),
+        &emails,
+        &variants as &[&str]
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        r#"UPDATE subject_tests SET decide_at = $1 WHERE newsletter_issue_id = $2"#,
+        Utc::now() + Duration::hours(test.window_hours.into()),
+        newsletter_issue_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(Some(result.rows_affected()))
+}
+
+/// 計測時間が過ぎたテストの勝者を決め、残りの購読者への配信をキューに登録する。判定したテストの数を返す
+#[tracing::instrument(name = "Decide due subject tests", skip_all, err)]
+pub async fn try_decide_subject_tests(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let due_tests = sqlx::query!(
+        r#"
+    SELECT t.newsletter_issue_id, i.lists, i.segment
+    FROM subject_tests t
+    JOIN newsletter_issues i ON i.id = t.newsletter_issue_id
+    WHERE t.winner IS NULL AND t.decide_at <= $1
+    ORDER BY t.decide_at
+    FOR UPDATE OF t
+    SKIP LOCKED
+            "#,
+        Utc::now()
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+
+    let mut decided = 0;
+    for test in due_tests {
+        let issue_id = test.newsletter_issue_id;
+        let winner = pick_winner(&mut transaction, issue_id).await?;
+        sqlx::query!(
+            r#"
+    UPDATE subject_tests SET winner = $1, decided_at = $2
+    WHERE newsletter_issue_id = $3
+            "#,
+            winner.as_str(),
+            Utc::now(),
+            issue_id
+        )
+        .execute(&mut *transaction)
+        .await?;
+        // 保存後に配信対象が解釈できなくなった号は、残りの購読者には送らない
+        match Audience::parse(test.lists, test.segment) {
+            Ok(audience) => {
+                let tasks =
+                    enqueue_remaining_delivery_tasks(&mut transaction, issue_id, &audience, winner)
+                        .await?;
+                tracing::info!(newsletter_issue_id = %issue_id, winner = winner.as_str(), tasks, "Decided a subject test");
+            }
+            Err(e) => {
+                tracing::error!(newsletter_issue_id = %issue_id, error.message = %e, "Failed to parse the audience of a subject test");
+            }
+        }
+        decided += 1;
+    }
+    transaction.commit().await?;
+    Ok(decided)
+}
+
+// 送信できた配信に対する開封した購読者の割合を比べる。同じならAを選ぶ
+async fn pick_winner(
+    transaction: &mut Transaction<'_, Postgres>,
+    issue_id: Uuid,
+) -> Result<Variant, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+    SELECT d.variant AS "variant!",
+        COUNT(DISTINCT d.subscriber_email) FILTER (WHERE d.status = 'sent') AS "sent!",
+        COUNT(DISTINCT e.subscriber_id) AS "opened!"
+    FROM issue_deliveries d
+    LEFT JOIN subscriptions s ON s.email = d.subscriber_email
+    LEFT JOIN tracking_events e ON e.newsletter_issue_id = d.newsletter_issue_id
+        AND e.subscriber_id = s.id AND e.kind = 'open'
+    WHERE d.newsletter_issue_id = $1 AND d.variant IS NOT NULL
+    GROUP BY d.variant
+            "#,
+        issue_id
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+    let (mut sent_a, mut opened_a, mut sent_b, mut opened_b) = (0, 0, 0, 0);
+    for row in rows {
+        match row.variant.as_str() {
+            "a" => (sent_a, opened_a) = (row.sent, row.opened),
+            "b" => (sent_b, opened_b) = (row.sent, row.opened),
+            _ => {}
+        }
+    }
+    // opened_b / sent_b > opened_a / sent_a を整数で比べる
+    if opened_b * sent_a > opened_a * sent_b {
+        Ok(Variant::B)
+    } else {
+        Ok(Variant::A)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::subject_tests::{split_sample, Variant};
+    use uuid::Uuid;
+
+    fn emails(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("user{}@example.com", i)).collect()
+    }
+
+    #[test]
+    fn the_sample_is_rounded_up_and_split_evenly() {
+        let sample = split_sample(Uuid::new_v4(), emails(101), 20);
+        assert_eq!(sample.len(), 21);
+        let b = sample.iter().filter(|(_, v)| *v == Variant::B).count();
+        assert_eq!(b, 10);
+    }
+
+    #[test]
+    fn the_assignment_is_deterministic_per_issue() {
+        let issue_id = Uuid::new_v4();
+        let mut shuffled = emails(50);
+        shuffled.reverse();
+        assert_eq!(
+            split_sample(issue_id, emails(50), 30),
+            split_sample(issue_id, shuffled, 30)
+        );
+    }
+
+    #[test]
+    fn different_issues_sample_different_subscribers() {
+        let first = split_sample(Uuid::new_v4(), emails(100), 10);
+        let second = split_sample(Uuid::new_v4(), emails(100), 10);
+        assert_ne!(first, second);
+    }
+}
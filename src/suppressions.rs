@@ -0,0 +1,100 @@
+// 送信抑止リスト
+// ハードバウンスや迷惑メール報告のあったアドレス、管理者が止めたアドレスには一切送信しない
+use chrono::Utc;
+
+// 抑止の理由
+#[derive(Clone, Copy, Debug)]
+pub enum SuppressionReason {
+    HardBounce,
+    SpamComplaint,
+    Manual,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuppressionReason::HardBounce => "hard_bounce",
+            SuppressionReason::SpamComplaint => "spam_complaint",
+            SuppressionReason::Manual => "manual",
+        }
+    }
+}
+
+// 抑止を登録した経路
+#[derive(Clone, Copy, Debug)]
+pub enum SuppressionSource {
+    PostmarkWebhook,
+    Admin,
+}
+
+impl SuppressionSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuppressionSource::PostmarkWebhook => "postmark_webhook",
+            SuppressionSource::Admin => "admin",
+        }
+    }
+}
+
+/// アドレスが抑止リストに載っているかを返す (大文字小文字は区別しない)
+#[tracing::instrument(name = "Check suppression list", skip(executor))]
+pub async fn is_suppressed(
+    executor: impl sqlx::PgExecutor<'_>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"SELECT EXISTS (SELECT 1 FROM suppressions WHERE email = lower($1)) AS "exists!""#,
+        email
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.exists)
+}
+
+/// アドレスを抑止リストに追加する。登録済みであれば最初の理由のままにしてfalseを返す
+#[tracing::instrument(name = "Add address to suppression list", skip(executor))]
+pub async fn suppress(
+    executor: impl sqlx::PgExecutor<'_>,
+    email: &str,
+    reason: SuppressionReason,
+    source: SuppressionSource,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+    INSERT INTO suppressions (email, reason, source, created_at)
+    VALUES (lower($1), $2, $3, $4)
+    ON CONFLICT (email) DO NOTHING
+            "#,
+        email,
+        reason.as_str(),
+        source.as_str(),
+        Utc::now()
+    )
+    .execute(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// アドレスを抑止リストから外す。載っていなければfalseを返す
+#[tracing::instrument(name = "Remove address from suppression list", skip(executor))]
+pub async fn unsuppress(
+    executor: impl sqlx::PgExecutor<'_>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(r#"DELETE FROM suppressions WHERE email = lower($1)"#, email)
+        .execute(executor)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    Ok(result.rows_affected() > 0)
+}
@@ -0,0 +1,193 @@
+// 開封とクリックの計測
+// 計測用のURLには号と購読者 (クリックの場合は元のURL) を入れ、HMACで署名して改ざんを防ぐ
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+// 号ごとの計測の設定
+#[derive(serde::Deserialize, Clone, Copy, Default, Debug)]
+pub struct TrackingOptions {
+    // 開封を計測する (メールに1x1の画像を入れる)
+    #[serde(default)]
+    pub opens: bool,
+    // クリックを計測する (本文のリンクを計測用のURLに置き換える)
+    #[serde(default)]
+    pub clicks: bool,
+}
+
+// 計測用のURLに入れる内容
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum TrackedEvent {
+    Open {
+        issue_id: Uuid,
+        subscriber_id: Uuid,
+    },
+    Click {
+        issue_id: Uuid,
+        subscriber_id: Uuid,
+        url: String,
+    },
+}
+
+// 署名付きの計測用URLを組み立て、検証する
+#[derive(Clone)]
+pub struct TrackingLinks {
+    base_url: String,
+    hmac_secret: Secret<String>,
+}
+
+impl TrackingLinks {
+    pub fn new(base_url: String, hmac_secret: Secret<String>) -> Self {
+        Self {
+            base_url,
+            hmac_secret,
+        }
+    }
+
+    /// 開封を記録する画像のURL
+    pub fn open_url(&self, issue_id: Uuid, subscriber_id: Uuid) -> String {
+        let token = self.sign(&TrackedEvent::Open {
+            issue_id,
+            subscriber_id,
+        });
+        format!("{}/t/o/{}", self.base_url, token)
+    }
+
+    /// クリックを記録して元のURLにリダイレクトするURL
+    pub fn click_url(&self, issue_id: Uuid, subscriber_id: Uuid, url: &str) -> String {
+        let token = self.sign(&TrackedEvent::Click {
+            issue_id,
+            subscriber_id,
+            url: url.into(),
+        });
+        format!("{}/t/c/{}", self.base_url, token)
+    }
+
+    // トークンは "<内容のJSON>.<署名>" をそれぞれURLセーフなbase64にしたもの
+    fn sign(&self, event: &TrackedEvent) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(event).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// 署名を検証してトークンの内容を返す。改ざんされていればエラーを返す
+    pub fn verify(&self, token: &str) -> Result<TrackedEvent, String> {
+        let (payload, signature) = token
+            .split_once('.')
+            .ok_or_else(|| "The token is not signed.".to_string())?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|e| e.to_string())?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| "The token signature is invalid.".to_string())?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&payload).map_err(|e| e.to_string())
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(self.hmac_secret.expose_secret().as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// HTMLのhref属性のうち、http(s)のURLをrewriteの結果に置き換える
+/// mailto:やアンカーへのリンクはそのままにする
+pub fn rewrite_links(html: &str, rewrite: impl Fn(&str) -> String) -> String {
+    // ASCIIの小文字化ではバイト位置が変わらないので、検索は小文字で行う
+    let lowercase_html = html.to_ascii_lowercase();
+    let mut output = String::with_capacity(html.len());
+    let mut position = 0;
+    while let Some(found) = lowercase_html[position..].find("href=") {
+        let value_start = position + found + "href=".len();
+        output.push_str(&html[position..value_start]);
+        position = value_start;
+        let quote = match html[value_start..].chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => continue,
+        };
+        let url_start = value_start + 1;
+        let url_end = match html[url_start..].find(quote) {
+            Some(length) => url_start + length,
+            None => break,
+        };
+        output.push(quote);
+        // 属性値の&amp;を戻してから置き換え、置き換えたURLは再度エスケープする
+        let url = html[url_start..url_end].replace("&amp;", "&");
+        let lowercase_url = &lowercase_html[url_start..url_end];
+        if lowercase_url.starts_with("http://") || lowercase_url.starts_with("https://") {
+            output.push_str(&rewrite(&url).replace('&', "&amp;"));
+        } else {
+            output.push_str(&html[url_start..url_end]);
+        }
+        position = url_end;
+    }
+    output.push_str(&html[position..]);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tracking::{rewrite_links, TrackedEvent, TrackingLinks};
+    use claim::{assert_err, assert_ok_eq};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn links(secret: &str) -> TrackingLinks {
+        TrackingLinks::new("https://example.com".into(), Secret::new(secret.into()))
+    }
+
+    fn token(url: &str) -> &str {
+        url.rsplit('/').next().unwrap()
+    }
+
+    #[test]
+    fn a_signed_token_round_trips() {
+        let links = links("secret");
+        let issue_id = Uuid::new_v4();
+        let subscriber_id = Uuid::new_v4();
+        let url = links.click_url(issue_id, subscriber_id, "https://example.com/docs?a=1&b=2");
+        assert!(url.starts_with("https://example.com/t/c/"));
+        assert_ok_eq!(
+            links.verify(token(&url)),
+            TrackedEvent::Click {
+                issue_id,
+                subscriber_id,
+                url: "https://example.com/docs?a=1&b=2".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn a_tampered_token_is_rejected() {
+        let links = links("secret");
+        let url = links.click_url(Uuid::new_v4(), Uuid::new_v4(), "https://example.com");
+        let (_, signature) = token(&url).split_once('.').unwrap();
+        let forged = links.click_url(Uuid::new_v4(), Uuid::new_v4(), "https://evil.example.com");
+        let (payload, _) = token(&forged).split_once('.').unwrap();
+        assert_err!(links.verify(&format!("{}.{}", payload, signature)));
+        assert_err!(links.verify(payload));
+    }
+
+    #[test]
+    fn a_token_signed_with_another_secret_is_rejected() {
+        let url = links("secret").open_url(Uuid::new_v4(), Uuid::new_v4());
+        assert_err!(links("another secret").verify(token(&url)));
+    }
+
+    #[test]
+    fn only_http_links_are_rewritten() {
+        let html = r##"<a href="https://example.com/?a=1&amp;b=2">x</a> <a HREF='http://example.com'>y</a> <a href="mailto:a@example.com">z</a> <a href="#top">t</a>"##;
+        let rewritten = rewrite_links(html, |url| format!("https://t.example.com/?u={}", url));
+        assert_eq!(
+            rewritten,
+            r##"<a href="https://t.example.com/?u=https://example.com/?a=1&amp;b=2">x</a> <a HREF='https://t.example.com/?u=http://example.com'>y</a> <a href="mailto:a@example.com">z</a> <a href="#top">t</a>"##
+        );
+    }
+}
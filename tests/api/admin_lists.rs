@@ -0,0 +1,92 @@
+use crate::helpers::spawn_app;
+
+// POST /admin/lists 新しいリストを作成できる
+#[tokio::test]
+async fn admins_can_create_a_new_list() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = app
+        .post_lists(serde_json::json!({"slug": "product-updates", "name": "Product updates"}))
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT name FROM lists WHERE slug = 'product-updates'")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved list.");
+    assert_eq!(saved.name, "Product updates");
+}
+
+// POST /admin/lists 既にあるスラッグの場合は409を返す
+#[tokio::test]
+async fn creating_a_list_with_an_existing_slug_returns_a_409() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = app
+        .post_lists(serde_json::json!({"slug": "newsletter", "name": "Another newsletter"}))
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 409);
+}
+
+// POST /admin/lists 入力が不正な場合は400を返す
+#[tokio::test]
+async fn creating_a_list_with_invalid_data_returns_a_400() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({"slug": "Product Updates", "name": "Product updates"}),
+            "invalid slug",
+        ),
+        (
+            serde_json::json!({"slug": "product-updates", "name": " "}),
+            "empty name",
+        ),
+        (
+            serde_json::json!({"slug": "product-updates"}),
+            "missing name",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // [Act]
+        let response = app.post_lists(body).await;
+
+        // [Assert]
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload was {}.",
+            description
+        );
+    }
+}
+
+// POST /admin/lists 認証情報がない場合は401を返す
+#[tokio::test]
+async fn requests_missing_authorization_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/lists", &app.address))
+        .json(&serde_json::json!({"slug": "events", "name": "Events"}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // [Assert]
+    assert_eq!(401, response.status().as_u16());
+    assert_eq!(
+        r#"Basic realm="admin""#,
+        response.headers()["WWW-Authenticate"]
+    );
+}
@@ -0,0 +1,122 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// プレビューとテスト送信のリクエストのボディを組み立てる
+fn issue_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Weekly",
+        "content": {
+            "markdown": "Read [the docs](https://example.com/docs).",
+        },
+    })
+}
+
+// POST /admin/newsletters/preview 指定した購読者に届くメールを返し、送信はしない
+#[tokio::test]
+async fn preview_returns_the_email_rendered_for_the_chosen_subscriber() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    let mut body = issue_body();
+    body["subscriber_email"] = "ursula_le_guin@gmail.com".into();
+
+    // [Act]
+    let response = app.post_admin("/admin/newsletters/preview", body).await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    let preview: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(preview["subject"], "Weekly");
+    let html = preview["html"].as_str().unwrap();
+    assert!(html.contains("Hi le guin,"));
+    assert!(html.contains(r#"<a href="https://example.com/docs""#));
+    let text = preview["text"].as_str().unwrap();
+    assert!(text.starts_with("Hi le guin,\n\nWeekly\n\nRead the docs [1]."));
+    assert!(text.contains("/subscriptions/unsubscribe?unsubscribe_token="));
+}
+
+// POST /admin/newsletters/preview 購読者が見つからない場合は404を返す
+#[tokio::test]
+async fn preview_returns_a_404_for_an_unknown_subscriber() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let mut body = issue_body();
+    body["subscriber_email"] = "nobody@example.com".into();
+
+    // [Act]
+    let response = app.post_admin("/admin/newsletters/preview", body).await;
+
+    // [Assert]
+    assert_eq!(404, response.status().as_u16());
+}
+
+// POST /admin/newsletters/test-send 設定された宛先にだけ[TEST]付きで送信する
+#[tokio::test]
+async fn test_send_delivers_only_to_the_configured_test_recipients() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_admin("/admin/newsletters/test-send", issue_body())
+        .await;
+    // 配信キューには何も登録されない
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "editors@example.com");
+    assert_eq!(body["Subject"], "[TEST] Weekly");
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .starts_with("Hi Editors,"));
+}
+
+// 認証情報がない場合は401を返す
+#[tokio::test]
+async fn preview_and_test_send_require_authentication() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    for path in ["/admin/newsletters/preview", "/admin/newsletters/test-send"] {
+        // [Act]
+        let response = reqwest::Client::new()
+            .post(format!("{}{}", &app.address, path))
+            .json(&issue_body())
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // [Assert]
+        assert_eq!(
+            401,
+            response.status().as_u16(),
+            "{} was not protected",
+            path
+        );
+    }
+}
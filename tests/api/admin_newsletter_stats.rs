@@ -0,0 +1,193 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{body_partial_json, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// 確認済みの購読者を2人作り、計測付きの号を配信キューに登録してIDを返す
+async fn publish_tracked_issue(app: &TestApp) -> Uuid {
+    create_confirmed_subscriber(app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    create_confirmed_subscriber(app, "name=octavia&email=octavia%40example.com").await;
+    let body: serde_json::Value = app
+        .post_newsletters(serde_json::json!({
+            "title": "Weekly",
+            "content": { "markdown": "Read [the docs](https://example.com/docs)." },
+            "lists": ["newsletter"],
+            "tracking": { "opens": true, "clicks": true }
+        }))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    body["id"].as_str().unwrap().parse().unwrap()
+}
+
+async fn subscriber_id(app: &TestApp, email: &str) -> Uuid {
+    sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id
+}
+
+/// 計測用のURLにアクセスする (テスト用のポートを設定し、リダイレクトは追わない)
+async fn visit(app: &TestApp, url: String) {
+    let mut url = reqwest::Url::parse(&url).unwrap();
+    url.set_port(Some(app.port)).unwrap();
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(url)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().as_u16() < 400);
+}
+
+// GET /admin/newsletters/{id}/stats 配信結果と開封・クリックを集計する
+#[tokio::test]
+async fn stats_summarise_deliveries_and_engagement() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let issue_id = publish_tracked_issue(&app).await;
+    let stats_path = format!("/admin/newsletters/{}/stats", issue_id);
+
+    // 配信前はすべてキューに残っている
+    let stats: serde_json::Value = app.get_admin(&stats_path).await.json().await.unwrap();
+    assert_eq!(stats["deliveries"]["queued"], 2);
+
+    // 1人への送信は失敗させる
+    Mock::given(path("/email"))
+        .and(body_partial_json(
+            serde_json::json!({ "To": "octavia@example.com" }),
+        ))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // 1人が2回開封して1回クリックし、もう1人が1回クリックする
+    let ursula = subscriber_id(&app, "ursula_le_guin@gmail.com").await;
+    let octavia = subscriber_id(&app, "octavia@example.com").await;
+    let links = &app.tracking_links;
+    visit(&app, links.open_url(issue_id, ursula)).await;
+    visit(&app, links.open_url(issue_id, ursula)).await;
+    visit(
+        &app,
+        links.click_url(issue_id, ursula, "https://example.com/docs"),
+    )
+    .await;
+    visit(
+        &app,
+        links.click_url(issue_id, octavia, "https://example.com/docs"),
+    )
+    .await;
+    visit(
+        &app,
+        links.click_url(issue_id, octavia, "https://example.com/blog"),
+    )
+    .await;
+
+    // [Act]
+    let response = app.get_admin(&stats_path).await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let stats: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(stats["issue"]["title"], "Weekly");
+    assert_eq!(
+        stats["deliveries"],
+        serde_json::json!({ "queued": 0, "sent": 1, "failed": 1, "bounced": 0, "suppressed": 0 })
+    );
+    assert_eq!(stats["opens"]["unique"], 1);
+    assert_eq!(stats["opens"]["total"], 2);
+    assert_eq!(stats["clicks"]["unique"], 2);
+    assert_eq!(stats["clicks"]["total"], 3);
+    // 時間ごとの推移の合計はユニーク数に一致する
+    let timeline_total: i64 = stats["clicks"]["timeline"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bucket| bucket["unique"].as_i64().unwrap())
+        .sum();
+    assert_eq!(timeline_total, 2);
+    assert_eq!(
+        stats["top_links"],
+        serde_json::json!([
+            { "url": "https://example.com/docs", "unique": 2, "total": 2 },
+            { "url": "https://example.com/blog", "unique": 1, "total": 1 }
+        ])
+    );
+}
+
+// ブラウザからのリクエストにはHTMLで返す
+#[tokio::test]
+async fn stats_are_rendered_as_html_for_browsers() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let issue_id = publish_tracked_issue(&app).await;
+
+    // [Act]
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/admin/newsletters/{}/stats",
+            app.address, issue_id
+        ))
+        .basic_auth(&app.admin_username, Some(&app.admin_password))
+        .header("Accept", "text/html,application/xhtml+xml")
+        .send()
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<h1>Weekly</h1>"));
+    assert!(html.contains("<tr><th>Queued</th><td>2</td></tr>"));
+}
+
+// 存在しない号は404を返す
+#[tokio::test]
+async fn stats_for_an_unknown_issue_are_not_found() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = app
+        .get_admin(&format!("/admin/newsletters/{}/stats", Uuid::new_v4()))
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+// 認証情報がない場合は401を返す
+#[tokio::test]
+async fn stats_require_admin_credentials() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let issue_id = publish_tracked_issue(&app).await;
+
+    // [Act]
+    let response = reqwest::get(format!(
+        "{}/admin/newsletters/{}/stats",
+        app.address, issue_id
+    ))
+    .await
+    .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 401);
+}
@@ -0,0 +1,135 @@
+use crate::helpers::TestApp;
+use crate::helpers::{create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// プラン・タグの異なる購読者を登録する
+async fn create_audience(app: &TestApp) {
+    create_confirmed_subscriber(app, "name=Alice&email=alice%40example.com&plan=pro").await;
+    create_confirmed_subscriber(app, "name=Bob&email=bob%40example.com&plan=pro").await;
+    create_confirmed_subscriber(app, "name=Carol&email=carol%40example.com&plan=free").await;
+    // 未確認の購読者はどのセグメントにも含まれない
+    create_unconfirmed_subscriber(app, "name=Dave&email=dave%40example.com&plan=pro").await;
+    app.post_admin(
+        "/admin/subscribers/tag",
+        serde_json::json!({
+            "tag": "beta",
+            "emails": ["bob@example.com", "carol@example.com", "dave@example.com"]
+        }),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+}
+
+// POST /admin/segments/preview 一致する購読者の人数とサンプルを返す
+#[tokio::test]
+async fn preview_returns_the_count_and_a_sample_of_matching_subscribers() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_audience(&app).await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            "/admin/segments/preview",
+            serde_json::json!({"segment": "confirmed AND tag:beta AND attributes.plan = 'pro'"}),
+        )
+        .await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["count"], 1);
+    assert_eq!(body["sample"][0]["email"], "bob@example.com");
+    assert_eq!(body["sample"][0]["name"], "Bob");
+}
+
+// POST /admin/segments/preview OR・NOT・!= を組み合わせられる
+#[tokio::test]
+async fn preview_supports_or_not_and_inequality() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_audience(&app).await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            "/admin/segments/preview",
+            serde_json::json!({
+                "lists": ["newsletter"],
+                "segment": "NOT tag:beta OR attributes.plan != 'pro'"
+            }),
+        )
+        .await;
+
+    // [Assert]
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["count"], 2);
+    let emails: Vec<_> = body["sample"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["email"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(emails, vec!["alice@example.com", "carol@example.com"]);
+}
+
+// POST /admin/segments/preview 不正な式は理由とともに400を返す
+#[tokio::test]
+async fn preview_returns_a_400_with_the_reason_for_an_invalid_segment() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            "/admin/segments/preview",
+            serde_json::json!({"segment": "confirmed AND"}),
+        )
+        .await;
+
+    // [Assert]
+    assert_eq!(400, response.status().as_u16());
+    assert!(!response.text().await.unwrap().is_empty());
+}
+
+// POST /admin/newsletters セグメントに一致する購読者にだけ配信する
+#[tokio::test]
+async fn newsletters_are_delivered_only_to_the_matching_segment() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_audience(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            },
+            "segment": "tag:beta AND attributes.plan = 'pro'"
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(202, response.status().as_u16());
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "bob@example.com");
+}
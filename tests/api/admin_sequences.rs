@@ -0,0 +1,198 @@
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, TestApp,
+};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// 0日目、3日目、7日目に送るウェルカムシリーズを既定のリストに作成する
+async fn create_welcome_sequence(app: &TestApp) {
+    let response = app
+        .post_admin(
+            "/admin/sequences",
+            serde_json::json!({
+                "list": "newsletter",
+                "name": "Welcome",
+                "steps": [
+                    { "delay_days": 0, "title": "Welcome!", "content": { "markdown": "Hello" } },
+                    { "delay_days": 3, "title": "Getting started", "content": { "markdown": "Tips" } },
+                    { "delay_days": 7, "title": "Our best issues", "content": { "markdown": "Reads" } }
+                ]
+            }),
+        )
+        .await;
+    assert_eq!(response.status().as_u16(), 201);
+}
+
+/// 登録を指定した日数だけ過去にずらす
+async fn advance_days(app: &TestApp, days: i32) {
+    sqlx::query!(
+        "UPDATE sequence_enrollments \
+        SET enrolled_at = enrolled_at - make_interval(days => $1), \
+            next_due_at = next_due_at - make_interval(days => $1)",
+        days
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+/// シーケンスのステップを送信し、送ったメールの件名を返す
+async fn send_due_steps(app: &TestApp) -> Vec<String> {
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.send_due_sequence_steps().await;
+    mock_guard
+        .received_requests()
+        .await
+        .into_iter()
+        .map(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            body["Subject"].as_str().unwrap().to_string()
+        })
+        .collect()
+}
+
+async fn enrollment_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM sequence_enrollments")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+// 購読を確認した購読者に、設定した日数ごとにステップを1通ずつ送る
+#[tokio::test]
+async fn confirmed_subscribers_receive_each_step_when_it_is_due() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    // [Act]
+    let day_0 = send_due_steps(&app).await;
+    let day_0_again = send_due_steps(&app).await;
+    advance_days(&app, 3).await;
+    let day_3 = send_due_steps(&app).await;
+    advance_days(&app, 4).await;
+    let day_7 = send_due_steps(&app).await;
+
+    // [Assert]
+    assert_eq!(day_0, vec!["Welcome!"]);
+    assert!(day_0_again.is_empty());
+    assert_eq!(day_3, vec!["Getting started"]);
+    assert_eq!(day_7, vec!["Our best issues"]);
+    assert_eq!(enrollment_status(&app).await, "completed");
+}
+
+// 確認していない購読者はシーケンスに登録しない
+#[tokio::test]
+async fn unconfirmed_subscribers_are_not_enrolled() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    // [Act]
+    let sent = send_due_steps(&app).await;
+
+    // [Assert]
+    assert!(sent.is_empty());
+}
+
+// 配信停止した購読者はシーケンスを抜け、残りのステップを受け取らない
+#[tokio::test]
+async fn unsubscribing_exits_the_sequence() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    send_due_steps(&app).await;
+    let unsubscribe_token = sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .unsubscribe_token;
+
+    // [Act]
+    reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+        app.address, unsubscribe_token
+    ))
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+    advance_days(&app, 7).await;
+    let sent = send_due_steps(&app).await;
+
+    // [Assert]
+    assert!(sent.is_empty());
+    assert_eq!(enrollment_status(&app).await, "exited");
+}
+
+// 購読者が他の経路 (バウンスなど) でリストから外れた場合も、送る時点でシーケンスを抜ける
+#[tokio::test]
+async fn subscribers_who_left_the_list_exit_when_the_next_step_is_due() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_welcome_sequence(&app).await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    send_due_steps(&app).await;
+    sqlx::query!("UPDATE list_memberships SET status = 'bounced'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // [Act]
+    advance_days(&app, 3).await;
+    let sent = send_due_steps(&app).await;
+
+    // [Assert]
+    assert!(sent.is_empty());
+    assert_eq!(enrollment_status(&app).await, "exited");
+}
+
+// POST /admin/sequences 不正な定義は400を返す
+#[tokio::test]
+async fn invalid_sequences_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let step = |delay_days: i32| serde_json::json!({ "delay_days": delay_days, "title": "Hi", "content": { "markdown": "x" } });
+    let test_cases = vec![
+        (
+            serde_json::json!({ "list": "newsletter", "name": "Welcome", "steps": [] }),
+            "no steps",
+        ),
+        (
+            serde_json::json!({ "list": "newsletter", "name": "Welcome", "steps": [step(3), step(0)] }),
+            "steps out of order",
+        ),
+        (
+            serde_json::json!({ "list": "newsletter", "name": "Welcome", "steps": [step(-1)] }),
+            "a negative delay",
+        ),
+        (
+            serde_json::json!({ "list": "no-such-list", "name": "Welcome", "steps": [step(0)] }),
+            "an unknown list",
+        ),
+        (
+            serde_json::json!({ "list": "newsletter", "name": " ", "steps": [step(0)] }),
+            "an empty name",
+        ),
+    ];
+    for (body, description) in test_cases {
+        // [Act]
+        let response = app.post_admin("/admin/sequences", body).await;
+
+        // [Assert]
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject a sequence with {}.",
+            description
+        );
+    }
+}
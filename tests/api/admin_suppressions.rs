@@ -0,0 +1,217 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+async fn suppress(app: &TestApp, email: &str) -> reqwest::Response {
+    app.post_admin("/admin/suppressions", serde_json::json!({ "email": email }))
+        .await
+}
+
+async fn suppression(app: &TestApp, email: &str) -> Option<(String, String)> {
+    sqlx::query!(
+        "SELECT reason, source FROM suppressions WHERE email = $1",
+        email
+    )
+    .fetch_optional(&app.db_pool)
+    .await
+    .unwrap()
+    .map(|r| (r.reason, r.source))
+}
+
+async fn delivery_statuses(app: &TestApp) -> Vec<String> {
+    sqlx::query!(
+        "SELECT status FROM issue_deliveries WHERE subscriber_email = $1",
+        EMAIL
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| r.status)
+    .collect()
+}
+
+// POST /admin/suppressions アドレスを小文字にして抑止リストに追加する
+#[tokio::test]
+async fn admins_can_suppress_an_address() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let created = suppress(&app, "Ursula_Le_Guin@gmail.com").await;
+    let again = suppress(&app, EMAIL).await;
+
+    // [Assert]
+    assert_eq!(created.status().as_u16(), 201);
+    // 登録済みであれば200を返す
+    assert_eq!(again.status().as_u16(), 200);
+    assert_eq!(
+        suppression(&app, EMAIL).await,
+        Some(("manual".into(), "admin".into()))
+    );
+}
+
+// DELETE /admin/suppressions/{email} 抑止リストから外す。載っていなければ404を返す
+#[tokio::test]
+async fn admins_can_remove_a_suppression() {
+    // [Arrange]
+    let app = spawn_app().await;
+    suppress(&app, EMAIL).await;
+
+    // [Act]
+    let removed = app
+        .delete_admin("/admin/suppressions/Ursula_Le_Guin@gmail.com")
+        .await;
+    let missing = app
+        .delete_admin(&format!("/admin/suppressions/{}", EMAIL))
+        .await;
+
+    // [Assert]
+    assert_eq!(removed.status().as_u16(), 204);
+    assert_eq!(missing.status().as_u16(), 404);
+    assert_eq!(suppression(&app, EMAIL).await, None);
+}
+
+// 不正なアドレスと認証されていないリクエストは拒否する
+#[tokio::test]
+async fn invalid_addresses_and_unauthenticated_requests_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let invalid = suppress(&app, "not-an-email").await;
+    let unauthenticated = reqwest::Client::new()
+        .post(format!("{}/admin/suppressions", &app.address))
+        .json(&serde_json::json!({ "email": EMAIL }))
+        .send()
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(invalid.status().as_u16(), 400);
+    assert_eq!(unauthenticated.status().as_u16(), 401);
+    assert_eq!(suppression(&app, EMAIL).await, None);
+}
+
+// POST /subscriptions 抑止されたアドレスからの購読は200を返すが、登録もメール送信もしない
+#[tokio::test]
+async fn subscribing_a_suppressed_address_is_quietly_ignored() {
+    // [Arrange]
+    let app = spawn_app().await;
+    suppress(&app, EMAIL).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", EMAIL)
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+// 抑止された購読者は号の配信キューに登録しない
+#[tokio::test]
+async fn suppressed_subscribers_are_not_enqueued() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    suppress(&app, EMAIL).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.publish_issue("Weekly", "Hello").await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert!(delivery_statuses(&app).await.is_empty());
+}
+
+// 配信キューへの登録後に抑止されたアドレスには送信せず、suppressedとして記録する
+#[tokio::test]
+async fn queued_deliveries_to_suppressed_addresses_are_skipped() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    app.publish_issue("Weekly", "Hello").await;
+    suppress(&app, EMAIL).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(delivery_statuses(&app).await, vec!["suppressed"]);
+}
+
+// POST /admin/newsletters/test-send 抑止されたテスト送信の宛先には送信しない
+#[tokio::test]
+async fn test_sends_to_suppressed_recipients_are_not_sent() {
+    // [Arrange]
+    let app = spawn_app().await;
+    suppress(&app, "editors@example.com").await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            "/admin/newsletters/test-send",
+            serde_json::json!({
+                "title": "Weekly",
+                "content": { "markdown": "Hello" },
+            }),
+        )
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["sent"], 0);
+    assert_eq!(body["suppressed"], 1);
+}
+
+// POST /subscriptions/email_change 抑止された現在のアドレスには承認のメールを送らない
+#[tokio::test]
+async fn email_change_requests_are_not_sent_to_suppressed_addresses() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    suppress(&app, EMAIL).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_email_change("email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com".into())
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+}
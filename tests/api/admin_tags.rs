@@ -0,0 +1,132 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
+
+/// 購読者の付いているタグを取得する
+async fn tags_of(app: &crate::helpers::TestApp, email: &str) -> Vec<String> {
+    sqlx::query!(
+        r#"
+        SELECT t.tag
+        FROM subscriber_tags t JOIN subscriptions s ON s.id = t.subscriber_id
+        WHERE s.email = $1
+        ORDER BY t.tag
+        "#,
+        email
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| r.tag)
+    .collect()
+}
+
+// POST /admin/subscribers/tag 指定した購読者にまとめてタグを付ける
+#[tokio::test]
+async fn admins_can_tag_subscribers_in_bulk() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    create_confirmed_subscriber(&app, "name=Ursula&email=ursula%40example.com").await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            "/admin/subscribers/tag",
+            serde_json::json!({
+                "tag": "beta",
+                "emails": ["ursula_le_guin@gmail.com", "ursula@example.com", "nobody@example.com"]
+            }),
+        )
+        .await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    // 登録されていないアドレスは数えない
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["affected"], 2);
+    assert_eq!(
+        tags_of(&app, "ursula_le_guin@gmail.com").await,
+        vec!["beta"]
+    );
+    assert_eq!(tags_of(&app, "ursula@example.com").await, vec!["beta"]);
+}
+
+// POST /admin/subscribers/tag 同じタグを2回付けても重複しない
+#[tokio::test]
+async fn tagging_is_idempotent() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    let body = serde_json::json!({"tag": "beta", "emails": ["ursula_le_guin@gmail.com"]});
+    app.post_admin("/admin/subscribers/tag", body.clone()).await;
+
+    // [Act]
+    let response = app.post_admin("/admin/subscribers/tag", body).await;
+
+    // [Assert]
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["affected"], 0);
+    assert_eq!(
+        tags_of(&app, "ursula_le_guin@gmail.com").await,
+        vec!["beta"]
+    );
+}
+
+// POST /admin/subscribers/untag 指定した購読者からまとめてタグを外す
+#[tokio::test]
+async fn admins_can_untag_subscribers_in_bulk() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    create_confirmed_subscriber(&app, "name=Ursula&email=ursula%40example.com").await;
+    app.post_admin(
+        "/admin/subscribers/tag",
+        serde_json::json!({
+            "tag": "beta",
+            "emails": ["ursula_le_guin@gmail.com", "ursula@example.com"]
+        }),
+    )
+    .await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            "/admin/subscribers/untag",
+            serde_json::json!({"tag": "beta", "emails": ["ursula@example.com"]}),
+        )
+        .await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["affected"], 1);
+    assert_eq!(
+        tags_of(&app, "ursula_le_guin@gmail.com").await,
+        vec!["beta"]
+    );
+    assert!(tags_of(&app, "ursula@example.com").await.is_empty());
+}
+
+// 不正なタグや認証情報のないリクエストは拒否する
+#[tokio::test]
+async fn invalid_tags_and_unauthenticated_requests_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let invalid_tag = app
+        .post_admin(
+            "/admin/subscribers/tag",
+            serde_json::json!({"tag": "Beta Testers", "emails": []}),
+        )
+        .await;
+    let unauthenticated = reqwest::Client::new()
+        .post(format!("{}/admin/subscribers/tag", &app.address))
+        .json(&serde_json::json!({"tag": "beta", "emails": []}))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // [Assert]
+    assert_eq!(400, invalid_tag.status().as_u16());
+    assert_eq!(401, unauthenticated.status().as_u16());
+}
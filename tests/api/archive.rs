@@ -0,0 +1,216 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
+use chrono::{Duration, Utc};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// GET /archive 配信済みの号を一覧表示し、予約中の号は表示しない
+#[tokio::test]
+async fn the_archive_lists_published_issues_only() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.publish_issue("Weekly Update", "Hello").await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Not yet",
+        "content": { "markdown": "Soon" },
+        "lists": ["newsletter"],
+        "scheduled_for": Utc::now() + Duration::hours(1)
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // [Act]
+    let response = reqwest::get(format!("{}/archive", app.address))
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"<a href="/archive/weekly-update">Weekly Update</a>"#));
+    assert!(!html.contains("Not yet"));
+}
+
+// GET /archive/{slug} 配信済みの号の本文を表示する
+#[tokio::test]
+async fn an_archived_issue_renders_its_html_body() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let issue = app.publish_issue("Weekly Update", "Read *this*.").await;
+    assert_eq!(issue["slug"], "weekly-update");
+
+    // [Act]
+    let response = reqwest::get(format!("{}/archive/weekly-update", app.address))
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<h1>Weekly Update</h1>"));
+    assert!(html.contains("<p>Read <em>this</em>.</p>"));
+}
+
+// GET /archive/{slug} HTMLで指定した本文もサニタイズしてから保存し、公開ページに載せる
+#[tokio::test]
+async fn raw_html_content_is_sanitized_before_it_is_stored_and_archived() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Weekly Update",
+        "content": {
+            "html": r#"<p onclick="steal()">Hello</p><script>steal()</script>"#,
+            "text": "Hello"
+        },
+        "lists": ["newsletter"]
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // [Act]
+    let response = reqwest::get(format!("{}/archive/weekly-update", app.address))
+        .await
+        .unwrap();
+
+    // [Assert]
+    let saved = sqlx::query!("SELECT html_content FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.html_content, "<p>Hello</p>");
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<p>Hello</p>"));
+    assert!(!html.contains("steal()"));
+}
+
+// GET /archive/{slug} サニタイズせずに保存されていた号も、公開ページではサニタイズする
+#[tokio::test]
+async fn stored_html_is_sanitized_when_the_archive_renders_it() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.publish_issue("Weekly Update", "Hello").await;
+    sqlx::query(
+        "UPDATE newsletter_issues SET html_content = '<p>Hello</p><script>steal()</script>'",
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // [Act]
+    let response = reqwest::get(format!("{}/archive/weekly-update", app.address))
+        .await
+        .unwrap();
+
+    // [Assert]
+    let html = response.text().await.unwrap();
+    assert!(html.contains("<p>Hello</p>"));
+    assert!(!html.contains("<script>"));
+}
+
+// GET /archive/{slug} 存在しない号や予約中の号は404を返す
+#[tokio::test]
+async fn unknown_or_unpublished_issues_are_not_found() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let scheduled: serde_json::Value = app
+        .post_newsletters(serde_json::json!({
+            "title": "Not yet",
+            "content": { "markdown": "Soon" },
+            "lists": ["newsletter"],
+            "scheduled_for": Utc::now() + Duration::hours(1)
+        }))
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    for slug in ["unknown", scheduled["slug"].as_str().unwrap()] {
+        // [Act]
+        let response = reqwest::get(format!("{}/archive/{}", app.address, slug))
+            .await
+            .unwrap();
+
+        // [Assert]
+        assert_eq!(response.status().as_u16(), 404, "{} was found", slug);
+    }
+}
+
+// POST /admin/newsletters 同じタイトルの号には連番付きのスラッグを付ける
+#[tokio::test]
+async fn issues_with_the_same_title_get_distinct_slugs() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let first = app.publish_issue("Weekly", "One").await;
+    let second = app.publish_issue("Weekly", "Two").await;
+
+    // [Assert]
+    assert_eq!(first["slug"], "weekly");
+    assert_eq!(second["slug"], "weekly-2");
+}
+
+// GET /archive 1ページに収まらない号はページ送りで表示する
+#[tokio::test]
+async fn the_archive_is_paginated() {
+    // [Arrange]
+    let app = spawn_app().await;
+    for i in 1..=21 {
+        app.publish_issue(&format!("Issue {}", i), "Body").await;
+    }
+    let get_page = |page: u32| {
+        let url = format!("{}/archive?page={}", app.address, page);
+        async move { reqwest::get(url).await.unwrap() }
+    };
+
+    // [Act]
+    let first_page = get_page(1).await.text().await.unwrap();
+    let second_page = get_page(2).await.text().await.unwrap();
+    let third_page = get_page(3).await;
+
+    // [Assert]
+    assert_eq!(first_page.matches("<li>").count(), 20);
+    assert!(first_page.contains(r#"href="/archive?page=2" rel="next""#));
+    assert!(!first_page.contains(r#"rel="prev""#));
+    assert_eq!(second_page.matches("<li>").count(), 1);
+    assert!(second_page.contains(r#"href="/archive?page=1" rel="prev""#));
+    assert!(!second_page.contains(r#"rel="next""#));
+    assert_eq!(third_page.status().as_u16(), 404);
+}
+
+// 配信するメールにはアーカイブの号へのリンクを入れる
+#[tokio::test]
+async fn emails_link_to_the_archived_issue() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.publish_issue("Weekly Update", "Hello").await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let links = app.get_links_to(&email_request, "/archive/weekly-update");
+    assert_eq!(links.html, links.plain_text);
+    let response = reqwest::get(links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
@@ -0,0 +1,85 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
+use web_prod::configuration::CircuitBreakerSettings;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+// 送信サービスの障害が続くと回路が開き、ヘルスチェックとメトリクスに表示される
+#[tokio::test]
+async fn the_open_circuit_is_reported_by_health_check_and_metrics() {
+    // [Arrange]
+    let app = spawn_app().await;
+    // 既定では5回続けて失敗すると回路を開く
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(5)
+        .mount(&app.email_server)
+        .await;
+    for i in 0..6 {
+        let response = app
+            .post_subscriptions(format!("name=reader{}&email=reader{}%40example.com", i, i))
+            .await;
+        assert_eq!(response.status().as_u16(), 500);
+    }
+
+    // [Act]
+    let health: serde_json::Value = reqwest::get(format!("{}/health_check", &app.address))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let metrics = reqwest::get(format!("{}/metrics", &app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(health["email_providers"][0]["circuit"], "open");
+    assert!(metrics.contains("email_provider_circuit_state{provider=\"postmark\"} 2"));
+    assert!(metrics.contains("email_provider_circuit_opened_total{provider=\"postmark\"} 1"));
+    assert!(metrics.contains("email_provider_circuit_rejected_total{provider=\"postmark\"} 1"));
+}
+
+// 回路が開いている間は、配信タスクを失敗にせずキューに残す
+#[tokio::test]
+async fn deliveries_stay_queued_while_the_circuit_is_open() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    for i in 0..3 {
+        create_confirmed_subscriber(
+            &app,
+            &format!("name=reader{}&email=reader{}%40example.com", i, i),
+        )
+        .await;
+    }
+    app.publish_issue("Weekly", "Hello").await;
+    app.email_client = app
+        .email_client
+        .clone()
+        .with_circuit_breaker(&CircuitBreakerSettings {
+            failure_threshold: 1,
+            open_seconds: 60,
+        });
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    // [Act]
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    let statuses: Vec<String> = sqlx::query!("SELECT status FROM issue_deliveries ORDER BY status")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.status)
+        .collect();
+    assert_eq!(statuses, vec!["failed", "queued", "queued"]);
+}
@@ -0,0 +1,154 @@
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, TestApp,
+};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// リストへの所属をリマインダーを送る時間より前に申し込んだことにする
+async fn make_memberships_due(app: &TestApp) {
+    let joined_at =
+        chrono::Utc::now() - app.confirmation.reminder_after() - chrono::Duration::minutes(1);
+    sqlx::query!("UPDATE list_memberships SET joined_at = $1", joined_at)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+// 確認されないまま時間が過ぎた購読者に一度だけリマインダーを送る
+#[tokio::test]
+async fn pending_subscribers_get_exactly_one_reminder() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    make_memberships_due(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.send_confirmation_reminders().await;
+    app.send_confirmation_reminders().await;
+
+    // [Assert]
+    // 最初の確認メールの後に送られたリマインダー
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["Subject"], "Please confirm your subscription");
+    let reminder_sent_at = sqlx::query!("SELECT reminder_sent_at FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .reminder_sent_at;
+    assert!(reminder_sent_at.is_some());
+}
+
+// リマインダーの確認リンクは元のリンクが期限切れでも使える
+#[tokio::test]
+async fn the_reminder_link_confirms_the_subscription() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    make_memberships_due(&app).await;
+    let expired_at =
+        chrono::Utc::now() - app.confirmation.token_lifetime() - chrono::Duration::minutes(1);
+    sqlx::query!("UPDATE subscription_tokens SET created_at = $1", expired_at)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.send_confirmation_reminders().await;
+    // 最初の確認メールの後に送られたリマインダー
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let confirmation_links = app.get_confirmation_links(&email_request);
+
+    // [Act]
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let membership = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(membership.status, "confirmed");
+}
+
+// 最近申し込んだ購読者、確認済みの購読者、抑止されたアドレスにはリマインダーを送らない
+#[tokio::test]
+async fn reminders_are_not_sent_to_recent_confirmed_or_suppressed_subscribers() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=confirmed&email=confirmed%40example.com").await;
+    create_unconfirmed_subscriber(&app, "name=suppressed&email=suppressed%40example.com").await;
+    make_memberships_due(&app).await;
+    create_unconfirmed_subscriber(&app, "name=recent&email=recent%40example.com").await;
+    app.post_admin(
+        "/admin/suppressions",
+        serde_json::json!({ "email": "suppressed@example.com" }),
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.send_confirmation_reminders().await;
+
+    // [Assert]
+    // Mockのexpect(0)で検証する
+}
+
+// リマインダーを送ると、最初の確認メールのリンクは使えなくなる
+#[tokio::test]
+async fn the_reminder_replaces_the_original_confirmation_link() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let original_links =
+        create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .await;
+    make_memberships_due(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.send_confirmation_reminders().await;
+
+    // [Act]
+    let response = reqwest::get(original_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 401);
+    let tokens = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscription_tokens"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count;
+    assert_eq!(tokens, 1);
+}
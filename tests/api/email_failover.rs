@@ -0,0 +1,51 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app};
+use secrecy::Secret;
+use web_prod::configuration::RateLimitSettings;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+// Postmarkが500を返し続けても、予備の送信サービスですべての配信を送る
+#[tokio::test]
+async fn deliveries_fail_over_to_the_fallback_provider() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    for i in 0..3 {
+        create_confirmed_subscriber(
+            &app,
+            &format!("name=reader{}&email=reader{}%40example.com", i, i),
+        )
+        .await;
+    }
+    app.publish_issue("Weekly", "Hello").await;
+    let fallback_server = MockServer::start().await;
+    app.email_client = app.email_client.clone().with_fallback_provider(
+        "fallback".into(),
+        fallback_server.uri(),
+        Secret::new("fallback-token".into()),
+        &RateLimitSettings::default(),
+    );
+    let _primary_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(3)
+        .mount(&fallback_server)
+        .await;
+
+    // [Act]
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    let statuses: Vec<String> = sqlx::query!("SELECT status FROM issue_deliveries")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.status)
+        .collect();
+    assert_eq!(statuses, vec!["sent", "sent", "sent"]);
+}
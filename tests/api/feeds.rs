@@ -0,0 +1,292 @@
+use crate::helpers::{spawn_app, TestApp};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+
+const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+
+async fn get_feed(app: &TestApp, path: &str) -> reqwest::Response {
+    reqwest::get(format!("{}{}", app.address, path))
+        .await
+        .unwrap()
+}
+
+// 絶対URLであることを確認する
+fn assert_absolute_url(url: &str) {
+    let url = reqwest::Url::parse(url).unwrap_or_else(|_| panic!("{} is not an absolute URL", url));
+    assert!(url.scheme() == "http" || url.scheme() == "https");
+}
+
+fn child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    name: &str,
+) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children()
+        .find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> &'a str {
+    child(node, name)
+        .and_then(|n| n.text())
+        .unwrap_or_else(|| panic!("<{}> is missing <{}>", node.tag_name().name(), name))
+}
+
+/// RSS 2.0の仕様で必須の要素と値の形式を確認し、itemを返す
+fn assert_valid_rss(xml: &str) -> Vec<(String, String)> {
+    let document = roxmltree::Document::parse(xml).expect("The feed is not well-formed XML");
+    let rss = document.root_element();
+    assert_eq!(rss.tag_name().name(), "rss");
+    assert_eq!(rss.attribute("version"), Some("2.0"));
+    let channels: Vec<_> = rss.children().filter(|n| n.is_element()).collect();
+    assert_eq!(channels.len(), 1);
+    let channel = channels[0];
+    assert_eq!(channel.tag_name().name(), "channel");
+
+    // channelにはtitle、link、descriptionが必須
+    assert!(!child_text(channel, "title").is_empty());
+    assert_absolute_url(child_text(channel, "link"));
+    child_text(channel, "description");
+    // 日付はRFC 822形式
+    if child(channel, "lastBuildDate").is_some() {
+        DateTime::parse_from_rfc2822(child_text(channel, "lastBuildDate")).unwrap();
+    }
+    // 自身のURLはatom:linkで示す
+    let self_link = channel
+        .children()
+        .find(|n| n.has_tag_name((ATOM_NAMESPACE, "link")))
+        .expect("<channel> is missing <atom:link>");
+    assert_eq!(self_link.attribute("rel"), Some("self"));
+    assert_absolute_url(self_link.attribute("href").unwrap());
+
+    let mut guids = HashSet::new();
+    channel
+        .children()
+        .filter(|n| n.has_tag_name("item"))
+        .map(|item| {
+            // itemにはtitleかdescriptionのどちらかが必須
+            assert!(child(item, "title").is_some() || child(item, "description").is_some());
+            let link = child_text(item, "link");
+            assert_absolute_url(link);
+            let guid = child(item, "guid").expect("<item> is missing <guid>");
+            if guid.attribute("isPermaLink") != Some("false") {
+                assert_absolute_url(guid.text().unwrap());
+            }
+            assert!(guids.insert(guid.text().unwrap().to_string()));
+            DateTime::parse_from_rfc2822(child_text(item, "pubDate")).unwrap();
+            (child_text(item, "title").to_string(), link.to_string())
+        })
+        .collect()
+}
+
+/// Atom 1.0の仕様で必須の要素と値の形式を確認し、entryを返す
+fn assert_valid_atom(xml: &str) -> Vec<(String, String)> {
+    let document = roxmltree::Document::parse(xml).expect("The feed is not well-formed XML");
+    let feed = document.root_element();
+    assert!(feed.has_tag_name((ATOM_NAMESPACE, "feed")));
+
+    // feedにはid、title、updatedが必須
+    assert_absolute_url(child_text(feed, "id"));
+    assert!(!child_text(feed, "title").is_empty());
+    // 日付はRFC 3339形式
+    DateTime::parse_from_rfc3339(child_text(feed, "updated")).unwrap();
+    // entryに著者がなければfeedに著者が必須
+    let author = child(feed, "author").expect("<feed> is missing <author>");
+    child_text(author, "name");
+    let self_link = feed
+        .children()
+        .find(|n| n.has_tag_name((ATOM_NAMESPACE, "link")) && n.attribute("rel") == Some("self"))
+        .expect("<feed> is missing a self link");
+    assert_absolute_url(self_link.attribute("href").unwrap());
+
+    let mut ids = HashSet::new();
+    feed.children()
+        .filter(|n| n.has_tag_name((ATOM_NAMESPACE, "entry")))
+        .map(|entry| {
+            // entryにはid、title、updatedが必須で、idは重複しない
+            let id = child_text(entry, "id");
+            assert_absolute_url(id);
+            assert!(ids.insert(id.to_string()));
+            DateTime::parse_from_rfc3339(child_text(entry, "updated")).unwrap();
+            DateTime::parse_from_rfc3339(child_text(entry, "published")).unwrap();
+            // 本文がなければalternateのリンクが必須
+            let content = child(entry, "content").expect("<entry> is missing <content>");
+            assert_eq!(content.attribute("type"), Some("html"));
+            let link = entry
+                .children()
+                .find(|n| {
+                    n.has_tag_name((ATOM_NAMESPACE, "link"))
+                        && n.attribute("rel") == Some("alternate")
+                })
+                .and_then(|n| n.attribute("href"))
+                .expect("<entry> is missing an alternate link");
+            assert_absolute_url(link);
+            (child_text(entry, "title").to_string(), link.to_string())
+        })
+        .collect()
+}
+
+// GET /feed.rss 配信済みの号を新しい順に絶対URLで載せる
+#[tokio::test]
+async fn the_rss_feed_lists_published_issues_with_absolute_links() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.publish_issue("First issue", "Hello").await;
+    app.publish_issue("Tips & <Tricks>", "World").await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Not yet",
+        "content": { "markdown": "Soon" },
+        "lists": ["newsletter"],
+        "scheduled_for": Utc::now() + Duration::hours(1)
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+
+    // [Act]
+    let response = get_feed(&app, "/feed.rss").await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/rss+xml; charset=utf-8"
+    );
+    let items = assert_valid_rss(&response.text().await.unwrap());
+    assert_eq!(
+        items,
+        vec![
+            (
+                "Tips & <Tricks>".to_string(),
+                format!("{}/archive/tips-tricks", app.base_url)
+            ),
+            (
+                "First issue".to_string(),
+                format!("{}/archive/first-issue", app.base_url)
+            ),
+        ]
+    );
+}
+
+// GET /feed.atom 配信済みの号を新しい順に絶対URLで載せる
+#[tokio::test]
+async fn the_atom_feed_lists_published_issues_with_absolute_links() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.publish_issue("First issue", "Hello").await;
+    app.publish_issue("Tips & <Tricks>", "Read *this*.").await;
+
+    // [Act]
+    let response = get_feed(&app, "/feed.atom").await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "application/atom+xml; charset=utf-8"
+    );
+    let xml = response.text().await.unwrap();
+    let entries = assert_valid_atom(&xml);
+    assert_eq!(
+        entries,
+        vec![
+            (
+                "Tips & <Tricks>".to_string(),
+                format!("{}/archive/tips-tricks", app.base_url)
+            ),
+            (
+                "First issue".to_string(),
+                format!("{}/archive/first-issue", app.base_url)
+            ),
+        ]
+    );
+    // 本文のHTMLはエスケープして入れる
+    assert!(xml.contains("&lt;p&gt;Read &lt;em&gt;this&lt;/em&gt;.&lt;/p&gt;"));
+}
+
+// 号がなくてもフィードは仕様を満たす
+#[tokio::test]
+async fn empty_feeds_are_valid() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let rss = get_feed(&app, "/feed.rss").await.text().await.unwrap();
+    let atom = get_feed(&app, "/feed.atom").await.text().await.unwrap();
+
+    // [Assert]
+    assert!(assert_valid_rss(&rss).is_empty());
+    assert!(assert_valid_atom(&atom).is_empty());
+}
+
+// If-None-Matchが現在のETagと一致すれば304を返し、号が増えれば200を返す
+#[tokio::test]
+async fn feeds_honour_if_none_match() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.publish_issue("First issue", "Hello").await;
+
+    for path in ["/feed.rss", "/feed.atom"] {
+        let response = get_feed(&app, path).await;
+        let etag = response.headers()["ETag"].to_str().unwrap().to_string();
+        let get_with_etag = || {
+            reqwest::Client::new()
+                .get(format!("{}{}", app.address, path))
+                .header("If-None-Match", &etag)
+                .send()
+        };
+
+        // [Act]
+        let not_modified = get_with_etag().await.unwrap();
+
+        // [Assert]
+        assert_eq!(not_modified.status().as_u16(), 304, "{}", path);
+        assert_eq!(not_modified.headers()["ETag"], etag.as_str());
+        assert!(not_modified.text().await.unwrap().is_empty());
+    }
+
+    // [Act]
+    app.publish_issue("Second issue", "World").await;
+    for path in ["/feed.rss", "/feed.atom"] {
+        let response = reqwest::Client::new()
+            .get(format!("{}{}", app.address, path))
+            .header("If-None-Match", r#""stale""#)
+            .send()
+            .await
+            .unwrap();
+
+        // [Assert]
+        assert_eq!(response.status().as_u16(), 200, "{}", path);
+    }
+}
+
+// If-Modified-Sinceが最新の号の配信日時以降なら304を返す
+#[tokio::test]
+async fn feeds_honour_if_modified_since() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.publish_issue("First issue", "Hello").await;
+    let response = get_feed(&app, "/feed.rss").await;
+    let last_modified = response.headers()["Last-Modified"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let get_since = |since: String| {
+        reqwest::Client::new()
+            .get(format!("{}/feed.rss", app.address))
+            .header("If-Modified-Since", since)
+            .send()
+    };
+
+    // [Act]
+    let not_modified = get_since(last_modified.clone()).await.unwrap();
+    let modified = get_since("Mon, 06 Nov 2000 10:15:30 GMT".to_string())
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(not_modified.status().as_u16(), 304);
+    assert_eq!(
+        not_modified.headers()["Last-Modified"],
+        last_modified.as_str()
+    );
+    assert_eq!(modified.status().as_u16(), 200);
+}
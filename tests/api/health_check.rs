@@ -10,12 +10,15 @@ async fn health_check_works() {
     // [Act]
     let response = client
         // Use the returned application address
-        .get(&format!("{}/health_check", &app.address))
+        .get(format!("{}/health_check", &app.address))
         .send()
         .await
         .expect("Failed to execute request.");
 
     // [Assert]
     assert!(response.status().is_success());
-    assert_eq!(Some(0), response.content_length());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["email_providers"][0]["name"], "postmark");
+    assert_eq!(body["email_providers"][0]["circuit"], "closed");
 }
@@ -1,9 +1,28 @@
 use once_cell::sync::Lazy;
+use secrecy::ExposeSecret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use web_prod::configuration::{get_configuration, DatabaseSettings};
+use web_prod::configuration::{
+    get_configuration, ConfirmationSettings, DatabaseSettings, PostmarkWebhookSettings,
+    RetentionSettings, Settings,
+};
+use web_prod::confirmation_reminders::try_send_confirmation_reminder;
+use web_prod::deferred_emails::try_send_deferred_email;
+use web_prod::email_client::EmailClient;
+use web_prod::email_templates::EmailTemplates;
+use web_prod::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use web_prod::retention::{try_apply_retention, RetentionCounters, RetentionReport};
+use web_prod::scheduler::try_enqueue_due_issues;
+use web_prod::sequences::try_send_sequence_step;
 use web_prod::startup::{get_connection_pool, Application};
+use web_prod::subject_tests::try_decide_subject_tests;
 use web_prod::telemetry::{get_subscriber, init_subscriber};
+use web_prod::tracking::TrackingLinks;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+// deliver_issueで送信したメールにPostmarkが付けるMessageID
+pub const MESSAGE_ID: &str = "883953f4-6105-42a2-a16a-77a8eac79483";
 
 // ログ設定を一度だけ初期化する
 static TRACING: Lazy<()> = Lazy::new(|| {
@@ -23,28 +42,338 @@ static TRACING: Lazy<()> = Lazy::new(|| {
 pub struct TestApp {
     // HTTPアドレス
     pub address: String,
+    // アプリケーションのポート番号
+    pub port: u16,
     // データベース接続プール
     pub db_pool: PgPool,
+    // Postmarkの代わりにリクエストを受けるモックサーバ
+    pub email_server: MockServer,
+    // 管理者用APIの認証情報
+    pub admin_username: String,
+    pub admin_password: String,
+    // 配信ワーカーをテストから直接動かすためのメールクライアント
+    pub email_client: EmailClient,
+    pub email_templates: EmailTemplates,
+    // メール内のリンク組立に使うベースURL
+    pub base_url: String,
+    pub tracking_links: TrackingLinks,
+    pub postmark_webhook: PostmarkWebhookSettings,
+    pub retention: RetentionSettings,
+    // サーバと共有する購読者の整理の件数 (メトリクスで返す)
+    pub retention_counters: RetentionCounters,
+    pub confirmation: ConfirmationSettings,
+}
+
+// メール本文に含まれるリンク (HTML版とテキスト版)
+pub struct ConfirmationLinks {
+    pub html: reqwest::Url,
+    pub plain_text: reqwest::Url,
 }
 
 impl TestApp {
+    /// 配信キューが空になるまで (1日の上限に達したか送信サービスの障害中はそこまで) 配信タスクを処理する
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            let outcome = try_execute_task(
+                &self.db_pool,
+                &self.email_client,
+                &self.email_templates,
+                &self.base_url,
+                &self.tracking_links,
+            )
+            .await
+            .unwrap();
+            // 送れるものがなくなるか、送信を止められたら終わる
+            if !matches!(outcome, ExecutionOutcome::TaskCompleted) {
+                break;
+            }
+        }
+    }
+
+    /// 送る日時が来たシーケンスのステップをすべて送信する
+    pub async fn send_due_sequence_steps(&self) {
+        loop {
+            let outcome = try_send_sequence_step(
+                &self.db_pool,
+                &self.email_client,
+                &self.email_templates,
+                &self.base_url,
+            )
+            .await
+            .unwrap();
+            // 送れるものがなくなるか、送信を止められたら終わる
+            if !matches!(outcome, ExecutionOutcome::TaskCompleted) {
+                break;
+            }
+        }
+    }
+
+    /// 送るべき確認のリマインダーをすべて送信する
+    pub async fn send_confirmation_reminders(&self) {
+        loop {
+            let outcome = try_send_confirmation_reminder(
+                &self.db_pool,
+                &self.email_client,
+                &self.email_templates,
+                &self.base_url,
+                &self.confirmation,
+            )
+            .await
+            .unwrap();
+            // 送れるものがなくなるか、送信を止められたら終わる
+            if !matches!(outcome, ExecutionOutcome::TaskCompleted) {
+                break;
+            }
+        }
+    }
+
+    /// 1日の上限に達したために後で送ることにしたメールをすべて送信する
+    pub async fn send_deferred_emails(&self) {
+        loop {
+            let outcome = try_send_deferred_email(&self.db_pool, &self.email_client)
+                .await
+                .unwrap();
+            // 送れるものがなくなるか、送信を止められたら終わる
+            if !matches!(outcome, ExecutionOutcome::TaskCompleted) {
+                break;
+            }
+        }
+    }
+
+    /// 予約日時を過ぎた号を配信キューに登録する
+    pub async fn enqueue_due_issues(&self) -> u64 {
+        try_enqueue_due_issues(&self.db_pool).await.unwrap()
+    }
+
+    /// 購読者の整理を1回実行する
+    pub async fn apply_retention(&self) -> RetentionReport {
+        try_apply_retention(&self.db_pool, &self.retention, &self.retention_counters)
+            .await
+            .unwrap()
+    }
+
+    /// 計測時間が過ぎた件名のA/Bテストを判定する
+    pub async fn decide_subject_tests(&self) -> u64 {
+        try_decide_subject_tests(&self.db_pool).await.unwrap()
+    }
+
     /// /subscriptinsにPOSTリクエストを送信する
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
+
+    /// /admin/newslettersにPOSTリクエストを送信する
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/newsletters", &self.address))
+            .basic_auth(&self.admin_username, Some(&self.admin_password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// 既定のリストに号を配信し、レスポンスのボディを返す
+    pub async fn publish_issue(&self, title: &str, markdown: &str) -> serde_json::Value {
+        self.post_newsletters(serde_json::json!({
+            "title": title,
+            "content": { "markdown": markdown },
+            "lists": ["newsletter"]
+        }))
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap()
+    }
+
+    /// 確認済みの購読者を1人作り、計測の設定を指定して号を配信する
+    /// (PostmarkはMESSAGE_IDをMessageIDとして返す)
+    /// 送信したメールのリクエストのボディを返す
+    pub async fn deliver_issue(&self, tracking: serde_json::Value) -> serde_json::Value {
+        create_confirmed_subscriber(self, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "To": "ursula_le_guin@gmail.com",
+                "SubmittedAt": "2023-11-20T09:05:12.0000000-05:00",
+                "MessageID": MESSAGE_ID,
+                "ErrorCode": 0,
+                "Message": "OK"
+            })))
+            .expect(1)
+            .mount_as_scoped(&self.email_server)
+            .await;
+        self.post_newsletters(serde_json::json!({
+            "title": "Weekly",
+            "content": { "markdown": "Read [the docs](https://example.com/docs?a=1&b=2)." },
+            "lists": ["newsletter"],
+            "tracking": tracking
+        }))
+        .await
+        .error_for_status()
+        .unwrap();
+        self.dispatch_all_pending_emails().await;
+
+        let email_request = self
+            .email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap();
+        serde_json::from_slice(&email_request.body).unwrap()
+    }
+
+    /// 配信キューに残っている配信タスクの数
+    pub async fn queued_deliveries(&self) -> i64 {
+        sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM issue_deliveries WHERE status = 'queued'"#)
+            .fetch_one(&self.db_pool)
+            .await
+            .unwrap()
+            .count
+    }
+
+    /// 購読者のリストへの所属の状態
+    pub async fn membership_status(&self, email: &str) -> String {
+        sqlx::query!(
+            "SELECT m.status FROM list_memberships m JOIN subscriptions s ON s.id = m.subscriber_id \
+            WHERE s.email = $1",
+            email
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .unwrap()
+        .status
+    }
+
+    /// /admin/listsにPOSTリクエストを送信する
+    pub async fn post_lists(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/admin/lists", &self.address))
+            .basic_auth(&self.admin_username, Some(&self.admin_password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// 管理者の認証情報付きで管理者用APIにJSONをPOSTする
+    pub async fn post_admin(&self, path: &str, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}{}", &self.address, path))
+            .basic_auth(&self.admin_username, Some(&self.admin_password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// 管理者の認証情報付きで管理者用APIにGETリクエストを送信する
+    pub async fn get_admin(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .get(format!("{}{}", &self.address, path))
+            .basic_auth(&self.admin_username, Some(&self.admin_password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// 管理者の認証情報付きで管理者用APIにDELETEリクエストを送信する
+    pub async fn delete_admin(&self, path: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .delete(format!("{}{}", &self.address, path))
+            .basic_auth(&self.admin_username, Some(&self.admin_password))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Basic認証付きで/webhooks/postmarkにイベントをPOSTする
+    pub async fn post_postmark_webhook(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &self.address))
+            .basic_auth(
+                &self.postmark_webhook.username,
+                Some(self.postmark_webhook.secret.expose_secret()),
+            )
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// /subscriptions/email_changeにPOSTリクエストを送信する
+    pub async fn post_email_change(&self, body: String) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/email_change", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// モックサーバが受け取ったメール送信リクエストから本文中のリンクを取り出す
+    pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
+        self.get_links_matching(email_request, |_| true)
+    }
+
+    /// メール本文中のリンクのうち、指定したパスへのリンクを取り出す
+    pub fn get_links_to(&self, email_request: &wiremock::Request, path: &str) -> ConfirmationLinks {
+        self.get_links_matching(email_request, |link| link.path() == path)
+    }
+
+    fn get_links_matching(
+        &self,
+        email_request: &wiremock::Request,
+        is_match: impl Fn(&reqwest::Url) -> bool,
+    ) -> ConfirmationLinks {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+
+        // 本文から条件に合うリンクを1つだけ取り出す
+        let get_link = |s: &str| {
+            let links: Vec<_> = linkify::LinkFinder::new()
+                .links(s)
+                .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .filter_map(|l| reqwest::Url::parse(l.as_str()).ok())
+                .filter(|l| is_match(l))
+                .collect();
+            assert_eq!(links.len(), 1);
+            let mut confirmation_link = links[0].clone();
+            // 外部のサーバにリクエストしないようにホストを確認する
+            assert_eq!(confirmation_link.host_str().unwrap(), "127.0.0.1");
+            // テスト用にランダムなポートを設定する
+            confirmation_link.set_port(Some(self.port)).unwrap();
+            confirmation_link
+        };
+
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
+        ConfirmationLinks { html, plain_text }
+    }
 }
 
 /// テスト用のHTTPサーバを起動する
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// テスト用の設定を変更してからアプリケーションを起動する
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     // 最初だけログ設定を初期化する
     Lazy::force(&TRACING);
 
+    // Postmarkの代わりにモックサーバを起動する
+    let email_server = MockServer::start().await;
+
     // テストの際には設定をランダム化して、テスト間の独立性を確保する
     let configuration = {
         let mut c = get_configuration().expect("Failed to read configuration.");
@@ -52,6 +381,11 @@ pub async fn spawn_app() -> TestApp {
         c.database.database_name = Uuid::new_v4().to_string();
         // Use a random OS port
         c.application.port = 0;
+        // Use the mock server as email API
+        c.email_client.base_url = email_server.uri();
+        // バックグラウンドジョブはテストから明示的に動かす
+        c.background_jobs.enabled = false;
+        configure(&mut c);
         c
     };
 
@@ -63,13 +397,35 @@ pub async fn spawn_app() -> TestApp {
         .await
         .expect("Failed to build application.");
     // アプリケーション起動前にアドレスを取得
-    let address = format!("http://127.0.0.1:{}", application.port());
+    let application_port = application.port();
+    let retention_counters = application.retention_counters();
+    let address = format!("http://127.0.0.1:{}", application_port);
     // アプリケーション実行
-    let _ = tokio::spawn(application.run_until_stopped());
+    drop(tokio::spawn(application.run_until_stopped()));
 
+    let db_pool = get_connection_pool(&configuration.database);
     TestApp {
         address,
-        db_pool: get_connection_pool(&configuration.database),
+        port: application_port,
+        db_pool: db_pool.clone(),
+        email_server,
+        admin_username: configuration.admin.username.clone(),
+        admin_password: configuration.admin.password.expose_secret().clone(),
+        email_client: configuration
+            .email_client
+            .clone()
+            .client()
+            .with_db_pool(db_pool),
+        email_templates: EmailTemplates::load(&configuration.templates).unwrap(),
+        base_url: configuration.application.base_url.clone(),
+        postmark_webhook: configuration.email_client.webhook.clone(),
+        retention: configuration.retention.clone(),
+        retention_counters,
+        confirmation: configuration.confirmation.clone(),
+        tracking_links: TrackingLinks::new(
+            configuration.application.base_url.clone(),
+            configuration.application.hmac_secret.clone(),
+        ),
     }
 }
 
@@ -82,7 +438,7 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
 
     // データベースを作成
     connection
-        .execute(&*format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
+        .execute(format!(r#"CREATE DATABASE "{}";"#, config.database_name).as_str())
         .await
         .expect("Failed to create database.");
 
@@ -100,3 +456,38 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
     // データベース接続プールを返す
     connection_pool
 }
+
+/// 購読を申し込み、確認メールに含まれるリンクを返す (確認はしない)
+pub async fn create_unconfirmed_subscriber(app: &TestApp, body: &str) -> ConfirmationLinks {
+    // このスコープの間だけ確認メールの送信を受け付ける
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let email_request = &app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.get_confirmation_links(email_request)
+}
+
+/// 購読を申し込み、確認リンクをクリックして確認済みにする
+pub async fn create_confirmed_subscriber(app: &TestApp, body: &str) {
+    let confirmation_link = create_unconfirmed_subscriber(app, body).await;
+    reqwest::get(confirmation_link.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+}
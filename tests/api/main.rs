@@ -1,3 +1,24 @@
+mod admin_lists;
+mod admin_newsletter_previews;
+mod admin_newsletter_stats;
+mod admin_segments;
+mod admin_sequences;
+mod admin_suppressions;
+mod admin_tags;
+mod archive;
+mod circuit_breaker;
+mod confirmation_reminders;
+mod email_failover;
+mod feeds;
 mod health_check;
 mod helpers;
+mod newsletters;
+mod newsletters_subject_tests;
+mod rate_limit;
+mod retention;
 mod subscriptions;
+mod subscriptions_confirm;
+mod subscriptions_email_change;
+mod subscriptions_unsubscribe;
+mod tracking;
+mod webhooks_postmark;
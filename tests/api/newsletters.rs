@@ -0,0 +1,519 @@
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, TestApp,
+};
+use chrono::{DateTime, Duration, Utc};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// 配信リクエストのボディを組み立てる
+fn newsletter_request_body(lists: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "lists": lists
+    })
+}
+
+/// 予約配信のリクエストのボディを組み立てる
+fn scheduled_request_body(scheduled_for: DateTime<Utc>) -> serde_json::Value {
+    let mut body = newsletter_request_body(serde_json::json!(["newsletter"]));
+    body["scheduled_for"] = serde_json::json!(scheduled_for);
+    body
+}
+
+/// 1時間後に配信する号を予約し、そのIDを返す
+async fn schedule_issue(app: &TestApp) -> String {
+    let response = app
+        .post_newsletters(scheduled_request_body(Utc::now() + Duration::hours(1)))
+        .await;
+    assert_eq!(response.status().as_u16(), 202);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["status"], "scheduled");
+    body["id"].as_str().unwrap().to_owned()
+}
+
+/// 予約日時を過去にずらして配信時刻が来た状態にする
+async fn make_issue_due(app: &TestApp, issue_id: &str) {
+    sqlx::query("UPDATE newsletter_issues SET scheduled_for = now() - interval '1 minute' WHERE id = $1::uuid")
+        .bind(issue_id)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+// POST /admin/newsletters 確認前の購読者には配信しない
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_newsletters(newsletter_request_body(serde_json::json!(["newsletter"])))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+// ニュースレターはbroadcastのメッセージストリームで送る
+#[tokio::test]
+async fn newsletters_are_sent_through_the_broadcast_stream() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.post_newsletters(newsletter_request_body(serde_json::json!(["newsletter"])))
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["MessageStream"], "broadcast");
+}
+
+// POST /admin/newsletters 指定したリストの確認済み購読者にだけ配信する
+#[tokio::test]
+async fn newsletters_are_delivered_only_to_confirmed_members_of_the_targeted_lists() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({"slug": "events", "name": "Events"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    create_confirmed_subscriber(&app, "name=Ursula&email=ursula%40example.com&list=events").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_newsletters(newsletter_request_body(serde_json::json!(["events"])))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 202);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "ursula@example.com");
+}
+
+// POST /admin/newsletters 複数のリストに所属していても1通だけ配信する
+#[tokio::test]
+async fn subscribers_in_several_targeted_lists_receive_a_single_email() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({"slug": "events", "name": "Events"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    create_confirmed_subscriber(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&list=events",
+    )
+    .await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_newsletters(newsletter_request_body(serde_json::json!([
+            "newsletter",
+            "events"
+        ])))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 202);
+}
+
+// POST /admin/newsletters 入力が不正な場合は400を返す
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                },
+                "lists": ["newsletter"]
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!", "lists": ["newsletter"]}),
+            "missing content",
+        ),
+        (newsletter_request_body(serde_json::json!([])), "no lists"),
+        (
+            newsletter_request_body(serde_json::json!(["newsletter", "unknown"])),
+            "an unknown list",
+        ),
+        (
+            scheduled_request_body(Utc::now() - Duration::minutes(1)),
+            "a past scheduled_for",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        // [Act]
+        let response = app.post_newsletters(invalid_body).await;
+
+        // [Assert]
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+// POST /admin/newsletters 認証情報がない、または誤っている場合は401を返す
+#[tokio::test]
+async fn requests_with_missing_or_invalid_credentials_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = newsletter_request_body(serde_json::json!(["newsletter"]));
+
+    // [Act]
+    let missing = reqwest::Client::new()
+        .post(format!("{}/admin/newsletters", &app.address))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    let invalid = reqwest::Client::new()
+        .post(format!("{}/admin/newsletters", &app.address))
+        .basic_auth(&app.admin_username, Some("wrong-password"))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // [Assert]
+    assert_eq!(401, missing.status().as_u16());
+    assert_eq!(401, invalid.status().as_u16());
+}
+
+// POST /admin/newsletters 予約した号は予約日時になるまで配信しない
+#[tokio::test]
+async fn scheduled_issues_are_not_delivered_before_they_are_due() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    schedule_issue(&app).await;
+    let enqueued = app.enqueue_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(enqueued, 0);
+}
+
+// POST /admin/newsletters 予約日時を過ぎた号はスケジューラが配信する
+#[tokio::test]
+async fn scheduled_issues_are_delivered_once_they_are_due() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    let issue_id = schedule_issue(&app).await;
+    make_issue_due(&app, &issue_id).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let enqueued = app.enqueue_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+    // 2回目の確認では同じ号を再度登録しない
+    let enqueued_again = app.enqueue_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(enqueued, 1);
+    assert_eq!(enqueued_again, 0);
+    let issue = sqlx::query!(
+        "SELECT status, published_at FROM newsletter_issues WHERE id = $1::text::uuid",
+        issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(issue.status, "published");
+    assert!(issue.published_at.is_some());
+    let delivery = sqlx::query!("SELECT status, sent_at FROM issue_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.status, "sent");
+    assert!(delivery.sent_at.is_some());
+}
+
+// POST /admin/newsletters 配信対象が解釈できなくなった号は失敗にし、公開日時を付けない
+#[tokio::test]
+async fn scheduled_issues_with_an_invalid_audience_are_marked_as_failed() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    let issue_id = schedule_issue(&app).await;
+    make_issue_due(&app, &issue_id).await;
+    // 保存後に配信対象のリストを空にして解釈できない状態にする
+    sqlx::query("UPDATE newsletter_issues SET lists = '{}', segment = NULL WHERE id = $1::uuid")
+        .bind(&issue_id)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let enqueued = app.enqueue_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(enqueued, 0);
+    let issue = sqlx::query!(
+        "SELECT status, published_at FROM newsletter_issues WHERE id = $1::text::uuid",
+        issue_id
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(issue.status, "failed");
+    assert!(issue.published_at.is_none());
+}
+
+// POST /admin/newsletters/{id}/cancel 取り消した号は配信しない
+#[tokio::test]
+async fn cancelled_issues_are_not_delivered() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    let issue_id = schedule_issue(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            &format!("/admin/newsletters/{}/cancel", issue_id),
+            serde_json::json!({}),
+        )
+        .await;
+    make_issue_due(&app, &issue_id).await;
+    let enqueued = app.enqueue_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(enqueued, 0);
+}
+
+// POST /admin/newsletters/{id}/cancel 予約中でない号は取り消せない
+#[tokio::test]
+async fn only_scheduled_issues_can_be_cancelled() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let issue_id = schedule_issue(&app).await;
+    let cancel_path = format!("/admin/newsletters/{}/cancel", issue_id);
+    app.post_admin(&cancel_path, serde_json::json!({}))
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // [Act]
+    let cancelled_twice = app.post_admin(&cancel_path, serde_json::json!({})).await;
+    let unknown = app
+        .post_admin(
+            &format!("/admin/newsletters/{}/cancel", uuid::Uuid::new_v4()),
+            serde_json::json!({}),
+        )
+        .await;
+
+    // [Assert]
+    assert_eq!(cancelled_twice.status().as_u16(), 409);
+    assert_eq!(unknown.status().as_u16(), 404);
+}
+
+// POST /admin/newsletters/{id}/reschedule 配信予定日時を変更できる
+#[tokio::test]
+async fn scheduled_issues_can_be_rescheduled() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    let issue_id = schedule_issue(&app).await;
+    make_issue_due(&app, &issue_id).await;
+    let reschedule_path = format!("/admin/newsletters/{}/reschedule", issue_id);
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let past = app
+        .post_admin(
+            &reschedule_path,
+            serde_json::json!({"scheduled_for": Utc::now() - Duration::hours(1)}),
+        )
+        .await;
+    let future = app
+        .post_admin(
+            &reschedule_path,
+            serde_json::json!({"scheduled_for": Utc::now() + Duration::days(3)}),
+        )
+        .await;
+    let enqueued = app.enqueue_due_issues().await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(past.status().as_u16(), 400);
+    assert_eq!(future.status().as_u16(), 200);
+    // 予約日時を先に延ばしたので配信されない
+    assert_eq!(enqueued, 0);
+}
+
+// 送信に失敗した配信は理由とともに記録する
+#[tokio::test]
+async fn failed_deliveries_are_recorded() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_newsletters(newsletter_request_body(serde_json::json!(["newsletter"])))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 202);
+    let delivery = sqlx::query!("SELECT status, failure_reason FROM issue_deliveries")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(delivery.status, "failed");
+    assert!(delivery.failure_reason.is_some());
+}
+
+// POST /admin/newsletters Markdownの本文からHTML版とテキスト版を生成して配信する
+#[tokio::test]
+async fn markdown_content_is_rendered_to_html_and_plain_text() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Weekly",
+            "content": {
+                "markdown": "Read [the docs](https://example.com/docs).\n\n<script>alert(1)</script>",
+            },
+            "lists": ["newsletter"]
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 202);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains(r#"<a href="https://example.com/docs""#));
+    assert!(!html.contains("<script>"));
+    let text = body["TextBody"].as_str().unwrap();
+    assert!(text.contains(
+        "Weekly\n\nRead the docs [1].\n\n[1] https://example.com/docs\n\nView in browser: "
+    ));
+}
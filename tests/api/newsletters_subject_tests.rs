@@ -0,0 +1,180 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// 確認済みの購読者を4人作り、半数でA/Bテストをする号を配信キューに登録する
+async fn publish_issue_with_subject_test(app: &TestApp) {
+    for i in 0..4 {
+        create_confirmed_subscriber(
+            app,
+            &format!("name=reader{}&email=reader{}%40example.com", i, i),
+        )
+        .await;
+    }
+    app.post_newsletters(serde_json::json!({
+        "title": "Weekly",
+        "content": { "markdown": "Hello" },
+        "lists": ["newsletter"],
+        "subject_test": { "subject_b": "Weekly B", "sample_percent": 50, "window_hours": 2 }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+}
+
+/// 配信キューを処理し、送ったメールの (宛先, 件名) を返す
+async fn dispatch(app: &TestApp) -> Vec<(String, String)> {
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+    let mut sent: Vec<_> = mock_guard
+        .received_requests()
+        .await
+        .into_iter()
+        .map(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            (
+                body["To"].as_str().unwrap().to_string(),
+                body["Subject"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect();
+    sent.sort();
+    sent
+}
+
+/// 判定の日時を過ぎたことにする
+async fn end_window(app: &TestApp) {
+    sqlx::query!("UPDATE subject_tests SET decide_at = now() - interval '1 minute'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+}
+
+// 抽出した購読者だけに件名AとBを半分ずつ送る
+#[tokio::test]
+async fn the_sample_receives_both_subjects() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    publish_issue_with_subject_test(&app).await;
+    let sent = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(sent.len(), 2);
+    let mut subjects: Vec<_> = sent.iter().map(|(_, subject)| subject.as_str()).collect();
+    subjects.sort();
+    assert_eq!(subjects, vec!["Weekly", "Weekly B"]);
+    // 勝者を決めるために開封を計測する
+    let track_opens = sqlx::query!("SELECT track_opens FROM newsletter_issues")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .track_opens;
+    assert!(track_opens);
+}
+
+// 計測時間が過ぎたら開封率の高い件名を残りの購読者に送る
+#[tokio::test]
+async fn the_winning_subject_is_sent_to_the_rest_of_the_audience() {
+    // [Arrange]
+    let app = spawn_app().await;
+    publish_issue_with_subject_test(&app).await;
+    let sample = dispatch(&app).await;
+    // 件名Bを受け取った購読者だけが開封する
+    let (b_recipient, _) = sample
+        .iter()
+        .find(|(_, subject)| subject == "Weekly B")
+        .unwrap();
+    sqlx::query!(
+        "INSERT INTO tracking_events (id, newsletter_issue_id, subscriber_id, kind, url, occurred_at) \
+        SELECT gen_random_uuid(), i.id, s.id, 'open', NULL, now() \
+        FROM newsletter_issues i, subscriptions s WHERE s.email = $1",
+        b_recipient
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // [Act]
+    let undecided = app.decide_subject_tests().await;
+    end_window(&app).await;
+    let decided = app.decide_subject_tests().await;
+    let rest = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(undecided, 0);
+    assert_eq!(decided, 1);
+    assert_eq!(rest.len(), 2);
+    assert!(rest.iter().all(|(_, subject)| subject == "Weekly B"));
+    assert!(rest.iter().all(|recipient| !sample.contains(recipient)));
+    let winner = sqlx::query!("SELECT winner FROM subject_tests")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .winner;
+    assert_eq!(winner.as_deref(), Some("b"));
+}
+
+// 開封率が同じ場合は件名Aを選ぶ
+#[tokio::test]
+async fn subject_a_wins_a_tie() {
+    // [Arrange]
+    let app = spawn_app().await;
+    publish_issue_with_subject_test(&app).await;
+    dispatch(&app).await;
+    end_window(&app).await;
+
+    // [Act]
+    app.decide_subject_tests().await;
+    let rest = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(rest.len(), 2);
+    assert!(rest.iter().all(|(_, subject)| subject == "Weekly"));
+}
+
+// POST /admin/newsletters 不正なA/Bテストの設定は400を返す
+#[tokio::test]
+async fn invalid_subject_tests_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (serde_json::json!({ "subject_b": " " }), "an empty subject"),
+        (
+            serde_json::json!({ "subject_b": "B", "sample_percent": 0 }),
+            "an empty sample",
+        ),
+        (
+            serde_json::json!({ "subject_b": "B", "sample_percent": 101 }),
+            "a sample over 100%",
+        ),
+        (
+            serde_json::json!({ "subject_b": "B", "window_hours": 0 }),
+            "no measurement window",
+        ),
+    ];
+    for (subject_test, description) in test_cases {
+        // [Act]
+        let response = app
+            .post_newsletters(serde_json::json!({
+                "title": "Weekly",
+                "content": { "markdown": "Hello" },
+                "lists": ["newsletter"],
+                "subject_test": subject_test
+            }))
+            .await;
+
+        // [Assert]
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject {}.",
+            description
+        );
+    }
+}
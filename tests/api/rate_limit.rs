@@ -0,0 +1,355 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, spawn_app_with, TestApp};
+use secrecy::Secret;
+use web_prod::circuit_breaker::CircuitState;
+use web_prod::configuration::{CircuitBreakerSettings, RateLimitSettings};
+use web_prod::domain::SubscriberEmail;
+use web_prod::email_client::SendEmailError;
+use web_prod::email_message::EmailMessage;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// 1日の上限を設定したメールクライアントに差し替える
+fn limit_daily_sends(app: &mut TestApp, daily_quota: u32) {
+    app.email_client = app
+        .email_client
+        .clone()
+        .with_rate_limit(&RateLimitSettings {
+            daily_quota: Some(daily_quota),
+            ..Default::default()
+        });
+}
+
+/// 確認済みの購読者を3人作り、号を配信キューに登録する
+async fn publish_issue_to_three_subscribers(app: &TestApp) {
+    for i in 0..3 {
+        create_confirmed_subscriber(
+            app,
+            &format!("name=reader{}&email=reader{}%40example.com", i, i),
+        )
+        .await;
+    }
+    app.publish_issue("Weekly", "Hello").await;
+}
+
+/// 配信キューを処理し、送ったメールの数を返す
+async fn dispatch(app: &TestApp) -> usize {
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+    mock_guard.received_requests().await.len()
+}
+
+async fn sent_count(app: &TestApp, provider: &str) -> Option<i32> {
+    sqlx::query!(
+        "SELECT sent_count FROM email_send_quotas WHERE provider = $1",
+        provider
+    )
+    .fetch_optional(&app.db_pool)
+    .await
+    .unwrap()
+    .map(|r| r.sent_count)
+}
+
+// 1日の上限に達したら、残りの配信は失敗にせずキューに残す
+#[tokio::test]
+async fn deliveries_over_the_daily_quota_stay_queued() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    limit_daily_sends(&mut app, 2);
+    publish_issue_to_three_subscribers(&app).await;
+
+    // [Act]
+    let sent = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(sent, 2);
+    assert_eq!(app.queued_deliveries().await, 1);
+    let sent_count = sqlx::query!("SELECT sent_count FROM email_send_quotas")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .sent_count;
+    assert_eq!(sent_count, 2);
+}
+
+// 送信数はデータベースに残るので、クライアントを作り直しても上限はリセットされない
+#[tokio::test]
+async fn the_daily_quota_survives_a_restart() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    limit_daily_sends(&mut app, 2);
+    publish_issue_to_three_subscribers(&app).await;
+    dispatch(&app).await;
+
+    // [Act]
+    limit_daily_sends(&mut app, 2);
+    let sent = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(sent, 0);
+    assert_eq!(app.queued_deliveries().await, 1);
+}
+
+// 日付が変わると残りの配信が送られる
+#[tokio::test]
+async fn queued_deliveries_are_sent_on_the_next_day() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    limit_daily_sends(&mut app, 2);
+    publish_issue_to_three_subscribers(&app).await;
+    dispatch(&app).await;
+    // 数えた送信を前日の分にする
+    sqlx::query!("UPDATE email_send_quotas SET day = day - 1")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // [Act]
+    let sent = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(sent, 1);
+    assert_eq!(app.queued_deliveries().await, 0);
+}
+
+// 1秒あたりの上限を超える分はエラーにせず、送信を待たせる
+#[tokio::test]
+async fn sends_over_the_per_second_limit_are_delayed() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    app.email_client = app
+        .email_client
+        .clone()
+        .with_rate_limit(&RateLimitSettings {
+            per_second: Some(10),
+            burst: Some(1),
+            daily_quota: None,
+        });
+    publish_issue_to_three_subscribers(&app).await;
+
+    // [Act]
+    let started = std::time::Instant::now();
+    let sent = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(sent, 3);
+    // 最初の1通の後は0.1秒に1通ずつ送る
+    assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+}
+
+// 主の送信サービスが1日の上限に達したら、残りは予備の送信サービスで送る
+#[tokio::test]
+async fn sends_over_the_primary_quota_go_to_the_fallback_provider() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    limit_daily_sends(&mut app, 1);
+    let fallback_server = MockServer::start().await;
+    app.email_client = app.email_client.clone().with_fallback_provider(
+        "fallback".into(),
+        fallback_server.uri(),
+        Secret::new("fallback-token".into()),
+        &RateLimitSettings::default(),
+    );
+    publish_issue_to_three_subscribers(&app).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&fallback_server)
+        .await;
+
+    // [Act]
+    let sent = dispatch(&app).await;
+
+    // [Assert]
+    assert_eq!(sent, 1);
+    assert_eq!(app.queued_deliveries().await, 0);
+    assert_eq!(sent_count(&app, "postmark").await, Some(1));
+}
+
+// 送信サービスが送れなかったメールは1日の送信数に数えない
+#[tokio::test]
+async fn failed_sends_do_not_use_up_the_daily_quota() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    limit_daily_sends(&mut app, 2);
+    publish_issue_to_three_subscribers(&app).await;
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    // [Act]
+    app.dispatch_all_pending_emails().await;
+
+    // [Assert]
+    assert_eq!(sent_count(&app, "postmark").await, Some(0));
+}
+
+// 確認メールなど購読者の操作に応じて送るメールも1日の送信数に数える
+#[tokio::test]
+async fn transactional_sends_count_against_the_daily_quota() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    limit_daily_sends(&mut app, 1);
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let message = || {
+        EmailMessage::new(
+            SubscriberEmail::parse("reader@example.com".into()).unwrap(),
+            "Welcome!",
+            "<p>Hi</p>",
+            "Hi",
+        )
+    };
+
+    // [Act]
+    let first = app.email_client.send_email(message()).await;
+    let second = app.email_client.send_email(message()).await;
+
+    // [Assert]
+    assert!(first.is_ok());
+    assert!(matches!(second, Err(SendEmailError::QuotaExhausted)));
+    assert_eq!(sent_count(&app, "postmark").await, Some(1));
+}
+
+// 1日の上限に達しているときは、回路が開いた送信サービスを試しに送る番を使わない
+#[tokio::test]
+async fn the_daily_quota_is_checked_before_the_circuit_breaker() {
+    // [Arrange]
+    let mut app = spawn_app().await;
+    limit_daily_sends(&mut app, 1);
+    // 1回失敗すると回路を開き、すぐに試しに送れるようにする
+    app.email_client = app
+        .email_client
+        .clone()
+        .with_circuit_breaker(&CircuitBreakerSettings {
+            failure_threshold: 1,
+            open_seconds: 0,
+        });
+    let message = || {
+        EmailMessage::new(
+            SubscriberEmail::parse("reader@example.com".into()).unwrap(),
+            "Welcome!",
+            "<p>Hi</p>",
+            "Hi",
+        )
+    };
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.email_client.send_email(message()).await.unwrap_err();
+    drop(mock_guard);
+    sqlx::query!("UPDATE email_send_quotas SET sent_count = 1")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // [Act]
+    let result = app.email_client.send_email(message()).await;
+
+    // [Assert]
+    assert!(matches!(result, Err(SendEmailError::QuotaExhausted)));
+    let circuit_breaker = app.email_client.providers()[0].circuit_breaker();
+    assert_eq!(circuit_breaker.state(), CircuitState::Open);
+    assert_eq!(circuit_breaker.counters().rejected, 0);
+}
+
+/// サーバのメールクライアントにも1日の上限を設定して起動する
+async fn spawn_app_with_daily_quota(daily_quota: u32) -> TestApp {
+    spawn_app_with(|c| c.email_client.rate_limit.daily_quota = Some(daily_quota)).await
+}
+
+async fn deferred_emails(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM deferred_emails"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+// 1日の上限に達しても購読の申し込みは受け付け、確認メールは日付が変わってから送る
+#[tokio::test]
+async fn confirmation_emails_over_the_daily_quota_are_sent_on_the_next_day() {
+    // [Arrange]
+    let app = spawn_app_with_daily_quota(1).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=first&email=first%40example.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // [Act]
+    let response = app
+        .post_subscriptions("name=second&email=second%40example.com".into())
+        .await;
+    // 同じ日のうちは送らない
+    app.send_deferred_emails().await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 1);
+    assert_eq!(deferred_emails(&app).await, 1);
+
+    // 数えた送信を前日の分にすると、後で送ることにした確認メールが送られる
+    sqlx::query!("UPDATE email_send_quotas SET day = day - 1")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.send_deferred_emails().await;
+    assert_eq!(deferred_emails(&app).await, 0);
+    let email_request = &app.email_server.received_requests().await.unwrap()[1];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["To"], "second@example.com");
+    let confirmation_links = app.get_confirmation_links(email_request);
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+// 1日の上限に達していればテスト送信は後で送り、後で送る数を返す
+#[tokio::test]
+async fn test_sends_over_the_daily_quota_are_deferred() {
+    // [Arrange]
+    let app = spawn_app_with_daily_quota(0).await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app
+        .post_admin(
+            "/admin/newsletters/test-send",
+            serde_json::json!({
+                "title": "Weekly",
+                "content": { "markdown": "Hello" }
+            }),
+        )
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(
+        body,
+        serde_json::json!({ "sent": 0, "deferred": 1, "suppressed": 0 })
+    );
+    assert_eq!(deferred_emails(&app).await, 1);
+}
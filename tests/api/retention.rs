@@ -0,0 +1,178 @@
+use crate::helpers::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app, TestApp,
+};
+use chrono::{Duration, Utc};
+use web_prod::retention::RetentionReport;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// 購読の申し込みとリストへの所属を指定した日数だけ過去にずらす
+async fn backdate_subscriber(app: &TestApp, email: &str, days: i64) {
+    let at = Utc::now() - Duration::days(days);
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = $1 WHERE email = $2",
+        at,
+        email
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        "UPDATE list_memberships SET joined_at = $1 \
+        WHERE subscriber_id = (SELECT id FROM subscriptions WHERE email = $2)",
+        at,
+        email
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn subscriber_emails(app: &TestApp) -> Vec<String> {
+    sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.email)
+        .collect()
+}
+
+async fn is_flagged_inactive(app: &TestApp) -> bool {
+    sqlx::query!(
+        r#"
+    SELECT EXISTS (
+        SELECT 1 FROM subscriber_tags t JOIN subscriptions s ON s.id = t.subscriber_id
+        WHERE s.email = 'ursula_le_guin@gmail.com' AND t.tag = 'inactive'
+    ) AS "exists!"
+            "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .exists
+}
+
+/// 1年前から購読している確認済みの購読者に、計測の設定を指定して号を配信する
+async fn deliver_issue_to_long_time_subscriber(app: &TestApp, tracking: serde_json::Value) {
+    create_confirmed_subscriber(app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    backdate_subscriber(app, "ursula_le_guin@gmail.com", 365).await;
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Weekly",
+        "content": { "markdown": "Hello" },
+        "lists": ["newsletter"],
+        "tracking": tracking
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+}
+
+// 設定した日数を過ぎても確認されない購読だけを削除する
+#[tokio::test]
+async fn stale_unconfirmed_subscribers_are_deleted() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app, "name=stale&email=stale%40example.com").await;
+    create_unconfirmed_subscriber(&app, "name=fresh&email=fresh%40example.com").await;
+    create_confirmed_subscriber(&app, "name=confirmed&email=confirmed%40example.com").await;
+    backdate_subscriber(&app, "stale@example.com", 31).await;
+    backdate_subscriber(&app, "confirmed@example.com", 31).await;
+
+    // [Act]
+    let report = app.apply_retention().await;
+
+    // [Assert]
+    assert_eq!(report.deleted_unconfirmed, 1);
+    assert_eq!(
+        subscriber_emails(&app).await,
+        vec!["confirmed@example.com", "fresh@example.com"]
+    );
+}
+
+// 開封を計測した号に反応のない購読者にはinactiveタグを付け、反応があれば外す
+#[tokio::test]
+async fn inactive_subscribers_are_flagged_until_they_engage_again() {
+    // [Arrange]
+    let app = spawn_app().await;
+    deliver_issue_to_long_time_subscriber(&app, serde_json::json!({ "opens": true })).await;
+
+    // [Act]
+    let flagged = app.apply_retention().await;
+    let flagged_again = app.apply_retention().await;
+    sqlx::query!(
+        "INSERT INTO tracking_events (id, newsletter_issue_id, subscriber_id, kind, url, occurred_at) \
+        SELECT gen_random_uuid(), i.id, s.id, 'open', NULL, now() \
+        FROM newsletter_issues i, subscriptions s"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let unflagged = app.apply_retention().await;
+
+    // [Assert]
+    assert_eq!(
+        flagged,
+        RetentionReport {
+            flagged_inactive: 1,
+            ..Default::default()
+        }
+    );
+    // 付いているタグは数え直さない
+    assert_eq!(flagged_again, RetentionReport::default());
+    assert_eq!(
+        unflagged,
+        RetentionReport {
+            unflagged_active: 1,
+            ..Default::default()
+        }
+    );
+    assert!(!is_flagged_inactive(&app).await);
+}
+
+// 開封を計測していない号しか受け取っていなければ、反応がないとは判断しない
+#[tokio::test]
+async fn subscribers_without_tracked_issues_are_not_flagged() {
+    // [Arrange]
+    let app = spawn_app().await;
+    deliver_issue_to_long_time_subscriber(&app, serde_json::json!({})).await;
+
+    // [Act]
+    let report = app.apply_retention().await;
+
+    // [Assert]
+    assert_eq!(report, RetentionReport::default());
+    assert!(!is_flagged_inactive(&app).await);
+}
+
+// 整理した件数の合計をメトリクスのカウンタとして返す
+#[tokio::test]
+async fn retention_counts_are_exported_as_metrics() {
+    // [Arrange]
+    let app = spawn_app().await;
+    deliver_issue_to_long_time_subscriber(&app, serde_json::json!({ "opens": true })).await;
+    create_unconfirmed_subscriber(&app, "name=stale&email=stale%40example.com").await;
+    backdate_subscriber(&app, "stale@example.com", 31).await;
+    app.apply_retention().await;
+
+    // [Act]
+    let metrics = reqwest::get(format!("{}/metrics", &app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert!(metrics.contains("# TYPE retention_deleted_unconfirmed_total counter"));
+    assert!(metrics.contains("\nretention_deleted_unconfirmed_total 1\n"));
+    assert!(metrics.contains("\nretention_flagged_inactive_total 1\n"));
+    assert!(metrics.contains("\nretention_unflagged_active_total 0\n"));
+}
@@ -1,4 +1,6 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{create_confirmed_subscriber, create_unconfirmed_subscriber, spawn_app};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
 
 // POST /subscriptions 成功時のテスト
 #[tokio::test]
@@ -7,6 +9,12 @@ async fn subscribe_returns_a_200_for_valid_form_data() {
     let app = spawn_app().await;
     let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
 
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
     // [Act]
     let response = app.post_subscriptions(body.into()).await;
 
@@ -73,3 +81,278 @@ async fn subscribe_returns_a_400_when_fields_are_present_but_invalid() {
         );
     }
 }
+
+// POST /subscriptions 確認リンク付きのメールを送信するテスト
+#[tokio::test]
+async fn subscribe_sends_a_confirmation_email_with_a_link() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.post_subscriptions(body.into()).await;
+
+    // [Assert]
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    // HTML版とテキスト版のリンクは同じ
+    assert_eq!(confirmation_links.html, confirmation_links.plain_text);
+}
+
+// POST /subscriptions 確認メールはtransactionalのメッセージストリームで送る
+#[tokio::test]
+async fn the_confirmation_email_is_sent_through_the_transactional_stream() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.post_subscriptions(body.into()).await;
+
+    // [Assert]
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(body["MessageStream"], "outbound");
+}
+
+// POST /subscriptions 確認メールは購読者の名前で呼びかける
+#[tokio::test]
+async fn the_confirmation_email_greets_the_subscriber_by_name() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = "name=Tom%20%26%20Jerry&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    app.post_subscriptions(body.into()).await;
+
+    // [Assert]
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    // HTML版では名前がエスケープされる
+    assert!(body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains("Hi Tom &amp; Jerry,"));
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .starts_with("Hi Tom & Jerry,"));
+}
+
+// POST /subscriptions listを省略した場合は既定のリストに確認待ちで登録される
+#[tokio::test]
+async fn subscribe_adds_a_pending_membership_to_the_default_list() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    // [Assert]
+    let saved = sqlx::query!(
+        r#"
+        SELECT l.slug, m.status
+        FROM list_memberships m JOIN lists l ON l.id = m.list_id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved membership.");
+    assert_eq!(saved.slug, "newsletter");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+// POST /subscriptions 同じアドレスで別のリストを購読すると購読者は1人のまま所属が増える
+#[tokio::test]
+async fn subscribing_to_a_second_list_reuses_the_existing_subscriber() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({"slug": "events", "name": "Events"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    // [Act]
+    create_unconfirmed_subscriber(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&list=events",
+    )
+    .await;
+
+    // [Assert]
+    let subscribers = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM subscriptions"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 1);
+    let memberships = sqlx::query!(
+        r#"
+        SELECT l.slug, m.status
+        FROM list_memberships m JOIN lists l ON l.id = m.list_id
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(memberships.len(), 2);
+    assert_eq!(memberships[0].slug, "events");
+    assert_eq!(memberships[0].status, "pending_confirmation");
+    assert_eq!(memberships[1].slug, "newsletter");
+    assert_eq!(memberships[1].status, "confirmed");
+}
+
+// POST /subscriptions 確認済みのリストを再度購読してもメールは送らない
+#[tokio::test]
+async fn subscribing_again_to_a_confirmed_list_does_not_send_an_email() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    create_confirmed_subscriber(&app, body).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = app.post_subscriptions(body.into()).await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+}
+
+// POST /subscriptions 存在しないリストや不正なスラッグを指定した場合は400を返す
+#[tokio::test]
+async fn subscribe_returns_a_400_when_the_list_is_unknown_or_invalid() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            "name=Ursula&email=ursula_le_guin%40gmail.com&list=unknown",
+            "an unknown list",
+        ),
+        (
+            "name=Ursula&email=ursula_le_guin%40gmail.com&list=Not%20A%20Slug",
+            "an invalid list slug",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // [Act]
+        let response = app.post_subscriptions(body.into()).await;
+
+        // [Assert]
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload was {}.",
+            description
+        );
+    }
+}
+
+// POST /subscriptions 設定で定義された追加属性を保存する
+#[tokio::test]
+async fn subscribe_persists_the_configured_attributes() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    create_unconfirmed_subscriber(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&company=Acme&signup_source=",
+    )
+    .await;
+
+    // [Assert]
+    let saved = sqlx::query!("SELECT attributes FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    // 空の値は保存しない
+    assert_eq!(saved.attributes, serde_json::json!({"company": "Acme"}));
+}
+
+// POST /subscriptions 既存の購読者の属性は、確認リンクで確認されるまで変えない
+#[tokio::test]
+async fn subscribing_an_existing_address_changes_attributes_only_after_confirmation() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({"slug": "events", "name": "Events"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    create_confirmed_subscriber(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&company=Acme",
+    )
+    .await;
+    let attributes = || async {
+        sqlx::query!("SELECT attributes FROM subscriptions")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .attributes
+    };
+
+    // [Act]
+    // 別のリストへの申し込みとして、他人が属性を書き換えようとする
+    let confirmation_links = create_unconfirmed_subscriber(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&list=events&company=Evil&plan=pro",
+    )
+    .await;
+    let before_confirmation = attributes().await;
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let after_confirmation = attributes().await;
+
+    // [Assert]
+    assert_eq!(before_confirmation, serde_json::json!({"company": "Acme"}));
+    assert_eq!(
+        after_confirmation,
+        serde_json::json!({"company": "Evil", "plan": "pro"})
+    );
+}
+
+// POST /subscriptions 定義されていない追加フィールドは400を返す
+#[tokio::test]
+async fn subscribe_returns_a_400_for_unknown_attributes() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&favourite_colour=blue";
+
+    // [Act]
+    let response = app.post_subscriptions(body.into()).await;
+
+    // [Assert]
+    assert_eq!(400, response.status().as_u16());
+}
@@ -0,0 +1,196 @@
+use crate::helpers::{create_unconfirmed_subscriber, spawn_app, TestApp};
+
+// GET /subscriptions/confirm トークンがない場合は400を返す
+#[tokio::test]
+async fn confirmations_without_token_are_rejected_with_a_400() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = reqwest::get(format!("{}/subscriptions/confirm", app.address))
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+// GET /subscriptions/confirm 不明なトークンの場合は401を返す
+#[tokio::test]
+async fn confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=unknown",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+// 確認リンクをクリックすると、そのリストへの所属だけが確認済みになる
+#[tokio::test]
+async fn clicking_on_the_confirmation_link_confirms_only_that_list() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.post_lists(serde_json::json!({"slug": "events", "name": "Events"}))
+        .await
+        .error_for_status()
+        .unwrap();
+    create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+    let confirmation_links = create_unconfirmed_subscriber(
+        &app,
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&list=events",
+    )
+    .await;
+
+    // [Act]
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let memberships = sqlx::query!(
+        r#"
+        SELECT l.slug, m.status
+        FROM list_memberships m JOIN lists l ON l.id = m.list_id
+        ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(memberships[0].slug, "events");
+    assert_eq!(memberships[0].status, "confirmed");
+    assert_eq!(memberships[1].slug, "newsletter");
+    assert_eq!(memberships[1].status, "pending_confirmation");
+}
+
+// GET /subscriptions/confirm 有効期限の切れたトークンは401を返す
+#[tokio::test]
+async fn confirmations_with_an_expired_token_are_rejected_with_a_401() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let confirmation_links =
+        create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .await;
+    let issued_at =
+        chrono::Utc::now() - app.confirmation.token_lifetime() - chrono::Duration::minutes(1);
+    sqlx::query!("UPDATE subscription_tokens SET created_at = $1", issued_at)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // [Act]
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        app.membership_status("ursula_le_guin@gmail.com").await,
+        "pending_confirmation"
+    );
+}
+
+/// 購読者の配信停止リンクをクリックする
+async fn unsubscribe(app: &TestApp) {
+    let unsubscribe_token = sqlx::query!("SELECT unsubscribe_token FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .unsubscribe_token;
+    reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+        app.address, unsubscribe_token
+    ))
+    .await
+    .unwrap()
+    .error_for_status()
+    .unwrap();
+}
+
+// 確認リンクは一度しか使えず、配信停止した後にクリックし直しても購読は再開しない
+#[tokio::test]
+async fn a_used_confirmation_link_does_not_resubscribe_after_unsubscribing() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let confirmation_links =
+        create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .await;
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    unsubscribe(&app).await;
+
+    // [Act]
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        app.membership_status("ursula_le_guin@gmail.com").await,
+        "unsubscribed"
+    );
+}
+
+// 使っていない古い確認リンクでも、配信停止した所属は確認済みに戻らない
+#[tokio::test]
+async fn an_unused_confirmation_link_does_not_resubscribe_after_unsubscribing() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let first_links =
+        create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .await;
+    let second_links =
+        create_unconfirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com")
+            .await;
+    reqwest::get(second_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    unsubscribe(&app).await;
+
+    // [Act]
+    reqwest::get(first_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(
+        app.membership_status("ursula_le_guin@gmail.com").await,
+        "unsubscribed"
+    );
+}
+
+// 配信停止した後に購読し直した場合は、新しい確認リンクで確認済みに戻る
+#[tokio::test]
+async fn resubscribing_after_unsubscribing_can_be_confirmed_again() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    let first_links = create_unconfirmed_subscriber(&app, body).await;
+    reqwest::get(first_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    unsubscribe(&app).await;
+
+    // [Act]
+    let second_links = create_unconfirmed_subscriber(&app, body).await;
+    let status_before_confirmation = app.membership_status("ursula_le_guin@gmail.com").await;
+    let response = reqwest::get(second_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(status_before_confirmation, "pending_confirmation");
+    assert_eq!(
+        app.membership_status("ursula_le_guin@gmail.com").await,
+        "confirmed"
+    );
+}
@@ -0,0 +1,329 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// 購読者を1人登録する
+async fn create_subscriber(app: &TestApp) {
+    create_confirmed_subscriber(app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+}
+
+/// モックサーバが受け取ったリクエストのうち、指定したアドレス宛の最後のものを返す
+async fn email_request_to(app: &TestApp, recipient: &str) -> wiremock::Request {
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .rev()
+        .find(|r| {
+            let body: serde_json::Value = serde_json::from_slice(&r.body).unwrap();
+            body["To"] == recipient
+        })
+        .expect("No email was sent to the recipient.")
+}
+
+/// 変更を申請し、現在のアドレスに届いた承認リンクをクリックして、新しいアドレスへの確認リンクを返す
+async fn request_and_authorize_email_change(app: &TestApp) -> reqwest::Url {
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+    app.post_email_change(body.into()).await;
+    let email_request = email_request_to(app, "ursula_le_guin@gmail.com").await;
+    let authorization_links = app.get_confirmation_links(&email_request);
+    let response = reqwest::get(authorization_links.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+
+    let email_request = email_request_to(app, "ursula@example.com").await;
+    app.get_confirmation_links(&email_request).html
+}
+
+// POST /subscriptions/email_change 現在のアドレスにだけ承認のリンクを送る
+#[tokio::test]
+async fn email_change_sends_an_authorization_link_to_the_current_address_only() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+    let response = app.post_email_change(body.into()).await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    let email_request = email_request_to(&app, "ursula_le_guin@gmail.com").await;
+    let authorization_links = app.get_confirmation_links(&email_request);
+    assert_eq!(authorization_links.html, authorization_links.plain_text);
+    assert_eq!(
+        authorization_links.html.path(),
+        "/subscriptions/email_change/authorize"
+    );
+}
+
+// 承認リンクをクリックすると新しいアドレスに確認リンクを送る
+#[tokio::test]
+async fn authorizing_the_change_sends_a_verification_to_the_new_address() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let confirmation_link = request_and_authorize_email_change(&app).await;
+
+    // [Assert]
+    assert_eq!(
+        confirmation_link.path(),
+        "/subscriptions/email_change/confirm"
+    );
+}
+
+// 現在のアドレスで承認されていない申請は、確認トークンを知っていても切り替えられない
+#[tokio::test]
+async fn an_unauthorized_email_change_is_rejected_with_a_401() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+    app.post_email_change(body.into()).await;
+    let email_change_token = sqlx::query!("SELECT email_change_token FROM email_change_requests")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the email change request.")
+        .email_change_token;
+
+    // [Act]
+    let response = reqwest::get(format!(
+        "{}/subscriptions/email_change/confirm?email_change_token={}",
+        app.address, email_change_token
+    ))
+    .await
+    .unwrap();
+
+    // [Assert]
+    assert_eq!(401, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+// 有効期限が切れた申請は承認できない
+#[tokio::test]
+async fn an_expired_email_change_cannot_be_authorized() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+    app.post_email_change(body.into()).await;
+    let email_request = email_request_to(&app, "ursula_le_guin@gmail.com").await;
+    let authorization_links = app.get_confirmation_links(&email_request);
+    sqlx::query!("UPDATE email_change_requests SET requested_at = now() - interval '30 days'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // [Act]
+    let response = reqwest::get(authorization_links.html).await.unwrap();
+
+    // [Assert]
+    assert_eq!(401, response.status().as_u16());
+}
+
+// 確認リンクをクリックするまでアドレスは変わらず、クリック後に切り替わる
+#[tokio::test]
+async fn clicking_on_the_verification_link_changes_the_subscriber_email() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    let confirmation_link = request_and_authorize_email_change(&app).await;
+
+    // 確認前は元のアドレスのまま
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+
+    // [Act]
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, name FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula@example.com");
+    assert_eq!(saved.name, "le guin");
+}
+
+// 新しいアドレスが既に購読済みでも200を返し、メールは送らない
+#[tokio::test]
+async fn email_change_to_an_already_subscribed_address_returns_a_200_and_sends_nothing() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+    create_confirmed_subscriber(&app, "name=Ursula&email=ursula%40example.com").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let body = "email=ursula_le_guin%40gmail.com&new_email=ursula%40example.com";
+    let response = app.post_email_change(body.into()).await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+    let requests = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM email_change_requests")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(requests.count, 0);
+}
+
+// 購読されていないアドレスからの申請でも200を返し、メールは送らない
+#[tokio::test]
+async fn email_change_from_an_unknown_address_returns_a_200_and_sends_nothing() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let body = "email=nobody%40gmail.com&new_email=ursula%40example.com";
+    let response = app.post_email_change(body.into()).await;
+
+    // [Assert]
+    assert_eq!(200, response.status().as_u16());
+}
+
+// 申請後に新しいアドレスで購読された場合は確認時に409を返す
+#[tokio::test]
+async fn verification_returns_a_409_when_the_new_address_was_taken_in_the_meantime() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount_as_scoped(&app.email_server)
+        .await;
+
+    let confirmation_link = request_and_authorize_email_change(&app).await;
+    drop(mock_guard);
+
+    create_confirmed_subscriber(&app, "name=Ursula&email=ursula%40example.com").await;
+
+    // [Act]
+    let response = reqwest::get(confirmation_link).await.unwrap();
+
+    // [Assert]
+    assert_eq!(409, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions WHERE name = 'le guin'")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "ursula_le_guin@gmail.com");
+}
+
+// POST /subscriptions/email_change 入力が不正な場合のテスト
+#[tokio::test]
+async fn email_change_rejects_invalid_requests() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_subscriber(&app).await;
+    let test_cases = vec![
+        (
+            "email=ursula_le_guin%40gmail.com",
+            400,
+            "missing the new email",
+        ),
+        (
+            "email=ursula_le_guin%40gmail.com&new_email=not-an-email",
+            400,
+            "invalid new email",
+        ),
+        (
+            "email=ursula_le_guin%40gmail.com&new_email=ursula_le_guin%40gmail.com",
+            400,
+            "the same email",
+        ),
+    ];
+
+    for (body, expected_status, description) in test_cases {
+        // [Act]
+        let response = app.post_email_change(body.into()).await;
+
+        // [Assert]
+        assert_eq!(
+            expected_status,
+            response.status().as_u16(),
+            "The API did not return {} when the payload was {}.",
+            expected_status,
+            description
+        );
+    }
+}
+
+// 不明なトークンで確認した場合は401を返す
+#[tokio::test]
+async fn verification_with_an_unknown_token_is_rejected_with_a_401() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = reqwest::get(format!(
+        "{}/subscriptions/email_change/confirm?email_change_token=unknown",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // [Assert]
+    assert_eq!(401, response.status().as_u16());
+}
@@ -0,0 +1,118 @@
+use crate::helpers::{create_confirmed_subscriber, spawn_app, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+/// 既定のリストにニュースレターを配信する
+async fn publish_newsletter(app: &TestApp) {
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        },
+        "lists": ["newsletter"]
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+}
+
+// ニュースレターは購読者の名前で呼びかけ、配信停止リンクを含む
+#[tokio::test]
+async fn newsletters_greet_the_subscriber_and_contain_an_unsubscribe_link() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    publish_newsletter(&app).await;
+
+    // [Assert]
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert!(body["HtmlBody"].as_str().unwrap().contains("Hi le guin,"));
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .starts_with("Hi le guin,"));
+    let links = app.get_links_to(&email_request, "/subscriptions/unsubscribe");
+    assert_eq!(links.html, links.plain_text);
+}
+
+// GET /subscriptions/unsubscribe 配信停止した購読者には以後配信しない
+#[tokio::test]
+async fn unsubscribed_subscribers_no_longer_receive_newsletters() {
+    // [Arrange]
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app, "name=le%20guin&email=ursula_le_guin%40gmail.com").await;
+
+    let mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let unsubscribe_link = app
+        .get_links_to(&email_request, "/subscriptions/unsubscribe")
+        .html;
+    drop(mock_guard);
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // [Act]
+    let response = reqwest::get(unsubscribe_link).await.unwrap();
+    publish_newsletter(&app).await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    let membership = sqlx::query!("SELECT status FROM list_memberships")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(membership.status, "unsubscribed");
+}
+
+// GET /subscriptions/unsubscribe 不明なトークンの場合は401を返す
+#[tokio::test]
+async fn unsubscribing_with_an_unknown_token_is_rejected_with_a_401() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = reqwest::get(format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token=unknown",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 401);
+}
@@ -0,0 +1,225 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+
+/// 本文から指定したパスで始まるリンクを取り出し、テスト用のポートを設定する
+fn tracking_links(app: &TestApp, body: &str, prefix: &str) -> Vec<reqwest::Url> {
+    linkify::LinkFinder::new()
+        .links(body)
+        .filter_map(|l| reqwest::Url::parse(l.as_str()).ok())
+        .filter(|l| l.path().starts_with(prefix))
+        .map(|mut l| {
+            assert_eq!(l.host_str().unwrap(), "127.0.0.1");
+            l.set_port(Some(app.port)).unwrap();
+            l
+        })
+        .collect()
+}
+
+async fn tracking_events(app: &TestApp) -> Vec<(String, Option<String>)> {
+    sqlx::query!(
+        r#"
+    SELECT e.kind, e.url
+    FROM tracking_events e
+    JOIN subscriptions s ON s.id = e.subscriber_id
+    JOIN newsletter_issues i ON i.id = e.newsletter_issue_id
+    WHERE s.email = 'ursula_le_guin@gmail.com' AND i.title = 'Weekly'
+    ORDER BY e.occurred_at
+            "#,
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| (r.kind, r.url))
+    .collect()
+}
+
+// 計測を指定しない号ではリンクを置き換えず、画像も入れない
+#[tokio::test]
+async fn issues_are_not_tracked_by_default() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let body = app.deliver_issue(serde_json::json!({})).await;
+
+    // [Assert]
+    let html = body["HtmlBody"].as_str().unwrap();
+    assert!(html.contains(r#"href="https://example.com/docs?a=1&amp;b=2""#));
+    assert!(tracking_links(&app, html, "/t/").is_empty());
+}
+
+// GET /t/o/{token} 開封を記録して1x1の画像を返す
+#[tokio::test]
+async fn the_open_pixel_records_an_open() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = app
+        .deliver_issue(serde_json::json!({ "opens": true }))
+        .await;
+    let pixels = tracking_links(&app, body["HtmlBody"].as_str().unwrap(), "/t/o/");
+    assert_eq!(pixels.len(), 1);
+
+    // [Act]
+    let response = reqwest::get(pixels[0].clone()).await.unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["Content-Type"], "image/gif");
+    assert_eq!(response.headers()["Cache-Control"], "no-store");
+    assert_eq!(
+        tracking_events(&app).await,
+        vec![("open".to_string(), None)]
+    );
+}
+
+// GET /t/c/{token} クリックを記録して元のURLにリダイレクトする
+#[tokio::test]
+async fn click_links_record_the_click_and_redirect() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = app
+        .deliver_issue(serde_json::json!({ "clicks": true }))
+        .await;
+    let html = body["HtmlBody"].as_str().unwrap();
+    // 本文のリンクだけを置き換え、配信停止などのリンクやテキスト版はそのままにする
+    assert!(!html.contains("https://example.com/docs"));
+    assert!(html.contains("/subscriptions/unsubscribe?unsubscribe_token="));
+    assert!(body["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("[1] https://example.com/docs?a=1&b=2"));
+    let links = tracking_links(&app, html, "/t/c/");
+    assert_eq!(links.len(), 1);
+    assert!(tracking_links(&app, html, "/t/o/").is_empty());
+
+    // [Act]
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(links[0].clone())
+        .send()
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 302);
+    assert_eq!(
+        response.headers()["Location"],
+        "https://example.com/docs?a=1&b=2"
+    );
+    assert_eq!(
+        tracking_events(&app).await,
+        vec![(
+            "click".to_string(),
+            Some("https://example.com/docs?a=1&b=2".to_string())
+        )]
+    );
+}
+
+// GET /t/c/{token} 署名済みでも、リンク先はヘッダに入れられる形に正規化してリダイレクトする
+#[tokio::test]
+async fn click_links_redirect_to_the_normalized_url() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let mut link = reqwest::Url::parse(&app.tracking_links.click_url(
+        Uuid::new_v4(),
+        Uuid::new_v4(),
+        "https://例え.jp/パス?q=値",
+    ))
+    .unwrap();
+    link.set_port(Some(app.port)).unwrap();
+
+    // [Act]
+    let response = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+        .get(link)
+        .send()
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 302);
+    assert_eq!(
+        response.headers()["Location"],
+        "https://xn--r8jz45g.jp/%E3%83%91%E3%82%B9?q=%E5%80%A4"
+    );
+}
+
+// GET /t/c/{token} 署名済みでも、http(s)以外やURLとして不正なリンク先にはリダイレクトしない
+#[tokio::test]
+async fn click_links_to_non_http_urls_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let test_cases = vec![
+        ("javascript:alert(1)", "a javascript URL"),
+        ("mailto:ursula@example.com", "a mailto URL"),
+        ("not a url", "an invalid URL"),
+    ];
+
+    for (url, description) in test_cases {
+        let mut link = reqwest::Url::parse(&app.tracking_links.click_url(
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            url,
+        ))
+        .unwrap();
+        link.set_port(Some(app.port)).unwrap();
+
+        // [Act]
+        let response = reqwest::get(link).await.unwrap();
+
+        // [Assert]
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject {}.",
+            description
+        );
+    }
+}
+
+// 改ざんされたトークンや種類の異なるトークンは400を返し、記録しない
+#[tokio::test]
+async fn tampered_or_mismatched_tokens_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let body = app
+        .deliver_issue(serde_json::json!({ "opens": true, "clicks": true }))
+        .await;
+    let html = body["HtmlBody"].as_str().unwrap();
+    let click_link = tracking_links(&app, html, "/t/c/").pop().unwrap();
+    let open_link = tracking_links(&app, html, "/t/o/").pop().unwrap();
+    let click_token = click_link.path().trim_start_matches("/t/c/");
+    let open_token = open_link.path().trim_start_matches("/t/o/");
+    let (_, signature) = click_token.split_once('.').unwrap();
+    let (payload, _) = open_token.split_once('.').unwrap();
+
+    let test_cases = vec![
+        (
+            format!("/t/c/{}.{}", payload, signature),
+            "a payload with another signature",
+        ),
+        (format!("/t/c/{}", payload), "an unsigned payload"),
+        (format!("/t/c/{}", open_token), "an open token"),
+        (format!("/t/o/{}", click_token), "a click token"),
+    ];
+    for (path, description) in test_cases {
+        // [Act]
+        let response = reqwest::get(format!("{}{}", app.address, path))
+            .await
+            .unwrap();
+
+        // [Assert]
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not reject {}.",
+            description
+        );
+    }
+    assert!(tracking_events(&app).await.is_empty());
+}
@@ -0,0 +1,301 @@
+use crate::helpers::{spawn_app, TestApp, MESSAGE_ID};
+use secrecy::ExposeSecret;
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+fn bounce(id: i64, kind: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "ID": id,
+        "Type": kind,
+        "TypeCode": 1,
+        "Name": "Hard bounce",
+        "MessageID": MESSAGE_ID,
+        "Description": "The server was unable to deliver your message.",
+        "Email": EMAIL,
+        "BouncedAt": "2023-11-20T09:06:12Z",
+        "Inactive": kind == "HardBounce"
+    })
+}
+
+async fn suppression_reason(app: &TestApp) -> Option<String> {
+    sqlx::query!("SELECT reason FROM suppressions WHERE email = $1", EMAIL)
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap()
+        .map(|r| r.reason)
+}
+
+async fn delivery(app: &TestApp) -> (String, Option<String>, bool) {
+    let r = sqlx::query!(
+        "SELECT status, message_id, delivered_at FROM issue_deliveries WHERE subscriber_email = $1",
+        EMAIL
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    (r.status, r.message_id, r.delivered_at.is_some())
+}
+
+// 送信時にPostmarkのMessageIDを配信タスクに記録する
+#[tokio::test]
+async fn deliveries_record_the_postmark_message_id() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    app.deliver_issue(serde_json::json!({})).await;
+
+    // [Assert]
+    assert_eq!(
+        delivery(&app).await,
+        ("sent".into(), Some(MESSAGE_ID.into()), false)
+    );
+}
+
+// POST /webhooks/postmark ハードバウンスした購読者には以降配信しない
+#[tokio::test]
+async fn a_hard_bounce_stops_future_deliveries() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+
+    // [Act]
+    let response = app.post_postmark_webhook(bounce(42, "HardBounce")).await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(delivery(&app).await.0, "bounced");
+    assert_eq!(app.membership_status(EMAIL).await, "bounced");
+    assert_eq!(
+        suppression_reason(&app).await.as_deref(),
+        Some("hard_bounce")
+    );
+    app.publish_issue("Weekly", "Hello").await;
+    assert_eq!(app.queued_deliveries().await, 0);
+}
+
+// POST /webhooks/postmark バウンスしたアドレスの大文字小文字が違っても購読者の配信を止める
+#[tokio::test]
+async fn a_hard_bounce_matches_the_subscriber_case_insensitively() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+    let mut event = bounce(42, "HardBounce");
+    event["Email"] = EMAIL.to_uppercase().into();
+
+    // [Act]
+    let response = app.post_postmark_webhook(event).await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.membership_status(EMAIL).await, "bounced");
+    app.publish_issue("Weekly", "Hello").await;
+    assert_eq!(app.queued_deliveries().await, 0);
+}
+
+// POST /webhooks/postmark 一時的なバウンスでは購読者の状態を変えない
+#[tokio::test]
+async fn a_soft_bounce_is_recorded_but_keeps_the_subscriber() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+
+    // [Act]
+    let response = app.post_postmark_webhook(bounce(42, "SoftBounce")).await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(delivery(&app).await.0, "bounced");
+    assert_eq!(app.membership_status(EMAIL).await, "confirmed");
+    assert_eq!(suppression_reason(&app).await, None);
+}
+
+// POST /webhooks/postmark 迷惑メールとして報告した購読者には以降配信しない
+#[tokio::test]
+async fn a_spam_complaint_stops_future_deliveries() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+
+    // [Act]
+    let response = app
+        .post_postmark_webhook(serde_json::json!({
+            "RecordType": "SpamComplaint",
+            "ID": 43,
+            "Type": "SpamComplaint",
+            "TypeCode": 512,
+            "MessageID": MESSAGE_ID,
+            "Email": EMAIL,
+            "BouncedAt": "2023-11-20T09:07:12Z",
+            "Inactive": true
+        }))
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.membership_status(EMAIL).await, "complained");
+    assert_eq!(
+        suppression_reason(&app).await.as_deref(),
+        Some("spam_complaint")
+    );
+    app.publish_issue("Weekly", "Hello").await;
+    assert_eq!(app.queued_deliveries().await, 0);
+}
+
+// POST /webhooks/postmark 配信完了の日時を記録する
+#[tokio::test]
+async fn a_delivery_event_is_recorded() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+
+    // [Act]
+    let response = app
+        .post_postmark_webhook(serde_json::json!({
+            "RecordType": "Delivery",
+            "ServerID": 23,
+            "MessageStream": "outbound",
+            "MessageID": MESSAGE_ID,
+            "Recipient": EMAIL,
+            "Tag": "",
+            "DeliveredAt": "2023-11-20T09:05:30Z",
+            "Details": "Test delivery webhook details"
+        }))
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        delivery(&app).await,
+        ("sent".into(), Some(MESSAGE_ID.into()), true)
+    );
+}
+
+// POST /webhooks/postmark 同じイベントが再送されても一度だけ処理する
+#[tokio::test]
+async fn redelivered_events_are_processed_once() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+    app.post_postmark_webhook(bounce(42, "HardBounce"))
+        .await
+        .error_for_status()
+        .unwrap();
+    // 処理済みであることが分かるように、購読者の状態を戻しておく
+    sqlx::query!("UPDATE list_memberships SET status = 'confirmed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // [Act]
+    let response = app.post_postmark_webhook(bounce(42, "HardBounce")).await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.membership_status(EMAIL).await, "confirmed");
+    let events = sqlx::query!(r#"SELECT COUNT(*) AS "count!" FROM postmark_events"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(events.count, 1);
+}
+
+// POST /webhooks/postmark 扱わない種類のイベントは無視して200を返す
+#[tokio::test]
+async fn unhandled_record_types_are_acknowledged() {
+    // [Arrange]
+    let app = spawn_app().await;
+
+    // [Act]
+    let response = app
+        .post_postmark_webhook(serde_json::json!({
+            "RecordType": "Open",
+            "MessageID": MESSAGE_ID,
+            "Recipient": EMAIL
+        }))
+        .await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+// POST /webhooks/postmark 必須の項目がないイベントは400を返す
+#[tokio::test]
+async fn malformed_events_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+    let mut event = bounce(42, "HardBounce");
+    event.as_object_mut().unwrap().remove("Email");
+
+    // [Act]
+    let response = app.post_postmark_webhook(event).await;
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+// POST /webhooks/postmark 共有の鍵のヘッダでも認証できる
+#[tokio::test]
+async fn the_shared_secret_header_is_accepted() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+    let secret = app.postmark_webhook.secret.expose_secret().clone();
+
+    // [Act]
+    let response = reqwest::Client::new()
+        .post(format!("{}/webhooks/postmark", app.address))
+        .header("X-Postmark-Webhook-Secret", secret)
+        .json(&bounce(42, "HardBounce"))
+        .send()
+        .await
+        .unwrap();
+
+    // [Assert]
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(app.membership_status(EMAIL).await, "bounced");
+}
+
+// POST /webhooks/postmark 認証できないリクエストは401を返し、処理しない
+#[tokio::test]
+async fn unauthenticated_requests_are_rejected() {
+    // [Arrange]
+    let app = spawn_app().await;
+    app.deliver_issue(serde_json::json!({})).await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/webhooks/postmark", app.address);
+    let test_cases = vec![
+        (client.post(&url), "no credentials"),
+        (
+            client
+                .post(&url)
+                .header("X-Postmark-Webhook-Secret", "wrong"),
+            "a wrong secret",
+        ),
+        (
+            client
+                .post(&url)
+                .basic_auth(&app.postmark_webhook.username, Some("wrong")),
+            "a wrong password",
+        ),
+    ];
+
+    for (request, description) in test_cases {
+        // [Act]
+        let response = request
+            .json(&bounce(42, "HardBounce"))
+            .send()
+            .await
+            .unwrap();
+
+        // [Assert]
+        assert_eq!(
+            response.status().as_u16(),
+            401,
+            "The API did not reject {}.",
+            description
+        );
+    }
+    assert_eq!(app.membership_status(EMAIL).await, "confirmed");
+}